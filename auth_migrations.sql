@@ -32,6 +32,7 @@ CREATE TABLE IF NOT EXISTS permissions (
 
 CREATE INDEX IF NOT EXISTS permissions_by_role_and_db ON permissions (role_id, database_name);
 
+-- superseded by refresh_tokens and access_tokens, see AuthDatabase::MIGRATIONS
 CREATE TABLE IF NOT EXISTS tokens (
     id INTEGER PRIMARY KEY,
     user_id INTEGER,
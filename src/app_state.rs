@@ -1,31 +1,82 @@
 use std::{
     env::temp_dir,
+    net::SocketAddr,
     path::{Path, PathBuf},
+    str::FromStr,
     sync::Arc,
+    time::Duration,
 };
 
-use axum::extract::FromRef;
+use axum::{extract::FromRef, http::HeaderValue};
+use axum_extra::extract::cookie::SameSite;
 
-use crate::database::changes::ChangeManager;
+use crate::{
+    audit::{AuditLog, FileAuditLog, TracingAuditLog},
+    auth::{AuthDatabase, DatabasePermissions, RateLimiter},
+    database::{changes::ChangeManager, ConnectionPool, Database},
+    error::CRRError,
+    mail::{
+        EmailNotifier, MailBackend, Mailer, Notifier, OtpChannel, SmtpMailer, StdoutMailer,
+        WebhookNotifier,
+    },
+};
 
 #[derive(Clone)]
 pub struct AppState {
     env: Arc<AppEnv>,
     change_manager: ChangeManager,
+    otp_rate_limiter: RateLimiter,
+    notifier: Arc<dyn Notifier>,
+    audit_log: Arc<dyn AuditLog>,
+    database_pool: ConnectionPool,
 }
 
 impl AppState {
-    pub fn init() -> Self {
-        Self {
-            env: Arc::new(AppEnv::load()),
-            change_manager: ChangeManager::new(),
-        }
+    pub fn init() -> Result<Self, CRRError> {
+        let env = Arc::new(AppEnv::load()?);
+
+        let notifier: Arc<dyn Notifier> = match (env.otp_channel(), env.otp_webhook_url()) {
+            (OtpChannel::Webhook, Some(url)) => Arc::new(WebhookNotifier::new(url.to_owned())),
+            _ => {
+                let mailer: Box<dyn Mailer> = match env.mail_backend() {
+                    MailBackend::Smtp => Box::new(SmtpMailer::from_env(env.smtp_port())?),
+                    MailBackend::Stdout => Box::new(StdoutMailer),
+                };
+
+                Arc::new(EmailNotifier::new(
+                    mailer,
+                    env.otp_template().map(ToOwned::to_owned),
+                ))
+            }
+        };
+
+        let audit_log: Arc<dyn AuditLog> = match env.audit_log() {
+            Some(path) => Arc::new(FileAuditLog::open(path)?),
+            None => Arc::new(TracingAuditLog),
+        };
+
+        AuthDatabase::spawn_token_cleanup(env.clone(), env.token_cleanup_interval());
+
+        Ok(Self {
+            otp_rate_limiter: RateLimiter::new(env.otp_rate_limit()),
+            notifier,
+            audit_log,
+            database_pool: ConnectionPool::new(env.pool_size(), env.pool_max_idle()),
+            change_manager: ChangeManager::from_env(&env),
+            env,
+        })
     }
 
     pub fn test_state() -> Self {
+        let env = AppEnv::test_env();
+
         Self {
-            env: AppEnv::test_env(),
-            change_manager: ChangeManager::new(),
+            otp_rate_limiter: RateLimiter::new(env.otp_rate_limit()),
+            notifier: Arc::new(EmailNotifier::new(Box::new(StdoutMailer), None)),
+            audit_log: Arc::new(TracingAuditLog),
+            database_pool: ConnectionPool::new(env.pool_size(), env.pool_max_idle()),
+            change_manager: ChangeManager::from_env(&env),
+            env,
         }
     }
 
@@ -36,26 +87,423 @@ impl AppState {
     pub(crate) fn change_manager(&self) -> &ChangeManager {
         &self.change_manager
     }
+
+    pub(crate) fn otp_rate_limiter(&self) -> &RateLimiter {
+        &self.otp_rate_limiter
+    }
+
+    pub(crate) fn database_pool(&self) -> &ConnectionPool {
+        &self.database_pool
+    }
+
+    pub(crate) fn notifier(&self) -> &dyn Notifier {
+        self.notifier.as_ref()
+    }
+
+    pub(crate) fn audit_log(&self) -> &dyn AuditLog {
+        self.audit_log.as_ref()
+    }
+
+    #[cfg(test)]
+    pub(crate) fn with_env(mut self, env: Arc<AppEnv>) -> Self {
+        self.env = env;
+        self
+    }
+
+    #[cfg(test)]
+    pub(crate) fn with_notifier(mut self, notifier: Arc<dyn Notifier>) -> Self {
+        self.notifier = notifier;
+        self
+    }
+
+    #[cfg(test)]
+    pub(crate) fn with_audit_log(mut self, audit_log: Arc<dyn AuditLog>) -> Self {
+        self.audit_log = audit_log;
+        self
+    }
+
+    /// tells all change stream subscribers that the server is going away
+    pub async fn shutdown(&self) {
+        self.change_manager.shutdown().await;
+    }
 }
 
 pub struct AppEnv {
     data_dir: PathBuf,
+    bind_addr: SocketAddr,
+    tls: Option<(PathBuf, PathBuf)>,
+    enable_http2: bool,
+    tcp_keepalive: Option<Duration>,
+    sse_keepalive: Duration,
+    otp_ttl: Duration,
+    otp_rate_limit: Duration,
+    access_token_ttl: Duration,
+    token_ttl_days: u64,
+    cookie_secure: bool,
+    cookie_same_site: SameSite,
+    admin_secret: Option<String>,
+    metrics_per_database: bool,
+    query_timeout: Duration,
+    slow_query_threshold: Option<Duration>,
+    log_query_params: bool,
+    shutdown_grace_period: Duration,
+    allowed_origins: Vec<HeaderValue>,
+    mail_backend: MailBackend,
+    smtp_port: u16,
+    otp_template: Option<String>,
+    otp_channel: OtpChannel,
+    otp_webhook_url: Option<String>,
+    otp_in_response: bool,
+    max_signed_url_ttl: Duration,
+    pool_size: usize,
+    pool_max_idle: usize,
+    change_channel_capacity: usize,
+    change_buffer_bytes: usize,
+    change_debounce: Duration,
+    gc_interval: Duration,
+    token_cleanup_interval: Duration,
+    gc_orphan_grace: Duration,
+    max_subscribers_per_db: usize,
+    sqlite_busy_timeout: Duration,
+    sqlite_pragmas: Vec<String>,
+    max_body_bytes: usize,
+    crsqlite_path: PathBuf,
+    audit_log: Option<PathBuf>,
+    replica_data_dir: Option<PathBuf>,
+    auth_db_path: Option<PathBuf>,
 }
 
 impl AppEnv {
     pub(crate) const TEST_DB_NAME: &str = "data";
+    const DEFAULT_BIND_ADDR: &str = "0.0.0.0:6839";
+    const DEFAULT_TCP_KEEPALIVE_SECONDS: u64 = 60;
+    const DEFAULT_SSE_KEEPALIVE_SECONDS: u64 = 15;
+    const DEFAULT_OTP_TTL_SECONDS: u64 = 600;
+    const DEFAULT_OTP_RATE_LIMIT_SECONDS: u64 = 60;
+    const DEFAULT_ACCESS_TOKEN_TTL_SECONDS: u64 = 900;
+    const DEFAULT_TOKEN_TTL_DAYS: u64 = 400;
+    const DEFAULT_QUERY_TIMEOUT_MS: u64 = 30_000;
+    const DEFAULT_SLOW_QUERY_MS: u64 = 1_000;
+    const DEFAULT_SHUTDOWN_GRACE_SECONDS: u64 = 10;
+    const DEFAULT_MAIL_BACKEND: MailBackend = MailBackend::Smtp;
+    const DEFAULT_SMTP_PORT: u16 = 465;
+    const DEFAULT_OTP_CHANNEL: OtpChannel = OtpChannel::Email;
+    const DEFAULT_SIGNED_URL_TTL_SECONDS: u64 = 100;
+    const DEFAULT_MAX_SIGNED_URL_TTL_SECONDS: u64 = 86_400;
+    const DEFAULT_POOL_SIZE: usize = 4;
+    const DEFAULT_POOL_MAX_IDLE: usize = 64;
+    const DEFAULT_CHANGE_CHANNEL_CAPACITY: usize = 1024;
+    const DEFAULT_CHANGE_BUFFER_BYTES: usize = 1_000_000;
+    const DEFAULT_CHANGE_DEBOUNCE_MS: u64 = 50;
+    const DEFAULT_GC_INTERVAL_SECONDS: u64 = 240;
+    const DEFAULT_TOKEN_CLEANUP_INTERVAL_SECONDS: u64 = 3_600;
+    const DEFAULT_GC_ORPHAN_GRACE_SECONDS: u64 = 60;
+    const DEFAULT_MAX_SUBSCRIBERS_PER_DB: usize = 10_000;
+    const DEFAULT_SQLITE_BUSY_TIMEOUT_MS: u64 = 5_000;
+    const DEFAULT_MAX_BODY_BYTES: usize = 16 * 1024 * 1024;
 
-    fn load() -> Self {
-        Self {
+    fn load() -> Result<Self, CRRError> {
+        let bind_addr =
+            std::env::var("CRR_BIND_ADDR").unwrap_or_else(|_| Self::DEFAULT_BIND_ADDR.to_owned());
+        let otp_channel = Self::var("CRR_OTP_CHANNEL", Self::DEFAULT_OTP_CHANNEL)?;
+        let cookie_secure = Self::var("CRR_COOKIE_SECURE", true)?;
+
+        Ok(Self {
             data_dir: PathBuf::from(
                 std::env::var("CRR_DATA_DIR").unwrap_or_else(|_| "./data".to_owned()),
             ),
+            bind_addr: Self::parse_bind_addr(&bind_addr)?,
+            tls: Self::parse_tls(
+                std::env::var("CRR_TLS_CERT").ok(),
+                std::env::var("CRR_TLS_KEY").ok(),
+            )?,
+            enable_http2: Self::var("CRR_ENABLE_HTTP2", false)?,
+            tcp_keepalive: match Self::var(
+                "CRR_TCP_KEEPALIVE_SECONDS",
+                Self::DEFAULT_TCP_KEEPALIVE_SECONDS,
+            )? {
+                0 => None,
+                seconds => Some(Duration::from_secs(seconds)),
+            },
+            sse_keepalive: match Self::var(
+                "CRR_SSE_KEEPALIVE_SECONDS",
+                Self::DEFAULT_SSE_KEEPALIVE_SECONDS,
+            )? {
+                0 => {
+                    return Err(CRRError::InvalidEnvVar(
+                        "CRR_SSE_KEEPALIVE_SECONDS",
+                        "0".to_owned(),
+                    ))
+                }
+                seconds => Duration::from_secs(seconds),
+            },
+            otp_ttl: Duration::from_secs(Self::var(
+                "CRR_OTP_TTL_SECONDS",
+                Self::DEFAULT_OTP_TTL_SECONDS,
+            )?),
+            otp_rate_limit: Duration::from_secs(Self::var(
+                "CRR_OTP_RATE_LIMIT_SECONDS",
+                Self::DEFAULT_OTP_RATE_LIMIT_SECONDS,
+            )?),
+            access_token_ttl: Duration::from_secs(Self::var(
+                "CRR_ACCESS_TOKEN_TTL_SECONDS",
+                Self::DEFAULT_ACCESS_TOKEN_TTL_SECONDS,
+            )?),
+            token_ttl_days: match Self::var("CRR_TOKEN_TTL_DAYS", Self::DEFAULT_TOKEN_TTL_DAYS)? {
+                0 => {
+                    return Err(CRRError::InvalidEnvVar(
+                        "CRR_TOKEN_TTL_DAYS",
+                        "0".to_owned(),
+                    ))
+                }
+                days => days,
+            },
+            cookie_secure,
+            cookie_same_site: Self::parse_cookie_same_site(
+                &std::env::var("CRR_COOKIE_SAMESITE").unwrap_or_else(|_| "strict".to_owned()),
+                cookie_secure,
+            )?,
+            admin_secret: std::env::var("CRR_ADMIN_SECRET")
+                .ok()
+                .filter(|secret| !secret.is_empty()),
+            metrics_per_database: Self::var("CRR_METRICS_PER_DATABASE", false)?,
+            query_timeout: Duration::from_millis(Self::var(
+                "CRR_QUERY_TIMEOUT_MS",
+                Self::DEFAULT_QUERY_TIMEOUT_MS,
+            )?),
+            slow_query_threshold: match Self::var("CRR_SLOW_QUERY_MS", Self::DEFAULT_SLOW_QUERY_MS)?
+            {
+                0 => None,
+                ms => Some(Duration::from_millis(ms)),
+            },
+            log_query_params: Self::var("CRR_SLOW_QUERY_LOG_PARAMS", false)?,
+            shutdown_grace_period: Duration::from_secs(Self::var(
+                "CRR_SHUTDOWN_GRACE_SECONDS",
+                Self::DEFAULT_SHUTDOWN_GRACE_SECONDS,
+            )?),
+            allowed_origins: Self::parse_allowed_origins(
+                &std::env::var("CRR_ALLOWED_ORIGINS").unwrap_or_default(),
+            )?,
+            mail_backend: Self::var("CRR_MAIL_BACKEND", Self::DEFAULT_MAIL_BACKEND)?,
+            smtp_port: Self::var("CRR_SMTP_PORT", Self::DEFAULT_SMTP_PORT)?,
+            otp_template: match std::env::var("CRR_OTP_TEMPLATE") {
+                Ok(path) => Some(std::fs::read_to_string(path)?),
+                Err(_) => None,
+            },
+            otp_channel,
+            otp_webhook_url: Self::parse_otp_webhook_url(
+                otp_channel,
+                std::env::var("CRR_OTP_WEBHOOK_URL").ok(),
+            )?,
+            otp_in_response: Self::var("CRR_OTP_IN_RESPONSE", false)?,
+            max_signed_url_ttl: Duration::from_secs(Self::var(
+                "CRR_MAX_SIGNED_URL_TTL",
+                Self::DEFAULT_MAX_SIGNED_URL_TTL_SECONDS,
+            )?),
+            pool_size: Self::var("CRR_POOL_SIZE", Self::DEFAULT_POOL_SIZE)?,
+            pool_max_idle: Self::var("CRR_POOL_MAX_IDLE", Self::DEFAULT_POOL_MAX_IDLE)?,
+            change_channel_capacity: match Self::var(
+                "CRR_CHANGE_CHANNEL_CAPACITY",
+                Self::DEFAULT_CHANGE_CHANNEL_CAPACITY,
+            )? {
+                0 => {
+                    return Err(CRRError::InvalidEnvVar(
+                        "CRR_CHANGE_CHANNEL_CAPACITY",
+                        "0".to_owned(),
+                    ))
+                }
+                capacity => capacity,
+            },
+            change_buffer_bytes: Self::var(
+                "CRR_CHANGE_BUFFER_BYTES",
+                Self::DEFAULT_CHANGE_BUFFER_BYTES,
+            )?,
+            change_debounce: Duration::from_millis(Self::var(
+                "CRR_CHANGE_DEBOUNCE_MS",
+                Self::DEFAULT_CHANGE_DEBOUNCE_MS,
+            )?),
+            gc_interval: match Self::var(
+                "CRR_GC_INTERVAL_SECONDS",
+                Self::DEFAULT_GC_INTERVAL_SECONDS,
+            )? {
+                0 => {
+                    return Err(CRRError::InvalidEnvVar(
+                        "CRR_GC_INTERVAL_SECONDS",
+                        "0".to_owned(),
+                    ))
+                }
+                seconds => Duration::from_secs(seconds),
+            },
+            token_cleanup_interval: match Self::var(
+                "CRR_TOKEN_CLEANUP_INTERVAL_SECONDS",
+                Self::DEFAULT_TOKEN_CLEANUP_INTERVAL_SECONDS,
+            )? {
+                0 => {
+                    return Err(CRRError::InvalidEnvVar(
+                        "CRR_TOKEN_CLEANUP_INTERVAL_SECONDS",
+                        "0".to_owned(),
+                    ))
+                }
+                seconds => Duration::from_secs(seconds),
+            },
+            gc_orphan_grace: Duration::from_secs(Self::var(
+                "CRR_GC_ORPHAN_GRACE_SECONDS",
+                Self::DEFAULT_GC_ORPHAN_GRACE_SECONDS,
+            )?),
+            max_subscribers_per_db: match Self::var(
+                "CRR_MAX_SUBSCRIBERS_PER_DB",
+                Self::DEFAULT_MAX_SUBSCRIBERS_PER_DB,
+            )? {
+                0 => {
+                    return Err(CRRError::InvalidEnvVar(
+                        "CRR_MAX_SUBSCRIBERS_PER_DB",
+                        "0".to_owned(),
+                    ))
+                }
+                max => max,
+            },
+            sqlite_busy_timeout: Duration::from_millis(Self::var(
+                "CRR_SQLITE_BUSY_TIMEOUT_MS",
+                Self::DEFAULT_SQLITE_BUSY_TIMEOUT_MS,
+            )?),
+            sqlite_pragmas: Self::parse_sqlite_pragmas(
+                &std::env::var("CRR_SQLITE_PRAGMAS").unwrap_or_default(),
+            )?,
+            max_body_bytes: Self::var("CRR_MAX_BODY_BYTES", Self::DEFAULT_MAX_BODY_BYTES)?,
+            crsqlite_path: match std::env::var("CRR_CRSQLITE_PATH") {
+                Ok(path) => PathBuf::from(path),
+                Err(_) => Database::default_crsqlite_path()?,
+            },
+            audit_log: std::env::var("CRR_AUDIT_LOG").ok().map(PathBuf::from),
+            replica_data_dir: std::env::var("CRR_REPLICA_DATA_DIR")
+                .ok()
+                .map(PathBuf::from),
+            auth_db_path: std::env::var("CRR_AUTH_DB_PATH").ok().map(PathBuf::from),
+        })
+    }
+
+    /// Creates the data directory if it's missing, makes sure it's writable
+    /// and that the crsqlite extension can be loaded.
+    /// Warns if one-time passwords are returned in responses.
+    pub fn validate(&self) -> Result<(), CRRError> {
+        if self.otp_in_response {
+            tracing::warn!(
+                "CRR_OTP_IN_RESPONSE is enabled: one-time passwords are returned in API responses \
+                 and anyone can log in as any user. NEVER enable this in production!"
+            );
+        }
+
+        std::fs::create_dir_all(&self.data_dir)
+            .map_err(|error| CRRError::InvalidDataDir(self.data_dir.clone(), error))?;
+
+        let mut probe = self.data_dir.clone();
+        probe.push(format!(".crr-write-test-{}", nanoid::nanoid!()));
+
+        std::fs::write(&probe, [])
+            .and_then(|_| std::fs::remove_file(&probe))
+            .map_err(|error| CRRError::InvalidDataDir(self.data_dir.clone(), error))?;
+
+        Database::open_in_memory(self, DatabasePermissions::Full)?;
+
+        if let Some((cert, key)) = &self.tls {
+            std::fs::metadata(cert)?;
+            std::fs::metadata(key)?;
         }
+
+        Ok(())
     }
 
-    pub(crate) fn test_env() -> Arc<Self> {
-        use crate::auth::AuthDatabase;
+    fn var<T: FromStr>(name: &'static str, default: T) -> Result<T, CRRError> {
+        match std::env::var(name) {
+            Ok(value) => value
+                .parse()
+                .map_err(|_| CRRError::InvalidEnvVar(name, value)),
+            Err(_) => Ok(default),
+        }
+    }
+
+    /// certificate and key have to be configured together
+    fn parse_tls(
+        cert: Option<String>,
+        key: Option<String>,
+    ) -> Result<Option<(PathBuf, PathBuf)>, CRRError> {
+        match (cert, key) {
+            (Some(cert), Some(key)) => Ok(Some((PathBuf::from(cert), PathBuf::from(key)))),
+            (None, None) => Ok(None),
+            (Some(_), None) => Err(CRRError::InvalidEnvVar("CRR_TLS_KEY", String::new())),
+            (None, Some(_)) => Err(CRRError::InvalidEnvVar("CRR_TLS_CERT", String::new())),
+        }
+    }
+
+    /// the webhook channel requires a url
+    fn parse_otp_webhook_url(
+        channel: OtpChannel,
+        url: Option<String>,
+    ) -> Result<Option<String>, CRRError> {
+        match (channel, url) {
+            (OtpChannel::Webhook, None) => Err(CRRError::InvalidEnvVar(
+                "CRR_OTP_WEBHOOK_URL",
+                String::new(),
+            )),
+            (_, Some(url)) => {
+                url::Url::parse(&url)?;
+                Ok(Some(url))
+            }
+            (_, None) => Ok(None),
+        }
+    }
+
+    fn parse_bind_addr(value: &str) -> Result<SocketAddr, CRRError> {
+        value
+            .parse()
+            .map_err(|error| CRRError::InvalidBindAddress(value.to_owned(), error))
+    }
+
+    fn parse_allowed_origins(value: &str) -> Result<Vec<HeaderValue>, CRRError> {
+        value
+            .split(',')
+            .map(str::trim)
+            .filter(|origin| !origin.is_empty())
+            .map(|origin| {
+                HeaderValue::from_str(origin)
+                    .map_err(|_| CRRError::InvalidEnvVar("CRR_ALLOWED_ORIGINS", origin.to_owned()))
+            })
+            .collect()
+    }
 
+    /// browsers drop `SameSite=None` cookies that aren't `Secure`
+    fn parse_cookie_same_site(value: &str, secure: bool) -> Result<SameSite, CRRError> {
+        match value.to_ascii_lowercase().as_str() {
+            "strict" => Ok(SameSite::Strict),
+            "lax" => Ok(SameSite::Lax),
+            "none" if secure => Ok(SameSite::None),
+            _ => Err(CRRError::InvalidEnvVar(
+                "CRR_COOKIE_SAMESITE",
+                value.to_owned(),
+            )),
+        }
+    }
+
+    fn parse_sqlite_pragmas(value: &str) -> Result<Vec<String>, CRRError> {
+        value
+            .split(',')
+            .map(str::trim)
+            .filter(|pragma| !pragma.is_empty())
+            .map(|pragma| {
+                // one `name=value` assignment per entry, no further statements
+                if pragma.contains(';') || !pragma.contains('=') {
+                    return Err(CRRError::InvalidEnvVar(
+                        "CRR_SQLITE_PRAGMAS",
+                        pragma.to_owned(),
+                    ));
+                }
+
+                Ok(pragma.to_owned())
+            })
+            .collect()
+    }
+
+    pub(crate) fn test_env() -> Arc<Self> {
         let mut data_dir = PathBuf::from(temp_dir());
         data_dir.push("crr-test-data");
         data_dir.push(nanoid::nanoid!());
@@ -64,7 +512,54 @@ impl AppEnv {
 
         let _err = std::fs::create_dir_all(&data_dir);
 
-        let app_env = Arc::new(AppEnv { data_dir });
+        let app_env = Arc::new(AppEnv {
+            data_dir,
+            bind_addr: Self::parse_bind_addr(Self::DEFAULT_BIND_ADDR)
+                .expect("Failed to parse default bind address"),
+            tls: None,
+            enable_http2: false,
+            tcp_keepalive: Some(Duration::from_secs(Self::DEFAULT_TCP_KEEPALIVE_SECONDS)),
+            sse_keepalive: Duration::from_secs(Self::DEFAULT_SSE_KEEPALIVE_SECONDS),
+            otp_ttl: Duration::from_secs(Self::DEFAULT_OTP_TTL_SECONDS),
+            otp_rate_limit: Duration::from_secs(Self::DEFAULT_OTP_RATE_LIMIT_SECONDS),
+            access_token_ttl: Duration::from_secs(Self::DEFAULT_ACCESS_TOKEN_TTL_SECONDS),
+            token_ttl_days: Self::DEFAULT_TOKEN_TTL_DAYS,
+            cookie_secure: true,
+            cookie_same_site: SameSite::Strict,
+            admin_secret: None,
+            metrics_per_database: false,
+            query_timeout: Duration::from_millis(Self::DEFAULT_QUERY_TIMEOUT_MS),
+            slow_query_threshold: Some(Duration::from_millis(Self::DEFAULT_SLOW_QUERY_MS)),
+            log_query_params: false,
+            shutdown_grace_period: Duration::from_secs(Self::DEFAULT_SHUTDOWN_GRACE_SECONDS),
+            allowed_origins: Vec::new(),
+            mail_backend: MailBackend::Stdout,
+            smtp_port: Self::DEFAULT_SMTP_PORT,
+            otp_template: None,
+            otp_channel: Self::DEFAULT_OTP_CHANNEL,
+            otp_webhook_url: None,
+            otp_in_response: false,
+            max_signed_url_ttl: Duration::from_secs(Self::DEFAULT_MAX_SIGNED_URL_TTL_SECONDS),
+            pool_size: Self::DEFAULT_POOL_SIZE,
+            pool_max_idle: Self::DEFAULT_POOL_MAX_IDLE,
+            change_channel_capacity: Self::DEFAULT_CHANGE_CHANNEL_CAPACITY,
+            change_buffer_bytes: Self::DEFAULT_CHANGE_BUFFER_BYTES,
+            change_debounce: Duration::from_millis(Self::DEFAULT_CHANGE_DEBOUNCE_MS),
+            gc_interval: Duration::from_secs(Self::DEFAULT_GC_INTERVAL_SECONDS),
+            token_cleanup_interval: Duration::from_secs(
+                Self::DEFAULT_TOKEN_CLEANUP_INTERVAL_SECONDS,
+            ),
+            gc_orphan_grace: Duration::from_secs(Self::DEFAULT_GC_ORPHAN_GRACE_SECONDS),
+            max_subscribers_per_db: Self::DEFAULT_MAX_SUBSCRIBERS_PER_DB,
+            sqlite_busy_timeout: Duration::from_millis(Self::DEFAULT_SQLITE_BUSY_TIMEOUT_MS),
+            sqlite_pragmas: Vec::new(),
+            max_body_bytes: Self::DEFAULT_MAX_BODY_BYTES,
+            crsqlite_path: Database::default_crsqlite_path()
+                .expect("Failed to determine crsqlite path"),
+            audit_log: None,
+            replica_data_dir: None,
+            auth_db_path: None,
+        });
         let auth = AuthDatabase::open(Arc::clone(&app_env)).expect("Failed to open AuthDatabase");
 
         auth.apply_migrations()
@@ -73,13 +568,310 @@ impl AppEnv {
         app_env
     }
 
+    #[cfg(test)]
+    pub(crate) fn with_allowed_origins(mut env: Arc<Self>, origins: &str) -> Arc<Self> {
+        Arc::get_mut(&mut env)
+            .expect("Test Env is already shared")
+            .allowed_origins =
+            Self::parse_allowed_origins(origins).expect("Failed to parse allowed origins");
+
+        env
+    }
+
+    #[cfg(test)]
+    pub(crate) fn with_crsqlite_path(mut env: Arc<Self>, path: &str) -> Arc<Self> {
+        Arc::get_mut(&mut env)
+            .expect("Test Env is already shared")
+            .crsqlite_path = PathBuf::from(path);
+
+        env
+    }
+
+    #[cfg(test)]
+    pub(crate) fn with_token_ttl_days(mut env: Arc<Self>, days: u64) -> Arc<Self> {
+        Arc::get_mut(&mut env)
+            .expect("Test Env is already shared")
+            .token_ttl_days = days;
+
+        env
+    }
+
+    #[cfg(test)]
+    pub(crate) fn with_insecure_cookies(mut env: Arc<Self>) -> Arc<Self> {
+        let env_mut = Arc::get_mut(&mut env).expect("Test Env is already shared");
+        env_mut.cookie_secure = false;
+        env_mut.cookie_same_site = SameSite::Lax;
+
+        env
+    }
+
+    #[cfg(test)]
+    pub(crate) fn with_otp_in_response(mut env: Arc<Self>) -> Arc<Self> {
+        Arc::get_mut(&mut env)
+            .expect("Test Env is already shared")
+            .otp_in_response = true;
+
+        env
+    }
+
+    #[cfg(test)]
+    pub(crate) fn with_max_subscribers_per_db(mut env: Arc<Self>, max: usize) -> Arc<Self> {
+        Arc::get_mut(&mut env)
+            .expect("Test Env is already shared")
+            .max_subscribers_per_db = max;
+
+        env
+    }
+
+    #[cfg(test)]
+    pub(crate) fn with_sse_keepalive(mut env: Arc<Self>, interval: Duration) -> Arc<Self> {
+        Arc::get_mut(&mut env)
+            .expect("Test Env is already shared")
+            .sse_keepalive = interval;
+
+        env
+    }
+
+    #[cfg(test)]
+    pub(crate) fn with_slow_query_threshold(mut env: Arc<Self>, threshold: Duration) -> Arc<Self> {
+        Arc::get_mut(&mut env)
+            .expect("Test Env is already shared")
+            .slow_query_threshold = Some(threshold);
+
+        env
+    }
+
+    #[cfg(test)]
+    pub(crate) fn with_admin_secret(mut env: Arc<Self>, secret: &str) -> Arc<Self> {
+        Arc::get_mut(&mut env)
+            .expect("Test Env is already shared")
+            .admin_secret = Some(secret.to_owned());
+
+        env
+    }
+
+    #[cfg(test)]
+    pub(crate) fn with_replica_data_dir(mut env: Arc<Self>, path: &str) -> Arc<Self> {
+        Arc::get_mut(&mut env)
+            .expect("Test Env is already shared")
+            .replica_data_dir = Some(PathBuf::from(path));
+
+        env
+    }
+
+    #[cfg(test)]
+    pub(crate) fn with_auth_db_path(mut env: Arc<Self>, path: PathBuf) -> Arc<Self> {
+        Arc::get_mut(&mut env)
+            .expect("Test Env is already shared")
+            .auth_db_path = Some(path);
+
+        env
+    }
+
     pub(crate) fn data_dir(&self) -> &Path {
         &self.data_dir
     }
 
-    pub fn test_db(&self) -> crate::database::Database {
-        use crate::{auth::DatabasePermissions, database::Database};
+    /// read-only copies of the databases, kept up to date outside of the server
+    pub(crate) fn replica_data_dir(&self) -> Option<&Path> {
+        self.replica_data_dir.as_deref()
+    }
+
+    /// overrides `auth.sqlite3` in the data dir, e.g. to keep it on separate storage
+    pub(crate) fn auth_db_path(&self) -> Option<&Path> {
+        self.auth_db_path.as_deref()
+    }
 
+    pub fn bind_addr(&self) -> SocketAddr {
+        self.bind_addr
+    }
+
+    /// certificate and private key in PEM format, plain HTTP is served if unset
+    pub fn tls(&self) -> Option<(&Path, &Path)> {
+        self.tls
+            .as_ref()
+            .map(|(cert, key)| (cert.as_path(), key.as_path()))
+    }
+
+    /// accept HTTP/2 with prior knowledge next to HTTP/1
+    pub fn enable_http2(&self) -> bool {
+        self.enable_http2
+    }
+
+    /// `None` leaves TCP keepalive to the OS
+    pub fn tcp_keepalive(&self) -> Option<Duration> {
+        self.tcp_keepalive
+    }
+
+    /// how often idle change streams send a comment so proxies keep them open
+    pub(crate) fn sse_keepalive(&self) -> Duration {
+        self.sse_keepalive
+    }
+
+    pub(crate) fn otp_ttl(&self) -> Duration {
+        self.otp_ttl
+    }
+
+    pub(crate) fn otp_rate_limit(&self) -> Duration {
+        self.otp_rate_limit
+    }
+
+    pub(crate) fn access_token_ttl(&self) -> Duration {
+        self.access_token_ttl
+    }
+
+    /// lifetime of refresh tokens and their cookie, always at least one day
+    pub(crate) fn token_ttl_days(&self) -> u64 {
+        self.token_ttl_days
+    }
+
+    /// disabled for local development over plain HTTP
+    pub(crate) fn cookie_secure(&self) -> bool {
+        self.cookie_secure
+    }
+
+    pub(crate) fn cookie_same_site(&self) -> SameSite {
+        self.cookie_same_site
+    }
+
+    /// bearer token for minting service tokens, the endpoint is disabled if unset
+    pub(crate) fn admin_secret(&self) -> Option<&str> {
+        self.admin_secret.as_deref()
+    }
+
+    /// label subscriber counts in `/metrics` with database names
+    pub(crate) fn metrics_per_database(&self) -> bool {
+        self.metrics_per_database
+    }
+
+    /// a zero duration disables the timeout
+    pub(crate) fn query_timeout(&self) -> Duration {
+        self.query_timeout
+    }
+
+    /// statements of `/run` taking longer are logged, `None` disables the log
+    pub(crate) fn slow_query_threshold(&self) -> Option<Duration> {
+        self.slow_query_threshold
+    }
+
+    /// whether slow query logs include the bound params instead of redacting them
+    pub(crate) fn log_query_params(&self) -> bool {
+        self.log_query_params
+    }
+
+    /// contains `*` if any origin is allowed
+    pub(crate) fn allowed_origins(&self) -> &[HeaderValue] {
+        &self.allowed_origins
+    }
+
+    pub(crate) fn mail_backend(&self) -> MailBackend {
+        self.mail_backend
+    }
+
+    pub(crate) fn smtp_port(&self) -> u16 {
+        self.smtp_port
+    }
+
+    /// HTML template for OTP emails, `{{otp}}` is replaced with the code
+    pub(crate) fn otp_template(&self) -> Option<&str> {
+        self.otp_template.as_deref()
+    }
+
+    pub(crate) fn otp_channel(&self) -> OtpChannel {
+        self.otp_channel
+    }
+
+    /// where the webhook channel POSTs OTPs to
+    pub(crate) fn otp_webhook_url(&self) -> Option<&str> {
+        self.otp_webhook_url.as_deref()
+    }
+
+    /// returns OTPs from `POST /auth/otp` as well, for local development only
+    pub(crate) fn otp_in_response(&self) -> bool {
+        self.otp_in_response
+    }
+
+    /// used for signed urls requested without `expires_in`
+    pub(crate) fn signed_url_ttl(&self) -> Duration {
+        Duration::from_secs(Self::DEFAULT_SIGNED_URL_TTL_SECONDS)
+    }
+
+    pub(crate) fn max_signed_url_ttl(&self) -> Duration {
+        self.max_signed_url_ttl
+    }
+
+    /// maximum number of idle connections kept per database
+    pub(crate) fn pool_size(&self) -> usize {
+        self.pool_size
+    }
+
+    /// maximum number of idle connections kept across all databases
+    pub(crate) fn pool_max_idle(&self) -> usize {
+        self.pool_max_idle
+    }
+
+    /// number of messages a change stream subscriber can fall behind before it has to resync
+    pub(crate) fn change_channel_capacity(&self) -> usize {
+        self.change_channel_capacity
+    }
+
+    /// size of the pages change streams backfill in, streams can ask for smaller ones
+    pub(crate) fn change_buffer_bytes(&self) -> usize {
+        self.change_buffer_bytes
+    }
+
+    /// how long watcher tasks collect update signals before reading changes,
+    /// a zero duration disables debouncing
+    pub(crate) fn change_debounce(&self) -> Duration {
+        self.change_debounce
+    }
+
+    pub(crate) fn gc_interval(&self) -> Duration {
+        self.gc_interval
+    }
+
+    /// how often expired access and refresh tokens are deleted
+    pub(crate) fn token_cleanup_interval(&self) -> Duration {
+        self.token_cleanup_interval
+    }
+
+    /// how long a watcher task without subscribers is kept for reconnecting clients
+    pub(crate) fn gc_orphan_grace(&self) -> Duration {
+        self.gc_orphan_grace
+    }
+
+    /// number of change stream subscribers a single database accepts
+    pub(crate) fn max_subscribers_per_db(&self) -> usize {
+        self.max_subscribers_per_db
+    }
+
+    pub(crate) fn crsqlite_path(&self) -> &Path {
+        &self.crsqlite_path
+    }
+
+    /// file that access records are appended to, logged via tracing if unset
+    pub(crate) fn audit_log(&self) -> Option<&Path> {
+        self.audit_log.as_deref()
+    }
+
+    pub(crate) fn sqlite_busy_timeout(&self) -> Duration {
+        self.sqlite_busy_timeout
+    }
+
+    /// extra `name=value` pragmas applied to every connection
+    pub(crate) fn sqlite_pragmas(&self) -> &[String] {
+        &self.sqlite_pragmas
+    }
+
+    pub(crate) fn max_body_bytes(&self) -> usize {
+        self.max_body_bytes
+    }
+
+    pub fn shutdown_grace_period(&self) -> Duration {
+        self.shutdown_grace_period
+    }
+
+    pub fn test_db(&self) -> crate::database::Database {
         Database::open(
             self,
             Self::TEST_DB_NAME.to_owned(),
@@ -87,6 +879,14 @@ impl AppEnv {
         )
         .expect("Failed to open Test Database")
     }
+
+    /// A fresh in-memory database with crsqlite loaded, for tests that don't need
+    /// persistence. Every call returns a new, empty database.
+    #[cfg(test)]
+    pub(crate) fn test_db_in_memory(&self) -> crate::database::Database {
+        Database::open_in_memory(self, DatabasePermissions::Create)
+            .expect("Failed to open in-memory Test Database")
+    }
 }
 
 impl FromRef<AppState> for Arc<AppEnv> {
@@ -94,3 +894,72 @@ impl FromRef<AppState> for Arc<AppEnv> {
         Arc::clone(input.env())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use axum_extra::extract::cookie::SameSite;
+    use tracing_test::traced_test;
+
+    use crate::error::CRRError;
+
+    use super::AppEnv;
+
+    #[test]
+    fn validate_test_env() {
+        AppEnv::test_env().validate().unwrap();
+    }
+
+    #[traced_test]
+    #[test]
+    fn warn_about_otp_in_response() {
+        AppEnv::test_env().validate().unwrap();
+        assert!(!logs_contain("CRR_OTP_IN_RESPONSE"));
+
+        AppEnv::with_otp_in_response(AppEnv::test_env())
+            .validate()
+            .unwrap();
+        assert!(logs_contain("CRR_OTP_IN_RESPONSE is enabled"));
+    }
+
+    #[test]
+    fn require_tls_cert_and_key() {
+        assert!(AppEnv::parse_tls(None, None).unwrap().is_none());
+        assert!(
+            AppEnv::parse_tls(Some("cert.pem".to_owned()), Some("key.pem".to_owned()))
+                .unwrap()
+                .is_some()
+        );
+        assert!(matches!(
+            AppEnv::parse_tls(Some("cert.pem".to_owned()), None),
+            Err(CRRError::InvalidEnvVar("CRR_TLS_KEY", _))
+        ));
+        assert!(matches!(
+            AppEnv::parse_tls(None, Some("key.pem".to_owned())),
+            Err(CRRError::InvalidEnvVar("CRR_TLS_CERT", _))
+        ));
+    }
+
+    #[test]
+    fn require_secure_cookie_for_same_site_none() {
+        assert!(matches!(
+            AppEnv::parse_cookie_same_site("None", true),
+            Ok(SameSite::None)
+        ));
+        assert!(matches!(
+            AppEnv::parse_cookie_same_site("none", false),
+            Err(CRRError::InvalidEnvVar("CRR_COOKIE_SAMESITE", _))
+        ));
+        assert!(matches!(
+            AppEnv::parse_cookie_same_site("lax", false),
+            Ok(SameSite::Lax)
+        ));
+    }
+
+    #[test]
+    fn reject_missing_extension() {
+        let env =
+            AppEnv::with_crsqlite_path(AppEnv::test_env(), "./extensions/crsqlite-missing.so");
+
+        assert!(matches!(env.validate(), Err(CRRError::MissingExtension(_))));
+    }
+}
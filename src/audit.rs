@@ -0,0 +1,272 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::Write,
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::Serialize;
+use tokio::sync::mpsc::{self, error::TrySendError};
+
+use crate::error::CRRError;
+
+/// Which token was granted which permissions on a database,
+/// or why resolving its permissions failed
+#[derive(Serialize, Debug)]
+pub(crate) struct AuditRecord {
+    /// unix timestamp in milliseconds
+    timestamp: i64,
+    token_id: Option<i64>,
+    database: String,
+    method: String,
+    path: String,
+    permissions: String,
+    error: Option<String>,
+}
+
+impl AuditRecord {
+    pub(crate) fn new(
+        token_id: Option<i64>,
+        database: String,
+        method: String,
+        path: String,
+        permissions: String,
+    ) -> Self {
+        Self {
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|duration| duration.as_millis() as i64)
+                .unwrap_or_default(),
+            token_id,
+            database,
+            method,
+            path,
+            permissions,
+            error: None,
+        }
+    }
+
+    /// A request that was turned away before any permissions were granted
+    pub(crate) fn failed(
+        token_id: Option<i64>,
+        database: String,
+        method: String,
+        path: String,
+        error: &CRRError,
+    ) -> Self {
+        Self {
+            error: Some(error.to_string()),
+            ..Self::new(token_id, database, method, path, "none".to_owned())
+        }
+    }
+}
+
+pub(crate) trait AuditLog: Send + Sync {
+    fn record(&self, record: &AuditRecord) -> Result<(), CRRError>;
+}
+
+/// Default sink, emits every record as an info event with target `audit`
+pub(crate) struct TracingAuditLog;
+
+impl AuditLog for TracingAuditLog {
+    fn record(&self, record: &AuditRecord) -> Result<(), CRRError> {
+        tracing::info!(
+            target: "audit",
+            token_id = record.token_id,
+            database = %record.database,
+            method = %record.method,
+            path = %record.path,
+            permissions = %record.permissions,
+            error = record.error.as_deref(),
+            "database access"
+        );
+
+        Ok(())
+    }
+}
+
+enum AuditMessage {
+    Line(Vec<u8>),
+    #[cfg(test)]
+    Flush(tokio::sync::oneshot::Sender<()>),
+}
+
+/// Appends every record as a JSON line to a file. Lines are written by a
+/// dedicated thread so recording never blocks a request on disk I/O,
+/// records are dropped with an error if the writer falls too far behind.
+pub(crate) struct FileAuditLog {
+    sender: mpsc::Sender<AuditMessage>,
+}
+
+impl FileAuditLog {
+    const QUEUE_CAPACITY: usize = 4096;
+
+    pub(crate) fn open(path: &Path) -> Result<Self, CRRError> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let (sender, receiver) = mpsc::channel(Self::QUEUE_CAPACITY);
+
+        std::thread::Builder::new()
+            .name("audit-log".to_owned())
+            .spawn(move || Self::write_lines(file, receiver))?;
+
+        Ok(Self { sender })
+    }
+
+    /// runs until the log is dropped
+    fn write_lines(mut file: File, mut receiver: mpsc::Receiver<AuditMessage>) {
+        while let Some(message) = receiver.blocking_recv() {
+            match message {
+                // a single write per line so records never interleave
+                AuditMessage::Line(line) => {
+                    if let Err(error) = file.write_all(&line) {
+                        tracing::error!("Failed to write audit record: {}", error);
+                    }
+                }
+                #[cfg(test)]
+                AuditMessage::Flush(done) => {
+                    let _ = done.send(());
+                }
+            }
+        }
+    }
+
+    /// resolves once all records sent so far are written
+    #[cfg(test)]
+    pub(crate) async fn flush(&self) {
+        let (done, written) = tokio::sync::oneshot::channel();
+        self.sender.send(AuditMessage::Flush(done)).await.unwrap();
+        written.await.unwrap();
+    }
+}
+
+impl AuditLog for FileAuditLog {
+    fn record(&self, record: &AuditRecord) -> Result<(), CRRError> {
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+
+        self.sender
+            .try_send(AuditMessage::Line(line))
+            .map_err(|error| match error {
+                TrySendError::Full(_) => CRRError::AuditLogError("queue is full"),
+                TrySendError::Closed(_) => CRRError::AuditLogError("writer has stopped"),
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use axum::{
+        body::Body,
+        http::{header::AUTHORIZATION, header::CONTENT_TYPE, Method, Request, StatusCode},
+    };
+    use tower::ServiceExt;
+
+    use super::FileAuditLog;
+    use crate::{
+        app_state::{AppEnv, AppState},
+        auth::AuthDatabase,
+    };
+
+    #[tokio::test]
+    async fn record_database_access() {
+        let state = AppState::test_state();
+        let path = state.env().data_dir().join("audit.jsonl");
+        let audit_log = Arc::new(FileAuditLog::open(&path).unwrap());
+        let state = state.with_audit_log(audit_log.clone());
+
+        let token = AuthDatabase::create_test_token(state.env());
+        let token_id = AuthDatabase::open(Arc::clone(state.env()))
+            .unwrap()
+            .get_token_id(&token)
+            .unwrap();
+
+        let res = crate::router(state.env())
+            .with_state(state.clone())
+            .oneshot(
+                Request::builder()
+                    .method(Method::POST)
+                    .uri(format!("/db/{}/run", AppEnv::TEST_DB_NAME))
+                    .header(AUTHORIZATION, format!("Bearer {}", token))
+                    .header(CONTENT_TYPE, "application/json")
+                    .body(Body::from(
+                        r#"{ "sql": "SELECT 1", "params": [], "method": "get" }"#,
+                    ))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(res.status(), StatusCode::OK);
+
+        audit_log.flush().await;
+        let log = std::fs::read_to_string(&path).unwrap();
+        let records: Vec<serde_json::Value> = log
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0]["token_id"], token_id);
+        assert_eq!(records[0]["database"], AppEnv::TEST_DB_NAME);
+        assert_eq!(records[0]["method"], "POST");
+        assert_eq!(
+            records[0]["path"],
+            format!("/db/{}/run", AppEnv::TEST_DB_NAME)
+        );
+        assert_eq!(records[0]["permissions"], "create");
+        assert!(records[0]["error"].is_null());
+    }
+
+    #[tokio::test]
+    async fn record_denied_access() {
+        let state = AppState::test_state();
+        let path = state.env().data_dir().join("audit.jsonl");
+        let audit_log = Arc::new(FileAuditLog::open(&path).unwrap());
+        let state = state.with_audit_log(audit_log.clone());
+
+        // the first token to touch the database becomes its owner
+        AuthDatabase::open(Arc::clone(state.env()))
+            .unwrap()
+            .get_permissions(
+                &AuthDatabase::create_test_token(state.env()),
+                AppEnv::TEST_DB_NAME,
+            )
+            .unwrap();
+
+        let token = AuthDatabase::create_test_token(state.env());
+
+        let res = crate::router(state.env())
+            .with_state(state.clone())
+            .oneshot(
+                Request::builder()
+                    .method(Method::POST)
+                    .uri(format!("/db/{}/run", AppEnv::TEST_DB_NAME))
+                    .header(AUTHORIZATION, format!("Bearer {}", token))
+                    .header(CONTENT_TYPE, "application/json")
+                    .body(Body::from(
+                        r#"{ "sql": "SELECT 1", "params": [], "method": "get" }"#,
+                    ))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+        audit_log.flush().await;
+        let log = std::fs::read_to_string(&path).unwrap();
+        let records: Vec<serde_json::Value> = log
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0]["permissions"], "none");
+        assert!(records[0]["error"]
+            .as_str()
+            .unwrap()
+            .contains("no access to database"));
+    }
+}
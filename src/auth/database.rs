@@ -1,19 +1,150 @@
-use std::{fs, path::PathBuf, sync::Arc};
+use std::{fs, path::PathBuf, sync::Arc, time::Duration};
 
-use rusqlite::named_params;
+use rusqlite::{named_params, OptionalExtension};
+use serde::Serialize;
 
 use crate::{app_state::AppEnv, error::CRRError};
 
-use super::{permissions::PartialPermissions, DatabasePermissions};
+use super::{
+    permissions::{ObjectPermissions, PartialPermissions},
+    DatabasePermissions,
+};
 
 pub struct AuthDatabase {
     conn: rusqlite::Connection,
 }
 
+/// A database a user has any permissions on. Flags are set if they're granted
+/// on the whole database or on at least one of its tables,
+/// `full` only for full access to the whole database.
+#[derive(Serialize, Debug, PartialEq)]
+pub(crate) struct DatabaseAccess {
+    name: String,
+    read: bool,
+    insert: bool,
+    update: bool,
+    delete: bool,
+    full: bool,
+}
+
 impl AuthDatabase {
     const RESERVED_NAMES: [&str; 2] = ["auth", "sync"];
 
+    /// `last_used_at` of access tokens is only as precise as this
+    const LAST_USED_INTERVAL: Duration = Duration::from_secs(60);
+
+    // incremental schema changes on top of auth_migrations.sql,
+    // tracked via PRAGMA user_version
+    const MIGRATIONS: &'static [&'static str] = &[
+        "ALTER TABLE users ADD COLUMN otp_expires TIMESTAMP",
+        "
+            CREATE TABLE denied_columns (
+                role_id INTEGER,
+                database_name TEXT NOT NULL,
+                table_name TEXT NOT NULL,
+                column_name TEXT NOT NULL,
+                FOREIGN KEY (role_id) REFERENCES roles (id),
+                PRIMARY KEY (role_id, database_name, table_name, column_name)
+            )
+        ",
+        // split tokens into long-lived refresh tokens (cookie) and
+        // short-lived access tokens (bearer), existing tokens become refresh tokens
+        "
+            CREATE TABLE refresh_tokens (
+                id INTEGER PRIMARY KEY,
+                user_id INTEGER,
+                token TEXT UNIQUE NOT NULL,
+                expires TIMESTAMP,
+                FOREIGN KEY (user_id) REFERENCES users (id)
+            );
+            CREATE TABLE access_tokens (
+                id INTEGER PRIMARY KEY,
+                user_id INTEGER,
+                token TEXT UNIQUE NOT NULL,
+                expires TIMESTAMP,
+                FOREIGN KEY (user_id) REFERENCES users (id)
+            );
+            INSERT INTO refresh_tokens (user_id, token, expires)
+                SELECT user_id, token, expires FROM tokens;
+            DELETE FROM tokens
+        ",
+        "
+            CREATE TABLE crr_server_databases (
+                name TEXT PRIMARY KEY,
+                public_read BOOLEAN NOT NULL DEFAULT FALSE
+            )
+        ",
+        // tokens issued before this migration keep a NULL created_at
+        "
+            ALTER TABLE access_tokens ADD COLUMN created_at TIMESTAMP;
+            ALTER TABLE access_tokens ADD COLUMN last_used_at TIMESTAMP;
+            ALTER TABLE refresh_tokens ADD COLUMN created_at TIMESTAMP;
+            ALTER TABLE refresh_tokens ADD COLUMN last_used_at TIMESTAMP
+        ",
+        // roles are managed by the user who created them,
+        // existing owner roles are assigned to their first member
+        "
+            ALTER TABLE roles ADD COLUMN created_by INTEGER REFERENCES users (id);
+            UPDATE roles SET created_by = (
+                SELECT user_id FROM user_roles
+                WHERE role_id = roles.id
+                ORDER BY rowid
+                LIMIT 1
+            )
+            WHERE EXISTS (
+                SELECT 1 FROM permissions
+                WHERE role_id = roles.id
+                AND table_name IS NULL
+                AND pfull
+                AND roles.name = database_name || '_owners'
+            )
+        ",
+        // service accounts are flagged and named in a namespace email addresses can't reach,
+        // the ones created before are the users without an email address
+        "
+            ALTER TABLE users ADD COLUMN service_account BOOLEAN NOT NULL DEFAULT FALSE;
+            UPDATE users SET service_account = TRUE, email = 'service:' || email
+            WHERE email NOT LIKE '%@%'
+        ",
+        // emails are looked up normalized, users that only differ in the case of their
+        // email are merged into the one that's already normalized or else the oldest
+        "
+            CREATE TEMP TABLE email_merges AS
+                SELECT id AS user_id, (
+                    SELECT kept.id FROM users kept
+                    WHERE NOT kept.service_account
+                    AND lower(trim(kept.email)) = lower(trim(users.email))
+                    ORDER BY kept.email = lower(trim(kept.email)) DESC, kept.id
+                    LIMIT 1
+                ) AS kept_id
+                FROM users
+                WHERE NOT service_account;
+            DELETE FROM email_merges WHERE user_id = kept_id;
+
+            INSERT OR IGNORE INTO user_roles (user_id, role_id)
+                SELECT kept_id, role_id FROM user_roles JOIN email_merges USING (user_id);
+            DELETE FROM user_roles WHERE user_id IN (SELECT user_id FROM email_merges);
+            UPDATE access_tokens
+                SET user_id = (SELECT kept_id FROM email_merges WHERE user_id = access_tokens.user_id)
+                WHERE user_id IN (SELECT user_id FROM email_merges);
+            UPDATE refresh_tokens
+                SET user_id = (SELECT kept_id FROM email_merges WHERE user_id = refresh_tokens.user_id)
+                WHERE user_id IN (SELECT user_id FROM email_merges);
+            UPDATE roles
+                SET created_by = (SELECT kept_id FROM email_merges WHERE user_id = roles.created_by)
+                WHERE created_by IN (SELECT user_id FROM email_merges);
+            DELETE FROM users WHERE id IN (SELECT user_id FROM email_merges);
+
+            UPDATE users SET email = lower(trim(email)) WHERE NOT service_account;
+            DROP TABLE email_merges
+        ",
+    ];
+
     fn file_path(env: &AppEnv) -> PathBuf {
+        if let Some(path) = env.auth_db_path() {
+            return path.to_owned();
+        }
+
         let mut path = PathBuf::from(env.data_dir());
         path.push("auth.sqlite3");
         path
@@ -29,17 +160,39 @@ impl AuthDatabase {
         tracing::info!("Applying metadata migrations");
         self.execute_batch(&fs::read_to_string("./auth_migrations.sql")?)?;
 
+        let user_version: i64 = self.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+
+        for (index, migration) in Self::MIGRATIONS
+            .iter()
+            .enumerate()
+            .skip(user_version as usize)
+        {
+            tracing::info!("Applying metadata migration {}", index + 1);
+            self.execute_batch(&format!(
+                "BEGIN; {}; PRAGMA user_version = {}; COMMIT;",
+                migration,
+                index + 1
+            ))?;
+        }
+
         Ok(())
     }
 
-    fn authenticate_user(&self, token: &str) -> Result<i64, CRRError> {
-        let id: i64 = self
-            .prepare("SELECT user_id FROM tokens WHERE token = :token AND expires < 'now'")?
+    pub(crate) fn authenticate_user(&self, token: &str) -> Result<i64, CRRError> {
+        let (id, stale): (i64, bool) = self
+            .prepare_cached(
+                "
+                SELECT user_id, last_used_at IS NULL OR last_used_at < JULIANDAY('now') - :interval
+                FROM access_tokens
+                WHERE token = :token AND expires > JULIANDAY('now')
+            ",
+            )?
             .query_row(
                 named_params! {
-                    ":token": token
+                    ":token": token,
+                    ":interval": Self::LAST_USED_INTERVAL.as_secs_f64() / 86400.0,
                 },
-                |row| row.get(0),
+                |row| Ok((row.get(0)?, row.get(1)?)),
             )
             .map_err(|error| match error {
                 rusqlite::Error::QueryReturnedNoRows => {
@@ -48,10 +201,70 @@ impl AuthDatabase {
                 error => error.into(),
             })?;
 
+        // writing on every request would serialize all requests on the auth db
+        if stale {
+            self.prepare_cached(
+                "UPDATE access_tokens SET last_used_at = JULIANDAY('now') WHERE token = :token",
+            )?
+            .execute(named_params! { ":token": token })?;
+        }
+
         Ok(id)
     }
 
-    fn get_permissions_for_user(
+    /// Deletes all access and refresh tokens of a user except the access token `except`,
+    /// returns the number of deleted tokens
+    pub(crate) fn revoke_all_tokens(
+        &self,
+        user_id: i64,
+        except: Option<&str>,
+    ) -> Result<usize, CRRError> {
+        let access_tokens = self
+            .prepare("DELETE FROM access_tokens WHERE user_id = :user_id AND token IS NOT :except")?
+            .execute(named_params! { ":user_id": user_id, ":except": except })?;
+
+        let refresh_tokens = self
+            .prepare("DELETE FROM refresh_tokens WHERE user_id = :user_id")?
+            .execute(named_params! { ":user_id": user_id })?;
+
+        Ok(access_tokens + refresh_tokens)
+    }
+
+    /// Deletes expired access and refresh tokens, returns the number of deleted tokens
+    pub(crate) fn delete_expired_tokens(&self) -> Result<usize, CRRError> {
+        let access_tokens = self
+            .prepare("DELETE FROM access_tokens WHERE expires <= JULIANDAY('now')")?
+            .execute([])?;
+
+        let refresh_tokens = self
+            .prepare("DELETE FROM refresh_tokens WHERE expires <= JULIANDAY('now')")?
+            .execute([])?;
+
+        Ok(access_tokens + refresh_tokens)
+    }
+
+    /// Deletes expired tokens every `interval` so the token tables don't grow unbounded
+    pub(crate) fn spawn_token_cleanup(env: Arc<AppEnv>, interval: Duration) {
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(interval).await;
+
+                let env = env.clone();
+                let result = tokio::task::spawn_blocking(move || {
+                    AuthDatabase::open(env)?.delete_expired_tokens()
+                })
+                .await;
+
+                match result {
+                    Ok(Ok(deleted)) => tracing::debug!("Deleted {} expired tokens", deleted),
+                    Ok(Err(error)) => tracing::error!("Failed to delete expired tokens: {}", error),
+                    Err(error) => tracing::error!("Failed to delete expired tokens: {}", error),
+                }
+            }
+        });
+    }
+
+    pub(crate) fn get_permissions_for_user(
         &self,
         user_id: i64,
         database_name: &str,
@@ -118,6 +331,24 @@ impl AuthDatabase {
             }
         }
 
+        let mut stmt = self.prepare(
+            "
+                SELECT table_name, column_name
+                FROM denied_columns
+                WHERE role_id IN (SELECT role_id FROM user_roles WHERE user_id = :user_id)
+                AND database_name = :database_name
+            ",
+        )?;
+
+        let mut rows = stmt.query(named_params! {
+            ":user_id": user_id,
+            ":database_name": database_name
+        })?;
+
+        while let Some(row) = rows.next()? {
+            permissions.deny_column(row.get(0)?, row.get(1)?);
+        }
+
         Ok(permissions)
     }
 
@@ -135,6 +366,10 @@ impl AuthDatabase {
         let permissions = self.get_permissions_for_user(user_id, db_name)?;
 
         if permissions.is_empty() {
+            if let Some(permissions) = self.get_public_permissions(db_name)? {
+                return Ok(permissions);
+            }
+
             if self.database_exists(db_name)? {
                 return Err(CRRError::unauthorized(format!(
                     "User has no access to database {}",
@@ -148,43 +383,188 @@ impl AuthDatabase {
         Ok(permissions)
     }
 
-    //pub(crate) fn update_permissions(
-    //    &self,
-    //    role_id: i64,
-    //    database_name: &str,
-    //    permissions: &DatabasePermissions,
-    //) -> Result<(), CRRError> {
-    //    let query = "
-    //        INSERT INTO permissions
-    //            (role_id, database_name, table_name, pread, pinsert, pupdate, pdelete, pfull)
-    //        VALUES
-    //            (:role_id, :database_name, :table_name, :pread, :pinsert, :pupdate, :pdelete, :pfull)
-    //        ON CONFLICT (role_id, database_name, table_name)
-    //        DO UPDATE SET
-    //            pread = excluded.pread,
-    //            pinsert = excluded.pinsert,
-    //            pupdate = excluded.pupdate,
-    //            pdelete = excluded.pdelete,
-    //            pfull = excluded.pfull;
-    //    ";
-
-    //    let mut stmt = self.prepare(query)?;
-
-    //    permissions.apply(|table_name, permissions| {
-    //        stmt.execute(named_params! {
-    //            ":role_id": role_id,
-    //            ":database_name": database_name,
-    //            ":table_name": table_name,
-    //            ":pread": permissions.read(),
-    //            ":pinsert": permissions.insert(),
-    //            ":pupdate": permissions.update(),
-    //            ":pdelete": permissions.delete(),
-    //            ":pfull": permissions.full()
-    //        })?;
-
-    //        Ok(())
-    //    })
-    //}
+    /// read access for requests without a token, `None` unless the database is public
+    pub(crate) fn get_public_permissions(
+        &self,
+        db_name: &str,
+    ) -> Result<Option<DatabasePermissions>, CRRError> {
+        if Self::RESERVED_NAMES.contains(&db_name) {
+            return Err(CRRError::ReservedName(db_name.to_owned()));
+        }
+
+        let public_read: Option<bool> = self
+            .prepare("SELECT public_read FROM crr_server_databases WHERE name = :name")?
+            .query_row(named_params! { ":name": db_name }, |row| row.get(0))
+            .optional()?;
+
+        if !public_read.unwrap_or_default() {
+            return Ok(None);
+        }
+
+        let mut permissions = DatabasePermissions::default();
+        permissions.set(PartialPermissions {
+            read: true,
+            ..Default::default()
+        });
+
+        Ok(Some(permissions))
+    }
+
+    pub(crate) fn set_public_read(&self, db_name: &str, public_read: bool) -> Result<(), CRRError> {
+        self.prepare(
+            "
+                INSERT INTO crr_server_databases (name, public_read) VALUES (:name, :public_read)
+                ON CONFLICT (name) DO UPDATE SET public_read = excluded.public_read
+            ",
+        )?
+        .execute(named_params! {
+            ":name": db_name,
+            ":public_read": public_read,
+        })?;
+
+        Ok(())
+    }
+
+    pub(crate) fn authenticate_database_owner(
+        &self,
+        token: &str,
+        db_name: &str,
+    ) -> Result<i64, CRRError> {
+        if Self::RESERVED_NAMES.contains(&db_name) {
+            return Err(CRRError::ReservedName(db_name.to_owned()));
+        }
+
+        let user_id = self.authenticate_user(token)?;
+
+        if !self.get_permissions_for_user(user_id, db_name)?.full() {
+            return Err(CRRError::unauthorized(format!(
+                "User has no full access to database {}",
+                db_name
+            )));
+        }
+
+        Ok(user_id)
+    }
+
+    pub(crate) fn update_permissions(
+        &self,
+        role_id: i64,
+        database_name: &str,
+        table_name: Option<&str>,
+        permissions: &ObjectPermissions,
+    ) -> Result<(), CRRError> {
+        self.execute("BEGIN", [])?;
+
+        // table_name is part of the primary key but may be NULL,
+        // so ON CONFLICT would never trigger for database-wide permissions
+        self.prepare(
+            "
+                DELETE FROM permissions
+                WHERE role_id = :role_id
+                AND database_name = :database_name
+                AND table_name IS :table_name
+            ",
+        )?
+        .execute(named_params! {
+            ":role_id": role_id,
+            ":database_name": database_name,
+            ":table_name": table_name,
+        })?;
+
+        if !permissions.is_empty() {
+            self.prepare(
+                "
+                    INSERT INTO permissions
+                        (role_id, database_name, table_name, pread, pinsert, pupdate, pdelete, pfull)
+                    VALUES
+                        (:role_id, :database_name, :table_name, :pread, :pinsert, :pupdate, :pdelete, :pfull)
+                ",
+            )?
+            .insert(named_params! {
+                ":role_id": role_id,
+                ":database_name": database_name,
+                ":table_name": table_name,
+                ":pread": permissions.read(),
+                ":pinsert": permissions.insert(),
+                ":pupdate": permissions.update(),
+                ":pdelete": permissions.delete(),
+                ":pfull": permissions.full()
+            })?;
+        }
+
+        self.execute("COMMIT", [])?;
+
+        Ok(())
+    }
+
+    pub(crate) fn create_role(&self, name: &str, created_by: i64) -> Result<i64, CRRError> {
+        self.prepare("INSERT INTO roles (name, created_by) VALUES (:role_name, :created_by)")?
+            .insert(named_params! { ":role_name": name, ":created_by": created_by })?;
+
+        Ok(self.last_insert_rowid())
+    }
+
+    pub(crate) fn add_user_to_role(&self, role_id: i64, user_id: i64) -> Result<(), CRRError> {
+        self.prepare(
+            "
+                INSERT INTO user_roles (user_id, role_id) VALUES (:user_id, :role_id)
+                ON CONFLICT (user_id, role_id) DO NOTHING
+            ",
+        )?
+        .execute(named_params! {
+            ":user_id": user_id,
+            ":role_id": role_id,
+        })?;
+
+        Ok(())
+    }
+
+    /// only the user who created a role manages it, permissions granted to the role
+    /// don't matter as anybody owning a database can grant them
+    pub(crate) fn can_manage_role(&self, user_id: i64, role_id: i64) -> Result<bool, CRRError> {
+        let mut stmt =
+            self.prepare("SELECT 1 FROM roles WHERE id = :role_id AND created_by = :user_id")?;
+
+        Ok(stmt.exists(named_params! { ":role_id": role_id, ":user_id": user_id })?)
+    }
+
+    pub(crate) fn list_databases_for_token(
+        &self,
+        token: &str,
+    ) -> Result<Vec<DatabaseAccess>, CRRError> {
+        let user_id = self.authenticate_user(token)?;
+
+        let mut stmt = self.prepare(
+            "
+                SELECT
+                    database_name,
+                    MAX(pread OR pfull),
+                    MAX(pinsert OR pfull),
+                    MAX(pupdate OR pfull),
+                    MAX(pdelete OR pfull),
+                    MAX(pfull AND table_name IS NULL)
+                FROM permissions
+                WHERE role_id IN (SELECT role_id FROM user_roles WHERE user_id = :user_id)
+                GROUP BY database_name
+                ORDER BY database_name
+            ",
+        )?;
+
+        let databases = stmt
+            .query_map(named_params! { ":user_id": user_id }, |row| {
+                Ok(DatabaseAccess {
+                    name: row.get(0)?,
+                    read: row.get(1)?,
+                    insert: row.get(2)?,
+                    update: row.get(3)?,
+                    delete: row.get(4)?,
+                    full: row.get(5)?,
+                })
+            })?
+            .collect::<Result<Vec<DatabaseAccess>, rusqlite::Error>>()?;
+
+        Ok(databases)
+    }
 
     fn database_exists(&self, db_name: &str) -> Result<bool, CRRError> {
         let mut stmt =
@@ -200,21 +580,9 @@ impl AuthDatabase {
     ) -> Result<DatabasePermissions, CRRError> {
         self.execute("BEGIN", [])?;
 
-        {
-            let mut stmt = self.prepare("INSERT INTO roles (name) VALUES (:role_name)")?;
-            stmt.insert(named_params! { ":role_name": format!("{}_owners", db_name) })?;
-        }
+        let role_id = self.create_role(&format!("{}_owners", db_name), user_id)?;
 
-        let role_id = self.last_insert_rowid();
-
-        {
-            let mut stmt = self
-                .prepare("INSERT INTO user_roles (user_id, role_id) VALUES (:user_id, :role_id)")?;
-            stmt.insert(named_params! {
-                ":user_id": user_id,
-                ":role_id": role_id,
-            })?;
-        }
+        self.add_user_to_role(role_id, user_id)?;
 
         {
             let mut stmt = self.prepare("INSERT INTO permissions (role_id, database_name, pfull) VALUES (:role_id, :db_name, TRUE)")?;
@@ -230,19 +598,44 @@ impl AuthDatabase {
     }
 
     pub(crate) fn get_token_id(&self, token: &str) -> Result<i64, CRRError> {
-        Ok(
-            self.query_row("SELECT id FROM tokens WHERE token = ?", [token], |row| {
-                row.get("id")
-            })?,
-        )
+        Ok(self.query_row(
+            "SELECT id FROM access_tokens WHERE token = ?",
+            [token],
+            |row| row.get("id"),
+        )?)
     }
 
+    /// expired and revoked tokens are rejected like an invalid token
     pub(crate) fn get_token_by_id(&self, token_id: i64) -> Result<String, CRRError> {
-        Ok(
-            self.query_row("SELECT token FROM tokens WHERE id = ?", [token_id], |row| {
-                row.get("token")
-            })?,
+        self.query_row(
+            "SELECT token FROM access_tokens WHERE id = ? AND expires > JULIANDAY('now')",
+            [token_id],
+            |row| row.get("token"),
         )
+        .map_err(|error| match error {
+            rusqlite::Error::QueryReturnedNoRows => {
+                CRRError::Unauthorized("Invalid Token".to_owned())
+            }
+            error => error.into(),
+        })
+    }
+
+    /// time until the access token `token_id` expires
+    pub(crate) fn get_token_lifetime(&self, token_id: i64) -> Result<Duration, CRRError> {
+        let seconds: f64 = self
+            .query_row(
+                "SELECT (expires - JULIANDAY('now')) * 86400.0 FROM access_tokens WHERE id = ? AND expires > JULIANDAY('now')",
+                [token_id],
+                |row| row.get(0),
+            )
+            .map_err(|error| match error {
+                rusqlite::Error::QueryReturnedNoRows => {
+                    CRRError::Unauthorized("Invalid Token".to_owned())
+                }
+                error => error.into(),
+            })?;
+
+        Ok(Duration::from_secs_f64(seconds.max(0.0)))
     }
 }
 
@@ -260,8 +653,174 @@ impl std::ops::DerefMut for AuthDatabase {
     }
 }
 
+#[cfg(test)]
+impl AuthDatabase {
+    pub(crate) fn create_test_token(env: &Arc<AppEnv>) -> String {
+        let auth = Self::open(Arc::clone(env)).expect("Failed to open AuthDatabase");
+
+        auth.prepare("INSERT INTO users (email) VALUES (?)")
+            .and_then(|mut stmt| stmt.insert([format!("{}@example.com", nanoid::nanoid!())]))
+            .expect("Failed to create test user");
+
+        let user_id = auth.last_insert_rowid();
+        let token = nanoid::nanoid!();
+
+        auth.prepare(
+            "INSERT INTO access_tokens (user_id, token, expires, created_at) VALUES (?, ?, JULIANDAY('now') + 1, JULIANDAY('now'))",
+        )
+        .and_then(|mut stmt| stmt.insert(rusqlite::params![user_id, token]))
+        .expect("Failed to create test token");
+
+        token
+    }
+}
+
 #[cfg(test)]
 mod tests {
+    use crate::{app_state::AppEnv, error::CRRError};
+
+    use super::AuthDatabase;
+
     #[test]
     fn do_nothing() {}
+
+    #[test]
+    fn override_file_path() {
+        let path = std::env::temp_dir().join(format!("crr-auth-{}.sqlite3", nanoid::nanoid!()));
+        let env = AppEnv::with_auth_db_path(AppEnv::test_env(), path.clone());
+
+        let auth = AuthDatabase::open(env.clone()).unwrap();
+        auth.apply_migrations().unwrap();
+
+        assert!(
+            path.exists(),
+            "Auth database is created at {}",
+            path.display()
+        );
+        assert!(matches!(
+            auth.get_public_permissions("auth"),
+            Err(CRRError::ReservedName(_))
+        ));
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn merge_users_with_mixed_case_emails() {
+        let path = std::env::temp_dir().join(format!("crr-auth-{}.sqlite3", nanoid::nanoid!()));
+        let env = AppEnv::with_auth_db_path(AppEnv::test_env(), path.clone());
+
+        let auth = AuthDatabase::open(env.clone()).unwrap();
+        auth.apply_migrations().unwrap();
+
+        auth.execute_batch(
+            "
+                INSERT INTO users (id, email) VALUES
+                    (1, 'Foo@Example.com'),
+                    (2, 'foo@example.com'),
+                    (3, ' Bar@Example.com');
+                INSERT INTO users (id, email, service_account) VALUES (4, 'service:Build', TRUE);
+                INSERT INTO roles (id, name, created_by) VALUES (1, 'a', 1), (2, 'b', 2);
+                INSERT INTO user_roles (user_id, role_id) VALUES (1, 1), (1, 2), (2, 2);
+                INSERT INTO access_tokens (user_id, token, expires)
+                    VALUES (1, 'mixed-case', JULIANDAY('now') + 1);
+            ",
+        )
+        .unwrap();
+
+        // only the latest migration runs again
+        auth.execute(
+            &format!(
+                "PRAGMA user_version = {}",
+                AuthDatabase::MIGRATIONS.len() - 1
+            ),
+            [],
+        )
+        .unwrap();
+        auth.apply_migrations().unwrap();
+
+        let users: Vec<(i64, String)> = auth
+            .prepare("SELECT id, email FROM users ORDER BY id")
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+
+        assert_eq!(
+            users,
+            vec![
+                (2, "foo@example.com".to_owned()),
+                (3, "bar@example.com".to_owned()),
+                (4, "service:Build".to_owned()),
+            ]
+        );
+
+        let roles: Vec<i64> = auth
+            .prepare("SELECT role_id FROM user_roles WHERE user_id = 2 ORDER BY role_id")
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+
+        assert_eq!(roles, vec![1, 2], "Roles of merged users are kept");
+        assert_eq!(auth.authenticate_user("mixed-case").unwrap(), 2);
+        assert_eq!(
+            auth.query_row("SELECT created_by FROM roles WHERE id = 1", [], |row| {
+                row.get::<_, i64>(0)
+            })
+            .unwrap(),
+            2
+        );
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn reject_expired_token() {
+        let env = AppEnv::test_env();
+        let auth = AuthDatabase::open(env.clone()).unwrap();
+
+        let valid = AuthDatabase::create_test_token(&env);
+        let user_id = auth.authenticate_user(&valid).unwrap();
+
+        auth.execute(
+            "INSERT INTO access_tokens (user_id, token, expires) VALUES (?, 'expired', JULIANDAY('now') - 1)",
+            [user_id],
+        )
+        .unwrap();
+
+        assert!(matches!(
+            auth.authenticate_user("expired"),
+            Err(CRRError::Unauthorized(_))
+        ));
+    }
+
+    #[test]
+    fn delete_expired_tokens() {
+        let env = AppEnv::test_env();
+        let auth = AuthDatabase::open(env.clone()).unwrap();
+
+        let valid = AuthDatabase::create_test_token(&env);
+        let user_id = auth.authenticate_user(&valid).unwrap();
+
+        auth.execute(
+            "INSERT INTO access_tokens (user_id, token, expires) VALUES (?, 'expired', JULIANDAY('now') - 1)",
+            [user_id],
+        )
+        .unwrap();
+        auth.execute(
+            "INSERT INTO refresh_tokens (user_id, token, expires) VALUES (?, 'expired', JULIANDAY('now') - 1)",
+            [user_id],
+        )
+        .unwrap();
+
+        assert_eq!(auth.delete_expired_tokens().unwrap(), 2);
+        assert_eq!(
+            auth.authenticate_user(&valid).unwrap(),
+            user_id,
+            "Valid tokens are kept"
+        );
+    }
 }
@@ -5,26 +5,43 @@ use axum::{
 
 use crate::app_state::AppState;
 
-use self::{otp::post_otp, signed_url::get_signed_url, token::post_token};
+use self::{
+    otp::post_otp,
+    permissions::{post_permissions, post_public_read},
+    roles::{post_role, post_role_user},
+    signed_url::get_signed_url,
+    token::{delete_token, get_token_info, post_rotate_token, post_service_token, post_token},
+};
 
 mod database;
 mod otp;
 mod permissions;
+mod rate_limit;
+mod roles;
 mod signed_url;
 mod token;
 
 pub use database::AuthDatabase;
-pub(crate) use permissions::{AllowedTables, DatabasePermissions};
-pub(crate) use token::Token;
+pub(crate) use database::DatabaseAccess;
+pub(crate) use permissions::{is_server_table, AllowedTables, DatabasePermissions};
+pub(crate) use rate_limit::RateLimiter;
+pub(crate) use token::{authenticate_admin, Token};
 
 #[cfg(test)]
-pub(crate) use permissions::PartialPermissions;
+pub(crate) use permissions::{ObjectPermissions, PartialPermissions};
 
 pub(crate) const COOKIE_NAME: &'static str = "CRR_TOKEN";
 
 pub(crate) fn router() -> Router<AppState> {
     Router::new()
         .route("/otp", post(post_otp))
-        .route("/token", post(post_token))
+        .route("/token", post(post_token).delete(delete_token))
+        .route("/token/info", get(get_token_info))
+        .route("/token/rotate", post(post_rotate_token))
+        .route("/service-token", post(post_service_token))
         .route("/signed-url", get(get_signed_url))
+        .route("/permissions", post(post_permissions))
+        .route("/public-read", post(post_public_read))
+        .route("/roles", post(post_role))
+        .route("/roles/:role_id/users", post(post_role_user))
 }
@@ -1,7 +1,7 @@
 use std::sync::Arc;
 
 use axum::extract::{Json, State};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
 use crate::{app_state::AppState, error::CRRError};
 
@@ -12,24 +12,322 @@ pub(crate) struct OtpRequestData {
     email: String,
 }
 
+#[derive(Serialize, Debug)]
+pub(crate) struct OtpResponse {
+    sent: bool,
+    /// only set with `CRR_OTP_IN_RESPONSE`
+    otp: Option<String>,
+}
+
 pub(crate) async fn post_otp(
     State(state): State<AppState>,
     Json(data): Json<OtpRequestData>,
-) -> Result<(), CRRError> {
+) -> Result<Json<OtpResponse>, CRRError> {
+    let email = crate::mail::normalize_email(&data.email)?;
+
+    state.otp_rate_limiter().check(&email)?;
+
+    // sending blocks on SMTP or the webhook
+    let otp = tokio::task::spawn_blocking({
+        let state = state.clone();
+        let email = email.clone();
+        move || send_otp(&state, &email)
+    })
+    .await
+    .map_err(CRRError::from)
+    .and_then(|result| result)
+    .map_err(|error| {
+        // failed deliveries don't count against the rate limit
+        state.otp_rate_limiter().release(&email);
+        error
+    })?;
+
+    Ok(Json(OtpResponse {
+        sent: true,
+        otp: state.env().otp_in_response().then_some(otp),
+    }))
+}
+
+fn send_otp(state: &AppState, email: &str) -> Result<String, CRRError> {
     let auth = AuthDatabase::open(Arc::clone(state.env()))?;
 
     let otp = nanoid::nanoid!();
 
     let mut stmt = auth.prepare(
         "
-        INSERT INTO users (email, otp)
-        VALUES (:email, :otp)
-        ON CONFLICT (email) DO UPDATE SET otp = :otp;
+        INSERT INTO users (email, otp, otp_expires)
+        VALUES (:email, :otp, JULIANDAY('now') + :ttl)
+        ON CONFLICT (email) DO UPDATE SET otp = :otp, otp_expires = excluded.otp_expires;
     ",
     )?;
 
-    stmt.insert(rusqlite::named_params! { ":email": data.email, ":otp": otp})?;
+    let ttl_days = state.env().otp_ttl().as_secs_f64() / 86400.0;
+
+    stmt.insert(rusqlite::named_params! { ":email": email, ":otp": otp, ":ttl": ttl_days })?;
+
+    state.notifier().send_otp(email, &otp)?;
+
+    Ok(otp)
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        extract::{Json, State},
+        http::{header::RETRY_AFTER, StatusCode},
+        response::IntoResponse,
+    };
+
+    use std::{
+        io::{BufRead, BufReader, Read, Write},
+        net::TcpListener,
+        sync::Arc,
+        time::Duration,
+    };
+
+    use super::{post_otp, OtpRequestData};
+    use crate::{
+        app_state::{AppEnv, AppState},
+        auth::database::AuthDatabase,
+        error::CRRError,
+        mail::WebhookNotifier,
+    };
+
+    #[tokio::test]
+    async fn rate_limit_otp_requests() {
+        let state = AppState::test_state();
+
+        let request = || {
+            Json(OtpRequestData {
+                email: "test@example.com".to_owned(),
+            })
+        };
+
+        post_otp(State(state.clone()), request())
+            .await
+            .expect("Failed to request OTP");
+
+        assert!(matches!(
+            post_otp(State(state.clone()), request()).await,
+            Err(CRRError::TooManyRequests(..))
+        ));
+    }
+
+    #[tokio::test]
+    async fn release_rate_limit_on_failed_send() {
+        // nothing listens on the port once the listener is dropped
+        let url = {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            format!("http://{}/otp", listener.local_addr().unwrap())
+        };
+
+        let state = AppState::test_state();
+        let failing_state = state
+            .clone()
+            .with_notifier(Arc::new(WebhookNotifier::new(url)));
+
+        let request = || {
+            Json(OtpRequestData {
+                email: "test@example.com".to_owned(),
+            })
+        };
+
+        assert!(matches!(
+            post_otp(State(failing_state), request()).await,
+            Err(CRRError::WebhookError(_))
+        ));
+
+        post_otp(State(state), request())
+            .await
+            .expect("Failed delivery was counted against the rate limit");
+    }
+
+    #[tokio::test]
+    async fn time_out_unresponsive_webhook() {
+        // accepts connections but never answers
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/otp", listener.local_addr().unwrap());
+
+        let state = AppState::test_state().with_notifier(Arc::new(WebhookNotifier::with_timeout(
+            url,
+            Duration::from_millis(100),
+        )));
+
+        let result = post_otp(
+            State(state),
+            Json(OtpRequestData {
+                email: "test@example.com".to_owned(),
+            }),
+        )
+        .await;
+
+        assert!(matches!(result, Err(CRRError::WebhookError(_))));
+
+        drop(listener);
+    }
+
+    #[tokio::test]
+    async fn retry_after_rate_limit() {
+        let state = AppState::test_state();
+
+        let request = || {
+            Json(OtpRequestData {
+                email: "test@example.com".to_owned(),
+            })
+        };
+
+        post_otp(State(state.clone()), request())
+            .await
+            .expect("Failed to request OTP");
+
+        let response = post_otp(State(state.clone()), request())
+            .await
+            .expect_err("Second OTP request is rate limited")
+            .into_response();
+
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        let retry_after: u64 = response.headers()[RETRY_AFTER]
+            .to_str()
+            .unwrap()
+            .parse()
+            .expect("Retry-After is a number of seconds");
+
+        assert!(retry_after > 0);
+        assert!(retry_after <= state.env().otp_rate_limit().as_secs());
+    }
+
+    #[tokio::test]
+    async fn respond_with_otp_in_dev_mode() {
+        let request = || {
+            Json(OtpRequestData {
+                email: "test@example.com".to_owned(),
+            })
+        };
+
+        let Json(response) = post_otp(State(AppState::test_state()), request())
+            .await
+            .expect("Failed to request OTP");
+
+        assert_eq!(
+            serde_json::to_value(response).unwrap(),
+            serde_json::json!({ "sent": true, "otp": null })
+        );
+
+        let state =
+            AppState::test_state().with_env(AppEnv::with_otp_in_response(AppEnv::test_env()));
+        let Json(response) = post_otp(State(state.clone()), request())
+            .await
+            .expect("Failed to request OTP");
+
+        let otp: String = AuthDatabase::open(Arc::clone(state.env()))
+            .unwrap()
+            .query_row(
+                "SELECT otp FROM users WHERE email = 'test@example.com'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+
+        assert_eq!(
+            serde_json::to_value(response).unwrap(),
+            serde_json::json!({ "sent": true, "otp": otp })
+        );
+    }
+
+    #[tokio::test]
+    async fn normalize_email() {
+        let state = AppState::test_state();
+
+        let request = |email: &str| {
+            Json(OtpRequestData {
+                email: email.to_owned(),
+            })
+        };
+
+        post_otp(State(state.clone()), request(" Foo@Bar.com "))
+            .await
+            .expect("Failed to request OTP");
+
+        // a fresh rate limiter, so the second request isn't rejected
+        let other_state = AppState::test_state().with_env(Arc::clone(state.env()));
+        post_otp(State(other_state), request("foo@bar.com"))
+            .await
+            .expect("Failed to request OTP");
+
+        let emails: Vec<String> = AuthDatabase::open(Arc::clone(state.env()))
+            .unwrap()
+            .prepare("SELECT email FROM users")
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+
+        assert_eq!(emails, vec!["foo@bar.com"]);
+
+        assert!(matches!(
+            post_otp(State(state), request("not an email")).await,
+            Err(CRRError::BadRequest(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn deliver_otp_via_webhook() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/otp", listener.local_addr().unwrap());
+
+        let server = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+
+            let mut content_length = 0;
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if line == "\r\n" {
+                    break;
+                }
+                if let Some((name, value)) = line.split_once(':') {
+                    if name.eq_ignore_ascii_case("content-length") {
+                        content_length = value.trim().parse().unwrap();
+                    }
+                }
+            }
+
+            let mut body = vec![0; content_length];
+            reader.read_exact(&mut body).unwrap();
+            reader
+                .get_mut()
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n")
+                .unwrap();
+
+            serde_json::from_slice::<serde_json::Value>(&body).unwrap()
+        });
+
+        let state = AppState::test_state().with_notifier(Arc::new(WebhookNotifier::new(url)));
+
+        post_otp(
+            State(state.clone()),
+            Json(OtpRequestData {
+                email: "test@example.com".to_owned(),
+            }),
+        )
+        .await
+        .expect("Failed to request OTP");
+
+        let otp: String = AuthDatabase::open(Arc::clone(state.env()))
+            .unwrap()
+            .query_row(
+                "SELECT otp FROM users WHERE email = 'test@example.com'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+
+        let payload = server.join().unwrap();
 
-    crate::mail::send_email(&data.email, "Your OTP".to_owned(), otp)?;
-    Ok(())
+        assert_eq!(payload["email"], "test@example.com");
+        assert_eq!(payload["otp"], otp.as_str());
+    }
 }
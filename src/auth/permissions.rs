@@ -1,13 +1,13 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
 use axum::{
     async_trait,
-    extract::{FromRequestParts, Path},
+    extract::{FromRequestParts, Json, Path, State},
     http::request::Parts,
 };
 use serde::Deserialize;
 
-use crate::{app_state::AppState, error::CRRError};
+use crate::{app_state::AppState, audit::AuditRecord, database::validate_db_name, error::CRRError};
 
 use super::{AuthDatabase, Token};
 
@@ -23,21 +23,57 @@ impl PartialPermissions {
     fn is_empty(&self) -> bool {
         return !self.read && !self.insert && !self.update && !self.delete;
     }
+
+    /// e.g. `read,insert`, `none` if nothing is granted
+    fn summary(&self) -> String {
+        if self.is_empty() {
+            return "none".to_owned();
+        }
+
+        [
+            ("read", self.read),
+            ("insert", self.insert),
+            ("update", self.update),
+            ("delete", self.delete),
+        ]
+        .into_iter()
+        .filter(|(_name, granted)| *granted)
+        .map(|(name, _granted)| name)
+        .collect::<Vec<_>>()
+        .join(",")
+    }
 }
 
 #[derive(Debug, Clone)]
 pub(crate) enum ObjectPermissions {
     Full,
-    Partial(PartialPermissions),
+    Partial {
+        permissions: PartialPermissions,
+        denied_columns: HashSet<String>,
+    },
 }
 
 impl Default for ObjectPermissions {
     fn default() -> Self {
-        Self::Partial(PartialPermissions::default())
+        Self::partial(PartialPermissions::default())
     }
 }
 
 impl ObjectPermissions {
+    pub(crate) fn partial(permissions: PartialPermissions) -> Self {
+        Self::Partial {
+            permissions,
+            denied_columns: HashSet::new(),
+        }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        match self {
+            Self::Full => false,
+            Self::Partial { permissions, .. } => permissions.is_empty(),
+        }
+    }
+
     pub(crate) fn set_full(&mut self) {
         *self = Self::Full;
     }
@@ -45,11 +81,19 @@ impl ObjectPermissions {
     pub(crate) fn set(&mut self, permissions: PartialPermissions) {
         match self {
             Self::Full => (),
-            Self::Partial(p) => *p = permissions,
+            Self::Partial { permissions: p, .. } => *p = permissions,
+        }
+    }
+
+    pub(crate) fn deny_column(&mut self, column_name: String) {
+        match self {
+            Self::Full => (),
+            Self::Partial { denied_columns, .. } => {
+                denied_columns.insert(column_name);
+            }
         }
     }
 
-    #[cfg(test)]
     pub(crate) fn full(&self) -> bool {
         match self {
             Self::Full => true,
@@ -59,25 +103,31 @@ impl ObjectPermissions {
     pub(crate) fn read(&self) -> bool {
         match self {
             Self::Full => true,
-            Self::Partial(p) => p.read,
+            Self::Partial { permissions, .. } => permissions.read,
+        }
+    }
+    pub(crate) fn read_column(&self, column_name: &str) -> bool {
+        match self {
+            Self::Full => true,
+            Self::Partial { denied_columns, .. } => !denied_columns.contains(column_name),
         }
     }
     pub(crate) fn insert(&self) -> bool {
         match self {
             Self::Full => true,
-            Self::Partial(p) => p.insert,
+            Self::Partial { permissions, .. } => permissions.insert,
         }
     }
     pub(crate) fn update(&self) -> bool {
         match self {
             Self::Full => true,
-            Self::Partial(p) => p.update,
+            Self::Partial { permissions, .. } => permissions.update,
         }
     }
     pub(crate) fn delete(&self) -> bool {
         match self {
             Self::Full => true,
-            Self::Partial(p) => p.delete,
+            Self::Partial { permissions, .. } => permissions.delete,
         }
     }
 }
@@ -101,6 +151,21 @@ impl Default for DatabasePermissions {
     }
 }
 
+/// tables that configure or keep track of crr-server, e.g. `crr_server_merge_policies`,
+/// only writable with full access
+pub(crate) fn is_server_table(table_name: &str) -> bool {
+    table_name.starts_with("crr_server_")
+}
+
+/// tables created by crr-server and crsqlite rather than by migrations
+fn is_internal_table(table_name: &str) -> bool {
+    is_server_table(table_name)
+        || table_name.starts_with("crsql_")
+        || table_name.starts_with("__crsql")
+        || table_name.ends_with("__crsql_clock")
+        || table_name.ends_with("__crsql_pks")
+}
+
 impl DatabasePermissions {
     pub(crate) fn set_full(&mut self) {
         *self = Self::Full;
@@ -115,6 +180,32 @@ impl DatabasePermissions {
         }
     }
 
+    /// Compact description for the audit log, e.g. `database: read; app_*: full`
+    pub(crate) fn summary(&self) -> String {
+        match self {
+            Self::Full => "full".to_owned(),
+            Self::Create => "create".to_owned(),
+            Self::Partial { database, tables } => {
+                let mut table_names: Vec<&String> = tables.keys().collect();
+                table_names.sort();
+
+                std::iter::once(format!("database: {}", database.summary()))
+                    .chain(
+                        table_names
+                            .into_iter()
+                            .map(|table_name| match &tables[table_name] {
+                                ObjectPermissions::Full => format!("{}: full", table_name),
+                                ObjectPermissions::Partial { permissions, .. } => {
+                                    format!("{}: {}", table_name, permissions.summary())
+                                }
+                            }),
+                    )
+                    .collect::<Vec<_>>()
+                    .join("; ")
+            }
+        }
+    }
+
     fn with_table<F>(&mut self, table_name: String, f: F)
     where
         F: FnOnce(&mut ObjectPermissions),
@@ -132,12 +223,38 @@ impl DatabasePermissions {
         }
     }
 
+    /// Exact table entries take precedence over prefix patterns like `app_*`,
+    /// of several matching patterns the longest one wins.
+    /// Patterns never match the bookkeeping tables of crr-server and crsqlite.
+    fn table_permissions<'p>(
+        tables: &'p HashMap<String, ObjectPermissions>,
+        table_name: &str,
+    ) -> Option<&'p ObjectPermissions> {
+        tables.get(table_name).or_else(|| {
+            if is_internal_table(table_name) {
+                return None;
+            }
+
+            tables
+                .iter()
+                .filter(|(pattern, _p)| match pattern.strip_suffix('*') {
+                    Some(prefix) => table_name.starts_with(prefix),
+                    None => false,
+                })
+                .max_by_key(|(pattern, _p)| pattern.len())
+                .map(|(_pattern, p)| p)
+        })
+    }
+
     pub(crate) fn set_table_full(&mut self, table_name: String) {
         self.with_table(table_name, |t| t.set_full());
     }
     pub(crate) fn set_table(&mut self, table_name: String, permissions: PartialPermissions) {
         self.with_table(table_name, |t| t.set(permissions));
     }
+    pub(crate) fn deny_column(&mut self, table_name: String, column_name: String) {
+        self.with_table(table_name, |t| t.deny_column(column_name));
+    }
 
     pub(crate) fn is_empty(&self) -> bool {
         match self {
@@ -149,24 +266,6 @@ impl DatabasePermissions {
         }
     }
 
-    //pub(crate) fn apply<F>(&self, mut f: F) -> Result<(), CRRError>
-    //where
-    //    F: FnMut(Option<&str>, ObjectPermissions) -> Result<(), CRRError>,
-    //{
-    //    match self {
-    //        Self::Full => f(None, ObjectPermissions::Full),
-    //        Self::Partial { database, tables } => {
-    //            f(None, ObjectPermissions::Partial(database.clone()))?;
-
-    //            for (table_name, permissions) in tables {
-    //                f(Some(table_name), permissions.clone())?;
-    //            }
-
-    //            Ok(())
-    //        }
-    //    }
-    //}
-
     pub(crate) fn full(&self) -> bool {
         match self {
             Self::Full => true,
@@ -196,9 +295,9 @@ impl DatabasePermissions {
         match self {
             Self::Full => true,
             Self::Create => true,
-            Self::Partial { tables, .. } => {
-                tables.get(table_name).map(|p| p.full()).unwrap_or(false)
-            }
+            Self::Partial { tables, .. } => Self::table_permissions(tables, table_name)
+                .map(|p| p.full())
+                .unwrap_or(false),
         }
     }
     pub(crate) fn read_table(&self, table_name: &str) -> bool {
@@ -206,16 +305,32 @@ impl DatabasePermissions {
             Self::Full => true,
             Self::Create => true,
             Self::Partial { database, tables } => {
-                database.read || tables.get(table_name).map(|p| p.read()).unwrap_or(false)
+                database.read
+                    || Self::table_permissions(tables, table_name)
+                        .map(|p| p.read())
+                        .unwrap_or(false)
             }
         }
     }
+    pub(crate) fn read_column(&self, table_name: &str, column_name: &str) -> bool {
+        match self {
+            Self::Full => true,
+            Self::Create => true,
+            Self::Partial { tables, .. } => Self::table_permissions(tables, table_name)
+                .map(|p| p.read_column(column_name))
+                .unwrap_or(true),
+        }
+    }
     pub(crate) fn update_table(&self, table_name: &str) -> bool {
         match self {
             Self::Full => true,
             Self::Create => true,
             Self::Partial { database, tables } => {
-                database.update || tables.get(table_name).map(|p| p.update()).unwrap_or(false)
+                !is_server_table(table_name)
+                    && (database.update
+                        || Self::table_permissions(tables, table_name)
+                            .map(|p| p.update())
+                            .unwrap_or(false))
             }
         }
     }
@@ -224,7 +339,11 @@ impl DatabasePermissions {
             Self::Full => true,
             Self::Create => true,
             Self::Partial { database, tables } => {
-                database.insert || tables.get(table_name).map(|p| p.insert()).unwrap_or(false)
+                !is_server_table(table_name)
+                    && (database.insert
+                        || Self::table_permissions(tables, table_name)
+                            .map(|p| p.insert())
+                            .unwrap_or(false))
             }
         }
     }
@@ -233,7 +352,27 @@ impl DatabasePermissions {
             Self::Full => true,
             Self::Create => true,
             Self::Partial { database, tables } => {
-                database.delete || tables.get(table_name).map(|p| p.delete()).unwrap_or(false)
+                !is_server_table(table_name)
+                    && (database.delete
+                        || Self::table_permissions(tables, table_name)
+                            .map(|p| p.delete())
+                            .unwrap_or(false))
+            }
+        }
+    }
+
+    /// whether anything at all can be written, some table might still deny it
+    pub(crate) fn write(&self) -> bool {
+        match self {
+            Self::Full => true,
+            Self::Create => true,
+            Self::Partial { database, tables } => {
+                database.insert
+                    || database.update
+                    || database.delete
+                    || tables
+                        .values()
+                        .any(|p| p.insert() || p.update() || p.delete())
             }
         }
     }
@@ -248,8 +387,9 @@ impl DatabasePermissions {
                 } else {
                     AllowedTables::Some(
                         tables
-                            .keys()
-                            .map(|table_name| table_name.to_owned())
+                            .iter()
+                            .filter(|(_table_name, p)| p.read())
+                            .map(|(table_name, _p)| table_name.to_owned())
                             .collect(),
                     )
                 }
@@ -257,6 +397,64 @@ impl DatabasePermissions {
         }
     }
 
+    /// rejects callers that can't read any table of the database
+    pub(crate) fn require_read(&self) -> Result<(), CRRError> {
+        if self.readable_tables().is_empty() {
+            return Err(CRRError::Unauthorized(
+                "User is not authorized to read database".to_owned(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Like [`DatabasePermissions::readable_tables`] but with prefix patterns
+    /// resolved against the existing `table_names`
+    pub(crate) fn readable_tables_in(&self, table_names: &[String]) -> AllowedTables {
+        match self.readable_tables() {
+            AllowedTables::All => AllowedTables::All,
+            AllowedTables::Some(_) => AllowedTables::Some(
+                table_names
+                    .iter()
+                    .filter(|table_name| self.read_table(table_name))
+                    .map(|table_name| table_name.to_owned())
+                    .collect(),
+            ),
+        }
+    }
+
+    /// Read-only permissions for those of `table_names` that can be read,
+    /// other tables are dropped. Denied columns are kept.
+    pub(crate) fn restrict_to_tables(&self, table_names: &[String]) -> Self {
+        let tables = table_names
+            .iter()
+            .filter(|table_name| self.read_table(table_name))
+            .map(|table_name| {
+                let mut permissions = ObjectPermissions::partial(PartialPermissions {
+                    read: true,
+                    ..PartialPermissions::default()
+                });
+
+                if let Self::Partial { tables, .. } = self {
+                    if let Some(ObjectPermissions::Partial { denied_columns, .. }) =
+                        Self::table_permissions(tables, table_name)
+                    {
+                        for column_name in denied_columns {
+                            permissions.deny_column(column_name.to_owned());
+                        }
+                    }
+                }
+
+                (table_name.to_owned(), permissions)
+            })
+            .collect();
+
+        Self::Partial {
+            database: PartialPermissions::default(),
+            tables,
+        }
+    }
+
     pub(crate) fn create(&self) -> bool {
         match self {
             Self::Create => true,
@@ -280,6 +478,76 @@ impl AllowedTables {
     }
 }
 
+#[derive(Deserialize)]
+pub(crate) struct PermissionsPostData {
+    role_id: i64,
+    database_name: String,
+    table_name: Option<String>,
+    #[serde(default)]
+    read: bool,
+    #[serde(default)]
+    insert: bool,
+    #[serde(default)]
+    update: bool,
+    #[serde(default)]
+    delete: bool,
+    #[serde(default)]
+    full: bool,
+}
+
+pub(crate) async fn post_permissions(
+    Token(token): Token,
+    State(state): State<AppState>,
+    Json(data): Json<PermissionsPostData>,
+) -> Result<(), CRRError> {
+    let auth = AuthDatabase::open(state.env().clone())?;
+
+    let user_id = auth.authenticate_database_owner(&token, &data.database_name)?;
+
+    if !auth.can_manage_role(user_id, data.role_id)? {
+        return Err(CRRError::Unauthorized(
+            "User must have created this role to grant it permissions".to_owned(),
+        ));
+    }
+
+    let permissions = if data.full {
+        ObjectPermissions::Full
+    } else {
+        ObjectPermissions::partial(PartialPermissions {
+            read: data.read,
+            insert: data.insert,
+            update: data.update,
+            delete: data.delete,
+        })
+    };
+
+    auth.update_permissions(
+        data.role_id,
+        &data.database_name,
+        data.table_name.as_deref(),
+        &permissions,
+    )
+}
+
+#[derive(Deserialize)]
+pub(crate) struct PublicReadPostData {
+    database_name: String,
+    public_read: bool,
+}
+
+/// Lets anybody read the database without a token, only the owner can change this
+pub(crate) async fn post_public_read(
+    Token(token): Token,
+    State(state): State<AppState>,
+    Json(data): Json<PublicReadPostData>,
+) -> Result<(), CRRError> {
+    let auth = AuthDatabase::open(state.env().clone())?;
+
+    auth.authenticate_database_owner(&token, &data.database_name)?;
+
+    auth.set_public_read(&data.database_name, data.public_read)
+}
+
 #[derive(Deserialize)]
 struct PathParams {
     db_name: String,
@@ -293,12 +561,42 @@ impl FromRequestParts<AppState> for DatabasePermissions {
         parts: &mut Parts,
         state: &AppState,
     ) -> Result<Self, Self::Rejection> {
-        let Token(token) = Token::from_request_parts(parts, state).await?;
+        // the path is checked first so routes without `:db_name` fail loudly
+        // instead of looking like an authentication problem
         let Path(PathParams { db_name }) =
             Path::<PathParams>::from_request_parts(parts, state).await?;
-
+        // every handler below /db/:db_name takes its permissions from here
+        validate_db_name(&db_name)?;
         let auth = AuthDatabase::open(state.env().clone())?;
-        auth.get_permissions(&token, &db_name)
+
+        // public databases can be read without a token
+        let (token_id, permissions) = match Token::from_request_parts(parts, state).await {
+            Ok(Token(token)) => (
+                auth.get_token_id(&token).ok(),
+                auth.get_permissions(&token, &db_name),
+            ),
+            Err(error) => (
+                None,
+                auth.get_public_permissions(&db_name)
+                    .and_then(|permissions| permissions.ok_or(error)),
+            ),
+        };
+
+        let method = parts.method.to_string();
+        let path = parts.uri.path().to_owned();
+        let record = match &permissions {
+            Ok(permissions) => {
+                AuditRecord::new(token_id, db_name, method, path, permissions.summary())
+            }
+            Err(error) => AuditRecord::failed(token_id, db_name, method, path, error),
+        };
+
+        // a failing audit sink shouldn't take down the api
+        if let Err(error) = state.audit_log().record(&record) {
+            tracing::error!("Failed to write audit record: {}", error);
+        }
+
+        permissions
     }
 }
 
@@ -306,9 +604,28 @@ impl FromRequestParts<AppState> for DatabasePermissions {
 mod tests {
     use std::collections::HashMap;
 
-    use crate::auth::AllowedTables;
+    use axum::{
+        body::Body,
+        extract::{Json, Path, State},
+        http::{header::AUTHORIZATION, Request, StatusCode},
+        response::Response,
+        routing::get,
+        Router,
+    };
+    use tower::ServiceExt;
+
+    use crate::{
+        app_state::AppState,
+        auth::{
+            roles::{post_role_user, RoleUserPostData},
+            AllowedTables, AuthDatabase, Token,
+        },
+    };
 
-    use super::{DatabasePermissions, ObjectPermissions, PartialPermissions};
+    use super::{
+        post_permissions, DatabasePermissions, ObjectPermissions, PartialPermissions,
+        PermissionsPostData,
+    };
 
     #[test]
     fn full() {
@@ -330,6 +647,38 @@ mod tests {
         assert_eq!(readable_tables, AllowedTables::All);
     }
 
+    #[test]
+    fn create() {
+        let mut p = DatabasePermissions::Create;
+
+        assert!(p.create(), "Owns a new Database");
+        assert!(p.full(), "Has full access to Database");
+        assert!(p.full_table("foo"), "Has full access to arbitrary table");
+        assert!(p.read(), "Has read access to Database");
+        assert!(p.read_column("bar", "secret"), "Can read arbitrary column");
+        assert!(
+            p.insert_table("bar"),
+            "Has insert access to arbitrary table"
+        );
+        assert!(
+            p.update_table("bar"),
+            "Has update access to arbitrary table"
+        );
+        assert!(
+            p.delete_table("baz"),
+            "Has delete access to arbitrary table"
+        );
+        assert!(!p.is_empty());
+        assert_eq!(p.readable_tables(), AllowedTables::All);
+
+        p.deny_column("bar".to_owned(), "secret".to_owned());
+        assert!(p.create(), "Denying columns doesn't narrow Create");
+        assert!(p.read_column("bar", "secret"));
+
+        assert!(!DatabasePermissions::Full.create(), "Full isn't Create");
+        assert!(!DatabasePermissions::default().create());
+    }
+
     #[test]
     fn readonly() {
         let p = DatabasePermissions::Partial {
@@ -357,7 +706,7 @@ mod tests {
         let mut tables = HashMap::new();
         tables.insert(
             "foo".to_owned(),
-            ObjectPermissions::Partial(PartialPermissions {
+            ObjectPermissions::partial(PartialPermissions {
                 read: true,
                 insert: false,
                 update: false,
@@ -381,4 +730,382 @@ mod tests {
             "Table is in readable tables"
         );
     }
+
+    #[test]
+    fn prefix_pattern() {
+        let mut p = DatabasePermissions::default();
+        p.set_table(
+            "app_*".to_owned(),
+            PartialPermissions {
+                read: true,
+                insert: true,
+                update: false,
+                delete: false,
+            },
+        );
+
+        assert!(p.read_table("app_users"), "Pattern grants read");
+        assert!(p.insert_table("app_"), "Pattern grants insert");
+        assert!(!p.update_table("app_users"));
+        assert!(!p.read_table("users"), "Pattern only matches prefix");
+
+        assert_eq!(
+            p.readable_tables_in(&[
+                "app_users".to_owned(),
+                "app_posts".to_owned(),
+                "users".to_owned()
+            ]),
+            AllowedTables::Some(vec!["app_users".to_owned(), "app_posts".to_owned()])
+        );
+    }
+
+    #[test]
+    fn prefix_pattern_skips_internal_tables() {
+        let mut p = DatabasePermissions::default();
+        p.set_table_full("*".to_owned());
+        p.set_table_full("crr*".to_owned());
+        p.set_table_full("foo*".to_owned());
+
+        assert!(p.full_table("foo"));
+        assert!(p.full_table("crr_items"));
+
+        for table_name in [
+            "crr_server_migrations",
+            "crr_server_checkpoints",
+            "crr_server_merge_policies",
+            "foo__crsql_clock",
+            "foo__crsql_pks",
+            "crsql_site_id",
+            "__crsql_master",
+        ] {
+            assert!(!p.full_table(table_name), "{} is not matched", table_name);
+            assert!(!p.read_table(table_name), "{} is not readable", table_name);
+        }
+
+        p.set_table_full("foo__crsql_clock".to_owned());
+        assert!(
+            p.full_table("foo__crsql_clock"),
+            "Exact entries still apply"
+        );
+    }
+
+    #[test]
+    fn server_tables_require_full_access() {
+        let mut p = DatabasePermissions::default();
+        p.set(PartialPermissions {
+            read: true,
+            insert: true,
+            update: true,
+            delete: true,
+        });
+        p.set_table_full("crr_server_merge_policies".to_owned());
+
+        assert!(p.insert_table("foo"));
+        assert!(p.read_table("crr_server_merge_policies"));
+        assert!(!p.insert_table("crr_server_merge_policies"));
+        assert!(!p.update_table("crr_server_merge_policies"));
+        assert!(!p.delete_table("crr_server_merge_policies"));
+        assert!(DatabasePermissions::Full.insert_table("crr_server_merge_policies"));
+    }
+
+    #[test]
+    fn summary() {
+        let mut p = DatabasePermissions::default();
+        p.set_table_full("app_*".to_owned());
+        p.set_table(
+            "app_secrets".to_owned(),
+            PartialPermissions {
+                read: true,
+                insert: true,
+                update: false,
+                delete: false,
+            },
+        );
+
+        assert_eq!(
+            p.summary(),
+            "database: none; app_*: full; app_secrets: read,insert"
+        );
+        assert_eq!(DatabasePermissions::Full.summary(), "full");
+        assert_eq!(DatabasePermissions::Create.summary(), "create");
+    }
+
+    #[test]
+    fn exact_match_precedence() {
+        let mut p = DatabasePermissions::default();
+        p.set_table_full("app_*".to_owned());
+        p.set_table(
+            "app_secrets".to_owned(),
+            PartialPermissions {
+                read: false,
+                insert: true,
+                update: false,
+                delete: false,
+            },
+        );
+        p.set_table(
+            "app_logs_*".to_owned(),
+            PartialPermissions {
+                read: true,
+                insert: false,
+                update: false,
+                delete: false,
+            },
+        );
+
+        assert!(p.full_table("app_users"));
+        assert!(
+            !p.read_table("app_secrets"),
+            "Exact match wins over pattern"
+        );
+        assert!(p.insert_table("app_secrets"));
+        assert!(p.read_table("app_logs_2023"));
+        assert!(
+            !p.delete_table("app_logs_2023"),
+            "Longest matching pattern wins"
+        );
+    }
+
+    #[tokio::test]
+    async fn grant_table_read() {
+        let state = AppState::test_state();
+        let auth = AuthDatabase::open(state.env().clone()).unwrap();
+
+        let owner = AuthDatabase::create_test_token(state.env());
+        assert!(auth.get_permissions(&owner, "shared").unwrap().create());
+
+        let reader = AuthDatabase::create_test_token(state.env());
+        let reader_id = auth.authenticate_user(&reader).unwrap();
+
+        let owner_id = auth.authenticate_user(&owner).unwrap();
+        auth.execute(
+            "INSERT INTO roles (name, created_by) VALUES ('shared_readers', ?)",
+            [owner_id],
+        )
+        .unwrap();
+        let role_id = auth.last_insert_rowid();
+        auth.execute(
+            "INSERT INTO user_roles (user_id, role_id) VALUES (?, ?)",
+            [reader_id, role_id],
+        )
+        .unwrap();
+
+        let grant = |token: &str| {
+            post_permissions(
+                Token(token.to_owned()),
+                State(state.clone()),
+                Json(PermissionsPostData {
+                    role_id,
+                    database_name: "shared".to_owned(),
+                    table_name: Some("foo".to_owned()),
+                    read: true,
+                    insert: false,
+                    update: false,
+                    delete: false,
+                    full: false,
+                }),
+            )
+        };
+
+        assert!(
+            grant(&reader).await.is_err(),
+            "Only owners can grant permissions"
+        );
+
+        grant(&owner).await.unwrap();
+
+        let p = auth.get_permissions(&reader, "shared").unwrap();
+
+        assert!(!p.full());
+        assert!(p.read_table("foo"));
+        assert!(!p.insert_table("foo"));
+        assert!(!p.read_table("bar"));
+        assert_eq!(
+            p.readable_tables(),
+            AllowedTables::Some(vec!["foo".to_owned()])
+        );
+    }
+
+    #[tokio::test]
+    async fn reject_grants_to_foreign_roles() {
+        let state = AppState::test_state();
+        let auth = AuthDatabase::open(state.env().clone()).unwrap();
+
+        let victim = AuthDatabase::create_test_token(state.env());
+        auth.get_permissions(&victim, "victim").unwrap();
+
+        let attacker = AuthDatabase::create_test_token(state.env());
+        let attacker_id = auth.authenticate_user(&attacker).unwrap();
+        auth.get_permissions(&attacker, "attacker").unwrap();
+
+        let owners_role_id: i64 = auth
+            .query_row(
+                "SELECT id FROM roles WHERE name = 'victim_owners'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+
+        assert!(post_permissions(
+            Token(attacker.clone()),
+            State(state.clone()),
+            Json(PermissionsPostData {
+                role_id: owners_role_id,
+                database_name: "attacker".to_owned(),
+                table_name: None,
+                read: true,
+                insert: false,
+                update: false,
+                delete: false,
+                full: false,
+            }),
+        )
+        .await
+        .is_err());
+
+        assert!(post_role_user(
+            Path(owners_role_id),
+            Token(attacker),
+            State(state.clone()),
+            Json(RoleUserPostData {
+                user_id: attacker_id
+            }),
+        )
+        .await
+        .is_err());
+
+        assert!(!auth
+            .get_permissions_for_user(attacker_id, "victim")
+            .unwrap()
+            .full());
+    }
+
+    /// responds with the permissions summary in the `x-permissions` header
+    async fn request_permissions(state: &AppState, db_name: &str, token: Option<&str>) -> Response {
+        let app = Router::new()
+            .route(
+                "/db/:db_name/permissions",
+                get(|permissions: DatabasePermissions| async move {
+                    [("x-permissions", permissions.summary())]
+                }),
+            )
+            .with_state(state.clone());
+
+        let mut req = Request::builder().uri(format!("/db/{}/permissions", db_name));
+
+        if let Some(token) = token {
+            req = req.header(AUTHORIZATION, format!("Bearer {}", token));
+        }
+
+        app.oneshot(req.body(Body::empty()).unwrap()).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn extract_owner_permissions() {
+        let state = AppState::test_state();
+        let owner = AuthDatabase::create_test_token(state.env());
+
+        let res = request_permissions(&state, "owned", Some(&owner)).await;
+
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(
+            res.headers()["x-permissions"],
+            "create",
+            "First access creates the database"
+        );
+
+        let res = request_permissions(&state, "owned", Some(&owner)).await;
+
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()["x-permissions"], "full");
+    }
+
+    #[tokio::test]
+    async fn extract_partial_permissions() {
+        let state = AppState::test_state();
+        let auth = AuthDatabase::open(state.env().clone()).unwrap();
+
+        let owner = AuthDatabase::create_test_token(state.env());
+        auth.get_permissions(&owner, "shared").unwrap();
+
+        let reader = AuthDatabase::create_test_token(state.env());
+        let reader_id = auth.authenticate_user(&reader).unwrap();
+
+        let owner_id = auth.authenticate_user(&owner).unwrap();
+        auth.execute(
+            "INSERT INTO roles (name, created_by) VALUES ('shared_readers', ?)",
+            [owner_id],
+        )
+        .unwrap();
+        let role_id = auth.last_insert_rowid();
+        auth.execute(
+            "INSERT INTO user_roles (user_id, role_id) VALUES (?, ?)",
+            [reader_id, role_id],
+        )
+        .unwrap();
+        auth.update_permissions(
+            role_id,
+            "shared",
+            None,
+            &ObjectPermissions::partial(PartialPermissions {
+                read: true,
+                insert: false,
+                update: false,
+                delete: false,
+            }),
+        )
+        .unwrap();
+
+        let res = request_permissions(&state, "shared", Some(&reader)).await;
+
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()["x-permissions"], "database: read");
+    }
+
+    #[tokio::test]
+    async fn reject_extraction() {
+        let state = AppState::test_state();
+        let auth = AuthDatabase::open(state.env().clone()).unwrap();
+
+        let owner = AuthDatabase::create_test_token(state.env());
+        auth.get_permissions(&owner, "private").unwrap();
+
+        let stranger = AuthDatabase::create_test_token(state.env());
+
+        assert_eq!(
+            request_permissions(&state, "private", Some(&stranger))
+                .await
+                .status(),
+            StatusCode::UNAUTHORIZED,
+            "Existing databases are only accessible with a role"
+        );
+        assert_eq!(
+            request_permissions(&state, "private", None).await.status(),
+            StatusCode::UNAUTHORIZED,
+            "Token is required"
+        );
+        assert_eq!(
+            request_permissions(&state, "private", Some("invalid"))
+                .await
+                .status(),
+            StatusCode::UNAUTHORIZED
+        );
+        for db_name in ["..%2Fauth", "foo%2Fbar", "foo.bar", "%2E%2E"] {
+            assert_eq!(
+                request_permissions(&state, db_name, Some(&owner))
+                    .await
+                    .status(),
+                StatusCode::BAD_REQUEST,
+                "{} escapes the data directory",
+                db_name
+            );
+        }
+        assert_eq!(
+            request_permissions(&state, "auth", Some(&owner))
+                .await
+                .status(),
+            StatusCode::BAD_REQUEST,
+            "Reserved names can't be used as databases"
+        );
+    }
 }
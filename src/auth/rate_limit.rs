@@ -0,0 +1,71 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::error::CRRError;
+
+#[derive(Clone)]
+pub(crate) struct RateLimiter {
+    window: Duration,
+    requests: Arc<Mutex<HashMap<String, Instant>>>,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(window: Duration) -> Self {
+        let requests = Arc::new(Mutex::new(HashMap::<String, Instant>::new()));
+
+        // prune expired entries so the map doesn't grow unbounded
+        let gc_requests = Arc::downgrade(&requests);
+
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(window.max(Duration::from_secs(1))).await;
+
+                match gc_requests.upgrade() {
+                    Some(requests) => {
+                        if let Ok(mut lock) = requests.lock() {
+                            lock.retain(|_key, last_request| last_request.elapsed() < window);
+                        }
+                    }
+                    None => return,
+                }
+            }
+        });
+
+        Self { window, requests }
+    }
+
+    pub(crate) fn check(&self, key: &str) -> Result<(), CRRError> {
+        let mut lock = self
+            .requests
+            .lock()
+            .map_err(|_| CRRError::PoisonedLockError("RateLimiter::check"))?;
+
+        if let Some(last_request) = lock.get(key) {
+            let elapsed = last_request.elapsed();
+
+            if elapsed < self.window {
+                return Err(CRRError::TooManyRequests(
+                    format!(
+                        "Please wait {} seconds between requests",
+                        self.window.as_secs()
+                    ),
+                    self.window.saturating_sub(elapsed),
+                ));
+            }
+        }
+
+        lock.insert(key.to_owned(), Instant::now());
+
+        Ok(())
+    }
+
+    /// frees the slot taken by the last `check` of `key`, for requests that failed after it
+    pub(crate) fn release(&self, key: &str) {
+        if let Ok(mut lock) = self.requests.lock() {
+            lock.remove(key);
+        }
+    }
+}
@@ -0,0 +1,172 @@
+use axum::extract::{Json, Path, State};
+use serde::{Deserialize, Serialize};
+
+use crate::{app_state::AppState, error::CRRError};
+
+use super::{AuthDatabase, Token};
+
+#[derive(Deserialize)]
+pub(crate) struct RolePostData {
+    name: String,
+}
+
+#[derive(Serialize)]
+pub(crate) struct RolePostResponse {
+    role_id: i64,
+}
+
+pub(crate) async fn post_role(
+    Token(token): Token,
+    State(state): State<AppState>,
+    Json(data): Json<RolePostData>,
+) -> Result<Json<RolePostResponse>, CRRError> {
+    let auth = AuthDatabase::open(state.env().clone())?;
+
+    let user_id = auth.authenticate_user(&token)?;
+
+    let role_id = auth.create_role(&data.name, user_id)?;
+
+    Ok(Json(RolePostResponse { role_id }))
+}
+
+#[derive(Deserialize)]
+pub(crate) struct RoleUserPostData {
+    pub(crate) user_id: i64,
+}
+
+/// Only the user who created the role can assign users to it. Ownership of a database
+/// the role has permissions on isn't enough, since any owner can grant permissions on
+/// their database to any role and would otherwise gain control over foreign roles.
+pub(crate) async fn post_role_user(
+    Path(role_id): Path<i64>,
+    Token(token): Token,
+    State(state): State<AppState>,
+    Json(data): Json<RoleUserPostData>,
+) -> Result<(), CRRError> {
+    let auth = AuthDatabase::open(state.env().clone())?;
+
+    let user_id = auth.authenticate_user(&token)?;
+
+    if !auth.can_manage_role(user_id, role_id)? {
+        return Err(CRRError::Unauthorized(
+            "User must have created this role to assign users to it".to_owned(),
+        ));
+    }
+
+    auth.add_user_to_role(role_id, data.user_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::extract::{Json, Path, State};
+
+    use super::{post_role, post_role_user, RolePostData, RoleUserPostData};
+    use crate::{
+        app_state::AppState,
+        auth::{permissions::ObjectPermissions, AuthDatabase, PartialPermissions, Token},
+    };
+
+    #[tokio::test]
+    async fn share_database_through_role() {
+        let state = AppState::test_state();
+        let auth = AuthDatabase::open(state.env().clone()).unwrap();
+
+        let owner = AuthDatabase::create_test_token(state.env());
+        auth.get_permissions(&owner, "shared").unwrap();
+
+        let reader = AuthDatabase::create_test_token(state.env());
+        let reader_id = auth.authenticate_user(&reader).unwrap();
+
+        let Json(role) = post_role(
+            Token(owner.clone()),
+            State(state.clone()),
+            Json(RolePostData {
+                name: "shared_readers".to_owned(),
+            }),
+        )
+        .await
+        .unwrap();
+
+        auth.update_permissions(
+            role.role_id,
+            "shared",
+            None,
+            &ObjectPermissions::partial(PartialPermissions {
+                read: true,
+                insert: false,
+                update: false,
+                delete: false,
+            }),
+        )
+        .unwrap();
+
+        let assign = |token: &str| {
+            post_role_user(
+                Path(role.role_id),
+                Token(token.to_owned()),
+                State(state.clone()),
+                Json(RoleUserPostData { user_id: reader_id }),
+            )
+        };
+
+        assert!(
+            assign(&reader).await.is_err(),
+            "Only the role's creator can assign users"
+        );
+
+        assign(&owner).await.unwrap();
+
+        let p = auth.get_permissions(&reader, "shared").unwrap();
+        assert!(p.read_table("foo"));
+        assert!(!p.full());
+    }
+
+    #[tokio::test]
+    async fn only_creators_manage_roles() {
+        let state = AppState::test_state();
+        let auth = AuthDatabase::open(state.env().clone()).unwrap();
+
+        let victim = AuthDatabase::create_test_token(state.env());
+        auth.get_permissions(&victim, "victim").unwrap();
+
+        let attacker = AuthDatabase::create_test_token(state.env());
+        let attacker_id = auth.authenticate_user(&attacker).unwrap();
+        auth.get_permissions(&attacker, "attacker").unwrap();
+
+        let owners_role_id: i64 = auth
+            .query_row(
+                "SELECT id FROM roles WHERE name = 'victim_owners'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+
+        // permissions on the attacker's own database don't make them manage the role
+        auth.update_permissions(
+            owners_role_id,
+            "attacker",
+            None,
+            &ObjectPermissions::partial(PartialPermissions {
+                read: true,
+                ..Default::default()
+            }),
+        )
+        .unwrap();
+
+        assert!(post_role_user(
+            Path(owners_role_id),
+            Token(attacker.clone()),
+            State(state.clone()),
+            Json(RoleUserPostData {
+                user_id: attacker_id
+            }),
+        )
+        .await
+        .is_err());
+
+        assert!(!auth
+            .get_permissions_for_user(attacker_id, "victim")
+            .unwrap()
+            .full());
+    }
+}
@@ -2,12 +2,13 @@ use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use axum::{
     extract::{Query, State},
+    http::{Method, Uri},
     Json,
 };
 use base64::{engine::general_purpose::STANDARD as base64, Engine};
+use hmac::{Hmac, Mac};
 use serde::{Deserialize, Serialize};
-use sha2::{Digest, Sha256};
-use url::Url;
+use sha2::Sha256;
 
 use crate::{app_state::AppState, error::CRRError};
 
@@ -16,6 +17,11 @@ use super::{AuthDatabase, Token};
 #[derive(Deserialize)]
 pub(crate) struct GetSignedUrlQuery {
     url: String,
+    /// seconds until the signed url expires, clamped to `CRR_MAX_SIGNED_URL_TTL`
+    /// and the remaining lifetime of the access token
+    expires_in: Option<u64>,
+    /// HTTP method the signed url may be used with, defaults to GET
+    method: Option<String>,
 }
 
 #[derive(Serialize)]
@@ -33,22 +39,37 @@ pub(crate) async fn get_signed_url(
     let auth = AuthDatabase::open(state.env().clone())?;
     let token_id = auth.get_token_id(&token)?;
     let mut url = url::Url::parse(&query.url)?;
-    let expiration = SystemTime::now() + Duration::from_secs(100);
-    let expiration = expiration
+    let method = match query.method.as_deref() {
+        Some(method) => Method::from_bytes(method.to_uppercase().as_bytes())
+            .map_err(|_| CRRError::BadRequest(format!("Invalid HTTP Method \"{}\"", method)))?,
+        None => Method::GET,
+    };
+
+    let ttl = query
+        .expires_in
+        .map(Duration::from_secs)
+        .unwrap_or(state.env().signed_url_ttl())
+        .min(state.env().max_signed_url_ttl())
+        // the url stops working with the access token it was signed with anyway
+        .min(auth.get_token_lifetime(token_id)?);
+
+    let expiration = (SystemTime::now() + ttl)
         .duration_since(UNIX_EPOCH)
-        .expect("This Server should not be run before the Unix Epoch")
-        .as_secs();
+        .expect("This Server should not be run before the Unix Epoch");
+    // round up so a url is never valid for less than the requested time
+    let expiration = expiration.as_secs() + u64::from(expiration.subsec_nanos() > 0);
 
     url.query_pairs_mut()
         .append_pair("crr-url-token-id", &token_id.to_string())
         .append_pair("crr-url-expires", &expiration.to_string())
+        .append_pair("crr-url-method", method.as_str())
         .append_pair("crr-url-nonce", &nanoid::nanoid!());
 
-    let mut hasher = Sha256::new();
-    hasher.update(&query.url);
-    hasher.update(&token);
-
-    let hash = base64.encode(hasher.finalize());
+    let hash = base64.encode(
+        signature(&token, &method, url.path(), url.query())
+            .finalize()
+            .into_bytes(),
+    );
 
     url.query_pairs_mut().append_pair("crr-url-hash", &hash);
 
@@ -58,39 +79,66 @@ pub(crate) async fn get_signed_url(
     }))
 }
 
+type HmacSha256 = Hmac<Sha256>;
+
+/// HMAC-SHA256 of method, path and query keyed by the token
+fn signature(token: &str, method: &Method, path: &str, query: Option<&str>) -> HmacSha256 {
+    let mut mac =
+        HmacSha256::new_from_slice(token.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(method.as_str().as_bytes());
+    mac.update(signature_input(path, query).as_bytes());
+
+    mac
+}
+
+/// Only path and query are signed so the signature doesn't depend
+/// on the host or scheme the server is reached through.
+fn signature_input(path: &str, query: Option<&str>) -> String {
+    let query = query
+        .unwrap_or_default()
+        .split('&')
+        .filter(|pair| !pair.is_empty() && !pair.starts_with("crr-url-hash="))
+        .collect::<Vec<&str>>()
+        .join("&");
+
+    format!("{}?{}", path, query)
+}
+
 #[derive(Deserialize)]
+#[serde(rename_all = "kebab-case")]
 pub(crate) struct SignedRequestQuery {
     crr_url_hash: String,
     crr_url_token_id: i64,
     crr_url_expires: u64,
+    crr_url_method: String,
 }
 
 impl SignedRequestQuery {
-    pub(crate) fn validate(&self, auth: &AuthDatabase, url: Url) -> Result<String, CRRError> {
-        let query_without_hash = url
-            .query_pairs()
-            .filter(|(key, _value)| key != "crr-url-hash");
-
-        let mut url = url.clone();
-        url.query_pairs_mut()
-            .clear()
-            .extend_pairs(query_without_hash);
+    pub(crate) fn validate(
+        &self,
+        auth: &AuthDatabase,
+        method: &Method,
+        uri: &Uri,
+    ) -> Result<String, CRRError> {
+        if self.crr_url_method != method.as_str() {
+            return Err(CRRError::Unauthorized(format!(
+                "Signed URL is not valid for {} requests",
+                method
+            )));
+        }
 
         let token = auth.get_token_by_id(self.crr_url_token_id)?;
 
-        let mut hasher: Sha256 = Sha256::new();
-        hasher.update(url.as_str());
-        hasher.update(&token);
+        let invalid_hash = || CRRError::Unauthorized("Invalid Signed Request Hash".to_owned());
 
-        // this sucks (we should decode the url hash to compare instead of encoding this one)
-        // but I don't get how this GenericArray stuff from the digest lib works
-        let hash = base64.encode(hasher.finalize());
+        let hash = base64
+            .decode(&self.crr_url_hash)
+            .map_err(|_| invalid_hash())?;
 
-        if hash != self.crr_url_hash {
-            return Err(CRRError::Unauthorized(
-                "Invalid Signed Request Hash".to_owned(),
-            ));
-        }
+        // verify_slice compares in constant time
+        signature(&token, method, uri.path(), uri.query())
+            .verify_slice(&hash)
+            .map_err(|_| invalid_hash())?;
 
         let expiration = UNIX_EPOCH + Duration::from_secs(self.crr_url_expires);
 
@@ -101,3 +149,160 @@ impl SignedRequestQuery {
         Ok(token)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::Arc,
+        time::{Duration, SystemTime, UNIX_EPOCH},
+    };
+
+    use axum::{
+        extract::{Query, State},
+        http::{Method, Uri},
+        Json,
+    };
+    use base64::{engine::general_purpose::STANDARD as base64, Engine};
+
+    use super::{get_signed_url, GetSignedUrlQuery, SignedRequestQuery};
+    use crate::{
+        app_state::AppState,
+        auth::{AuthDatabase, Token},
+        error::CRRError,
+    };
+
+    #[tokio::test]
+    async fn expire_signed_url() {
+        let state = AppState::test_state();
+        let token = AuthDatabase::create_test_token(state.env());
+
+        let Json(response) = get_signed_url(
+            Query(GetSignedUrlQuery {
+                url: "http://localhost:6839/db/data/changes?schema_version=0".to_owned(),
+                expires_in: Some(1),
+                method: None,
+            }),
+            Token(token.clone()),
+            State(state.clone()),
+        )
+        .await
+        .expect("Failed to sign url");
+
+        let uri: Uri = response.signed_url.parse().unwrap();
+        let Query(query) = Query::<SignedRequestQuery>::try_from_uri(&uri).unwrap();
+        let auth = AuthDatabase::open(Arc::clone(state.env())).unwrap();
+
+        assert_eq!(query.validate(&auth, &Method::GET, &uri).unwrap(), token);
+
+        tokio::time::sleep(Duration::from_secs(2)).await;
+
+        assert!(matches!(
+            query.validate(&auth, &Method::GET, &uri),
+            Err(CRRError::Unauthorized(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn reject_other_method() {
+        let state = AppState::test_state();
+        let token = AuthDatabase::create_test_token(state.env());
+
+        let Json(response) = get_signed_url(
+            Query(GetSignedUrlQuery {
+                url: "http://localhost:6839/db/data/changes?schema_version=0".to_owned(),
+                expires_in: None,
+                method: Some("GET".to_owned()),
+            }),
+            Token(token.clone()),
+            State(state.clone()),
+        )
+        .await
+        .expect("Failed to sign url");
+
+        let uri: Uri = response.signed_url.parse().unwrap();
+        let Query(query) = Query::<SignedRequestQuery>::try_from_uri(&uri).unwrap();
+        let auth = AuthDatabase::open(Arc::clone(state.env())).unwrap();
+
+        assert_eq!(query.validate(&auth, &Method::GET, &uri).unwrap(), token);
+        assert!(matches!(
+            query.validate(&auth, &Method::POST, &uri),
+            Err(CRRError::Unauthorized(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn reject_tampered_hash() {
+        let state = AppState::test_state();
+        let token = AuthDatabase::create_test_token(state.env());
+
+        let Json(response) = get_signed_url(
+            Query(GetSignedUrlQuery {
+                url: "http://localhost:6839/db/data/changes?schema_version=0".to_owned(),
+                expires_in: None,
+                method: None,
+            }),
+            Token(token),
+            State(state.clone()),
+        )
+        .await
+        .expect("Failed to sign url");
+
+        let uri: Uri = response.signed_url.parse().unwrap();
+        let Query(mut query) = Query::<SignedRequestQuery>::try_from_uri(&uri).unwrap();
+        let auth = AuthDatabase::open(Arc::clone(state.env())).unwrap();
+
+        let mut hash = base64.decode(&query.crr_url_hash).unwrap();
+        hash[0] ^= 1;
+        query.crr_url_hash = base64.encode(hash);
+
+        assert!(matches!(
+            query.validate(&auth, &Method::GET, &uri),
+            Err(CRRError::Unauthorized(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn bind_to_token_lifetime() {
+        let state = AppState::test_state();
+        let token = AuthDatabase::create_test_token(state.env());
+        let auth = AuthDatabase::open(Arc::clone(state.env())).unwrap();
+
+        auth.execute(
+            "UPDATE access_tokens SET expires = JULIANDAY('now') + 60.0 / 86400.0 WHERE token = ?",
+            [&token],
+        )
+        .unwrap();
+
+        let Json(response) = get_signed_url(
+            Query(GetSignedUrlQuery {
+                url: "http://localhost:6839/db/data/changes?schema_version=0".to_owned(),
+                expires_in: Some(3600),
+                method: None,
+            }),
+            Token(token.clone()),
+            State(state.clone()),
+        )
+        .await
+        .expect("Failed to sign url");
+
+        let uri: Uri = response.signed_url.parse().unwrap();
+        let Query(query) = Query::<SignedRequestQuery>::try_from_uri(&uri).unwrap();
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        assert!(
+            query.crr_url_expires <= now + 61,
+            "Capped to the token's lifetime"
+        );
+
+        auth.execute("DELETE FROM access_tokens WHERE token = ?", [&token])
+            .unwrap();
+
+        assert!(matches!(
+            query.validate(&auth, &Method::GET, &uri),
+            Err(CRRError::Unauthorized(_))
+        ));
+    }
+}
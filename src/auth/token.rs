@@ -5,11 +5,13 @@ use axum::{
     extract::TypedHeader,
     extract::{FromRequestParts, Json, Query, State},
     headers::{authorization::Bearer, Authorization},
-    http::request::Parts,
+    http::{request::Parts, StatusCode},
 };
-use axum_extra::extract::cookie::{Cookie, CookieJar, SameSite};
+use axum_extra::extract::cookie::{Cookie, CookieJar};
+use hmac::{Hmac, Mac};
 use rusqlite::named_params;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
 use time::Duration;
 
 use crate::{app_state::AppState, error::CRRError};
@@ -21,52 +23,317 @@ pub(crate) struct TokenRequestData {
     otp: Option<String>,
 }
 
+#[derive(Serialize)]
+pub(crate) struct TokenResponse {
+    access_token: String,
+    expires_in: u64,
+}
+
+/// Exchanges an OTP or the refresh token cookie for a new refresh token
+/// and a short-lived access token. A refresh token can only be used once.
 pub(crate) async fn post_token(
-    mut cookies: CookieJar,
+    cookies: CookieJar,
     State(state): State<AppState>,
     Json(data): Json<TokenRequestData>,
-) -> Result<CookieJar, CRRError> {
+) -> Result<(CookieJar, Json<TokenResponse>), CRRError> {
     let auth = AuthDatabase::open(Arc::clone(state.env()))?;
 
     let user_id: i64 = match data.otp.as_ref() {
-        Some(otp) => auth
-            .prepare("SELECT id FROM users WHERE otp = :otp")?
-            .query_row(named_params! { ":otp": otp }, |row| row.get(0))?,
+        Some(otp) => {
+            // consumed in the same statement, so concurrent requests can't both redeem it
+            let (user_id, expired): (i64, bool) = auth
+                .prepare(
+                    "
+                    UPDATE users
+                    SET otp = NULL
+                    WHERE otp = :otp
+                    RETURNING id, otp_expires IS NULL OR otp_expires < JULIANDAY('now')
+                ",
+                )?
+                .query_row(named_params! { ":otp": otp }, |row| {
+                    Ok((row.get(0)?, row.get(1)?))
+                })
+                .map_err(|error| match error {
+                    rusqlite::Error::QueryReturnedNoRows => {
+                        CRRError::Unauthorized("Invalid OTP".to_owned())
+                    }
+                    error => error.into(),
+                })?;
+
+            if expired {
+                return Err(CRRError::Unauthorized("OTP expired".to_owned()));
+            }
+
+            user_id
+        }
 
         None => {
             let token = cookies
-                .get(super::COOKIE_NAME)
+                .get(COOKIE_NAME)
                 .ok_or(CRRError::Unauthorized("Token Not Found".to_owned()))?
                 .value();
 
-            auth.prepare("SELECT user_id FROM tokens WHERE token = :token AND expires > 'now'")?
-                .query_row(named_params! { ":token": token }, |row| row.get(0))?
+            // expiring the token right away means a reused refresh token is rejected,
+            // the row is kept until the cleanup so its use is still recorded
+            auth.prepare(
+                "
+                UPDATE refresh_tokens
+                SET expires = JULIANDAY('now'), last_used_at = JULIANDAY('now')
+                WHERE token = :token AND expires > JULIANDAY('now')
+                RETURNING user_id
+            ",
+            )?
+            .query_row(named_params! { ":token": token }, |row| row.get(0))
+            .map_err(|error| match error {
+                rusqlite::Error::QueryReturnedNoRows => {
+                    CRRError::Unauthorized("Invalid Token".to_owned())
+                }
+                error => error.into(),
+            })?
         }
     };
 
+    let (cookies, response) = issue_tokens(&auth, &state, cookies, user_id)?;
+
+    Ok((cookies, Json(response)))
+}
+
+/// stores a new refresh token in the cookie and returns a new access token
+fn issue_tokens(
+    auth: &AuthDatabase,
+    state: &AppState,
+    mut cookies: CookieJar,
+    user_id: i64,
+) -> Result<(CookieJar, TokenResponse), CRRError> {
     {
         let token = nanoid::nanoid!();
+        let ttl_days = state.env().token_ttl_days();
 
-        auth.prepare("INSERT INTO tokens (user_id, token, expires) VALUES (:user_id, :token, JULIANDAY('now') + 400)")?
-            .insert(named_params! { ":user_id": user_id, ":token": token })?;
+        auth.prepare("INSERT INTO refresh_tokens (user_id, token, expires, created_at) VALUES (:user_id, :token, JULIANDAY('now') + :ttl_days, JULIANDAY('now'))")?
+            .insert(named_params! { ":user_id": user_id, ":token": token, ":ttl_days": ttl_days as i64 })?;
 
-        let cookie = Cookie::build(super::COOKIE_NAME, token)
+        let cookie = Cookie::build(COOKIE_NAME, token)
             .http_only(true)
-            .max_age(Duration::days(400))
-            .same_site(SameSite::Strict)
-            .secure(true)
+            .max_age(Duration::days(ttl_days as i64))
+            .same_site(state.env().cookie_same_site())
+            .secure(state.env().cookie_secure())
             .path("/")
             .finish();
 
         cookies = cookies.add(cookie);
     }
 
-    auth.prepare("UPDATE users SET otp = NULL WHERE id = :user_id AND otp = :otp")?
-        .execute(named_params! { ":user_id": user_id, ":otp": data.otp })?;
+    let access_token = nanoid::nanoid!();
+    let ttl = state.env().access_token_ttl();
+
+    auth.prepare("INSERT INTO access_tokens (user_id, token, expires, created_at) VALUES (:user_id, :token, JULIANDAY('now') + :ttl, JULIANDAY('now'))")?
+        .insert(named_params! {
+            ":user_id": user_id,
+            ":token": access_token,
+            ":ttl": ttl.as_secs_f64() / 86400.0
+        })?;
+
+    Ok((
+        cookies,
+        TokenResponse {
+            access_token,
+            expires_in: ttl.as_secs(),
+        },
+    ))
+}
+
+/// Revokes the bearer access token and the refresh token cookie, whichever are present.
+pub(crate) async fn delete_token(
+    cookies: CookieJar,
+    token: Option<Token>,
+    State(state): State<AppState>,
+) -> Result<(StatusCode, CookieJar), CRRError> {
+    let auth = AuthDatabase::open(Arc::clone(state.env()))?;
+
+    let mut deleted = 0;
+
+    if let Some(Token(token)) = token {
+        deleted += auth
+            .prepare("DELETE FROM access_tokens WHERE token = :token")?
+            .execute(named_params! { ":token": token })?;
+    }
+
+    if let Some(cookie) = cookies.get(COOKIE_NAME) {
+        deleted += auth
+            .prepare("DELETE FROM refresh_tokens WHERE token = :token")?
+            .execute(named_params! { ":token": cookie.value() })?;
+    }
+
+    if deleted == 0 {
+        return Err(CRRError::Unauthorized("Invalid Token".to_owned()));
+    }
+
+    let cookie = Cookie::build(COOKIE_NAME, "").path("/").finish();
 
-    Ok(cookies)
+    Ok((StatusCode::NO_CONTENT, cookies.remove(cookie)))
 }
 
+#[derive(Deserialize, Default)]
+pub(crate) struct RotateTokenRequestData {
+    #[serde(default)]
+    keep_current: bool,
+}
+
+/// Revokes every token of the user, e.g. after a suspected compromise,
+/// and issues new ones. `keep_current` spares the access token of the request.
+pub(crate) async fn post_rotate_token(
+    cookies: CookieJar,
+    Token(token): Token,
+    State(state): State<AppState>,
+    data: Option<Json<RotateTokenRequestData>>,
+) -> Result<(CookieJar, Json<TokenResponse>), CRRError> {
+    // the body is optional, a bare POST rotates every token
+    let data = data.map(|Json(data)| data).unwrap_or_default();
+    let auth = AuthDatabase::open(Arc::clone(state.env()))?;
+    let user_id = auth.authenticate_user(&token)?;
+
+    auth.revoke_all_tokens(user_id, data.keep_current.then_some(token.as_str()))?;
+
+    let (cookies, response) = issue_tokens(&auth, &state, cookies, user_id)?;
+
+    Ok((cookies, Json(response)))
+}
+
+/// timestamps are unix seconds, `null` if unknown
+#[derive(Serialize)]
+pub(crate) struct TokenInfoResponse {
+    user_id: i64,
+    created_at: Option<i64>,
+    last_used_at: Option<i64>,
+    expires_at: i64,
+}
+
+/// Metadata of the current access token, without the token itself.
+/// Doesn't count as a use of the token.
+pub(crate) async fn get_token_info(
+    Token(token): Token,
+    State(state): State<AppState>,
+) -> Result<Json<TokenInfoResponse>, CRRError> {
+    let auth = AuthDatabase::open(Arc::clone(state.env()))?;
+
+    let info = auth
+        .prepare(
+            "
+            SELECT
+                user_id,
+                CAST((created_at - 2440587.5) * 86400 AS INTEGER),
+                CAST((last_used_at - 2440587.5) * 86400 AS INTEGER),
+                CAST((expires - 2440587.5) * 86400 AS INTEGER)
+            FROM access_tokens
+            WHERE token = :token AND expires > JULIANDAY('now')
+        ",
+        )?
+        .query_row(named_params! { ":token": token }, |row| {
+            Ok(TokenInfoResponse {
+                user_id: row.get(0)?,
+                created_at: row.get(1)?,
+                last_used_at: row.get(2)?,
+                expires_at: row.get(3)?,
+            })
+        })
+        .map_err(|error| match error {
+            rusqlite::Error::QueryReturnedNoRows => {
+                CRRError::Unauthorized("Invalid Token".to_owned())
+            }
+            error => error.into(),
+        })?;
+
+    Ok(Json(info))
+}
+
+#[derive(Deserialize)]
+pub(crate) struct ServiceTokenRequestData {
+    name: String,
+    expires_in: Option<u64>,
+}
+
+/// Mints an access token for a service account without the OTP flow.
+/// Callers authenticate with `CRR_ADMIN_SECRET` as bearer token.
+pub(crate) async fn post_service_token(
+    admin_secret: Option<TypedHeader<Authorization<Bearer>>>,
+    State(state): State<AppState>,
+    Json(data): Json<ServiceTokenRequestData>,
+) -> Result<Json<TokenResponse>, CRRError> {
+    authenticate_admin(&state, admin_secret)?;
+
+    let auth = AuthDatabase::open(Arc::clone(state.env()))?;
+
+    // service accounts are users that never receive an OTP, identified by their name
+    // prefixed with `service:`, which no valid email address starts with
+    let user_id: i64 = auth
+        .prepare(
+            "
+            INSERT INTO users (email, service_account) VALUES ('service:' || :name, TRUE)
+            ON CONFLICT (email) DO UPDATE SET email = excluded.email WHERE users.service_account
+            RETURNING id
+        ",
+        )?
+        .query_row(named_params! { ":name": data.name }, |row| row.get(0))
+        .map_err(|error| match error {
+            rusqlite::Error::QueryReturnedNoRows => {
+                CRRError::BadRequest(format!("{} is not a service account", data.name))
+            }
+            error => error.into(),
+        })?;
+
+    let access_token = nanoid::nanoid!();
+    let expires_in = data
+        .expires_in
+        .unwrap_or(state.env().token_ttl_days() * 86400);
+
+    auth.prepare("INSERT INTO access_tokens (user_id, token, expires, created_at) VALUES (:user_id, :token, JULIANDAY('now') + :ttl, JULIANDAY('now'))")?
+        .insert(named_params! {
+            ":user_id": user_id,
+            ":token": access_token,
+            ":ttl": expires_in as f64 / 86400.0
+        })?;
+
+    Ok(Json(TokenResponse {
+        access_token,
+        expires_in,
+    }))
+}
+
+/// Checks the bearer token against `CRR_ADMIN_SECRET`,
+/// every request is rejected if the secret isn't set
+pub(crate) fn authenticate_admin(
+    state: &AppState,
+    admin_secret: Option<TypedHeader<Authorization<Bearer>>>,
+) -> Result<(), CRRError> {
+    let authorized = match (state.env().admin_secret(), admin_secret) {
+        (Some(expected), Some(TypedHeader(given))) => secrets_match(expected, given.token()),
+        _ => false,
+    };
+
+    if !authorized {
+        return Err(CRRError::Unauthorized("Invalid Admin Secret".to_owned()));
+    }
+
+    Ok(())
+}
+
+/// Both secrets are hashed first, so `verify_slice` compares equally long
+/// digests in constant time and doesn't reveal the length of the secret.
+fn secrets_match(expected: &str, given: &str) -> bool {
+    fn digest(secret: &str) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(b"crr-server admin secret")
+            .expect("HMAC accepts keys of any length");
+        mac.update(secret.as_bytes());
+        mac
+    }
+
+    digest(given)
+        .verify_slice(&digest(expected).finalize().into_bytes())
+        .is_ok()
+}
+
+/// An access token, either passed as bearer token or resolved from a signed url.
+/// The refresh token cookie is only accepted by `POST /auth/token`.
 pub(crate) struct Token(pub(crate) String);
 
 #[async_trait]
@@ -77,12 +344,6 @@ impl FromRequestParts<AppState> for Token {
         parts: &mut Parts,
         state: &AppState,
     ) -> Result<Self, Self::Rejection> {
-        let cookies = CookieJar::from_request_parts(parts, state).await?;
-
-        if let Some(cookie) = cookies.get(COOKIE_NAME) {
-            return Ok(Self(cookie.value().to_owned()));
-        }
-
         if let Ok(TypedHeader(token)) =
             TypedHeader::<Authorization<Bearer>>::from_request_parts(parts, state).await
         {
@@ -94,9 +355,7 @@ impl FromRequestParts<AppState> for Token {
         {
             let auth = AuthDatabase::open(state.env().clone())?;
 
-            return Ok(Token(
-                query.validate(&auth, parts.uri.to_string().parse()?)?,
-            ));
+            return Ok(Token(query.validate(&auth, &parts.method, &parts.uri)?));
         }
 
         Err(CRRError::Unauthorized(
@@ -104,3 +363,483 @@ impl FromRequestParts<AppState> for Token {
         ))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use axum::{
+        body::Body,
+        extract::{Json, State, TypedHeader},
+        headers::Authorization,
+        http::{header::AUTHORIZATION, Method, Request, StatusCode},
+    };
+    use axum_extra::extract::{
+        cookie::{Cookie, SameSite},
+        CookieJar,
+    };
+    use rusqlite::params;
+    use tower::ServiceExt;
+
+    use super::{
+        delete_token, get_token_info, post_rotate_token, post_service_token, post_token,
+        RotateTokenRequestData, ServiceTokenRequestData, Token, TokenRequestData,
+    };
+    use crate::{
+        app_state::{AppEnv, AppState},
+        auth::{AuthDatabase, COOKIE_NAME},
+        error::CRRError,
+    };
+
+    fn create_user_with_otp(auth: &AuthDatabase) -> String {
+        let otp = nanoid::nanoid!();
+
+        auth.prepare(
+            "INSERT INTO users (email, otp, otp_expires) VALUES (?, ?, JULIANDAY('now') + 1)",
+        )
+        .unwrap()
+        .insert(params!["test@example.com", otp])
+        .unwrap();
+
+        otp
+    }
+
+    fn refresh_cookies(refresh_token: &str) -> CookieJar {
+        CookieJar::new().add(Cookie::new(COOKIE_NAME, refresh_token.to_owned()))
+    }
+
+    #[tokio::test]
+    async fn revoke_token() {
+        let state = AppState::test_state();
+        let auth = AuthDatabase::open(Arc::clone(state.env())).unwrap();
+
+        auth.prepare("INSERT INTO users (email) VALUES (?)")
+            .unwrap()
+            .insert(["test@example.com"])
+            .unwrap();
+
+        let user_id = auth.last_insert_rowid();
+        let token = nanoid::nanoid!();
+
+        auth.prepare(
+            "INSERT INTO access_tokens (user_id, token, expires) VALUES (?, ?, JULIANDAY('now') + 1)",
+        )
+        .unwrap()
+        .insert(params![user_id, token])
+        .unwrap();
+
+        let (status, _cookies) = delete_token(
+            CookieJar::new(),
+            Some(Token(token.clone())),
+            State(state.clone()),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(status, StatusCode::NO_CONTENT);
+
+        let exists = auth
+            .prepare("SELECT id FROM access_tokens WHERE token = ?")
+            .unwrap()
+            .exists([&token])
+            .unwrap();
+
+        assert!(!exists, "Token has been deleted");
+
+        assert!(
+            delete_token(CookieJar::new(), Some(Token(token)), State(state))
+                .await
+                .is_err(),
+            "Revoking a token twice fails"
+        );
+    }
+
+    #[tokio::test]
+    async fn reject_expired_otp() {
+        let state = AppState::test_state();
+        let auth = AuthDatabase::open(Arc::clone(state.env())).unwrap();
+
+        let otp = nanoid::nanoid!();
+
+        auth.prepare(
+            "INSERT INTO users (email, otp, otp_expires) VALUES (?, ?, JULIANDAY('now') - 1)",
+        )
+        .unwrap()
+        .insert(params!["test@example.com", otp])
+        .unwrap();
+
+        let result = post_token(
+            CookieJar::new(),
+            State(state),
+            Json(TokenRequestData { otp: Some(otp) }),
+        )
+        .await;
+
+        assert!(matches!(result, Err(CRRError::Unauthorized(_))));
+    }
+
+    #[tokio::test]
+    async fn redeem_otp_once() {
+        let state = AppState::test_state();
+        let auth = AuthDatabase::open(Arc::clone(state.env())).unwrap();
+        let otp = create_user_with_otp(&auth);
+
+        let redeem = |otp: &str| {
+            post_token(
+                CookieJar::new(),
+                State(state.clone()),
+                Json(TokenRequestData {
+                    otp: Some(otp.to_owned()),
+                }),
+            )
+        };
+
+        redeem(&otp).await.expect("Failed to redeem OTP");
+
+        assert!(matches!(redeem(&otp).await, Err(CRRError::Unauthorized(_))));
+        assert!(matches!(
+            redeem("unknown").await,
+            Err(CRRError::Unauthorized(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn rotate_refresh_token() {
+        let state = AppState::test_state();
+        let auth = AuthDatabase::open(Arc::clone(state.env())).unwrap();
+        let otp = create_user_with_otp(&auth);
+
+        let (cookies, Json(first)) = post_token(
+            CookieJar::new(),
+            State(state.clone()),
+            Json(TokenRequestData { otp: Some(otp) }),
+        )
+        .await
+        .unwrap();
+
+        let refresh_token = cookies.get(COOKIE_NAME).unwrap().value().to_owned();
+        let user_id = auth.authenticate_user(&first.access_token).unwrap();
+
+        assert_eq!(first.expires_in, state.env().access_token_ttl().as_secs());
+        assert!(
+            auth.authenticate_user(&refresh_token).is_err(),
+            "Refresh tokens can't be used as access tokens"
+        );
+
+        let (cookies, Json(second)) = post_token(
+            refresh_cookies(&refresh_token),
+            State(state.clone()),
+            Json(TokenRequestData { otp: None }),
+        )
+        .await
+        .unwrap();
+
+        let rotated = cookies.get(COOKIE_NAME).unwrap().value().to_owned();
+
+        assert_ne!(rotated, refresh_token);
+        assert_ne!(second.access_token, first.access_token);
+        assert_eq!(
+            auth.authenticate_user(&second.access_token).unwrap(),
+            user_id
+        );
+
+        post_token(
+            refresh_cookies(&rotated),
+            State(state),
+            Json(TokenRequestData { otp: None }),
+        )
+        .await
+        .expect("Rotated refresh token is valid");
+    }
+
+    #[tokio::test]
+    async fn configure_token_ttl() {
+        let state =
+            AppState::test_state().with_env(AppEnv::with_token_ttl_days(AppEnv::test_env(), 1));
+        let auth = AuthDatabase::open(Arc::clone(state.env())).unwrap();
+        let otp = create_user_with_otp(&auth);
+
+        let (cookies, _) = post_token(
+            CookieJar::new(),
+            State(state),
+            Json(TokenRequestData { otp: Some(otp) }),
+        )
+        .await
+        .unwrap();
+
+        let cookie = cookies.get(COOKIE_NAME).unwrap();
+
+        assert_eq!(cookie.max_age(), Some(time::Duration::days(1)));
+
+        let expires_in: f64 = auth
+            .prepare("SELECT expires - JULIANDAY('now') FROM refresh_tokens WHERE token = ?")
+            .unwrap()
+            .query_row([cookie.value()], |row| row.get(0))
+            .unwrap();
+
+        assert!(
+            (expires_in - 1.0).abs() < 0.01,
+            "Expires in {} days",
+            expires_in
+        );
+    }
+
+    #[tokio::test]
+    async fn insecure_cookie() {
+        let state =
+            AppState::test_state().with_env(AppEnv::with_insecure_cookies(AppEnv::test_env()));
+        let auth = AuthDatabase::open(Arc::clone(state.env())).unwrap();
+        let otp = create_user_with_otp(&auth);
+
+        let (cookies, _) = post_token(
+            CookieJar::new(),
+            State(state),
+            Json(TokenRequestData { otp: Some(otp) }),
+        )
+        .await
+        .unwrap();
+
+        let cookie = cookies.get(COOKIE_NAME).unwrap();
+
+        assert_ne!(cookie.secure(), Some(true));
+        assert_eq!(cookie.same_site(), Some(SameSite::Lax));
+        assert!(!cookie.to_string().contains("Secure"));
+    }
+
+    #[tokio::test]
+    async fn mint_service_token() {
+        let state = AppState::test_state()
+            .with_env(AppEnv::with_admin_secret(AppEnv::test_env(), "secret"));
+        let auth = AuthDatabase::open(Arc::clone(state.env())).unwrap();
+
+        let request = |secret: &str| {
+            post_service_token(
+                Some(TypedHeader(Authorization::bearer(secret).unwrap())),
+                State(state.clone()),
+                Json(ServiceTokenRequestData {
+                    name: "ci".to_owned(),
+                    expires_in: Some(3600),
+                }),
+            )
+        };
+
+        let Json(first) = request("secret").await.unwrap();
+        let Json(second) = request("secret").await.unwrap();
+
+        assert_eq!(first.expires_in, 3600);
+        assert_eq!(
+            auth.authenticate_user(&first.access_token).unwrap(),
+            auth.authenticate_user(&second.access_token).unwrap(),
+            "Tokens for the same name belong to the same service account"
+        );
+
+        assert!(matches!(
+            request("wrong").await,
+            Err(CRRError::Unauthorized(_))
+        ));
+
+        let service_email: String = auth
+            .query_row("SELECT email FROM users WHERE service_account", [], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(service_email, "service:ci");
+        assert!(matches!(
+            post_service_token(
+                None,
+                State(state.clone()),
+                Json(ServiceTokenRequestData {
+                    name: "ci".to_owned(),
+                    expires_in: None,
+                }),
+            )
+            .await,
+            Err(CRRError::Unauthorized(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn keep_service_accounts_apart_from_users() {
+        let state = AppState::test_state()
+            .with_env(AppEnv::with_admin_secret(AppEnv::test_env(), "secret"));
+        let auth = AuthDatabase::open(Arc::clone(state.env())).unwrap();
+
+        auth.prepare("INSERT INTO users (email) VALUES (?)")
+            .unwrap()
+            .insert(["test@example.com"])
+            .unwrap();
+        let user_id = auth.last_insert_rowid();
+
+        let Json(service) = post_service_token(
+            Some(TypedHeader(Authorization::bearer("secret").unwrap())),
+            State(state.clone()),
+            Json(ServiceTokenRequestData {
+                name: "test@example.com".to_owned(),
+                expires_in: None,
+            }),
+        )
+        .await
+        .unwrap();
+
+        assert_ne!(
+            auth.authenticate_user(&service.access_token).unwrap(),
+            user_id,
+            "A service account named like a user is a different account"
+        );
+    }
+
+    #[tokio::test]
+    async fn reject_reused_refresh_token() {
+        let state = AppState::test_state();
+        let auth = AuthDatabase::open(Arc::clone(state.env())).unwrap();
+        let otp = create_user_with_otp(&auth);
+
+        let (cookies, _) = post_token(
+            CookieJar::new(),
+            State(state.clone()),
+            Json(TokenRequestData { otp: Some(otp) }),
+        )
+        .await
+        .unwrap();
+
+        let refresh_token = cookies.get(COOKIE_NAME).unwrap().value().to_owned();
+
+        post_token(
+            refresh_cookies(&refresh_token),
+            State(state.clone()),
+            Json(TokenRequestData { otp: None }),
+        )
+        .await
+        .unwrap();
+
+        let result = post_token(
+            refresh_cookies(&refresh_token),
+            State(state),
+            Json(TokenRequestData { otp: None }),
+        )
+        .await;
+
+        assert!(matches!(result, Err(CRRError::Unauthorized(_))));
+
+        let last_used_at: Option<f64> = auth
+            .query_row(
+                "SELECT last_used_at FROM refresh_tokens WHERE token = ?",
+                [&refresh_token],
+                |row| row.get(0),
+            )
+            .unwrap();
+
+        assert!(
+            last_used_at.is_some(),
+            "The refresh token's use is recorded"
+        );
+    }
+
+    #[tokio::test]
+    async fn track_token_usage() {
+        let state = AppState::test_state();
+        let auth = AuthDatabase::open(Arc::clone(state.env())).unwrap();
+        let token = AuthDatabase::create_test_token(state.env());
+
+        let info = || get_token_info(Token(token.clone()), State(state.clone()));
+
+        let Json(created) = info().await.unwrap();
+
+        assert!(created.created_at.is_some());
+        assert_eq!(created.last_used_at, None);
+        assert!(created.expires_at > created.created_at.unwrap());
+
+        auth.execute(
+            "UPDATE access_tokens SET last_used_at = JULIANDAY('now') - 1 WHERE token = ?",
+            [&token],
+        )
+        .unwrap();
+
+        let Json(before) = info().await.unwrap();
+        let user_id = auth.authenticate_user(&token).unwrap();
+        let Json(after) = info().await.unwrap();
+
+        assert_eq!(after.user_id, user_id);
+        assert!(after.last_used_at.unwrap() > before.last_used_at.unwrap());
+        assert_eq!(
+            after.last_used_at,
+            info().await.unwrap().0.last_used_at,
+            "Reading the info doesn't count as a use"
+        );
+
+        auth.authenticate_user(&token).unwrap();
+
+        assert_eq!(
+            after.last_used_at,
+            info().await.unwrap().0.last_used_at,
+            "Uses within a minute aren't written again"
+        );
+    }
+
+    #[tokio::test]
+    async fn rotate_all_tokens() {
+        let state = AppState::test_state();
+        let auth = AuthDatabase::open(Arc::clone(state.env())).unwrap();
+
+        let first = AuthDatabase::create_test_token(state.env());
+        let user_id = auth.authenticate_user(&first).unwrap();
+
+        let others: Vec<String> = (0..2)
+            .map(|_| {
+                let token = nanoid::nanoid!();
+                auth.execute(
+                    "INSERT INTO access_tokens (user_id, token, expires) VALUES (?, ?, JULIANDAY('now') + 1)",
+                    params![user_id, token],
+                )
+                .unwrap();
+                token
+            })
+            .collect();
+
+        let (cookies, Json(rotated)) = post_rotate_token(
+            CookieJar::new(),
+            Token(first.clone()),
+            State(state.clone()),
+            Some(Json(RotateTokenRequestData::default())),
+        )
+        .await
+        .unwrap();
+
+        for token in std::iter::once(&first).chain(others.iter()) {
+            assert!(
+                matches!(
+                    auth.authenticate_user(token),
+                    Err(CRRError::Unauthorized(_))
+                ),
+                "Old tokens are revoked"
+            );
+        }
+
+        assert_eq!(
+            auth.authenticate_user(&rotated.access_token).unwrap(),
+            user_id
+        );
+        assert!(cookies.get(COOKIE_NAME).is_some());
+    }
+
+    #[tokio::test]
+    async fn rotate_without_body() {
+        let state = AppState::test_state();
+        let token = AuthDatabase::create_test_token(state.env());
+
+        let res = crate::router(state.env())
+            .with_state(state.clone())
+            .oneshot(
+                Request::builder()
+                    .method(Method::POST)
+                    .uri("/auth/token/rotate")
+                    .header(AUTHORIZATION, format!("Bearer {}", token))
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+}
@@ -0,0 +1,102 @@
+use std::io::Write;
+
+use async_stream::try_stream;
+use axum::{
+    body::{boxed, BoxBody, Bytes, HttpBody, StreamBody},
+    http::{
+        header::{ACCEPT_ENCODING, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE, VARY},
+        HeaderMap, HeaderValue, Request,
+    },
+    middleware::Next,
+    response::Response,
+};
+use flate2::{write::GzEncoder, Compression};
+use futures::Stream;
+
+const EVENT_STREAM: &str = "text/event-stream";
+
+/// Gzips SSE responses, which tower-http's `CompressionLayer` leaves alone because it
+/// buffers until a whole deflate block is full. Every chunk of the stream is a complete
+/// event or keep-alive comment, so a sync flush after each one gets it to the client
+/// right away.
+pub(crate) async fn compress_event_stream<B>(request: Request<B>, next: Next<B>) -> Response {
+    let gzip = accepts_gzip(request.headers());
+    let response = next.run(request).await;
+
+    let is_event_stream = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .map_or(false, |content_type| content_type.starts_with(EVENT_STREAM));
+
+    if !gzip || !is_event_stream || response.headers().contains_key(CONTENT_ENCODING) {
+        return response;
+    }
+
+    let (mut parts, body) = response.into_parts();
+
+    parts.headers.remove(CONTENT_LENGTH);
+    parts
+        .headers
+        .insert(CONTENT_ENCODING, HeaderValue::from_static("gzip"));
+    parts
+        .headers
+        .append(VARY, HeaderValue::from_static("accept-encoding"));
+
+    Response::from_parts(parts, boxed(StreamBody::new(gzip_chunks(body))))
+}
+
+fn gzip_chunks(mut body: BoxBody) -> impl Stream<Item = Result<Bytes, axum::Error>> {
+    try_stream! {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+
+        while let Some(chunk) = body.data().await {
+            encoder.write_all(&chunk?).map_err(axum::Error::new)?;
+            // flate2's writers do a sync flush, which ends the deflate block
+            encoder.flush().map_err(axum::Error::new)?;
+
+            yield Bytes::from(std::mem::take(encoder.get_mut()));
+        }
+
+        yield Bytes::from(encoder.finish().map_err(axum::Error::new)?);
+    }
+}
+
+fn accepts_gzip(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(ACCEPT_ENCODING)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|coding| {
+            let mut params = coding.split(';').map(str::trim);
+
+            params
+                .next()
+                .map_or(false, |name| name.eq_ignore_ascii_case("gzip"))
+                && params.all(|param| match param.strip_prefix("q=") {
+                    Some(quality) => quality.parse::<f32>().map_or(false, |q| q > 0.0),
+                    None => true,
+                })
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{header::ACCEPT_ENCODING, HeaderMap, HeaderValue};
+
+    fn accept(value: &'static str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(ACCEPT_ENCODING, HeaderValue::from_static(value));
+        headers
+    }
+
+    #[test]
+    fn negotiate_gzip() {
+        assert!(super::accepts_gzip(&accept("gzip")));
+        assert!(super::accepts_gzip(&accept("deflate, gzip;q=0.5, br")));
+        assert!(!super::accepts_gzip(&accept("gzip;q=0")));
+        assert!(!super::accepts_gzip(&accept("deflate, br")));
+        assert!(!super::accepts_gzip(&HeaderMap::new()));
+    }
+}
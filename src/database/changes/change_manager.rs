@@ -1,76 +1,204 @@
-use std::{collections::hash_map::Entry, sync::Arc};
+use std::{
+    collections::hash_map::Entry,
+    fmt::Write,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
 
 use tokio::sync::broadcast::{self, error::SendError};
 
 use crate::{app_state::AppEnv, auth::DatabasePermissions, database::Database, error::CRRError};
 
 use super::{
-    ChangesIter, Changeset, DatabaseHandle, Message, Migration, Subscription, CHANGE_BUFFER_SIZE,
+    Acks, ChangesIter, Changeset, DatabaseHandle, Message, Migration, Subscription,
+    CHANGE_BUFFER_SIZE,
 };
 
+/// minimum time between two checkpoints written by a watcher task
+const CHECKPOINT_INTERVAL: Duration = Duration::from_secs(60);
+
+/// subscribers that take longer than this to catch up lag behind instead of holding up the others
+const MAX_ACK_WAIT: Duration = Duration::from_secs(1);
+
+#[derive(Default)]
+struct Counters {
+    changesets_broadcast: AtomicU64,
+    change_scans: AtomicU64,
+    gc_runs: AtomicU64,
+}
+
 #[derive(Clone)]
-pub(crate) struct ChangeManager(
-    Arc<tokio::sync::RwLock<std::collections::HashMap<String, DatabaseHandle>>>,
-);
+pub(crate) struct ChangeManager {
+    handles: Arc<tokio::sync::RwLock<std::collections::HashMap<String, DatabaseHandle>>>,
+    schema_locks:
+        Arc<tokio::sync::Mutex<std::collections::HashMap<String, Arc<tokio::sync::RwLock<()>>>>>,
+    counters: Arc<Counters>,
+    channel_capacity: usize,
+    debounce: Duration,
+    max_ack_wait: Duration,
+}
 
 impl ChangeManager {
-    pub(crate) fn new() -> Self {
+    /// configured by `CRR_CHANGE_CHANNEL_CAPACITY`, `CRR_CHANGE_DEBOUNCE_MS`,
+    /// `CRR_GC_INTERVAL_SECONDS` and `CRR_GC_ORPHAN_GRACE_SECONDS`
+    pub(crate) fn from_env(env: &AppEnv) -> Self {
+        Self::new(
+            env.change_channel_capacity(),
+            env.change_debounce(),
+            env.gc_interval(),
+            env.gc_orphan_grace(),
+        )
+    }
+
+    /// `channel_capacity` is the number of messages a subscriber can fall behind
+    /// before it lags and has to resync, `debounce` is how long update signals are
+    /// collected before changes are read. Every `gc_interval` handles that have been
+    /// without subscribers for longer than `orphan_grace` are stopped.
+    pub(crate) fn new(
+        channel_capacity: usize,
+        debounce: Duration,
+        gc_interval: Duration,
+        orphan_grace: Duration,
+    ) -> Self {
         let handles = Arc::new(tokio::sync::RwLock::new(std::collections::HashMap::<
             String,
             DatabaseHandle,
         >::new()));
+        let counters = Arc::new(Counters::default());
 
         // garbage collection for database handles
         let gc_handles = Arc::downgrade(&handles);
+        let gc_counters = Arc::clone(&counters);
 
         tokio::spawn(async move {
             loop {
                 tracing::debug!("Run GC");
-                tokio::time::sleep(tokio::time::Duration::from_secs(240)).await;
+                tokio::time::sleep(gc_interval).await;
 
                 match gc_handles.upgrade() {
-                    Some(handles) => {
-                        let mut lock = handles.write().await;
-
-                        let mut collect = Vec::new();
-                        for (db_name, handle) in lock.iter() {
-                            if handle.is_orphan() {
-                                collect.push(db_name.to_owned());
-                            }
-                        }
-
-                        for db_name in collect.into_iter() {
-                            lock.remove(&db_name);
-                        }
-                    }
+                    Some(handles) => Self::collect_garbage(&handles, orphan_grace).await,
                     None => return,
                 }
 
+                gc_counters.gc_runs.fetch_add(1, Ordering::Relaxed);
                 tracing::debug!("GC Done");
             }
         });
 
-        Self(handles)
+        Self {
+            handles,
+            schema_locks: Arc::default(),
+            counters,
+            channel_capacity,
+            debounce,
+            max_ack_wait: MAX_ACK_WAIT,
+        }
+    }
+
+    #[cfg(test)]
+    pub(crate) fn with_max_ack_wait(mut self, max_ack_wait: Duration) -> Self {
+        self.max_ack_wait = max_ack_wait;
+        self
+    }
+
+    /// Keeps migrations from interleaving with change stream backfills of the same database:
+    /// migrations hold it exclusively while they're applied, backfills hold it shared while
+    /// they take the snapshot they read migrations and changes from. It has to be acquired
+    /// before the database is read and is never held while waiting for `handles`.
+    /// Locks nobody holds on to anymore are dropped whenever one is handed out.
+    pub(crate) async fn schema_lock(&self, db_name: &str) -> Arc<tokio::sync::RwLock<()>> {
+        let mut locks = self.schema_locks.lock().await;
+
+        // every clone is made under this mutex, so no other lock can be handed out meanwhile
+        locks.retain(|_db_name, lock| Arc::strong_count(lock) > 1);
+
+        Arc::clone(locks.entry(db_name.to_owned()).or_default())
+    }
+
+    /// a handle is only stopped once its last subscriber left at least `orphan_grace` ago,
+    /// so clients reconnecting in between keep it
+    async fn collect_garbage(
+        handles: &tokio::sync::RwLock<std::collections::HashMap<String, DatabaseHandle>>,
+        orphan_grace: Duration,
+    ) {
+        let mut lock = handles.write().await;
+
+        lock.retain(|db_name, handle| match handle.orphaned_for() {
+            Some(orphaned_for) if orphaned_for >= orphan_grace => {
+                tracing::info!("Stop Database Watcher Task for \"{}\"", db_name);
+                false
+            }
+            _ => true,
+        });
+    }
+
+    pub(crate) async fn metrics(&self) -> ChangeManagerMetrics {
+        let lock = self.handles.read().await;
+
+        let mut connections: Vec<(String, usize)> = lock
+            .iter()
+            .map(|(db_name, handle)| (db_name.to_owned(), handle.connection_count()))
+            .collect();
+        connections.sort();
+
+        ChangeManagerMetrics {
+            handles: lock.len(),
+            connections,
+            changesets_broadcast: self.counters.changesets_broadcast.load(Ordering::Relaxed),
+            change_scans: self.counters.change_scans.load(Ordering::Relaxed),
+            gc_runs: self.counters.gc_runs.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Sends [`Message::Closing`] to every subscriber and stops all watcher tasks
+    pub(crate) async fn shutdown(&self) {
+        let mut lock = self.handles.write().await;
+
+        for (db_name, handle) in lock.drain() {
+            tracing::info!("Close change streams for \"{}\"", db_name);
+            handle.close();
+        }
+    }
+
+    /// Sends [`Message::Closing`] to the subscribers of `db_name` and stops its watcher task,
+    /// the next subscription starts a new one
+    pub(crate) async fn close(&self, db_name: &str) {
+        if let Some(handle) = self.handles.write().await.remove(db_name) {
+            tracing::info!("Close change streams for \"{}\"", db_name);
+            handle.close();
+        }
     }
 
     pub(crate) async fn subscribe(
         &self,
-        env: &AppEnv,
+        env: &Arc<AppEnv>,
         db_name: &str,
     ) -> Result<Subscription, CRRError> {
-        if let Some(handle) = self.0.read().await.get(db_name) {
-            return Ok(handle.subscribe());
+        if let Some(handle) = self.handles.read().await.get(db_name) {
+            return handle.subscribe(env.max_subscribers_per_db());
         }
 
-        match self.0.write().await.entry(db_name.to_owned()) {
-            Entry::Occupied(entry) => Ok(entry.get().subscribe()),
+        match self.handles.write().await.entry(db_name.to_owned()) {
+            Entry::Occupied(entry) => entry.get().subscribe(env.max_subscribers_per_db()),
             Entry::Vacant(entry) => {
                 let database = Database::open_readonly_latest(
                     env,
                     db_name.to_owned(),
                     DatabasePermissions::Full,
                 )?;
-                let (handle, subscription) = Self::add_handle(database).await?;
+                let (handle, subscription) = Self::add_handle(
+                    Arc::clone(env),
+                    database,
+                    Arc::clone(&self.counters),
+                    self.channel_capacity,
+                    self.debounce,
+                    self.max_ack_wait,
+                    env.max_subscribers_per_db(),
+                )
+                .await?;
                 entry.insert(handle);
 
                 Ok(subscription)
@@ -79,14 +207,23 @@ impl ChangeManager {
     }
 
     async fn add_handle(
+        env: Arc<AppEnv>,
         mut database: Database,
+        counters: Arc<Counters>,
+        channel_capacity: usize,
+        debounce: Duration,
+        max_ack_wait: Duration,
+        max_subscribers: usize,
     ) -> Result<(DatabaseHandle, Subscription), CRRError> {
         tracing::info!(
             "Start new Database Watcher Task for \"{}\"",
             database.name()
         );
-        let (message_sender, message_receiver) = tokio::sync::broadcast::channel::<Message>(32);
+        let (message_sender, message_receiver) =
+            tokio::sync::broadcast::channel::<Message>(channel_capacity);
         let (signal_sender, mut signal_receiver) = tokio::sync::mpsc::channel::<()>(1);
+        let acks = Arc::new(Acks::default());
+        let subscription = Subscription::new(message_receiver, Arc::clone(&acks), max_subscribers)?;
 
         let hook_signal_sender = signal_sender.downgrade();
 
@@ -101,6 +238,7 @@ impl ChangeManager {
                 if table_name.starts_with("__crsql")
                     || table_name.starts_with("crsql")
                     || table_name == "crr_server_migrations"
+                    || table_name == "crr_server_checkpoints"
                 {
                     return;
                 }
@@ -112,43 +250,206 @@ impl ChangeManager {
         ));
 
         let task_message_sender = message_sender.clone();
+        let task_acks = Arc::clone(&acks);
 
         tokio::spawn(async move {
-            if let Err(_) = Self::send_changes(&mut database, &task_message_sender) {
+            let db_name = database.name().to_owned();
+            let mut last_checkpoint = None;
+
+            if let Err(_) =
+                Self::send_changes(&mut database, &task_message_sender, &task_acks, &counters)
+            {
                 // no receivers, stop this task
                 return;
             }
+            let db_version = database.db_version();
+            Self::checkpoint(&env, &db_name, db_version, &mut last_checkpoint).await;
 
             while let Some(_) = signal_receiver.recv().await {
-                if let Err(_) = Self::send_changes(&mut database, &task_message_sender) {
+                if !debounce.is_zero() {
+                    tokio::time::sleep(debounce).await;
+                }
+
+                task_acks.wait(max_ack_wait).await;
+
+                // signals received while waiting are covered by the upcoming scan
+                let _ = signal_receiver.try_recv();
+
+                if let Err(_) =
+                    Self::send_changes(&mut database, &task_message_sender, &task_acks, &counters)
+                {
                     // no receivers, stop this task
                     return;
                 }
+                let db_version = database.db_version();
+                Self::checkpoint(&env, &db_name, db_version, &mut last_checkpoint).await;
             }
         });
 
-        let handle = DatabaseHandle::from(message_sender, signal_sender);
+        let handle = DatabaseHandle::from(message_sender, signal_sender, acks);
 
-        Ok((handle, message_receiver))
+        Ok((handle, subscription))
     }
 
     fn send_changes(
         database: &mut Database,
         sender: &broadcast::Sender<Message>,
+        acks: &Acks,
+        counters: &Counters,
     ) -> Result<(), SendError<Message>> {
+        counters.change_scans.fetch_add(1, Ordering::Relaxed);
+
         for message in database.all_changes() {
+            if let Ok(changeset) = &message {
+                acks.set_broadcast_version(changeset.db_version());
+            }
             sender.send(message.into())?;
+            counters
+                .changesets_broadcast
+                .fetch_add(1, Ordering::Relaxed);
         }
 
         Ok(())
     }
 
+    /// Maps the current time to the watched `db_version` so streams can resume
+    /// from a wallclock, at most once per [`CHECKPOINT_INTERVAL`] and only if there are new changes.
+    async fn checkpoint(
+        env: &Arc<AppEnv>,
+        db_name: &str,
+        db_version: i64,
+        last_checkpoint: &mut Option<(Instant, i64)>,
+    ) {
+        if let Some((written_at, written_version)) = last_checkpoint {
+            if *written_version == db_version || written_at.elapsed() < CHECKPOINT_INTERVAL {
+                return;
+            }
+        }
+
+        let env = Arc::clone(env);
+        let name = db_name.to_owned();
+
+        // the watcher connection is read-only, opening a writer would block the watcher task
+        let result = tokio::task::spawn_blocking(move || {
+            let writer = Database::open(&env, name, DatabasePermissions::Full)?;
+            writer.write_checkpoint(Database::wallclock_now(), db_version)?;
+            writer.prune_checkpoints()
+        })
+        .await;
+
+        match result {
+            Ok(Ok(())) => *last_checkpoint = Some((Instant::now(), db_version)),
+            Ok(Err(error)) => {
+                tracing::error!("Failed to write checkpoint for \"{}\": {}", db_name, error)
+            }
+            Err(error) => {
+                tracing::error!("Failed to write checkpoint for \"{}\": {}", db_name, error)
+            }
+        }
+    }
+
+    /// Tells the watcher of `db_name` that changes were written,
+    /// for writes the watcher's update hook might not see right away
+    pub(crate) async fn send_signal(&self, db_name: &str) {
+        if let Some(handle) = self.handles.read().await.get(db_name) {
+            handle.signal();
+        }
+    }
+
+    /// Sends the migration to the live subscribers of `db_name`,
+    /// there's nothing to do if nobody is subscribed
     pub(crate) async fn publish_migration(&self, db_name: &str, migration: Migration) {
-        let lock = self.0.read().await;
+        let lock = self.handles.read().await;
         if let Some(handle) = lock.get(db_name) {
             handle.publish_migration(migration);
         }
     }
+
+    /// Like [`ChangeManager::publish_migration`], `migration` holds the down migration
+    pub(crate) async fn publish_rollback(&self, db_name: &str, migration: Migration) {
+        let lock = self.handles.read().await;
+        if let Some(handle) = lock.get(db_name) {
+            handle.publish_rollback(migration);
+        }
+    }
+}
+
+pub(crate) struct ChangeManagerMetrics {
+    handles: usize,
+    connections: Vec<(String, usize)>,
+    changesets_broadcast: u64,
+    change_scans: u64,
+    gc_runs: u64,
+}
+
+impl ChangeManagerMetrics {
+    #[cfg(test)]
+    pub(crate) fn change_scans(&self) -> u64 {
+        self.change_scans
+    }
+
+    /// Renders the metrics in the Prometheus text exposition format,
+    /// subscribers are only labeled with database names if `per_database` is set
+    pub(crate) fn to_prometheus(&self, per_database: bool) -> String {
+        let mut out = String::new();
+
+        let _ = writeln!(
+            out,
+            "# HELP crr_database_handles Number of active database watcher tasks"
+        );
+        let _ = writeln!(out, "# TYPE crr_database_handles gauge");
+        let _ = writeln!(out, "crr_database_handles {}", self.handles);
+
+        let _ = writeln!(
+            out,
+            "# HELP crr_database_connections Number of change stream subscribers"
+        );
+        let _ = writeln!(out, "# TYPE crr_database_connections gauge");
+        if per_database {
+            for (db_name, connections) in self.connections.iter() {
+                let _ = writeln!(
+                    out,
+                    "crr_database_connections{{database=\"{}\"}} {}",
+                    db_name.replace('\\', "\\\\").replace('"', "\\\""),
+                    connections
+                );
+            }
+        } else {
+            let connections: usize = self
+                .connections
+                .iter()
+                .map(|(_db_name, connections)| connections)
+                .sum();
+            let _ = writeln!(out, "crr_database_connections {}", connections);
+        }
+
+        let _ = writeln!(
+            out,
+            "# HELP crr_changesets_broadcast_total Number of changesets sent to subscribers"
+        );
+        let _ = writeln!(out, "# TYPE crr_changesets_broadcast_total counter");
+        let _ = writeln!(
+            out,
+            "crr_changesets_broadcast_total {}",
+            self.changesets_broadcast
+        );
+
+        let _ = writeln!(
+            out,
+            "# HELP crr_change_scans_total Number of times watcher tasks read new changes"
+        );
+        let _ = writeln!(out, "# TYPE crr_change_scans_total counter");
+        let _ = writeln!(out, "crr_change_scans_total {}", self.change_scans);
+
+        let _ = writeln!(
+            out,
+            "# HELP crr_gc_runs_total Number of database handle garbage collection runs"
+        );
+        let _ = writeln!(out, "# TYPE crr_gc_runs_total counter");
+        let _ = writeln!(out, "crr_gc_runs_total {}", self.gc_runs);
+
+        out
+    }
 }
 
 impl Database {
@@ -198,3 +499,433 @@ impl Database {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::{
+        app_state::{AppEnv, AppState},
+        database::migrate::tests::setup_foo,
+        error::CRRError,
+    };
+
+    use super::{ChangeManager, Message, Migration};
+
+    #[tokio::test]
+    async fn count_connections() {
+        let state = AppState::test_state();
+        setup_foo(state.env());
+
+        let change_manager = ChangeManager::from_env(state.env());
+
+        let _sub = change_manager
+            .subscribe(state.env(), AppEnv::TEST_DB_NAME)
+            .await
+            .expect("Failed to set up subscription");
+
+        let metrics = change_manager.metrics().await;
+
+        assert_eq!(metrics.handles, 1);
+        assert_eq!(
+            metrics.connections,
+            vec![(AppEnv::TEST_DB_NAME.to_owned(), 1)]
+        );
+        assert!(metrics
+            .to_prometheus(true)
+            .contains("crr_database_connections{database=\"data\"} 1"));
+        assert!(metrics
+            .to_prometheus(false)
+            .contains("crr_database_connections 1"));
+    }
+
+    #[tokio::test]
+    async fn evict_unused_schema_locks() {
+        let change_manager = AppState::test_state().change_manager().clone();
+
+        let held = change_manager.schema_lock("held").await;
+        drop(change_manager.schema_lock("dropped").await);
+
+        let again = change_manager.schema_lock("held").await;
+        assert!(std::sync::Arc::ptr_eq(&held, &again), "Held locks are kept");
+
+        let mut names: Vec<String> = change_manager
+            .schema_locks
+            .lock()
+            .await
+            .keys()
+            .cloned()
+            .collect();
+        names.sort();
+
+        assert_eq!(names, vec!["held".to_owned()]);
+    }
+
+    #[tokio::test]
+    async fn limit_subscribers() {
+        let env = AppEnv::with_max_subscribers_per_db(AppEnv::test_env(), 2);
+        setup_foo(&env);
+
+        let change_manager = ChangeManager::from_env(&env);
+
+        let _first = change_manager
+            .subscribe(&env, AppEnv::TEST_DB_NAME)
+            .await
+            .unwrap();
+        let second = change_manager
+            .subscribe(&env, AppEnv::TEST_DB_NAME)
+            .await
+            .unwrap();
+
+        assert!(matches!(
+            change_manager.subscribe(&env, AppEnv::TEST_DB_NAME).await,
+            Err(CRRError::TooManyConnections(2))
+        ));
+
+        drop(second);
+
+        assert!(change_manager
+            .subscribe(&env, AppEnv::TEST_DB_NAME)
+            .await
+            .is_ok());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn limit_concurrent_subscribers() {
+        let env = AppEnv::with_max_subscribers_per_db(AppEnv::test_env(), 5);
+        setup_foo(&env);
+
+        let change_manager = ChangeManager::from_env(&env);
+
+        // starts the watcher task so all subscribers below share its handle
+        let _first = change_manager
+            .subscribe(&env, AppEnv::TEST_DB_NAME)
+            .await
+            .unwrap();
+
+        let subscribers: Vec<_> = (0..20)
+            .map(|_| {
+                let change_manager = change_manager.clone();
+                let env = env.clone();
+                tokio::spawn(
+                    async move { change_manager.subscribe(&env, AppEnv::TEST_DB_NAME).await },
+                )
+            })
+            .collect();
+
+        let mut subscriptions = Vec::new();
+        for subscriber in subscribers {
+            if let Ok(subscription) = subscriber.await.unwrap() {
+                subscriptions.push(subscription);
+            }
+        }
+
+        assert_eq!(subscriptions.len(), 4);
+    }
+
+    #[tokio::test]
+    async fn publish_migration() {
+        let state = AppState::test_state();
+        setup_foo(state.env());
+
+        let change_manager = ChangeManager::from_env(state.env());
+
+        change_manager
+            .publish_migration(
+                AppEnv::TEST_DB_NAME,
+                Migration::new(2, "SELECT 1".to_owned()),
+            )
+            .await;
+
+        assert_eq!(
+            change_manager.metrics().await.handles,
+            0,
+            "Publishing without subscribers doesn't start a watcher"
+        );
+
+        let mut sub = change_manager
+            .subscribe(state.env(), AppEnv::TEST_DB_NAME)
+            .await
+            .unwrap();
+
+        change_manager
+            .publish_migration(
+                AppEnv::TEST_DB_NAME,
+                Migration::new(2, "SELECT 1".to_owned()),
+            )
+            .await;
+
+        match sub.recv().await.expect("Failed to receive message") {
+            Message::Migration(migration) => assert_eq!(migration.version(), 2),
+            message => panic!("Unexpected message {:?}", message),
+        }
+    }
+
+    #[tokio::test]
+    async fn keep_briefly_orphaned_handles() {
+        let state = AppState::test_state();
+        setup_foo(state.env());
+
+        let orphan_grace = Duration::from_millis(100);
+        let change_manager = ChangeManager::new(
+            state.env().change_channel_capacity(),
+            state.env().change_debounce(),
+            state.env().gc_interval(),
+            orphan_grace,
+        );
+
+        let collect_garbage =
+            || ChangeManager::collect_garbage(&change_manager.handles, orphan_grace);
+
+        let sub = change_manager
+            .subscribe(state.env(), AppEnv::TEST_DB_NAME)
+            .await
+            .unwrap();
+        drop(sub);
+
+        collect_garbage().await;
+        assert_eq!(change_manager.metrics().await.handles, 1);
+
+        let sub = change_manager
+            .subscribe(state.env(), AppEnv::TEST_DB_NAME)
+            .await
+            .unwrap();
+
+        tokio::time::sleep(orphan_grace).await;
+        collect_garbage().await;
+        assert_eq!(
+            change_manager.metrics().await.handles,
+            1,
+            "Handle survives after a reconnect"
+        );
+
+        drop(sub);
+
+        collect_garbage().await;
+        assert_eq!(change_manager.metrics().await.handles, 1);
+
+        tokio::time::sleep(orphan_grace).await;
+        collect_garbage().await;
+        assert_eq!(
+            change_manager.metrics().await.handles,
+            0,
+            "Handle is stopped after the grace period"
+        );
+    }
+
+    #[tokio::test]
+    async fn count_orphan_grace_from_last_unsubscribe() {
+        let state = AppState::test_state();
+        setup_foo(state.env());
+
+        let orphan_grace = Duration::from_millis(100);
+        let change_manager = ChangeManager::new(
+            state.env().change_channel_capacity(),
+            state.env().change_debounce(),
+            state.env().gc_interval(),
+            orphan_grace,
+        );
+
+        let sub = change_manager
+            .subscribe(state.env(), AppEnv::TEST_DB_NAME)
+            .await
+            .unwrap();
+        drop(sub);
+
+        tokio::time::sleep(orphan_grace).await;
+        ChangeManager::collect_garbage(&change_manager.handles, orphan_grace).await;
+
+        assert_eq!(
+            change_manager.metrics().await.handles,
+            0,
+            "A single pass stops a handle orphaned for longer than the grace period"
+        );
+    }
+
+    #[tokio::test]
+    async fn close_subscriptions_on_shutdown() {
+        let state = AppState::test_state();
+        setup_foo(state.env());
+
+        let change_manager = ChangeManager::from_env(state.env());
+
+        let mut sub = change_manager
+            .subscribe(state.env(), AppEnv::TEST_DB_NAME)
+            .await
+            .expect("Failed to set up subscription");
+
+        change_manager.shutdown().await;
+
+        loop {
+            match sub.recv().await.expect("Failed to receive message") {
+                Message::Closing => break,
+                Message::Change(_) => continue,
+                message => panic!("Unexpected message {:?}", message),
+            }
+        }
+
+        assert_eq!(change_manager.metrics().await.handles, 0);
+    }
+
+    #[tokio::test]
+    async fn honor_channel_capacity() {
+        let state = AppState::test_state();
+        setup_foo(state.env());
+
+        let capacity = 100;
+        let change_manager = ChangeManager::new(
+            capacity,
+            state.env().change_debounce(),
+            state.env().gc_interval(),
+            state.env().gc_orphan_grace(),
+        );
+
+        let mut sub = change_manager
+            .subscribe(state.env(), AppEnv::TEST_DB_NAME)
+            .await
+            .expect("Failed to set up subscription");
+
+        for version in 0..(capacity - 1) {
+            change_manager
+                .publish_migration(
+                    AppEnv::TEST_DB_NAME,
+                    Migration::new(version as i64, "SELECT 1".to_owned()),
+                )
+                .await;
+        }
+
+        for version in 0..(capacity - 1) {
+            match sub.recv().await.expect("Subscription lagged behind") {
+                Message::Migration(migration) => assert_eq!(migration.version(), version as i64),
+                message => panic!("Unexpected message {:?}", message),
+            }
+        }
+    }
+
+    // the watcher task has to run while the inserts happen
+    #[tokio::test(flavor = "multi_thread")]
+    async fn coalesce_scans_for_slow_subscribers() {
+        let state = AppState::test_state();
+        setup_foo(state.env());
+
+        // the subscriber is never given up on, so the test doesn't depend on timing
+        let change_manager =
+            ChangeManager::from_env(state.env()).with_max_ack_wait(Duration::from_secs(3600));
+
+        let mut sub = change_manager
+            .subscribe(state.env(), AppEnv::TEST_DB_NAME)
+            .await
+            .expect("Failed to set up subscription");
+
+        let inserts = 20;
+
+        {
+            let db = state.env().test_db();
+            for i in 0..inserts {
+                db.execute("INSERT INTO foo (bar) VALUES (?)", [i])
+                    .expect("Failed to insert data");
+            }
+        }
+
+        let mut received = 0;
+        while received < inserts {
+            match sub.recv().await.expect("Subscription lagged behind") {
+                Message::Change(_) => received += 1,
+                message => panic!("Unexpected message {:?}", message),
+            }
+        }
+
+        let change_scans = change_manager.metrics().await.change_scans;
+
+        // one when the watcher starts, one for the first insert(s) and one for
+        // everything inserted before the subscriber acknowledged those
+        assert!(
+            change_scans <= 3,
+            "{} scans for {} inserts",
+            change_scans,
+            inserts
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn dont_wait_for_slow_subscribers() {
+        let state = AppState::test_state();
+        setup_foo(state.env());
+
+        // subscribers that are behind are marked slow right away
+        let change_manager = ChangeManager::from_env(state.env()).with_max_ack_wait(Duration::ZERO);
+
+        let mut sub = change_manager
+            .subscribe(state.env(), AppEnv::TEST_DB_NAME)
+            .await
+            .expect("Failed to set up subscription");
+        let mut slow_sub = change_manager
+            .subscribe(state.env(), AppEnv::TEST_DB_NAME)
+            .await
+            .expect("Failed to set up subscription");
+
+        let db = state.env().test_db();
+
+        for i in 0..3 {
+            db.execute("INSERT INTO foo (bar) VALUES (?)", [i])
+                .expect("Failed to insert data");
+
+            match sub.recv().await.expect("Subscription lagged behind") {
+                Message::Change(_) => (),
+                message => panic!("Unexpected message {:?}", message),
+            }
+        }
+
+        for _ in 0..3 {
+            match slow_sub.recv().await.expect("Subscription lagged behind") {
+                Message::Change(_) => (),
+                message => panic!("Unexpected message {:?}", message),
+            }
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn debounce_update_signals() {
+        let state = AppState::test_state();
+        setup_foo(state.env());
+
+        let change_manager = ChangeManager::from_env(state.env());
+
+        let mut sub = change_manager
+            .subscribe(state.env(), AppEnv::TEST_DB_NAME)
+            .await
+            .expect("Failed to set up subscription");
+
+        let inserts = 100;
+
+        // keeps up with the watcher so only debouncing coalesces scans
+        let reader = tokio::spawn(async move {
+            let mut received = 0;
+            while received < inserts {
+                match sub.recv().await.expect("Subscription lagged behind") {
+                    Message::Change(_) => received += 1,
+                    message => panic!("Unexpected message {:?}", message),
+                }
+            }
+        });
+
+        {
+            let db = state.env().test_db();
+            for i in 0..inserts {
+                db.execute("INSERT INTO foo (bar) VALUES (?)", [i])
+                    .expect("Failed to insert data");
+            }
+        }
+
+        reader.await.unwrap();
+
+        let change_scans = change_manager.metrics().await.change_scans;
+
+        assert!(
+            change_scans < inserts as u64 / 4,
+            "{} scans for {} inserts",
+            change_scans,
+            inserts
+        );
+    }
+}
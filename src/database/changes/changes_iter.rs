@@ -1,4 +1,4 @@
-use std::fmt::Debug;
+use std::{fmt::Debug, sync::PoisonError};
 
 use crate::error::CRRError;
 
@@ -26,6 +26,40 @@ where
     }
 }
 
+impl<F> ChangesIter<F>
+where
+    F: FnMut() -> Result<(Vec<Changeset>, bool), CRRError> + Send,
+{
+    /// The mutex only makes the iterator `Sync`, going through `get_mut` means a
+    /// panic in `load_page` doesn't poison it and the next call retries the page.
+    fn load_next_page(&mut self) -> Result<Vec<Changeset>, CRRError> {
+        let load_page = self
+            .load_page
+            .get_mut()
+            .unwrap_or_else(PoisonError::into_inner);
+
+        let (page, has_next_page) = load_page()?;
+        self.has_next_page = has_next_page;
+
+        Ok(page)
+    }
+
+    /// returns the remainder of the current page or loads the next one
+    pub(crate) fn next_page(&mut self) -> Option<Result<Vec<Changeset>, CRRError>> {
+        let current_page: Vec<Changeset> = self.current_page.by_ref().collect();
+
+        if !current_page.is_empty() {
+            return Some(Ok(current_page));
+        }
+
+        if !self.has_next_page {
+            return None;
+        }
+
+        Some(self.load_next_page())
+    }
+}
+
 impl<F> Debug for ChangesIter<F>
 where
     F: FnMut() -> Result<(Vec<Changeset>, bool), CRRError> + Send,
@@ -53,15 +87,10 @@ where
         }
 
         if self.has_next_page {
-            match self
-                .load_page
-                .lock()
-                .map_err(|_| CRRError::PoisonedLockError("ChangesIter::next"))
-                .and_then(|mut lock| lock())
-            {
-                Ok((page, has_next_page)) => {
+            // a failed page is retried on the next call
+            match self.load_next_page() {
+                Ok(page) => {
                     self.current_page = page.into_iter();
-                    self.has_next_page = has_next_page;
                     return self.current_page.next().map(|changeset| Ok(changeset));
                 }
                 Err(error) => return Some(Err(error)),
@@ -71,3 +100,35 @@ where
         None
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+
+    use crate::error::CRRError;
+
+    use super::ChangesIter;
+
+    #[test]
+    fn recover_from_failed_page() {
+        let mut calls = 0;
+        let mut iter = ChangesIter::new(move || {
+            calls += 1;
+            match calls {
+                1 => Err(CRRError::BadRequest("transient".to_owned())),
+                2 => panic!("load_page panicked"),
+                _ => Ok((Vec::new(), false)),
+            }
+        });
+
+        assert!(matches!(iter.next(), Some(Err(CRRError::BadRequest(_)))));
+
+        catch_unwind(AssertUnwindSafe(|| iter.next())).expect_err("Closure panics");
+
+        assert!(
+            matches!(iter.next_page(), Some(Ok(page)) if page.is_empty()),
+            "Poisoned lock is recovered"
+        );
+        assert!(iter.next_page().is_none());
+    }
+}
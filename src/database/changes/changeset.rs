@@ -53,6 +53,11 @@ impl Changeset {
     pub(crate) fn site_id(&self) -> &Vec<u8> {
         &self.site_id
     }
+
+    pub(crate) fn with_col_version(mut self, col_version: i64) -> Self {
+        self.col_version = col_version;
+        self
+    }
 }
 
 impl<'a> TryFrom<&Row<'a>> for Changeset {
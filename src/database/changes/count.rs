@@ -0,0 +1,161 @@
+use axum::extract::{Json, Path, Query, State};
+use rusqlite::params_from_iter;
+use serde::{Deserialize, Serialize};
+
+use crate::{app_state::AppState, auth::DatabasePermissions, database::Database, error::CRRError};
+
+use super::stream::deserialize_site_ids;
+
+#[derive(Deserialize)]
+pub(crate) struct CountChangesQuery {
+    #[serde(rename = "site_id", deserialize_with = "deserialize_site_ids")]
+    site_ids: Vec<Vec<u8>>,
+    db_version: i64,
+}
+
+#[derive(Serialize)]
+pub(crate) struct CountChangesResponse {
+    count: u64,
+}
+
+/// Number of changes a change stream with the same `site_id` and `db_version`
+/// would backfill, so clients can show their sync progress.
+pub(crate) async fn count_changes(
+    Path(db_name): Path<String>,
+    Query(query): Query<CountChangesQuery>,
+    State(state): State<AppState>,
+    permissions: DatabasePermissions,
+) -> Result<Json<CountChangesResponse>, CRRError> {
+    let mut db = Database::open_pooled(state.env(), state.database_pool(), db_name, permissions)?;
+    db.set_db_version(query.db_version);
+
+    let count = db.count_changes(&query.site_ids)?;
+
+    Ok(Json(CountChangesResponse { count }))
+}
+
+impl Database {
+    /// Counts the changes [`Database::changes`] would return without `seq`
+    pub(crate) fn count_changes(&mut self, site_ids: &[Vec<u8>]) -> Result<u64, CRRError> {
+        let (filter, readable_tables) = self.changes_filter(site_ids.len())?;
+        let permissions = self.permissions().clone();
+        let params = Self::changes_params(self.db_version() + 1, site_ids, &readable_tables);
+
+        let authorized = self.disable_authorization();
+        let mut stmt = authorized.prepare(&format!(
+            "SELECT \"table\", cid FROM crsql_changes {}",
+            filter
+        ))?;
+        let mut rows = stmt.query(params_from_iter(params.iter()))?;
+
+        let mut count = 0;
+
+        // denied columns are skipped row by row, just like the stream does
+        while let Some(row) = rows.next()? {
+            let table_name: String = row.get(0)?;
+            let cid: Option<String> = row.get(1)?;
+
+            if let Some(cid) = cid {
+                if !permissions.read_column(&table_name, &cid) {
+                    continue;
+                }
+            }
+
+            count += 1;
+        }
+
+        Ok(count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use axum::extract::{Json, Path, Query, State};
+    use futures::StreamExt;
+
+    use super::count_changes;
+    use crate::{
+        app_state::{AppEnv, AppState},
+        auth::{DatabasePermissions, PartialPermissions},
+        database::changes::{stream::subscribe_changes, Message},
+    };
+
+    #[tokio::test]
+    async fn count_streamed_changes() {
+        let state = AppState::test_state();
+
+        state
+            .env()
+            .test_db()
+            .apply_migration(
+                vec![
+                    "CREATE TABLE \"foo\" (id INTEGER PRIMARY KEY, bar TEXT, secret TEXT)"
+                        .to_string(),
+                ],
+                Vec::new(),
+            )
+            .unwrap();
+
+        state
+            .env()
+            .test_db()
+            .execute_batch(
+                "
+                    INSERT INTO foo (bar, secret) VALUES ('a', 'x');
+                    INSERT INTO foo (bar, secret) VALUES ('b', 'y');
+                    UPDATE foo SET bar = 'c' WHERE id = 1;
+                ",
+            )
+            .unwrap();
+
+        let mut permissions = DatabasePermissions::Partial {
+            database: PartialPermissions {
+                read: true,
+                insert: false,
+                update: false,
+                delete: false,
+            },
+            tables: HashMap::new(),
+        };
+        permissions.deny_column("foo".to_owned(), "secret".to_owned());
+
+        let uri = "/?site_id=&db_version=0&schema_version=1".parse().unwrap();
+
+        let Json(response) = count_changes(
+            Path(AppEnv::TEST_DB_NAME.to_owned()),
+            Query::try_from_uri(&uri).unwrap(),
+            State(state.clone()),
+            permissions.clone(),
+        )
+        .await
+        .unwrap();
+
+        let messages = subscribe_changes(
+            &state,
+            AppEnv::TEST_DB_NAME.to_owned(),
+            Query::try_from_uri(&uri).unwrap().0,
+            permissions,
+        )
+        .await
+        .unwrap();
+        futures::pin_mut!(messages);
+
+        let mut streamed = 0;
+
+        while let Some(message) = messages.next().await {
+            match message.unwrap() {
+                Message::Change(changeset) => {
+                    assert_ne!(changeset.cid(), Some("secret"));
+                    streamed += 1;
+                }
+                Message::Ready(_) => break,
+                _ => (),
+            }
+        }
+
+        assert!(streamed > 0);
+        assert_eq!(response.count, streamed);
+    }
+}
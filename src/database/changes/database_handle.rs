@@ -1,34 +1,248 @@
-use tokio::sync::{broadcast, mpsc};
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicI64, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use tokio::sync::{
+    broadcast::{self, error::RecvError},
+    mpsc, Notify,
+};
 
 use super::{Message, Migration};
+use crate::error::CRRError;
+
+/// The `db_version` each subscriber of a database has received so far.
+/// The watcher task holds off reading new changes until the subscribers it waits
+/// for have caught up, subscribers that don't catch up in time are marked slow
+/// and left to lag behind until they caught up again.
+/// Also records when the last subscriber left.
+#[derive(Default)]
+pub(crate) struct Acks {
+    subscribers: Mutex<HashMap<u64, Ack>>,
+    orphaned_since: Mutex<Option<Instant>>,
+    next_id: AtomicU64,
+    broadcast_version: AtomicI64,
+    notify: Notify,
+}
+
+struct Ack {
+    db_version: i64,
+    slow: bool,
+}
+
+impl Acks {
+    /// New subscribers only receive changes broadcast after this point.
+    /// The limit is checked under the same lock the subscriber is added with,
+    /// so concurrent subscribers can't exceed it.
+    fn register(&self, max_subscribers: usize) -> Result<u64, CRRError> {
+        let mut subscribers = self
+            .subscribers
+            .lock()
+            .map_err(|_| CRRError::PoisonedLockError("Acks"))?;
+
+        if subscribers.len() >= max_subscribers {
+            return Err(CRRError::TooManyConnections(max_subscribers));
+        }
+
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let db_version = self.broadcast_version.load(Ordering::Acquire);
+
+        subscribers.insert(
+            id,
+            Ack {
+                db_version,
+                slow: false,
+            },
+        );
+
+        if let Ok(mut orphaned_since) = self.orphaned_since.lock() {
+            *orphaned_since = None;
+        }
+
+        Ok(id)
+    }
+
+    fn ack(&self, id: u64, db_version: i64) {
+        let broadcast_version = self.broadcast_version.load(Ordering::Acquire);
+
+        if let Ok(mut subscribers) = self.subscribers.lock() {
+            if let Some(ack) = subscribers.get_mut(&id) {
+                ack.db_version = db_version;
+                ack.slow = ack.slow && db_version < broadcast_version;
+            }
+        }
+
+        self.notify.notify_one();
+    }
+
+    fn remove(&self, id: u64) {
+        if let Ok(mut subscribers) = self.subscribers.lock() {
+            subscribers.remove(&id);
+
+            if subscribers.is_empty() {
+                if let Ok(mut orphaned_since) = self.orphaned_since.lock() {
+                    *orphaned_since = Some(Instant::now());
+                }
+            }
+        }
+
+        self.notify.notify_one();
+    }
+
+    /// set before the changes of `db_version` are sent
+    pub(crate) fn set_broadcast_version(&self, db_version: i64) {
+        self.broadcast_version.store(db_version, Ordering::Release);
+    }
+
+    fn waiting(&self) -> bool {
+        let broadcast_version = self.broadcast_version.load(Ordering::Acquire);
+
+        self.subscribers
+            .lock()
+            .map(|subscribers| {
+                subscribers
+                    .values()
+                    .any(|ack| !ack.slow && ack.db_version < broadcast_version)
+            })
+            .unwrap_or(false)
+    }
+
+    fn mark_slow(&self) {
+        let broadcast_version = self.broadcast_version.load(Ordering::Acquire);
+
+        if let Ok(mut subscribers) = self.subscribers.lock() {
+            for ack in subscribers.values_mut() {
+                if ack.db_version < broadcast_version {
+                    ack.slow = true;
+                }
+            }
+        }
+    }
 
-pub(crate) type Subscription = broadcast::Receiver<Message>;
+    /// Resolves once all subscribers that aren't marked slow received every
+    /// broadcast change, those still behind after `max_wait` are marked slow.
+    pub(crate) async fn wait(&self, max_wait: Duration) {
+        let deadline = tokio::time::Instant::now() + max_wait;
+
+        loop {
+            // created before checking so acks in between aren't missed
+            let acked = self.notify.notified();
+
+            if !self.waiting() {
+                return;
+            }
+
+            if tokio::time::timeout_at(deadline, acked).await.is_err() {
+                self.mark_slow();
+                return;
+            }
+        }
+    }
+}
+
+/// Receives the messages of a database and acknowledges the changes it received
+pub(crate) struct Subscription {
+    id: u64,
+    receiver: broadcast::Receiver<Message>,
+    acks: Arc<Acks>,
+}
+
+impl Subscription {
+    pub(crate) fn new(
+        receiver: broadcast::Receiver<Message>,
+        acks: Arc<Acks>,
+        max_subscribers: usize,
+    ) -> Result<Self, CRRError> {
+        Ok(Self {
+            id: acks.register(max_subscribers)?,
+            receiver,
+            acks,
+        })
+    }
+
+    pub(crate) async fn recv(&mut self) -> Result<Message, RecvError> {
+        let message = self.receiver.recv().await?;
+
+        if let Message::Change(changeset) = &message {
+            self.acks.ack(self.id, changeset.db_version());
+        }
+
+        Ok(message)
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        self.acks.remove(self.id);
+    }
+}
 
 pub(crate) struct DatabaseHandle {
     message_sender: broadcast::Sender<Message>,
-    _signal_sender: mpsc::Sender<()>,
+    signal_sender: mpsc::Sender<()>,
+    acks: Arc<Acks>,
 }
 
 impl DatabaseHandle {
     pub(crate) fn from(
         message_sender: broadcast::Sender<Message>,
         signal_sender: mpsc::Sender<()>,
+        acks: Arc<Acks>,
     ) -> Self {
         Self {
             message_sender,
-            _signal_sender: signal_sender,
+            signal_sender,
+            acks,
         }
     }
 
     pub(crate) fn is_orphan(&self) -> bool {
-        self.message_sender.receiver_count() < 1
+        self.connection_count() < 1
+    }
+
+    /// How long the handle has been without subscribers since the last one left,
+    /// `None` while somebody is subscribed
+    pub(crate) fn orphaned_for(&self) -> Option<Duration> {
+        if !self.is_orphan() {
+            return None;
+        }
+
+        let orphaned_since = *self.acks.orphaned_since.lock().ok()?;
+
+        orphaned_since.map(|orphaned_since| orphaned_since.elapsed())
     }
 
-    pub(crate) fn subscribe(&self) -> Subscription {
-        self.message_sender.subscribe()
+    pub(crate) fn connection_count(&self) -> usize {
+        self.message_sender.receiver_count()
+    }
+
+    pub(crate) fn subscribe(&self, max_subscribers: usize) -> Result<Subscription, CRRError> {
+        Subscription::new(
+            self.message_sender.subscribe(),
+            Arc::clone(&self.acks),
+            max_subscribers,
+        )
+    }
+
+    /// wakes the watcher task up to read new changes,
+    /// a signal that is already pending covers this one
+    pub(crate) fn signal(&self) {
+        let _ = self.signal_sender.try_send(());
     }
 
     pub(crate) fn publish_migration(&self, migration: Migration) {
         let _ = self.message_sender.send(Message::Migration(migration));
     }
+
+    pub(crate) fn publish_rollback(&self, migration: Migration) {
+        let _ = self.message_sender.send(Message::Rollback(migration));
+    }
+
+    pub(crate) fn close(&self) {
+        let _ = self.message_sender.send(Message::Closing);
+    }
 }
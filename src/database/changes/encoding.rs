@@ -0,0 +1,178 @@
+use std::convert::Infallible;
+
+use axum::{
+    async_trait,
+    body::{Bytes, HttpBody},
+    extract::{FromRequest, FromRequestParts},
+    http::{
+        header::{ACCEPT, CONTENT_TYPE},
+        request::Parts,
+        HeaderValue, Request, StatusCode,
+    },
+    BoxError, Json,
+};
+
+use crate::error::CRRError;
+
+use super::Changeset;
+
+pub(crate) const MSGPACK_CONTENT_TYPE: &str = "application/msgpack";
+
+/// Wire format for changesets, taken from the `Accept` header when extracted
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub(crate) enum Encoding {
+    #[default]
+    Json,
+    MsgPack,
+}
+
+impl Encoding {
+    fn from_header(value: Option<&HeaderValue>) -> Self {
+        match value.and_then(|value| value.to_str().ok()) {
+            Some(value) if value.contains(MSGPACK_CONTENT_TYPE) => Self::MsgPack,
+            _ => Self::Json,
+        }
+    }
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for Encoding {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Ok(Self::from_header(parts.headers.get(ACCEPT)))
+    }
+}
+
+/// Changesets in the request body, MessagePack if the `Content-Type` says so, JSON otherwise
+pub(crate) struct Changesets(pub(crate) Vec<Changeset>);
+
+#[async_trait]
+impl<S, B> FromRequest<S, B> for Changesets
+where
+    B: HttpBody + Send + 'static,
+    B::Data: Send,
+    B::Error: Into<BoxError>,
+    S: Send + Sync,
+{
+    type Rejection = CRRError;
+
+    async fn from_request(req: Request<B>, state: &S) -> Result<Self, Self::Rejection> {
+        match Encoding::from_header(req.headers().get(CONTENT_TYPE)) {
+            Encoding::Json => {
+                let Json(changes) = Json::<Vec<Changeset>>::from_request(req, state)
+                    .await
+                    .map_err(|rejection| {
+                        rejection_error(rejection.status(), rejection.body_text())
+                    })?;
+
+                Ok(Self(changes))
+            }
+            Encoding::MsgPack => {
+                let body = Bytes::from_request(req, state).await.map_err(|rejection| {
+                    rejection_error(rejection.status(), rejection.body_text())
+                })?;
+
+                Ok(Self(rmp_serde::from_slice(&body)?))
+            }
+        }
+    }
+}
+
+/// keeps 413 for bodies over the configured limit
+fn rejection_error(status: StatusCode, message: String) -> CRRError {
+    if status == StatusCode::PAYLOAD_TOO_LARGE {
+        CRRError::PayloadTooLarge(message)
+    } else {
+        CRRError::BadRequest(message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::Body,
+        extract::FromRequest,
+        http::{header::CONTENT_TYPE, Request},
+    };
+
+    use super::{Changesets, MSGPACK_CONTENT_TYPE};
+    use crate::{
+        app_state::AppEnv,
+        database::{
+            changes::{post::ApplyMode, Changeset},
+            migrate::tests::setup_foo,
+            Value,
+        },
+        error::CRRError,
+    };
+
+    fn read_foo(env: &AppEnv) -> Vec<(i64, String)> {
+        env.test_db()
+            .prepare("SELECT id, bar FROM foo ORDER BY id")
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap()
+            .collect::<Result<Vec<(i64, String)>, rusqlite::Error>>()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn apply_msgpack_changes() {
+        let source = AppEnv::test_env();
+        setup_foo(&source);
+
+        let changes = {
+            let mut db = source.test_db();
+            db.execute("INSERT INTO foo (bar) VALUES ('a'), ('b')", [])
+                .unwrap();
+
+            db.all_changes()
+                .collect::<Result<Vec<Changeset>, CRRError>>()
+                .unwrap()
+        };
+
+        let Changesets(from_msgpack) = Changesets::from_request(
+            Request::builder()
+                .header(CONTENT_TYPE, MSGPACK_CONTENT_TYPE)
+                .body(Body::from(rmp_serde::to_vec_named(&changes).unwrap()))
+                .unwrap(),
+            &(),
+        )
+        .await
+        .expect("Failed to decode msgpack changes");
+
+        let Changesets(from_json) = Changesets::from_request(
+            Request::builder()
+                .header(CONTENT_TYPE, "application/json")
+                .body(Body::from(serde_json::to_vec(&changes).unwrap()))
+                .unwrap(),
+            &(),
+        )
+        .await
+        .expect("Failed to decode json changes");
+
+        assert_eq!(from_msgpack, changes);
+        assert_eq!(from_msgpack, from_json);
+        assert!(from_msgpack
+            .iter()
+            .any(|changeset| changeset.val() == &Value::text("a")));
+
+        let msgpack_target = AppEnv::test_env();
+        setup_foo(&msgpack_target);
+        msgpack_target
+            .test_db()
+            .apply_changes(from_msgpack, ApplyMode::Strict)
+            .unwrap();
+
+        let json_target = AppEnv::test_env();
+        setup_foo(&json_target);
+        json_target
+            .test_db()
+            .apply_changes(from_json, ApplyMode::Strict)
+            .unwrap();
+
+        assert_eq!(read_foo(&msgpack_target), read_foo(&source));
+        assert_eq!(read_foo(&msgpack_target), read_foo(&json_target));
+    }
+}
@@ -0,0 +1,402 @@
+use std::{collections::HashMap, str::FromStr};
+
+use rusqlite::{params_from_iter, types::Value as RusqliteValue, OptionalExtension};
+
+use crate::{database::Value, error::CRRError};
+
+use super::Changeset;
+
+const POLICIES_TABLE: &str = "crr_server_merge_policies";
+
+/// How incoming changes to a column are merged with its current value,
+/// columns without a policy use crsqlite's last-writer-wins.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum MergePolicy {
+    /// only changes with a newer `col_version` are applied, ties keep the current value
+    RejectOlder,
+    /// the larger integer is kept, non-integer values fall back to last-writer-wins
+    MaxWins,
+}
+
+impl FromStr for MergePolicy {
+    type Err = CRRError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reject_older" => Ok(Self::RejectOlder),
+            "max_wins" => Ok(Self::MaxWins),
+            policy => Err(CRRError::BadRequest(format!(
+                "Unknown merge policy \"{}\"",
+                policy
+            ))),
+        }
+    }
+}
+
+/// Policies per table and column as configured in `crr_server_merge_policies`
+pub(crate) struct MergePolicies(HashMap<(String, String), MergePolicy>);
+
+impl MergePolicies {
+    pub(crate) fn load(conn: &rusqlite::Connection) -> Result<Self, CRRError> {
+        let mut policies = HashMap::new();
+
+        if !conn
+            .prepare("SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = ?")?
+            .exists([POLICIES_TABLE])?
+        {
+            return Ok(Self(policies));
+        }
+
+        let mut stmt = conn.prepare(&format!(
+            "SELECT table_name, column_name, policy FROM \"{}\"",
+            POLICIES_TABLE
+        ))?;
+        let mut rows = stmt.query([])?;
+
+        while let Some(row) = rows.next()? {
+            let policy: String = row.get(2)?;
+            policies.insert((row.get(0)?, row.get(1)?), policy.parse()?);
+        }
+
+        Ok(Self(policies))
+    }
+
+    /// The changeset to insert into `crsql_changes`, `None` if the policy rejects it.
+    /// A rejected change that outranks the current value by `col_version` would win
+    /// on the client that sent it, so the current value is sent back as a newer change.
+    pub(crate) fn resolve(
+        &self,
+        conn: &rusqlite::Connection,
+        changeset: Changeset,
+    ) -> Result<Option<Changeset>, CRRError> {
+        let (policy, cid) = match changeset.cid().and_then(|cid| {
+            self.0
+                .get(&(changeset.table().to_owned(), cid.to_owned()))
+                .map(|policy| (*policy, cid.to_owned()))
+        }) {
+            Some(policy) => policy,
+            None => return Ok(Some(changeset)),
+        };
+
+        let incoming_version = changeset.col_version();
+        let row = ChangedRow::find(conn, changeset.table(), changeset.pk())?;
+
+        let (current_val, current_version) = match row.current_value(conn, &cid)? {
+            Some(current) => current,
+            None => return Ok(Some(changeset)),
+        };
+
+        let resolved = match policy {
+            MergePolicy::RejectOlder if incoming_version <= current_version => None,
+            MergePolicy::RejectOlder => Some(changeset),
+            MergePolicy::MaxWins => {
+                match (changeset.val().as_integer(), current_val.as_integer()) {
+                    (Some(incoming), Some(current)) if incoming <= current => None,
+                    // outranks the current value even if it was written more often
+                    (Some(_), Some(_)) => {
+                        let col_version = incoming_version.max(current_version + 1);
+                        Some(changeset.with_col_version(col_version))
+                    }
+                    _ => Some(changeset),
+                }
+            }
+        };
+
+        if resolved.is_none() && incoming_version >= current_version {
+            row.bump_col_version(conn, &cid, incoming_version + 1)?;
+        }
+
+        Ok(resolved)
+    }
+}
+
+/// A row of a crr addressed by the `pk` of its changes
+struct ChangedRow {
+    table: String,
+    /// `"id" = ? AND ...`, bound to `pk_values`
+    condition: String,
+    pk_values: Vec<RusqliteValue>,
+}
+
+impl ChangedRow {
+    fn find(conn: &rusqlite::Connection, table: &str, pk: &Value) -> Result<Self, CRRError> {
+        let pk_columns = conn
+            .prepare_cached("SELECT name FROM pragma_table_info(?) WHERE pk > 0 ORDER BY pk")?
+            .query_map([table], |row| row.get::<_, String>(0))?
+            .collect::<Result<Vec<String>, rusqlite::Error>>()?;
+        let pk_values = pk.pk_values()?;
+
+        if pk_columns.len() != pk_values.len() {
+            return Err(CRRError::BadRequest(format!(
+                "Primary key {:?} doesn't match the {} primary key column(s) of \"{}\"",
+                pk,
+                pk_columns.len(),
+                table
+            )));
+        }
+
+        Ok(Self {
+            table: table.replace('"', "\"\""),
+            condition: pk_columns
+                .iter()
+                .map(|column| format!("\"{}\" = ?", column.replace('"', "\"\"")))
+                .collect::<Vec<String>>()
+                .join(" AND "),
+            pk_values,
+        })
+    }
+
+    /// the value of column `cid` and its `col_version`, `None` if the row doesn't exist
+    fn current_value(
+        &self,
+        conn: &rusqlite::Connection,
+        cid: &str,
+    ) -> Result<Option<(Value, i64)>, CRRError> {
+        let val: Option<Value> = conn
+            .prepare_cached(&format!(
+                "SELECT \"{}\" FROM \"{}\" WHERE {}",
+                cid.replace('"', "\"\""),
+                self.table,
+                self.condition
+            ))?
+            .query_row(params_from_iter(&self.pk_values), |row| row.get(0))
+            .optional()?;
+
+        let val = match val {
+            Some(val) => val,
+            None => return Ok(None),
+        };
+
+        let cid = RusqliteValue::Text(cid.to_owned());
+        let col_version: Option<i64> = conn
+            .prepare_cached(&format!(
+                "SELECT __crsql_col_version FROM \"{}__crsql_clock\" WHERE {} AND __crsql_col_name = ?",
+                self.table, self.condition
+            ))?
+            .query_row(
+                params_from_iter(self.pk_values.iter().chain([&cid])),
+                |row| row.get(0),
+            )
+            .optional()?;
+
+        Ok(col_version.map(|col_version| (val, col_version)))
+    }
+
+    /// Records the current value of column `cid` as a new local change,
+    /// the way crsqlite's triggers record updates
+    fn bump_col_version(
+        &self,
+        conn: &rusqlite::Connection,
+        cid: &str,
+        col_version: i64,
+    ) -> Result<(), CRRError> {
+        conn.prepare_cached(&format!(
+            "
+                UPDATE \"{}__crsql_clock\"
+                SET __crsql_col_version = ?,
+                    __crsql_db_version = crsql_nextdbversion(),
+                    __crsql_site_id = NULL
+                WHERE {} AND __crsql_col_name = ?
+            ",
+            self.table, self.condition
+        ))?
+        .execute(params_from_iter(
+            std::iter::once(&RusqliteValue::Integer(col_version))
+                .chain(self.pk_values.iter())
+                .chain([&RusqliteValue::Text(cid.to_owned())]),
+        ))?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        app_state::AppEnv,
+        database::{
+            changes::{post::ApplyMode, Changeset},
+            migrate::tests::setup_foo,
+        },
+        error::CRRError,
+    };
+
+    fn sync(from: &AppEnv, to: &AppEnv) {
+        let changes = from
+            .test_db()
+            .all_changes()
+            .collect::<Result<Vec<Changeset>, CRRError>>()
+            .unwrap();
+
+        to.test_db()
+            .apply_changes(changes, ApplyMode::Strict)
+            .unwrap();
+    }
+
+    fn set_policy(env: &AppEnv, table_name: &str, column_name: &str, policy: &str) {
+        let db = env.test_db();
+
+        db.execute_batch(
+            "
+                CREATE TABLE IF NOT EXISTS crr_server_merge_policies (
+                    table_name TEXT NOT NULL,
+                    column_name TEXT NOT NULL,
+                    policy TEXT NOT NULL,
+                    PRIMARY KEY (table_name, column_name)
+                )
+            ",
+        )
+        .unwrap();
+        db.execute(
+            "INSERT INTO crr_server_merge_policies VALUES (?, ?, ?)",
+            [table_name, column_name, policy],
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn reject_older() {
+        let server = AppEnv::test_env();
+        let client = AppEnv::test_env();
+        setup_foo(&server);
+        setup_foo(&client);
+
+        server
+            .test_db()
+            .execute("INSERT INTO foo (id, bar) VALUES (1, 'a')", [])
+            .unwrap();
+        sync(&server, &client);
+
+        // concurrent updates end up with the same col_version
+        server
+            .test_db()
+            .execute("UPDATE foo SET bar = 'b' WHERE id = 1", [])
+            .unwrap();
+        client
+            .test_db()
+            .execute("UPDATE foo SET bar = 'z' WHERE id = 1", [])
+            .unwrap();
+
+        set_policy(&server, "foo", "bar", "reject_older");
+        sync(&client, &server);
+
+        let bar: String = server
+            .test_db()
+            .query_row("SELECT bar FROM foo WHERE id = 1", [], |row| row.get(0))
+            .unwrap();
+
+        assert_eq!(bar, "b", "The tie keeps the server's value");
+    }
+
+    #[test]
+    fn max_wins() {
+        let migration =
+            vec!["CREATE TABLE \"counters\" (id INTEGER PRIMARY KEY, hits INTEGER)".to_owned()];
+
+        let server = AppEnv::test_env();
+        let client = AppEnv::test_env();
+        for env in [&server, &client] {
+            env.test_db()
+                .apply_migration(migration.clone(), Vec::new())
+                .unwrap();
+        }
+
+        let hits = |env: &AppEnv| -> i64 {
+            env.test_db()
+                .query_row("SELECT hits FROM counters WHERE id = 1", [], |row| {
+                    row.get(0)
+                })
+                .unwrap()
+        };
+
+        server
+            .test_db()
+            .execute("INSERT INTO counters (id, hits) VALUES (1, 5)", [])
+            .unwrap();
+        sync(&server, &client);
+
+        client
+            .test_db()
+            .execute("UPDATE counters SET hits = 10 WHERE id = 1", [])
+            .unwrap();
+
+        // the server's value has the higher col_version but is smaller
+        for value in [6, 7] {
+            server
+                .test_db()
+                .execute("UPDATE counters SET hits = ? WHERE id = 1", [value])
+                .unwrap();
+        }
+
+        set_policy(&server, "counters", "hits", "max_wins");
+        sync(&client, &server);
+
+        assert_eq!(hits(&server), 10);
+
+        server
+            .test_db()
+            .execute("UPDATE counters SET hits = 20 WHERE id = 1", [])
+            .unwrap();
+        sync(&client, &server);
+
+        assert_eq!(hits(&server), 20, "Smaller values are rejected");
+    }
+
+    #[test]
+    fn converge_after_rejecting_changes() {
+        let migration =
+            vec!["CREATE TABLE \"counters\" (id INTEGER PRIMARY KEY, hits INTEGER)".to_owned()];
+
+        let server = AppEnv::test_env();
+        let alice = AppEnv::test_env();
+        let bob = AppEnv::test_env();
+        for env in [&server, &alice, &bob] {
+            env.test_db()
+                .apply_migration(migration.clone(), Vec::new())
+                .unwrap();
+        }
+
+        let hits = |env: &AppEnv| -> i64 {
+            env.test_db()
+                .query_row("SELECT hits FROM counters WHERE id = 1", [], |row| {
+                    row.get(0)
+                })
+                .unwrap()
+        };
+
+        server
+            .test_db()
+            .execute("INSERT INTO counters (id, hits) VALUES (1, 5)", [])
+            .unwrap();
+        sync(&server, &alice);
+        sync(&server, &bob);
+
+        alice
+            .test_db()
+            .execute("UPDATE counters SET hits = 10 WHERE id = 1", [])
+            .unwrap();
+
+        // bob's smaller value has the higher col_version and would win everywhere else
+        for value in [6, 7, 3] {
+            bob.test_db()
+                .execute("UPDATE counters SET hits = ? WHERE id = 1", [value])
+                .unwrap();
+        }
+
+        set_policy(&server, "counters", "hits", "max_wins");
+        sync(&alice, &server);
+        sync(&bob, &server);
+
+        assert_eq!(hits(&server), 10);
+
+        sync(&server, &alice);
+        sync(&server, &bob);
+
+        assert_eq!(hits(&alice), 10);
+        assert_eq!(
+            hits(&bob),
+            10,
+            "The rejected client receives the winning value"
+        );
+    }
+}
@@ -1,9 +1,10 @@
 use axum::response::sse::Event;
+use base64::{engine::general_purpose::STANDARD as base64, Engine};
 use serde::{Deserialize, Serialize};
 
 use crate::error::{CRRError, HttpError};
 
-use super::Changeset;
+use super::{Changeset, Encoding};
 
 #[derive(Clone, Serialize, Debug, Deserialize)]
 pub(crate) struct Migration {
@@ -20,11 +21,68 @@ impl Migration {
     }
 }
 
+/// Position in the change stream: `seq` changes of `db_version` have been delivered
+#[derive(Clone, Copy, Serialize, Debug, Deserialize, PartialEq)]
+pub(crate) struct Cursor {
+    db_version: i64,
+    seq: i64,
+}
+
+impl Cursor {
+    pub(crate) fn new(db_version: i64, seq: i64) -> Self {
+        Self { db_version, seq }
+    }
+
+    pub(crate) fn db_version(&self) -> i64 {
+        self.db_version
+    }
+
+    pub(crate) fn seq(&self) -> i64 {
+        self.seq
+    }
+
+    pub(crate) fn advance(&mut self, db_version: i64) {
+        if db_version == self.db_version {
+            self.seq += 1;
+        } else {
+            self.db_version = db_version;
+            self.seq = 1;
+        }
+    }
+}
+
+/// Sent once the initial migrations and changes have been delivered
+#[derive(Clone, Copy, Serialize, Debug, Deserialize, PartialEq)]
+pub(crate) struct Ready {
+    db_version: i64,
+    schema_version: i64,
+}
+
+impl Ready {
+    pub(crate) fn new(db_version: i64, schema_version: i64) -> Self {
+        Self {
+            db_version,
+            schema_version,
+        }
+    }
+}
+
 #[derive(Clone, Debug)]
 pub(crate) enum Message {
     Change(Changeset),
+    /// backfilled changes packed into one event, see `batch_size`
+    Changes(Vec<Changeset>),
     Migration(Migration),
+    /// the migration of this version was rolled back by running `sql`
+    Rollback(Migration),
+    Cursor(Cursor),
+    Ready(Ready),
     Error(HttpError),
+    /// the stream fell behind and missed changes, they are sent again
+    /// (possibly with duplicates) before live changes resume
+    Resync,
+    /// the server is shutting down, clients should reconnect
+    Closing,
 }
 
 #[cfg(test)]
@@ -37,6 +95,51 @@ impl Message {
     }
 }
 
+impl Message {
+    /// With [`Encoding::MsgPack`] change events carry base64 encoded MessagePack,
+    /// all other events stay JSON.
+    pub(crate) fn into_event(self, encoding: Encoding) -> Result<Event, CRRError> {
+        match (self, encoding) {
+            (Message::Change(changeset), Encoding::MsgPack) => Ok(Event::default()
+                .event("change")
+                .data(base64.encode(rmp_serde::to_vec_named(&changeset)?))),
+            (Message::Changes(changes), Encoding::MsgPack) => Ok(Event::default()
+                .event("changes")
+                .data(base64.encode(rmp_serde::to_vec_named(&changes)?))),
+            (message, _) => message.try_into(),
+        }
+    }
+}
+
+impl TryFrom<Message> for Event {
+    type Error = CRRError;
+
+    fn try_from(value: Message) -> Result<Self, Self::Error> {
+        match value {
+            Message::Change(changeset) => changeset.try_into(),
+            Message::Changes(changes) => {
+                Ok(Event::default().event("changes").json_data(changes)?)
+            }
+            Message::Migration(migration) => migration.try_into(),
+            Message::Rollback(migration) => {
+                Ok(Event::default().event("rollback").json_data(migration)?)
+            }
+            Message::Cursor(cursor) => Ok(Event::default().event("cursor").json_data(cursor)?),
+            Message::Ready(ready) => Ok(Event::default().event("ready").json_data(ready)?),
+            Message::Resync => Ok(Event::default().event("resync").data("")),
+            Message::Closing => Ok(Event::default().event("closing").data("")),
+            Message::Error(error) => {
+                Ok(Event::default()
+                    .event("error")
+                    .json_data(serde_json::json!({
+                        "code": error.code(),
+                        "message": error.message()
+                    }))?)
+            }
+        }
+    }
+}
+
 impl TryFrom<Migration> for Event {
     type Error = CRRError;
 
@@ -1,20 +1,34 @@
 mod change_manager;
 mod changes_iter;
 mod changeset;
+mod count;
 mod database_handle;
+mod encoding;
+mod merge;
 mod message;
 mod post;
+mod range;
 mod stream;
+mod websocket;
 
 pub(crate) use change_manager::ChangeManager;
 pub(crate) use changes_iter::ChangesIter;
 pub(crate) use changeset::Changeset;
-pub(crate) use database_handle::{DatabaseHandle, Subscription};
+pub(crate) use count::count_changes;
+pub(crate) use database_handle::{Acks, DatabaseHandle, Subscription};
+pub(crate) use encoding::{Changesets, Encoding};
+pub(crate) use merge::MergePolicies;
+pub(crate) use message::Cursor;
 pub(crate) use message::Message;
 pub(crate) use message::Migration;
+pub(crate) use message::Ready;
 pub(crate) use post::post_changes;
-pub(crate) use stream::stream_changes;
+pub(crate) use range::get_changes_range;
+pub(crate) use stream::{stream_changes, StreamChangesQuery};
+pub(crate) use websocket::stream_changes_ws;
 
+/// page size of the live changes read by the watcher tasks and of `changes/range`,
+/// backfill pages are sized by `CRR_CHANGE_BUFFER_BYTES`
 const CHANGE_BUFFER_SIZE: usize = 1_000_000;
 
 #[cfg(test)]
@@ -24,7 +38,7 @@ mod tests {
     use crate::{
         app_state::{AppEnv, AppState},
         database::{
-            changes::{change_manager::ChangeManager, Changeset},
+            changes::{change_manager::ChangeManager, post::ApplyMode, Changeset},
             migrate::tests::setup_foo,
             Value,
         },
@@ -81,7 +95,7 @@ mod tests {
         let state = AppState::test_state();
         setup_foo(state.env());
 
-        let change_manager = ChangeManager::new();
+        let change_manager = ChangeManager::from_env(state.env());
 
         let mut sub = change_manager
             .subscribe(state.env(), AppEnv::TEST_DB_NAME)
@@ -125,7 +139,7 @@ mod tests {
             .expect("Failed to retrieve changes");
 
         let mut db_b = env_b.test_db();
-        db_b.apply_changes(changes)
+        db_b.apply_changes(changes, ApplyMode::Strict)
             .expect("Failed to apply changes");
 
         let baz: String = db_b
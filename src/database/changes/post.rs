@@ -1,67 +1,199 @@
-use axum::extract::{Json, Path, State};
+use std::collections::HashSet;
+
+use axum::{
+    extract::{Json, Path, Query, State},
+    response::{IntoResponse, Response},
+};
 use rusqlite::named_params;
+use serde::{Deserialize, Serialize};
 
 use crate::{app_state::AppState, auth::DatabasePermissions, database::Database, error::CRRError};
 
-use super::Changeset;
+use super::{Changeset, Changesets, MergePolicies};
+
+const INSERT_CHANGE: &str = "
+    INSERT INTO crsql_changes (\"table\", pk, cid, val, col_version, db_version, site_id)
+    VALUES (:table, :pk, :cid, :val, :col_version, :db_version, :site_id)
+";
+
+#[derive(Deserialize, Default, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum ApplyMode {
+    /// the first unauthorized changeset fails the request
+    #[default]
+    Strict,
+    /// unauthorized changesets are skipped and reported
+    SkipUnauthorized,
+}
+
+#[derive(Deserialize, Default)]
+pub(crate) struct PostChangesQuery {
+    #[serde(default)]
+    mode: ApplyMode,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub(crate) struct ApplySummary {
+    applied: usize,
+    /// indices of the skipped changesets in the request
+    skipped: Vec<usize>,
+}
 
 pub(crate) async fn post_changes(
     Path(db_name): Path<String>,
+    Query(query): Query<PostChangesQuery>,
     State(state): State<AppState>,
     permissions: DatabasePermissions,
-    Json(changes): Json<Vec<Changeset>>,
-) -> Result<(), CRRError> {
-    let mut db = Database::open(&state.env(), db_name, permissions)?;
+    Changesets(changes): Changesets,
+) -> Result<Response, CRRError> {
+    let mut db = Database::open_pooled(
+        state.env(),
+        state.database_pool(),
+        db_name.clone(),
+        permissions,
+    )?;
+
+    let summary = db.apply_changes(changes, query.mode)?;
+
+    let response = match query.mode {
+        ApplyMode::Strict => ().into_response(),
+        ApplyMode::SkipUnauthorized => Json(summary).into_response(),
+    };
 
-    db.apply_changes(changes)?;
+    state.change_manager().send_signal(&db_name).await;
 
-    Ok(())
+    Ok(response)
 }
 
 impl Database {
-    pub(crate) fn apply_changes(&mut self, changes: Vec<Changeset>) -> Result<(), CRRError> {
-        let query = "
-            INSERT INTO crsql_changes (\"table\", pk, cid, val, col_version, db_version, site_id)
-            VALUES (:table, :pk, :cid, :val, :col_version, :db_version, :site_id)
-        ";
+    /// Applies the changesets in a single savepoint, so it also works inside an outer
+    /// transaction. Unauthorized changesets roll back all of them in [`ApplyMode::Strict`]
+    /// and are skipped and reported in [`ApplyMode::SkipUnauthorized`].
+    pub(crate) fn apply_changes(
+        &mut self,
+        changes: Vec<Changeset>,
+        mode: ApplyMode,
+    ) -> Result<ApplySummary, CRRError> {
+        let mut authorized = self.disable_authorization();
+
+        Self::validate_changes(&authorized, &changes)?;
+
+        let permissions = authorized.permissions().clone();
+        let savepoint = authorized.savepoint()?;
+        let mut summary = ApplySummary {
+            applied: 0,
+            skipped: Vec::new(),
+        };
+
+        {
+            let policies = MergePolicies::load(&savepoint)?;
+            let mut stmt = savepoint.prepare(INSERT_CHANGE)?;
+
+            for (index, changeset) in changes.into_iter().enumerate() {
+                match Self::authorize_change(&permissions, &changeset) {
+                    Ok(()) => {
+                        // changes rejected by a merge policy still count as applied
+                        if let Some(changeset) = policies.resolve(&savepoint, changeset)? {
+                            Self::insert_change(&mut stmt, &changeset)?;
+                        }
+                        summary.applied += 1;
+                    }
+                    Err(CRRError::Unauthorized(_)) if mode == ApplyMode::SkipUnauthorized => {
+                        summary.skipped.push(index)
+                    }
+                    Err(error) => return Err(error),
+                }
+            }
+        }
+
+        savepoint.commit()?;
+
+        Ok(summary)
+    }
+
+    fn authorize_change(
+        permissions: &DatabasePermissions,
+        changeset: &Changeset,
+    ) -> Result<(), CRRError> {
+        if changeset.cid() == Some("__crsql_del") {
+            if !permissions.delete_table(changeset.table()) {
+                return Err(CRRError::Unauthorized(format!(
+                    "User is not authorized to delete from table \"{}\"",
+                    changeset.table()
+                )));
+            }
+        } else if changeset.col_version() == 1 {
+            if !permissions.insert_table(changeset.table()) {
+                return Err(CRRError::Unauthorized(format!(
+                    "User is not authorized to insert into table \"{}\"",
+                    changeset.table()
+                )));
+            }
+        } else {
+            if !permissions.update_table(changeset.table()) {
+                return Err(CRRError::Unauthorized(format!(
+                    "User is not authorized to update table \"{}\"",
+                    changeset.table()
+                )));
+            }
+        }
 
-        let authorized = self.disable_authorization();
+        Ok(())
+    }
 
-        let mut stmt = authorized.prepare(query)?;
+    fn insert_change(
+        stmt: &mut rusqlite::Statement<'_>,
+        changeset: &Changeset,
+    ) -> Result<(), CRRError> {
+        stmt.insert(named_params! {
+            ":table": changeset.table(),
+            ":pk": changeset.pk(),
+            ":cid": changeset.cid(),
+            ":val": changeset.val(),
+            ":col_version": changeset.col_version(),
+            ":db_version": changeset.db_version(),
+            ":site_id": changeset.site_id(),
+        })?;
+
+        Ok(())
+    }
+
+    /// rejects changesets crsqlite can't apply before any of them is written
+    fn validate_changes(
+        conn: &rusqlite::Connection,
+        changes: &[Changeset],
+    ) -> Result<(), CRRError> {
+        let mut crr_tables = HashSet::<&str>::new();
+        let mut table_stmt =
+            conn.prepare("SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = ?")?;
 
         for changeset in changes {
-            if changeset.cid() == Some("__crsql_del") {
-                if !authorized.permissions().delete_table(changeset.table()) {
-                    return Err(CRRError::Unauthorized(format!(
-                        "User is not authorized to delete from table \"{}\"",
-                        changeset.table()
-                    )));
-                }
-            } else if changeset.col_version() == 1 {
-                if !authorized.permissions().insert_table(changeset.table()) {
-                    return Err(CRRError::Unauthorized(format!(
-                        "User is not authorized to insert into table \"{}\"",
-                        changeset.table()
-                    )));
-                }
-            } else {
-                if !authorized.permissions().update_table(changeset.table()) {
-                    return Err(CRRError::Unauthorized(format!(
-                        "User is not authorized to update table \"{}\"",
-                        changeset.table()
-                    )));
-                }
+            if changeset.site_id().len() != 16 {
+                return Err(CRRError::BadRequest(format!(
+                    "Invalid site_id of {} bytes, expected 16",
+                    changeset.site_id().len()
+                )));
             }
 
-            stmt.insert(named_params! {
-                ":table": changeset.table(),
-                ":pk": changeset.pk(),
-                ":cid": changeset.cid(),
-                ":val": changeset.val(),
-                ":col_version": changeset.col_version(),
-                ":db_version": changeset.db_version(),
-                ":site_id": changeset.site_id(),
-            })?;
+            if crr_tables.contains(changeset.table()) {
+                continue;
+            }
+
+            if !table_stmt.exists([changeset.table()])? {
+                return Err(CRRError::BadRequest(format!(
+                    "Table \"{}\" does not exist",
+                    changeset.table()
+                )));
+            }
+
+            if !table_stmt.exists([format!("{}__crsql_clock", changeset.table())])? {
+                return Err(CRRError::BadRequest(format!(
+                    "Table \"{}\" is not a crr",
+                    changeset.table()
+                )));
+            }
+
+            crr_tables.insert(changeset.table());
         }
 
         Ok(())
@@ -70,15 +202,18 @@ impl Database {
 
 #[cfg(test)]
 mod tests {
-    use std::collections::HashMap;
+    use std::{collections::HashMap, time::Duration};
 
-    use axum::extract::{Path, State};
+    use axum::{
+        body::HttpBody,
+        extract::{Path, Query, State},
+    };
 
-    use super::post_changes;
+    use super::{post_changes, ApplyMode, ApplySummary, Changesets, PostChangesQuery};
     use crate::{
         app_state::{AppEnv, AppState},
-        auth::{DatabasePermissions, PartialPermissions},
-        database::{changes::Changeset, migrate::tests::setup_foo},
+        auth::{DatabasePermissions, ObjectPermissions, PartialPermissions},
+        database::{changes::Changeset, migrate::tests::setup_foo, Value},
         error::CRRError,
     };
 
@@ -144,29 +279,36 @@ mod tests {
 
         assert!(post_changes(
             Path(AppEnv::TEST_DB_NAME.to_owned()),
+            Query(PostChangesQuery::default()),
             State(state.clone()),
             permissions.clone(),
-            axum::extract::Json(inserts.clone()),
+            Changesets(inserts.clone()),
         )
         .await
         .is_err());
 
-        state.env().test_db().apply_changes(inserts).unwrap();
+        state
+            .env()
+            .test_db()
+            .apply_changes(inserts, ApplyMode::Strict)
+            .unwrap();
 
         assert!(post_changes(
             Path(AppEnv::TEST_DB_NAME.to_owned()),
+            Query(PostChangesQuery::default()),
             State(state.clone()),
             permissions.clone(),
-            axum::extract::Json(updates),
+            Changesets(updates),
         )
         .await
         .is_err());
 
         assert!(post_changes(
             Path(AppEnv::TEST_DB_NAME.to_owned()),
+            Query(PostChangesQuery::default()),
             State(state.clone()),
             permissions,
-            axum::extract::Json(deletes),
+            Changesets(deletes),
         )
         .await
         .is_err());
@@ -190,9 +332,10 @@ mod tests {
 
         assert!(post_changes(
             Path(AppEnv::TEST_DB_NAME.to_owned()),
+            Query(PostChangesQuery::default()),
             State(state.clone()),
             permissions.clone(),
-            axum::extract::Json(inserts),
+            Changesets(inserts),
         )
         .await
         .is_ok());
@@ -212,18 +355,20 @@ mod tests {
 
         assert!(post_changes(
             Path(AppEnv::TEST_DB_NAME.to_owned()),
+            Query(PostChangesQuery::default()),
             State(state.clone()),
             permissions.clone(),
-            axum::extract::Json(updates),
+            Changesets(updates),
         )
         .await
         .is_err());
 
         assert!(post_changes(
             Path(AppEnv::TEST_DB_NAME.to_owned()),
+            Query(PostChangesQuery::default()),
             State(state.clone()),
             permissions,
-            axum::extract::Json(deletes),
+            Changesets(deletes),
         )
         .await
         .is_err());
@@ -247,20 +392,26 @@ mod tests {
 
         assert!(post_changes(
             Path(AppEnv::TEST_DB_NAME.to_owned()),
+            Query(PostChangesQuery::default()),
             State(state.clone()),
             permissions.clone(),
-            axum::extract::Json(inserts.clone())
+            Changesets(inserts.clone())
         )
         .await
         .is_err());
 
-        state.env().test_db().apply_changes(inserts).unwrap();
+        state
+            .env()
+            .test_db()
+            .apply_changes(inserts, ApplyMode::Strict)
+            .unwrap();
 
         assert!(post_changes(
             Path(AppEnv::TEST_DB_NAME.to_owned()),
+            Query(PostChangesQuery::default()),
             State(state.clone()),
             permissions.clone(),
-            axum::extract::Json(updates)
+            Changesets(updates)
         )
         .await
         .is_ok());
@@ -280,9 +431,10 @@ mod tests {
 
         assert!(post_changes(
             Path(AppEnv::TEST_DB_NAME.to_owned()),
+            Query(PostChangesQuery::default()),
             State(state.clone()),
             permissions,
-            axum::extract::Json(deletes),
+            Changesets(deletes),
         )
         .await
         .is_err());
@@ -306,29 +458,36 @@ mod tests {
 
         assert!(post_changes(
             Path(AppEnv::TEST_DB_NAME.to_owned()),
+            Query(PostChangesQuery::default()),
             State(state.clone()),
             permissions.clone(),
-            axum::extract::Json(inserts.clone())
+            Changesets(inserts.clone())
         )
         .await
         .is_err());
 
-        state.env().test_db().apply_changes(inserts).unwrap();
+        state
+            .env()
+            .test_db()
+            .apply_changes(inserts, ApplyMode::Strict)
+            .unwrap();
 
         assert!(post_changes(
             Path(AppEnv::TEST_DB_NAME.to_owned()),
+            Query(PostChangesQuery::default()),
             State(state.clone()),
             permissions.clone(),
-            axum::extract::Json(updates)
+            Changesets(updates)
         )
         .await
         .is_err());
 
         assert!(post_changes(
             Path(AppEnv::TEST_DB_NAME.to_owned()),
+            Query(PostChangesQuery::default()),
             State(state.clone()),
             permissions,
-            axum::extract::Json(deletes),
+            Changesets(deletes),
         )
         .await
         .is_ok());
@@ -346,4 +505,450 @@ mod tests {
             vec!["b", "c"]
         );
     }
+
+    /// inserts into `foo` and `bar`, only `foo` may be written
+    fn mixed_batch() -> (Vec<String>, Vec<Changeset>, DatabasePermissions) {
+        let migration = vec![
+            "CREATE TABLE \"foo\" (id INTEGER PRIMARY KEY, bar TEXT)".to_string(),
+            "CREATE TABLE \"bar\" (id INTEGER PRIMARY KEY, val TEXT)".to_string(),
+        ];
+
+        let env = AppEnv::test_env();
+        env.test_db()
+            .apply_migration(migration.clone(), Vec::new())
+            .unwrap();
+
+        let mut db = env.test_db();
+        db.execute_batch(
+            "
+                INSERT INTO foo (bar) VALUES ('a');
+                INSERT INTO bar (val) VALUES ('b');
+                INSERT INTO foo (bar) VALUES ('c');
+            ",
+        )
+        .unwrap();
+
+        let changes = db
+            .all_changes()
+            .collect::<Result<Vec<Changeset>, CRRError>>()
+            .unwrap();
+
+        let permissions = DatabasePermissions::Partial {
+            database: PartialPermissions::default(),
+            tables: HashMap::from([(
+                "foo".to_owned(),
+                ObjectPermissions::partial(PartialPermissions {
+                    insert: true,
+                    ..PartialPermissions::default()
+                }),
+            )]),
+        };
+
+        (migration, changes, permissions)
+    }
+
+    #[tokio::test]
+    async fn notify_subscribers() {
+        let state = AppState::test_state();
+        setup_foo(state.env());
+
+        let [inserts, _, _] = get_changes();
+
+        let mut sub = state
+            .change_manager()
+            .subscribe(state.env(), AppEnv::TEST_DB_NAME)
+            .await
+            .unwrap();
+
+        // the watcher's first scan would pick up the changes without any signal
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while state.change_manager().metrics().await.change_scans() < 1 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("Watcher didn't start");
+
+        post_changes(
+            Path(AppEnv::TEST_DB_NAME.to_owned()),
+            Query(PostChangesQuery::default()),
+            State(state.clone()),
+            DatabasePermissions::Full,
+            Changesets(inserts),
+        )
+        .await
+        .unwrap();
+
+        let message = tokio::time::timeout(Duration::from_secs(5), sub.recv())
+            .await
+            .expect("Subscriber wasn't notified")
+            .unwrap();
+
+        assert_eq!(message.changeset().unwrap().table(), "foo");
+    }
+
+    #[tokio::test]
+    async fn reject_mixed_batch() {
+        let (migration, changes, permissions) = mixed_batch();
+
+        let state = AppState::test_state();
+        state
+            .env()
+            .test_db()
+            .apply_migration(migration, Vec::new())
+            .unwrap();
+
+        assert!(matches!(
+            post_changes(
+                Path(AppEnv::TEST_DB_NAME.to_owned()),
+                Query(PostChangesQuery::default()),
+                State(state.clone()),
+                permissions,
+                Changesets(changes),
+            )
+            .await,
+            Err(CRRError::Unauthorized(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn roll_back_rejected_batch() {
+        let (migration, changes, permissions) = mixed_batch();
+
+        let state = AppState::test_state();
+        state
+            .env()
+            .test_db()
+            .apply_migration(migration, Vec::new())
+            .unwrap();
+
+        post_changes(
+            Path(AppEnv::TEST_DB_NAME.to_owned()),
+            Query(PostChangesQuery::default()),
+            State(state.clone()),
+            permissions,
+            Changesets(changes),
+        )
+        .await
+        .expect_err("Batch contains an unauthorized changeset");
+
+        let count: i64 = state
+            .env()
+            .test_db()
+            .query_row("SELECT COUNT(*) FROM foo", [], |row| row.get(0))
+            .unwrap();
+
+        assert_eq!(
+            count, 0,
+            "Changesets before the rejected one are rolled back"
+        );
+    }
+
+    #[tokio::test]
+    async fn skip_unauthorized_changes() {
+        let (migration, changes, permissions) = mixed_batch();
+
+        let state = AppState::test_state();
+        state
+            .env()
+            .test_db()
+            .apply_migration(migration, Vec::new())
+            .unwrap();
+
+        let expected_skipped: Vec<usize> = changes
+            .iter()
+            .enumerate()
+            .filter(|(_, changeset)| changeset.table() == "bar")
+            .map(|(index, _)| index)
+            .collect();
+
+        let res = post_changes(
+            Path(AppEnv::TEST_DB_NAME.to_owned()),
+            Query(PostChangesQuery {
+                mode: ApplyMode::SkipUnauthorized,
+            }),
+            State(state.clone()),
+            permissions,
+            Changesets(changes.clone()),
+        )
+        .await
+        .expect("Failed to apply permitted changes");
+
+        let body = res
+            .into_body()
+            .data()
+            .await
+            .expect("Body is empty")
+            .expect("Failed to read body");
+        let summary: serde_json::Value = serde_json::from_slice(&body).unwrap();
+
+        assert_eq!(
+            summary,
+            serde_json::to_value(ApplySummary {
+                applied: changes.len() - expected_skipped.len(),
+                skipped: expected_skipped,
+            })
+            .unwrap()
+        );
+
+        let db = state.env().test_db();
+
+        assert_eq!(
+            db.prepare("SELECT bar FROM foo ORDER BY id")
+                .unwrap()
+                .query_map([], |row| row.get::<usize, String>(0))
+                .unwrap()
+                .collect::<Result<Vec<String>, rusqlite::Error>>()
+                .unwrap(),
+            vec!["a", "c"]
+        );
+        assert_eq!(
+            db.query_row("SELECT COUNT(*) FROM bar", [], |row| row
+                .get::<usize, i64>(0))
+                .unwrap(),
+            0
+        );
+    }
+
+    #[test]
+    fn parse_apply_mode() {
+        let Query(query) =
+            Query::<PostChangesQuery>::try_from_uri(&"/?mode=skip_unauthorized".parse().unwrap())
+                .unwrap();
+
+        assert_eq!(query.mode, ApplyMode::SkipUnauthorized);
+
+        let Query(query) = Query::<PostChangesQuery>::try_from_uri(&"/".parse().unwrap()).unwrap();
+
+        assert_eq!(query.mode, ApplyMode::Strict);
+    }
+
+    fn modify_changeset(changeset: &Changeset, field: &str, value: serde_json::Value) -> Changeset {
+        let mut json = serde_json::to_value(changeset).unwrap();
+        json[field] = value;
+        serde_json::from_value(json).unwrap()
+    }
+
+    async fn post_invalid_change(state: &AppState, changeset: Changeset) -> CRRError {
+        post_changes(
+            Path(AppEnv::TEST_DB_NAME.to_owned()),
+            Query(PostChangesQuery::default()),
+            State(state.clone()),
+            DatabasePermissions::Full,
+            Changesets(vec![changeset]),
+        )
+        .await
+        .expect_err("Invalid changeset was applied")
+    }
+
+    #[tokio::test]
+    async fn reject_unknown_table() {
+        let state = AppState::test_state();
+        setup_foo(state.env());
+
+        let [inserts, _, _] = get_changes();
+        let changeset = modify_changeset(&inserts[0], "table", "nope".into());
+
+        assert!(matches!(
+            post_invalid_change(&state, changeset).await,
+            CRRError::BadRequest(_)
+        ));
+    }
+
+    #[tokio::test]
+    async fn reject_non_crr_table() {
+        let state = AppState::test_state();
+        setup_foo(state.env());
+
+        state
+            .env()
+            .test_db()
+            .execute("CREATE TABLE plain (id INTEGER PRIMARY KEY, bar TEXT)", [])
+            .unwrap();
+
+        let [inserts, _, _] = get_changes();
+        let changeset = modify_changeset(&inserts[0], "table", "plain".into());
+
+        assert!(matches!(
+            post_invalid_change(&state, changeset).await,
+            CRRError::BadRequest(_)
+        ));
+    }
+
+    #[tokio::test]
+    async fn reject_invalid_site_id() {
+        let state = AppState::test_state();
+        setup_foo(state.env());
+
+        let [inserts, _, _] = get_changes();
+        // 4 bytes
+        let changeset = modify_changeset(&inserts[0], "site_id", "AAAAAA==".into());
+
+        assert!(matches!(
+            post_invalid_change(&state, changeset).await,
+            CRRError::BadRequest(_)
+        ));
+
+        assert_eq!(
+            state
+                .env()
+                .test_db()
+                .query_row("SELECT COUNT(*) FROM foo", [], |row| row
+                    .get::<usize, i64>(0))
+                .unwrap(),
+            0
+        );
+    }
+
+    #[tokio::test]
+    async fn round_trip_quoted_pk() {
+        let migration =
+            vec!["CREATE TABLE \"quoted\" (val TEXT PRIMARY KEY, note TEXT)".to_string()];
+
+        let env = AppEnv::test_env();
+        env.test_db()
+            .apply_migration(migration.clone(), Vec::new())
+            .unwrap();
+
+        let mut db = env.test_db();
+        db.execute_batch("INSERT INTO quoted (val, note) VALUES ('b', 'x'), ('it''s', 'y')")
+            .unwrap();
+
+        let changes = db
+            .all_changes()
+            .collect::<Result<Vec<Changeset>, CRRError>>()
+            .unwrap();
+
+        assert!(changes
+            .iter()
+            .any(|changeset| changeset.pk() == &Value::text("it's")));
+
+        let state = AppState::test_state();
+        state
+            .env()
+            .test_db()
+            .apply_migration(migration, Vec::new())
+            .unwrap();
+
+        post_changes(
+            Path(AppEnv::TEST_DB_NAME.to_owned()),
+            Query(PostChangesQuery::default()),
+            State(state.clone()),
+            DatabasePermissions::Full,
+            Changesets(changes.clone()),
+        )
+        .await
+        .unwrap();
+
+        let mut db = state.env().test_db();
+
+        assert_eq!(
+            db.prepare("SELECT val FROM quoted ORDER BY val")
+                .unwrap()
+                .query_map([], |row| row.get::<usize, String>(0))
+                .unwrap()
+                .collect::<Result<Vec<String>, rusqlite::Error>>()
+                .unwrap(),
+            vec!["b", "it's"],
+            "Primary keys are not quoted twice"
+        );
+
+        let round_tripped = db
+            .all_changes()
+            .collect::<Result<Vec<Changeset>, CRRError>>()
+            .unwrap();
+
+        assert_eq!(round_tripped.len(), changes.len());
+
+        for changeset in changes {
+            assert!(
+                round_tripped
+                    .iter()
+                    .any(|other| other.table() == changeset.table()
+                        && other.pk() == changeset.pk()
+                        && other.cid() == changeset.cid()
+                        && other.val() == changeset.val()),
+                "{:?} was not round-tripped",
+                changeset
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn round_trip_compound_pk() {
+        let migration = vec![
+            "CREATE TABLE \"pairs\" (a INTEGER NOT NULL, b TEXT NOT NULL, val TEXT, PRIMARY KEY (a, b))"
+                .to_string(),
+        ];
+
+        let env = AppEnv::test_env();
+        env.test_db()
+            .apply_migration(migration.clone(), Vec::new())
+            .unwrap();
+
+        let mut db = env.test_db();
+        db.execute_batch(
+            "
+                INSERT INTO pairs (a, b, val) VALUES (1, 'x', 'one'), (1, 'a|b', 'two'), (2, 'it''s', 'three');
+                UPDATE pairs SET val = 'uno' WHERE a = 1 AND b = 'x';
+                DELETE FROM pairs WHERE a = 2;
+            ",
+        )
+        .unwrap();
+
+        let changes = db
+            .all_changes()
+            .collect::<Result<Vec<Changeset>, CRRError>>()
+            .unwrap();
+
+        // crsqlite joins the quoted primary key columns with `|`
+        assert!(changes
+            .iter()
+            .any(|changeset| changeset.pk() == &Value::Text("1|'x'".to_owned())));
+
+        let state = AppState::test_state();
+        state
+            .env()
+            .test_db()
+            .apply_migration(migration, Vec::new())
+            .unwrap();
+        state
+            .env()
+            .test_db()
+            .execute(
+                "INSERT INTO pairs (a, b, val) VALUES (2, 'it''s', 'local')",
+                [],
+            )
+            .unwrap();
+
+        post_changes(
+            Path(AppEnv::TEST_DB_NAME.to_owned()),
+            Query(PostChangesQuery::default()),
+            State(state.clone()),
+            DatabasePermissions::Full,
+            Changesets(changes),
+        )
+        .await
+        .unwrap();
+
+        let read_pairs = |env: &AppEnv| {
+            env.test_db()
+                .prepare("SELECT a, b, val FROM pairs ORDER BY a, b")
+                .unwrap()
+                .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+                .unwrap()
+                .collect::<Result<Vec<(i64, String, String)>, rusqlite::Error>>()
+                .unwrap()
+        };
+
+        assert_eq!(
+            read_pairs(state.env()),
+            vec![
+                (1, "a|b".to_owned(), "two".to_owned()),
+                (1, "x".to_owned(), "uno".to_owned()),
+            ],
+            "Updates and deletes are applied to the matching compound key"
+        );
+        assert_eq!(read_pairs(state.env()), read_pairs(&env));
+    }
 }
@@ -0,0 +1,210 @@
+use axum::extract::{Json, Path, Query, State};
+use serde::{Deserialize, Serialize};
+
+use crate::{app_state::AppState, auth::DatabasePermissions, database::Database, error::CRRError};
+
+use super::{Changeset, CHANGE_BUFFER_SIZE};
+
+#[derive(Deserialize)]
+pub(crate) struct ChangesRangeQuery {
+    from: i64,
+    to: i64,
+}
+
+#[derive(Serialize, Debug)]
+pub(crate) struct ChangesRange {
+    changes: Vec<Changeset>,
+    /// `from` of the next request if the range didn't fit into one page
+    next_from: Option<i64>,
+}
+
+/// The changes with `from < db_version <= to` as a JSON array, one page at a time,
+/// for auditing and replication tools that don't need a stream.
+pub(crate) async fn get_changes_range(
+    Path(db_name): Path<String>,
+    Query(query): Query<ChangesRangeQuery>,
+    State(state): State<AppState>,
+    permissions: DatabasePermissions,
+) -> Result<Json<ChangesRange>, CRRError> {
+    if query.from >= query.to {
+        return Err(CRRError::BadRequest(
+            "\"from\" has to be smaller than \"to\"".to_owned(),
+        ));
+    }
+
+    let mut db = Database::open_pooled(state.env(), state.database_pool(), db_name, permissions)?;
+
+    Ok(Json(db.changes_range(
+        query.from,
+        query.to,
+        CHANGE_BUFFER_SIZE,
+    )?))
+}
+
+impl Database {
+    /// Reads the first page of [`Database::changes`] up to `to`, always including
+    /// all changes of its last `db_version`
+    pub(crate) fn changes_range(
+        &mut self,
+        from: i64,
+        to: i64,
+        page_bytes: usize,
+    ) -> Result<ChangesRange, CRRError> {
+        self.set_db_version(from);
+
+        let mut pages = self.changes(&[], None, page_bytes)?;
+
+        let page = match pages.next_page() {
+            Some(page) => page?,
+            None => Vec::new(),
+        };
+        let passed_to = page.iter().any(|changeset| changeset.db_version() > to);
+        let mut changes: Vec<Changeset> = page
+            .into_iter()
+            .filter(|changeset| changeset.db_version() <= to)
+            .collect();
+
+        let last_version = match changes.last() {
+            Some(changeset) if !passed_to => changeset.db_version(),
+            _ => {
+                return Ok(ChangesRange {
+                    changes,
+                    next_from: None,
+                })
+            }
+        };
+
+        // pages can end in the middle of a db_version, the rest of it is on the next pages
+        while let Some(page) = pages.next_page() {
+            let page = page?;
+            let complete = page
+                .iter()
+                .any(|changeset| changeset.db_version() != last_version);
+
+            changes.extend(
+                page.into_iter()
+                    .take_while(|changeset| changeset.db_version() == last_version),
+            );
+
+            if complete {
+                return Ok(ChangesRange {
+                    changes,
+                    next_from: (last_version < to).then_some(last_version),
+                });
+            }
+        }
+
+        Ok(ChangesRange {
+            changes,
+            next_from: None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::extract::{Json, Path, Query, State};
+
+    use super::get_changes_range;
+    use crate::{
+        app_state::{AppEnv, AppState},
+        auth::DatabasePermissions,
+        database::{changes::Changeset, migrate::tests::setup_foo},
+        error::CRRError,
+    };
+
+    #[tokio::test]
+    async fn return_changes_in_range() {
+        let state = AppState::test_state();
+        setup_foo(state.env());
+
+        for bar in ["a", "b", "c", "d"] {
+            state
+                .env()
+                .test_db()
+                .execute("INSERT INTO foo (bar) VALUES (?)", [bar])
+                .unwrap();
+        }
+
+        let get = |uri: &str, permissions: DatabasePermissions| {
+            get_changes_range(
+                Path(AppEnv::TEST_DB_NAME.to_owned()),
+                Query::try_from_uri(&uri.parse().unwrap()).unwrap(),
+                State(state.clone()),
+                permissions,
+            )
+        };
+
+        let Json(range) = get("/?from=1&to=3", DatabasePermissions::Full)
+            .await
+            .unwrap();
+
+        assert_eq!(range.next_from, None);
+
+        let mut versions: Vec<i64> = range
+            .changes
+            .iter()
+            .map(|changeset| changeset.db_version())
+            .collect();
+        versions.dedup();
+
+        assert_eq!(versions, vec![2, 3]);
+
+        assert!(matches!(
+            get("/?from=3&to=3", DatabasePermissions::Full).await,
+            Err(CRRError::BadRequest(_))
+        ));
+        assert!(matches!(
+            get("/?from=0&to=4", DatabasePermissions::default()).await,
+            Err(CRRError::Unauthorized(_))
+        ));
+    }
+
+    #[test]
+    fn page_through_range() {
+        let env = AppEnv::test_env();
+        setup_foo(&env);
+
+        // two changes of the same db_version end up on different pages
+        for bars in [["a", "b"], ["c", "d"], ["e", "f"]] {
+            env.test_db()
+                .execute_batch(&format!(
+                    "BEGIN; INSERT INTO foo (bar) VALUES ('{}'); INSERT INTO foo (bar) VALUES ('{}'); COMMIT;",
+                    bars[0], bars[1]
+                ))
+                .unwrap();
+        }
+
+        let mut db = env.test_db();
+        let mut from = 0;
+        let mut changes = Vec::<Changeset>::new();
+
+        loop {
+            let range = db.changes_range(from, 2, 1).unwrap();
+
+            let mut versions: Vec<i64> = range
+                .changes
+                .iter()
+                .map(|changeset| changeset.db_version())
+                .collect();
+            versions.dedup();
+            assert_eq!(versions.len(), 1, "One complete db_version per page");
+
+            changes.extend(range.changes);
+
+            match range.next_from {
+                Some(next_from) => from = next_from,
+                None => break,
+            }
+        }
+
+        let mut versions: Vec<i64> = changes
+            .iter()
+            .map(|changeset| changeset.db_version())
+            .collect();
+        versions.dedup();
+
+        assert_eq!(versions, vec![1, 2]);
+        assert_eq!(changes, db.changes_range(0, 2, usize::MAX).unwrap().changes);
+    }
+}
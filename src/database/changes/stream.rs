@@ -1,12 +1,17 @@
-use async_stream::try_stream;
+use std::sync::Arc;
+
+use async_stream::{stream, try_stream};
 use axum::{
     extract::{Path, Query, State},
-    response::{sse::Event, Sse},
+    response::{
+        sse::{Event, KeepAlive},
+        Sse,
+    },
 };
-use futures::Stream;
+use futures::{Stream, StreamExt};
 use rusqlite::{params_from_iter, ToSql};
-use serde::Deserialize;
-use tokio::sync::Mutex;
+use serde::{Deserialize, Deserializer};
+use tokio::sync::{broadcast::error::RecvError, Mutex};
 
 use crate::{
     auth::{AllowedTables, DatabasePermissions},
@@ -15,14 +20,62 @@ use crate::{
     AppState,
 };
 
-use super::{ChangesIter, Changeset, CHANGE_BUFFER_SIZE};
+use super::{ChangesIter, Changeset, Cursor, Encoding, Migration, Ready};
 
 #[derive(Deserialize)]
 pub(crate) struct StreamChangesQuery {
-    #[serde(with = "crate::serde_base64")]
-    site_id: Vec<u8>,
+    /// comma-separated list of the client's site ids, changes from them aren't sent back.
+    /// Local changes of the server count as coming from its own site id in both the
+    /// backfill and the live changes, so they're always sent to clients.
+    /// Repeating `site_id` is rejected as a duplicate field rather than silently
+    /// using one of the values.
+    #[serde(rename = "site_id", deserialize_with = "deserialize_site_ids")]
+    site_ids: Vec<Vec<u8>>,
     db_version: i64,
     schema_version: i64,
+    /// number of changes of `db_version` the client already received,
+    /// as reported by the last `cursor` event
+    seq: Option<i64>,
+    /// unix timestamp in milliseconds of the last sync, replaces `db_version` and `seq`
+    /// with the `db_version` of the latest checkpoint before it
+    since_wallclock: Option<i64>,
+    /// comma-separated list of tables to stream, tables the caller can't read are ignored
+    #[serde(default, deserialize_with = "deserialize_table_list")]
+    tables: Option<Vec<String>>,
+    /// only send the migrations after `schema_version`, then end the stream
+    #[serde(default)]
+    schema_only: bool,
+    /// approximate size of the backfill pages in bytes, at most `CRR_CHANGE_BUFFER_BYTES`
+    page_bytes: Option<usize>,
+    /// pack up to this many backfilled changes into one `changes` event,
+    /// live changes are always sent one by one
+    batch_size: Option<usize>,
+}
+
+pub(super) fn deserialize_site_ids<'de, D: Deserializer<'de>>(
+    d: D,
+) -> Result<Vec<Vec<u8>>, D::Error> {
+    String::deserialize(d)?
+        .split(',')
+        .map(str::trim)
+        .filter(|site_id| !site_id.is_empty())
+        .map(|site_id| {
+            crate::serde_base64_urlsafe::decode(site_id).map_err(serde::de::Error::custom)
+        })
+        .collect()
+}
+
+fn deserialize_table_list<'de, D: Deserializer<'de>>(
+    d: D,
+) -> Result<Option<Vec<String>>, D::Error> {
+    Ok(Option::<String>::deserialize(d)?.map(|tables| {
+        tables
+            .split(',')
+            .map(str::trim)
+            .filter(|table_name| !table_name.is_empty())
+            .map(str::to_owned)
+            .collect()
+    }))
 }
 
 pub(crate) async fn stream_changes(
@@ -30,36 +83,187 @@ pub(crate) async fn stream_changes(
     Query(query): Query<StreamChangesQuery>,
     State(state): State<AppState>,
     permissions: DatabasePermissions,
+    encoding: Encoding,
 ) -> Result<Sse<impl Stream<Item = Result<Event, HttpError>>>, CRRError> {
+    // sent as a bare `:` comment between events, which clients ignore
+    let keep_alive = KeepAlive::new().interval(state.env().sse_keepalive());
+    let messages = subscribe_changes(&state, db_name, query, permissions).await?;
+
+    // errors are sent as an `error` event that ends the stream,
+    // so clients can tell them apart from the connection closing
+    Ok(Sse::new(stream! {
+        futures::pin_mut!(messages);
+
+        while let Some(message) = messages.next().await {
+            let message = message.unwrap_or_else(Message::Error);
+            let is_error = matches!(message, Message::Error(_));
+
+            yield message.into_event(encoding).map_err(HttpError::from);
+
+            if is_error {
+                break;
+            }
+        }
+    })
+    .keep_alive(keep_alive))
+}
+
+pub(crate) async fn subscribe_changes(
+    state: &AppState,
+    db_name: String,
+    query: StreamChangesQuery,
+    permissions: DatabasePermissions,
+) -> Result<impl Stream<Item = Result<Message, HttpError>>, CRRError> {
     if permissions.create() {
         Database::create(state.env(), &db_name)?;
+        // reads go to the replica, which might not have caught up with the new database
+        Database::wait_for_replica(state.env(), &db_name).await?;
     }
     tracing::debug!("lets go");
-    let mut subscription = state
-        .change_manager()
-        .subscribe(state.env(), &db_name)
-        .await?;
+    permissions.require_read()?;
+
+    // schema only streams end after the migrations, they don't need a watcher
+    let subscription = match query.schema_only {
+        true => None,
+        false => Some(
+            state
+                .change_manager()
+                .subscribe(state.env(), &db_name)
+                .await?,
+        ),
+    };
+
+    let permissions = match &query.tables {
+        Some(tables) => permissions.restrict_to_tables(tables),
+        None => permissions,
+    };
 
     tracing::debug!("open db now");
-    let db = Database::open_readonly(state.env(), db_name, query.db_version, permissions.clone())?;
-    let initial_migrations = db.migrations(query.schema_version)?;
-    let db = Mutex::new(db);
+    let env = Arc::clone(state.env());
+    let mut db =
+        Database::open_readonly(&env, db_name.clone(), query.db_version, permissions.clone())?;
+    let mut seq = query.seq;
+    let page_bytes = match query.page_bytes {
+        Some(page_bytes) => page_bytes.min(env.change_buffer_bytes()),
+        None => env.change_buffer_bytes(),
+    };
+
+    if let Some(wallclock) = query.since_wallclock {
+        let db_version = db.db_version_at(wallclock)?;
+        db.set_db_version(db_version);
+        seq = None;
+    }
+
+    let schema_lock = state.change_manager().schema_lock(&db_name).await;
+
+    Ok(try_stream! {
+        // only held until the snapshot is taken, so slow clients don't block migrations
+        let first_migration = {
+            let _schema_guard = Arc::clone(&schema_lock).read_owned().await;
+            db.begin_snapshot(query.schema_version)?
+        };
 
-    Ok(Sse::new(try_stream! {
         let mut schema_version = query.schema_version;
-        for migration in initial_migrations.into_iter() {
+        if let Some(migration) = first_migration {
             schema_version = migration.version();
-            yield Event::try_from(migration)?;
-        }
+            yield Message::Migration(migration);
 
-        for message in db.lock().await.changes(&query.site_id)? {
-            yield Event::try_from(message?)?;
+            // the others are loaded one at a time from the same snapshot
+            for migration in db.disable_authorization().migrations(schema_version) {
+                let migration = migration?;
+                schema_version = migration.version();
+                yield Message::Migration(migration);
+            }
         }
 
-        let mut db_version = db.lock().await.db_version() + 1;
-        drop(db);
+        let mut subscription = match subscription {
+            Some(subscription) => subscription,
+            None => return,
+        };
+
+        // the initial backfill and every resync after the subscription lagged behind
+        let mut backfill = Some((Mutex::new(db), seq));
+        let mut db_version = 0;
+
+        loop {
+            if let Some((db, seq)) = backfill.take() {
+                // nothing to backfill if none of the requested tables can be read
+                if !permissions.readable_tables().is_empty() {
+                    let mut db = db.lock().await;
+                    let mut cursor = Cursor::new(db.db_version(), seq.unwrap_or_default());
+                    let mut changes = db.changes(&query.site_ids, seq, page_bytes)?;
+
+                    while let Some(page) = changes.next_page() {
+                        let page = page?;
+
+                        if page.is_empty() {
+                            continue;
+                        }
+
+                        match query.batch_size {
+                            Some(batch_size) if batch_size > 1 => {
+                                for batch in page.chunks(batch_size) {
+                                    for changeset in batch {
+                                        cursor.advance(changeset.db_version());
+                                    }
+                                    yield Message::Changes(batch.to_vec());
+                                }
+                            }
+                            _ => {
+                                for changeset in page {
+                                    cursor.advance(changeset.db_version());
+                                    yield Message::Change(changeset);
+                                }
+                            }
+                        }
+
+                        yield Message::Cursor(cursor);
+                    }
+                }
+
+                let caught_up_version = db.lock().await.db_version();
+                drop(db);
+
+                yield Message::Ready(Ready::new(caught_up_version, schema_version));
+
+                db_version = caught_up_version + 1;
+            }
+
+            let message = match subscription.recv().await {
+                Ok(message) => message,
+                Err(RecvError::Lagged(skipped)) => {
+                    tracing::warn!(
+                        "Change stream for \"{}\" lagged behind by {} messages, resyncing",
+                        db_name,
+                        skipped
+                    );
+                    yield Message::Resync;
+
+                    // the last delivered db_version might be incomplete, send it again
+                    let mut db = Database::open_readonly(&env, db_name.clone(), db_version - 1, permissions.clone())?;
+
+                    let first_migration = {
+                        let _schema_guard = Arc::clone(&schema_lock).read_owned().await;
+                        db.begin_snapshot(schema_version)?
+                    };
+
+                    if let Some(migration) = first_migration {
+                        schema_version = migration.version();
+                        yield Message::Migration(migration);
+
+                        for migration in db.disable_authorization().migrations(schema_version) {
+                            let migration = migration?;
+                            schema_version = migration.version();
+                            yield Message::Migration(migration);
+                        }
+                    }
+
+                    backfill = Some((Mutex::new(db), None));
+                    continue;
+                }
+                Err(RecvError::Closed) => break,
+            };
 
-        while let Ok(message) = subscription.recv().await {
             tracing::debug!("Stream Subscription received Message {:?}", message);
             match message {
                 Message::Change(changeset) => {
@@ -67,101 +271,134 @@ pub(crate) async fn stream_changes(
                         continue;
                     }
 
+                    if let Some(cid) = changeset.cid() {
+                        if !permissions.read_column(changeset.table(), cid) {
+                            continue;
+                        }
+                    }
+
                     if changeset.db_version() < db_version {
                         continue;
                     }
 
-                    if changeset.site_id() == &query.site_id {
+                    if query.site_ids.contains(changeset.site_id()) {
                         continue;
                     }
 
                     db_version = changeset.db_version();
 
-                    yield Event::try_from(changeset)?;
+                    yield Message::Change(changeset);
                 },
                 Message::Migration(migration) => {
                     if migration.version() > schema_version {
                         schema_version = migration.version();
-                        yield Event::try_from(migration)?;
+                        yield Message::Migration(migration);
                     }
                 },
+                Message::Rollback(migration) => {
+                    // clients that never got the migration have nothing to undo
+                    if migration.version() <= schema_version {
+                        yield Message::Rollback(migration);
+                    }
+                },
+                Message::Changes(_) | Message::Cursor(_) | Message::Ready(_) | Message::Resync => (),
                 Message::Error(error) => {
                     yield Err(error)?;
                 }
+                Message::Closing => {
+                    yield Message::Closing;
+                    break;
+                }
             }
         }
 
-    }))
+    })
 }
 
 impl Database {
+    /// Starts a read transaction and returns the first migration after `schema_version`,
+    /// the others can be read lazily with [`Database::migrations`] in the same transaction.
+    /// Changes read later on match these migrations even if another one is applied
+    /// meanwhile, the transaction ends when the connection is dropped.
+    fn begin_snapshot(&mut self, schema_version: i64) -> Result<Option<Migration>, CRRError> {
+        let mut authorized = self.disable_authorization();
+
+        authorized.execute_batch("BEGIN")?;
+
+        // the snapshot is taken by the first read rather than by BEGIN
+        authorized.migrations(schema_version).next().transpose()
+    }
+
+    /// Changes are ordered by `db_version` and then by table, primary key and column,
+    /// so a [`Cursor`] of `db_version` and `seq` identifies a unique position.
+    /// Passing `seq` resumes after the first `seq` changes of `self.db_version()`,
+    /// otherwise all changes after `self.db_version()` are returned.
+    /// Pages are cut once they exceed `page_bytes`.
     pub(crate) fn changes<'d, 's>(
         &'d mut self,
-        site_id: &'s Vec<u8>,
+        site_ids: &'s [Vec<u8>],
+        seq: Option<i64>,
+        page_bytes: usize,
     ) -> Result<ChangesIter<impl FnMut() -> Result<(Vec<Changeset>, bool), CRRError> + 'd>, CRRError>
     where
         's: 'd,
     {
-        let readable_tables = self.permissions().readable_tables();
-
-        if readable_tables.is_empty() {
-            return Err(CRRError::Unauthorized(
-                "User is not authorized to read database".to_string(),
-            ));
-        }
+        let (filter, readable_tables) = self.changes_filter(site_ids.len())?;
 
-        let query = match &readable_tables {
-            AllowedTables::All => "
+        let query = format!(
+            "
                 SELECT \"table\", pk, cid, val, col_version, db_version, COALESCE(site_id, crsql_siteid())
                 FROM crsql_changes
-                WHERE db_version > ?
-                AND site_id IS NOT ?
-            ".to_string(),
-            AllowedTables::Some(table_names) => format!(
-                "
-                    SELECT \"table\", pk, cid, val, col_version, db_version, COALESCE(site_id, crsql_siteid())
-                    FROM crsql_changes
-                    WHERE db_version > ?
-                    AND site_id IS NOT ?
-                    AND \"table\" IN ({})
-                ",
-                vec!["?"].repeat(table_names.len()).join(", ")
-            ),
-        };
+                {}
+                ORDER BY db_version, \"table\", pk, cid
+            ",
+            filter
+        );
+
+        let permissions = self.permissions().clone();
+        let mut seq = seq;
 
         Ok(ChangesIter::new(move || {
             let mut buffer = Vec::<Changeset>::new();
             let mut has_next_page = false;
-            let mut db_version = self.db_version();
+
+            let (start_version, skip) = match seq {
+                Some(seq) => (self.db_version(), seq),
+                None => (self.db_version() + 1, 0),
+            };
+
+            let mut cursor = Cursor::new(self.db_version(), seq.unwrap_or_default());
 
             {
                 let mut buffer_size = 0usize;
+                let mut skipped = 0i64;
 
                 let authorized = self.disable_authorization();
                 let mut stmt = authorized.prepare(&query)?;
 
-                let mut params: Vec<Box<dyn ToSql>> = Vec::new();
-
-                params.push(Box::new(authorized.db_version()));
-                params.push(Box::new(site_id));
-
-                if let AllowedTables::Some(table_names) = &readable_tables {
-                    for table_name in table_names {
-                        params.push(Box::new(table_name));
-                    }
-                }
-
+                let params = Self::changes_params(start_version, site_ids, &readable_tables);
                 let mut rows = stmt.query(params_from_iter(params.iter()))?;
 
                 while let Ok(Some(row)) = rows.next() {
                     let changeset: Changeset = row.try_into()?;
 
-                    if buffer_size > CHANGE_BUFFER_SIZE && changeset.db_version() > db_version {
+                    if let Some(cid) = changeset.cid() {
+                        if !permissions.read_column(changeset.table(), cid) {
+                            continue;
+                        }
+                    }
+
+                    if changeset.db_version() == start_version && skipped < skip {
+                        skipped += 1;
+                        continue;
+                    }
+
+                    if buffer_size > page_bytes {
                         has_next_page = true;
                         break;
                     }
 
-                    db_version = changeset.db_version();
+                    cursor.advance(changeset.db_version());
 
                     buffer_size += changeset.size();
 
@@ -169,38 +406,109 @@ impl Database {
                 }
             }
 
-            self.set_db_version(db_version);
+            if !buffer.is_empty() {
+                self.set_db_version(cursor.db_version());
+                seq = Some(cursor.seq());
+            }
 
             Ok((buffer, has_next_page))
         }))
     }
+
+    /// `WHERE` clause for changes from `db_version` on (the first parameter) to tables
+    /// the caller can read, excluding changes from `site_count` site ids.
+    /// Parameters are bound by [`Database::changes_params`].
+    pub(super) fn changes_filter(
+        &mut self,
+        site_count: usize,
+    ) -> Result<(String, AllowedTables), CRRError> {
+        let readable_tables = self.permissions().readable_tables();
+
+        if readable_tables.is_empty() {
+            return Err(CRRError::Unauthorized(
+                "User is not authorized to read database".to_string(),
+            ));
+        }
+
+        let readable_tables = match readable_tables {
+            AllowedTables::All => AllowedTables::All,
+            AllowedTables::Some(_) => {
+                let table_names = self.table_names()?;
+                self.permissions().readable_tables_in(&table_names)
+            }
+        };
+
+        // local changes have a NULL site_id, which NOT IN alone would filter out,
+        // the live changes read them as crsql_siteid() so both are excluded alike
+        let mut filter = format!(
+            "WHERE db_version >= ? AND COALESCE(site_id, crsql_siteid()) NOT IN ({})",
+            vec!["?"].repeat(site_count).join(", ")
+        );
+
+        if let AllowedTables::Some(table_names) = &readable_tables {
+            filter.push_str(&format!(
+                " AND \"table\" IN ({})",
+                vec!["?"].repeat(table_names.len()).join(", ")
+            ));
+        }
+
+        Ok((filter, readable_tables))
+    }
+
+    pub(super) fn changes_params<'p>(
+        db_version: i64,
+        site_ids: &'p [Vec<u8>],
+        readable_tables: &'p AllowedTables,
+    ) -> Vec<Box<dyn ToSql + 'p>> {
+        let mut params: Vec<Box<dyn ToSql + 'p>> = Vec::new();
+
+        params.push(Box::new(db_version));
+
+        for site_id in site_ids {
+            params.push(Box::new(site_id));
+        }
+
+        if let AllowedTables::Some(table_names) = readable_tables {
+            for table_name in table_names {
+                params.push(Box::new(table_name));
+            }
+        }
+
+        params
+    }
 }
 
 #[cfg(test)]
 mod tests {
 
-    use std::collections::HashMap;
+    use std::{collections::HashMap, io::Write, sync::Arc, time::Duration};
 
     use axum::{
-        body::{BoxBody, HttpBody},
+        body::{Body, BoxBody, HttpBody},
         extract::{Path, Query, State},
+        http::{
+            header::{ACCEPT_ENCODING, AUTHORIZATION, CONTENT_ENCODING},
+            Request, StatusCode,
+        },
         response::{IntoResponse, Response},
         Json,
     };
+    use flate2::write::GzDecoder;
+    use tower::ServiceExt;
     use tracing_test::traced_test;
 
     use crate::{
         app_state::{AppEnv, AppState},
-        auth::{DatabasePermissions, PartialPermissions},
+        auth::{AuthDatabase, DatabasePermissions, PartialPermissions},
         database::{
-            changes::{Changeset, Migration},
+            changes::{post::ApplyMode, Changeset, Cursor, Encoding, Migration, Ready},
             migrate::{post_migrate, tests::setup_foo, MigratePostData},
             Database, Value,
         },
         error::CRRError,
     };
 
-    use super::stream_changes;
+    use super::{stream_changes, subscribe_changes};
 
     const SITE_ID: [u8; 16] = [
         113, 203, 3, 166, 76, 47, 79, 47, 178, 78, 194, 120, 89, 221, 198, 42,
@@ -217,7 +525,7 @@ mod tests {
             .unwrap();
 
         let changes = db
-            .changes(&Vec::from(SITE_ID))
+            .changes(&[Vec::from(SITE_ID)], None, env.change_buffer_bytes())
             .unwrap()
             .collect::<Result<Vec<Changeset>, CRRError>>()
             .unwrap();
@@ -232,12 +540,15 @@ mod tests {
         let env = AppEnv::test_env();
 
         env.test_db()
-            .apply_migration(vec![
-                "CREATE TABLE \"foo\" (val TEXT PRIMARY KEY)".to_string(),
-                "CREATE TABLE \"bar\" (val TEXT PRIMARY KEY)".to_string(),
-                "INSERT INTO foo (val) VALUES ('a')".to_string(),
-                "INSERT INTO bar (val) VALUES ('b')".to_string(),
-            ])
+            .apply_migration(
+                vec![
+                    "CREATE TABLE \"foo\" (val TEXT PRIMARY KEY)".to_string(),
+                    "CREATE TABLE \"bar\" (val TEXT PRIMARY KEY)".to_string(),
+                    "INSERT INTO foo (val) VALUES ('a')".to_string(),
+                    "INSERT INTO bar (val) VALUES ('b')".to_string(),
+                ],
+                Vec::new(),
+            )
             .unwrap();
 
         {
@@ -257,7 +568,7 @@ mod tests {
             let mut db =
                 Database::open(&env, AppEnv::TEST_DB_NAME.to_owned(), permissions).unwrap();
 
-            db.changes(&Vec::from(SITE_ID))
+            db.changes(&[Vec::from(SITE_ID)], None, env.change_buffer_bytes())
                 .and_then(|changes| changes.collect::<Result<Vec<Changeset>, CRRError>>())
         };
 
@@ -287,6 +598,149 @@ mod tests {
         assert_eq!(changes.get(1).unwrap().pk(), &Value::Text("'a'".to_owned()));
     }
 
+    #[test]
+    fn with_permissions_compound_pk() {
+        let env = AppEnv::test_env();
+
+        env.test_db()
+            .apply_migration(
+                vec![
+                    "CREATE TABLE \"keys\" (a INTEGER NOT NULL, b TEXT NOT NULL, PRIMARY KEY (a, b))"
+                        .to_string(),
+                ],
+                Vec::new(),
+            )
+            .unwrap();
+        env.test_db()
+            .execute("INSERT INTO keys (a, b) VALUES (2, 'z')", [])
+            .unwrap();
+
+        let mut db = Database::open(
+            &env,
+            AppEnv::TEST_DB_NAME.to_owned(),
+            DatabasePermissions::Partial {
+                database: PartialPermissions {
+                    read: true,
+                    insert: false,
+                    update: false,
+                    delete: false,
+                },
+                tables: HashMap::new(),
+            },
+        )
+        .unwrap();
+
+        let changes = db
+            .changes(&[Vec::from(SITE_ID)], None, env.change_buffer_bytes())
+            .and_then(|changes| changes.collect::<Result<Vec<Changeset>, CRRError>>())
+            .expect("Failed to retrieve changes with database read permission");
+
+        // rows of pk-only tables are a single sentinel change, also for compound keys
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].table(), "keys");
+        assert_eq!(changes[0].cid(), Some("__crsql_pko"));
+        assert_eq!(changes[0].val(), &Value::Null);
+        assert_eq!(changes[0].pk(), &Value::Text("2|'z'".to_owned()));
+    }
+
+    #[test]
+    fn with_denied_column() {
+        let env = AppEnv::test_env();
+
+        env.test_db()
+            .apply_migration(
+                vec![
+                    "CREATE TABLE \"foo\" (id INTEGER PRIMARY KEY, bar TEXT, secret TEXT)"
+                        .to_string(),
+                ],
+                Vec::new(),
+            )
+            .unwrap();
+
+        env.test_db()
+            .execute("INSERT INTO foo (bar, secret) VALUES ('a', 'b')", [])
+            .unwrap();
+
+        let mut permissions = DatabasePermissions::Partial {
+            database: PartialPermissions {
+                read: true,
+                insert: false,
+                update: false,
+                delete: false,
+            },
+            tables: HashMap::new(),
+        };
+        permissions.deny_column("foo".to_owned(), "secret".to_owned());
+
+        let mut db = Database::open(&env, AppEnv::TEST_DB_NAME.to_owned(), permissions).unwrap();
+
+        let changes = db
+            .changes(&[Vec::from(SITE_ID)], None, env.change_buffer_bytes())
+            .unwrap()
+            .collect::<Result<Vec<Changeset>, CRRError>>()
+            .unwrap();
+
+        assert!(changes
+            .iter()
+            .any(|changeset| changeset.cid() == Some("bar")));
+        assert!(!changes
+            .iter()
+            .any(|changeset| changeset.cid() == Some("secret")));
+    }
+
+    #[test]
+    fn resume_within_db_version() {
+        let env = AppEnv::test_env();
+        setup_foo(&env);
+
+        // three changes in a single db_version, each exceeding the change buffer
+        env.test_db()
+            .execute(
+                "
+                    INSERT INTO foo (bar)
+                    SELECT hex(zeroblob(600000)) FROM (SELECT 1 UNION ALL SELECT 2 UNION ALL SELECT 3)
+                ",
+                [],
+            )
+            .unwrap();
+
+        let all = env
+            .test_db()
+            .changes(&[Vec::from(SITE_ID)], None, env.change_buffer_bytes())
+            .unwrap()
+            .collect::<Result<Vec<Changeset>, CRRError>>()
+            .unwrap();
+
+        assert_eq!(all.len(), 3);
+        assert!(all.iter().all(|changeset| changeset.db_version() == 1));
+
+        let first_page = env
+            .test_db()
+            .changes(&[Vec::from(SITE_ID)], None, env.change_buffer_bytes())
+            .unwrap()
+            .next_page()
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(first_page, all[..1]);
+
+        let mut resumed = Database::open_readonly(
+            &env,
+            AppEnv::TEST_DB_NAME.to_owned(),
+            1,
+            DatabasePermissions::Full,
+        )
+        .unwrap();
+
+        let rest = resumed
+            .changes(&[Vec::from(SITE_ID)], Some(1), env.change_buffer_bytes())
+            .unwrap()
+            .collect::<Result<Vec<Changeset>, CRRError>>()
+            .unwrap();
+
+        assert_eq!(rest, all[1..]);
+    }
+
     #[tokio::test]
     async fn stream_simple_changes() {
         let state = AppState::test_state();
@@ -302,12 +756,19 @@ mod tests {
         let res: Response = stream_changes(
             Path(AppEnv::TEST_DB_NAME.to_owned()),
             Query(super::StreamChangesQuery {
-                site_id: Vec::new(),
+                site_ids: Vec::new(),
                 db_version: 0,
                 schema_version: 1,
+                seq: None,
+                since_wallclock: None,
+                tables: None,
+                schema_only: false,
+                page_bytes: None,
+                batch_size: None,
             }),
             State(state.clone()),
             DatabasePermissions::Full,
+            Encoding::Json,
         )
         .await
         .expect("Failed to start stream")
@@ -319,6 +780,9 @@ mod tests {
         assert_eq!(changeset.table(), "foo");
         assert_eq!(changeset.cid(), Some("bar"));
 
+        assert_eq!(read_cursor_event(&mut body).await, Cursor::new(1, 1));
+        assert_eq!(read_ready_event(&mut body).await, Ready::new(1, 1));
+
         assert!(!body.is_end_stream());
 
         state
@@ -337,50 +801,412 @@ mod tests {
         );
     }
 
-    async fn read_change_event(body: &mut BoxBody) -> Changeset {
-        let event_data = body
-            .data()
+    #[tokio::test]
+    async fn stream_compressed_changes() {
+        let state = AppState::test_state();
+
+        setup_foo(state.env());
+
+        state
+            .env()
+            .test_db()
+            .execute("INSERT INTO foo (bar) VALUES ('foo')", [])
+            .unwrap();
+
+        let expected = state
+            .env()
+            .test_db()
+            .changes(&[], None, state.env().change_buffer_bytes())
+            .unwrap()
+            .collect::<Result<Vec<Changeset>, CRRError>>()
+            .unwrap();
+
+        let token = AuthDatabase::create_test_token(state.env());
+
+        let res = crate::router(state.env())
+            .with_state(state.clone())
+            .oneshot(
+                Request::builder()
+                    .uri(format!(
+                        "/db/{}/changes?site_id=&db_version=0&schema_version=1",
+                        AppEnv::TEST_DB_NAME
+                    ))
+                    .header(AUTHORIZATION, format!("Bearer {}", token))
+                    .header(ACCEPT_ENCODING, "gzip")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
             .await
-            .expect("Stream is empty")
-            .expect("Received Error");
+            .unwrap();
 
-        assert!(event_data.starts_with("event:change\ndata:".as_bytes()));
-        let data = event_data.slice(18..);
-        serde_json::from_slice(&data).expect("Failed to parse response data")
-    }
+        assert_eq!(res.headers()[CONTENT_ENCODING], "gzip");
 
-    async fn read_migration_event(body: &mut BoxBody) -> Migration {
-        let event_data = body
-            .data()
+        let mut body = res.into_body();
+        let mut decoder = GzDecoder::new(Vec::new());
+
+        let event = read_gzip_event(&mut body, &mut decoder).await;
+        let changeset: Changeset =
+            serde_json::from_str(event.strip_prefix("event:change\ndata:").unwrap()).unwrap();
+
+        assert_eq!(vec![changeset], expected);
+
+        assert!(read_gzip_event(&mut body, &mut decoder)
             .await
-            .expect("Stream is empty")
-            .expect("Received Error");
+            .starts_with("event:cursor\n"));
+        assert!(read_gzip_event(&mut body, &mut decoder)
+            .await
+            .starts_with("event:ready\n"));
 
-        assert!(event_data.starts_with("event:migration\ndata:".as_bytes()));
-        let data = event_data.slice(21..);
-        serde_json::from_slice(&data).expect("Failed to parse response data")
+        state
+            .env()
+            .test_db()
+            .execute("INSERT INTO foo (bar) VALUES ('bar')", [])
+            .unwrap();
+
+        // a live event is too small to fill a deflate block, it only arrives if flushed
+        let event = read_gzip_event(&mut body, &mut decoder).await;
+        let changeset: Changeset =
+            serde_json::from_str(event.strip_prefix("event:change\ndata:").unwrap()).unwrap();
+
+        assert_eq!(changeset.val(), &Value::Text("'bar'".to_owned()));
+    }
+
+    /// decodes chunks until a whole event is available, keep-alive comments are skipped
+    async fn read_gzip_event(body: &mut BoxBody, decoder: &mut GzDecoder<Vec<u8>>) -> String {
+        loop {
+            if let Some(end) = decoder
+                .get_ref()
+                .windows(2)
+                .position(|window| window == b"\n\n")
+            {
+                let event = decoder.get_mut().drain(..end + 2).collect::<Vec<u8>>();
+                let event = String::from_utf8(event).unwrap();
+
+                match event.starts_with(':') {
+                    true => continue,
+                    false => return event.trim_end().to_owned(),
+                }
+            }
+
+            let chunk = tokio::time::timeout(Duration::from_secs(5), body.data())
+                .await
+                .expect("Event was not flushed")
+                .expect("Stream is empty")
+                .expect("Received Error");
+
+            decoder.write_all(&chunk).unwrap();
+            decoder.flush().unwrap();
+        }
     }
 
-    #[traced_test]
     #[tokio::test]
-    async fn receive_streamed_migration() {
+    async fn stream_public_database() {
         let state = AppState::test_state();
 
-        let res: Response = stream_changes(
-            Path(AppEnv::TEST_DB_NAME.to_owned()),
-            Query(super::StreamChangesQuery {
-                site_id: Vec::new(),
-                db_version: 0,
-                schema_version: 0,
-            }),
-            State(state.clone()),
-            DatabasePermissions::Create,
-        )
-        .await
-        .expect("Failed to start stream")
-        .into_response();
+        setup_foo(state.env());
 
-        let mut body = res.into_body();
+        let auth = AuthDatabase::open(state.env().clone()).unwrap();
+        let owner = AuthDatabase::create_test_token(state.env());
+        auth.get_permissions(&owner, AppEnv::TEST_DB_NAME).unwrap();
+
+        let request = || {
+            Request::builder()
+                .uri(format!(
+                    "/db/{}/changes?site_id=&db_version=0&schema_version=1",
+                    AppEnv::TEST_DB_NAME
+                ))
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let res = crate::router(state.env())
+            .with_state(state.clone())
+            .oneshot(request())
+            .await
+            .unwrap();
+
+        assert_eq!(
+            res.status(),
+            StatusCode::UNAUTHORIZED,
+            "Private databases require a token"
+        );
+
+        auth.set_public_read(AppEnv::TEST_DB_NAME, true).unwrap();
+
+        let res = crate::router(state.env())
+            .with_state(state.clone())
+            .oneshot(request())
+            .await
+            .unwrap();
+
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let mut body = res.into_body();
+        assert_eq!(read_ready_event(&mut body).await, Ready::new(0, 1));
+    }
+
+    #[tokio::test]
+    async fn exclude_own_changes() {
+        use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+
+        let state = AppState::test_state();
+        setup_foo(state.env());
+
+        let client = AppEnv::test_env();
+        setup_foo(&client);
+
+        let client_site_id: Vec<u8> = client
+            .test_db()
+            .query_row("SELECT crsql_siteid()", [], |row| row.get(0))
+            .unwrap();
+
+        let push_from_client = |id: i64, bar: &str| {
+            let mut db = client.test_db();
+            db.execute(
+                "INSERT INTO foo (id, bar) VALUES (?, ?)",
+                rusqlite::params![id, bar],
+            )
+            .unwrap();
+
+            let changes = db
+                .all_changes()
+                .collect::<Result<Vec<Changeset>, CRRError>>()
+                .unwrap();
+            state
+                .env()
+                .test_db()
+                .apply_changes(changes, ApplyMode::Strict)
+                .unwrap();
+        };
+        let insert_on_server = |id: i64, bar: &str| {
+            state
+                .env()
+                .test_db()
+                .execute(
+                    "INSERT INTO foo (id, bar) VALUES (?, ?)",
+                    rusqlite::params![id, bar],
+                )
+                .unwrap();
+        };
+
+        push_from_client(1, "client");
+        insert_on_server(2, "server");
+
+        let res: Response = stream_changes(
+            Path(AppEnv::TEST_DB_NAME.to_owned()),
+            Query::try_from_uri(
+                &format!(
+                    "/?site_id={}&db_version=0&schema_version=1",
+                    URL_SAFE_NO_PAD.encode(&client_site_id)
+                )
+                .parse()
+                .unwrap(),
+            )
+            .unwrap(),
+            State(state.clone()),
+            DatabasePermissions::Full,
+            Encoding::Json,
+        )
+        .await
+        .expect("Failed to start stream")
+        .into_response();
+
+        let mut body = res.into_body();
+
+        assert_eq!(
+            read_change_event(&mut body).await.val(),
+            &Value::Text("'server'".to_owned()),
+            "Backfill skips the client's own change"
+        );
+        read_cursor_event(&mut body).await;
+        read_ready_event(&mut body).await;
+
+        push_from_client(3, "client again");
+        insert_on_server(4, "server again");
+
+        assert_eq!(
+            read_change_event(&mut body).await.val(),
+            &Value::Text("'server again'".to_owned()),
+            "Live changes skip the client's own change"
+        );
+    }
+
+    #[tokio::test]
+    async fn paginate_backfill() {
+        let state = AppState::test_state();
+        setup_foo(state.env());
+
+        for bar in ["a", "b", "c"] {
+            state
+                .env()
+                .test_db()
+                .execute("INSERT INTO foo (bar) VALUES (?)", [bar])
+                .unwrap();
+        }
+
+        let res: Response = stream_changes(
+            Path(AppEnv::TEST_DB_NAME.to_owned()),
+            Query::try_from_uri(
+                &"/?site_id=&db_version=0&schema_version=1&page_bytes=1"
+                    .parse()
+                    .unwrap(),
+            )
+            .unwrap(),
+            State(state.clone()),
+            DatabasePermissions::Full,
+            Encoding::Json,
+        )
+        .await
+        .expect("Failed to start stream")
+        .into_response();
+
+        let mut body = res.into_body();
+
+        // every change is a page of its own
+        for db_version in 1..=3 {
+            assert_eq!(read_change_event(&mut body).await.db_version(), db_version);
+            assert_eq!(
+                read_cursor_event(&mut body).await,
+                Cursor::new(db_version, 1)
+            );
+        }
+
+        assert_eq!(read_ready_event(&mut body).await, Ready::new(3, 1));
+    }
+
+    #[tokio::test]
+    async fn batch_backfill() {
+        let state = AppState::test_state();
+        setup_foo(state.env());
+
+        for bar in ["a", "b", "c", "d", "e"] {
+            state
+                .env()
+                .test_db()
+                .execute("INSERT INTO foo (bar) VALUES (?)", [bar])
+                .unwrap();
+        }
+
+        let res: Response = stream_changes(
+            Path(AppEnv::TEST_DB_NAME.to_owned()),
+            Query::try_from_uri(
+                &"/?site_id=&db_version=0&schema_version=1&batch_size=5"
+                    .parse()
+                    .unwrap(),
+            )
+            .unwrap(),
+            State(state.clone()),
+            DatabasePermissions::Full,
+            Encoding::Json,
+        )
+        .await
+        .expect("Failed to start stream")
+        .into_response();
+
+        let mut body = res.into_body();
+
+        let batch = read_changes_batch(&mut body).await;
+
+        assert_eq!(
+            batch
+                .iter()
+                .map(|changeset| changeset.db_version())
+                .collect::<Vec<_>>(),
+            vec![1, 2, 3, 4, 5]
+        );
+        assert_eq!(read_cursor_event(&mut body).await, Cursor::new(5, 1));
+        assert_eq!(read_ready_event(&mut body).await, Ready::new(5, 1));
+    }
+
+    async fn read_change_event(body: &mut BoxBody) -> Changeset {
+        let event_data = body
+            .data()
+            .await
+            .expect("Stream is empty")
+            .expect("Received Error");
+
+        assert!(event_data.starts_with("event:change\ndata:".as_bytes()));
+        let data = event_data.slice(18..);
+        serde_json::from_slice(&data).expect("Failed to parse response data")
+    }
+
+    async fn read_changes_batch(body: &mut BoxBody) -> Vec<Changeset> {
+        let event_data = body
+            .data()
+            .await
+            .expect("Stream is empty")
+            .expect("Received Error");
+
+        assert!(event_data.starts_with("event:changes\ndata:".as_bytes()));
+        let data = event_data.slice(19..);
+        serde_json::from_slice(&data).expect("Failed to parse response data")
+    }
+
+    async fn read_cursor_event(body: &mut BoxBody) -> Cursor {
+        let event_data = body
+            .data()
+            .await
+            .expect("Stream is empty")
+            .expect("Received Error");
+
+        assert!(event_data.starts_with("event:cursor\ndata:".as_bytes()));
+        let data = event_data.slice(18..);
+        serde_json::from_slice(&data).expect("Failed to parse response data")
+    }
+
+    async fn read_ready_event(body: &mut BoxBody) -> Ready {
+        let event_data = body
+            .data()
+            .await
+            .expect("Stream is empty")
+            .expect("Received Error");
+
+        assert!(event_data.starts_with("event:ready\ndata:".as_bytes()));
+        let data = event_data.slice(17..);
+        serde_json::from_slice(&data).expect("Failed to parse response data")
+    }
+
+    async fn read_migration_event(body: &mut BoxBody) -> Migration {
+        let event_data = body
+            .data()
+            .await
+            .expect("Stream is empty")
+            .expect("Received Error");
+
+        assert!(event_data.starts_with("event:migration\ndata:".as_bytes()));
+        let data = event_data.slice(21..);
+        serde_json::from_slice(&data).expect("Failed to parse response data")
+    }
+
+    #[traced_test]
+    #[tokio::test]
+    async fn receive_streamed_migration() {
+        let state = AppState::test_state();
+
+        let res: Response = stream_changes(
+            Path(AppEnv::TEST_DB_NAME.to_owned()),
+            Query(super::StreamChangesQuery {
+                site_ids: Vec::new(),
+                db_version: 0,
+                schema_version: 0,
+                seq: None,
+                since_wallclock: None,
+                tables: None,
+                schema_only: false,
+                page_bytes: None,
+                batch_size: None,
+            }),
+            State(state.clone()),
+            DatabasePermissions::Create,
+            Encoding::Json,
+        )
+        .await
+        .expect("Failed to start stream")
+        .into_response();
+
+        let mut body = res.into_body();
+
+        assert_eq!(read_ready_event(&mut body).await, Ready::new(0, 0));
 
         post_migrate(
             Path(AppEnv::TEST_DB_NAME.to_owned()),
@@ -388,6 +1214,8 @@ mod tests {
             State(state.clone()),
             Json(MigratePostData {
                 queries: vec!["CREATE TABLE foo (bar text)".to_owned()],
+                down: Vec::new(),
+                dry_run: false,
             }),
         )
         .await
@@ -395,4 +1223,777 @@ mod tests {
 
         assert_eq!(read_migration_event(&mut body).await.version(), 1);
     }
+
+    #[tokio::test]
+    async fn signal_ready_after_backfill() {
+        let state = AppState::test_state();
+
+        setup_foo(state.env());
+
+        state
+            .env()
+            .test_db()
+            .execute("INSERT INTO foo (bar) VALUES ('foo')", [])
+            .unwrap();
+        state
+            .env()
+            .test_db()
+            .execute("INSERT INTO foo (bar) VALUES ('bar')", [])
+            .unwrap();
+
+        let res: Response = stream_changes(
+            Path(AppEnv::TEST_DB_NAME.to_owned()),
+            Query(super::StreamChangesQuery {
+                site_ids: Vec::new(),
+                db_version: 0,
+                schema_version: 0,
+                seq: None,
+                since_wallclock: None,
+                tables: None,
+                schema_only: false,
+                page_bytes: None,
+                batch_size: None,
+            }),
+            State(state.clone()),
+            DatabasePermissions::Full,
+            Encoding::Json,
+        )
+        .await
+        .expect("Failed to start stream")
+        .into_response();
+
+        let mut body = res.into_body();
+
+        let ready = loop {
+            let event_data = body
+                .data()
+                .await
+                .expect("Stream is empty")
+                .expect("Received Error");
+
+            if event_data.starts_with("event:ready\ndata:".as_bytes()) {
+                break serde_json::from_slice::<Ready>(&event_data.slice(17..))
+                    .expect("Failed to parse response data");
+            }
+        };
+
+        assert_eq!(ready, Ready::new(2, 1));
+    }
+
+    #[tokio::test]
+    async fn resume_from_wallclock() {
+        let state = AppState::test_state();
+
+        setup_foo(state.env());
+
+        let db = state.env().test_db();
+
+        db.execute("INSERT INTO foo (bar) VALUES ('a')", [])
+            .unwrap();
+        db.write_checkpoint(1_000, 1).unwrap();
+        db.execute("INSERT INTO foo (bar) VALUES ('b')", [])
+            .unwrap();
+        db.write_checkpoint(2_000, 2).unwrap();
+        db.execute("INSERT INTO foo (bar) VALUES ('c')", [])
+            .unwrap();
+        drop(db);
+
+        let res: Response = stream_changes(
+            Path(AppEnv::TEST_DB_NAME.to_owned()),
+            Query(super::StreamChangesQuery {
+                site_ids: Vec::new(),
+                db_version: 0,
+                schema_version: 1,
+                seq: None,
+                since_wallclock: Some(1_500),
+                tables: None,
+                schema_only: false,
+                page_bytes: None,
+                batch_size: None,
+            }),
+            State(state.clone()),
+            DatabasePermissions::Full,
+            Encoding::Json,
+        )
+        .await
+        .expect("Failed to start stream")
+        .into_response();
+
+        let mut body = res.into_body();
+
+        assert_eq!(
+            read_change_event(&mut body).await.val(),
+            &Value::Text("'b'".to_owned())
+        );
+        assert_eq!(
+            read_change_event(&mut body).await.val(),
+            &Value::Text("'c'".to_owned())
+        );
+        assert_eq!(read_cursor_event(&mut body).await, Cursor::new(3, 1));
+        assert_eq!(read_ready_event(&mut body).await, Ready::new(3, 1));
+
+        let mut db = state.env().test_db();
+        assert_eq!(db.db_version_at(500).unwrap(), 0);
+        assert_eq!(db.db_version_at(2_000).unwrap(), 2);
+    }
+
+    #[tokio::test]
+    async fn resync_lagged_subscription() {
+        let state = AppState::test_state();
+
+        setup_foo(state.env());
+
+        state
+            .env()
+            .test_db()
+            .execute("INSERT INTO foo (bar) VALUES ('a')", [])
+            .unwrap();
+
+        let res: Response = stream_changes(
+            Path(AppEnv::TEST_DB_NAME.to_owned()),
+            Query(super::StreamChangesQuery {
+                site_ids: Vec::new(),
+                db_version: 0,
+                schema_version: 1,
+                seq: None,
+                since_wallclock: None,
+                tables: None,
+                schema_only: false,
+                page_bytes: None,
+                batch_size: None,
+            }),
+            State(state.clone()),
+            DatabasePermissions::Full,
+            Encoding::Json,
+        )
+        .await
+        .expect("Failed to start stream")
+        .into_response();
+
+        let mut body = res.into_body();
+
+        read_change_event(&mut body).await;
+        read_cursor_event(&mut body).await;
+        assert_eq!(read_ready_event(&mut body).await, Ready::new(1, 1));
+
+        state
+            .env()
+            .test_db()
+            .execute("INSERT INTO foo (bar) VALUES ('b')", [])
+            .unwrap();
+
+        // already applied migrations are ignored by the stream but overflow the channel
+        for _ in 0..(state.env().change_channel_capacity() + 8) {
+            state
+                .change_manager()
+                .publish_migration(
+                    AppEnv::TEST_DB_NAME,
+                    Migration::new(1, "SELECT 1".to_owned()),
+                )
+                .await;
+        }
+
+        let event_data = body
+            .data()
+            .await
+            .expect("Stream is empty")
+            .expect("Received Error");
+        assert!(event_data.starts_with("event:resync\n".as_bytes()));
+
+        assert_eq!(
+            read_change_event(&mut body).await.val(),
+            &Value::Text("'b'".to_owned())
+        );
+        assert_eq!(read_cursor_event(&mut body).await, Cursor::new(2, 1));
+        assert_eq!(read_ready_event(&mut body).await, Ready::new(2, 1));
+
+        state
+            .env()
+            .test_db()
+            .execute("INSERT INTO foo (bar) VALUES ('c')", [])
+            .unwrap();
+
+        assert_eq!(
+            read_change_event(&mut body).await.val(),
+            &Value::Text("'c'".to_owned())
+        );
+    }
+
+    #[tokio::test]
+    async fn stream_selected_tables() {
+        let state = AppState::test_state();
+
+        state
+            .env()
+            .test_db()
+            .apply_migration(
+                vec![
+                    "CREATE TABLE \"foo\" (val TEXT PRIMARY KEY)".to_string(),
+                    "CREATE TABLE \"bar\" (val TEXT PRIMARY KEY)".to_string(),
+                ],
+                Vec::new(),
+            )
+            .unwrap();
+
+        state
+            .env()
+            .test_db()
+            .execute_batch(
+                "INSERT INTO foo (val) VALUES ('a'); INSERT INTO bar (val) VALUES ('b');",
+            )
+            .unwrap();
+
+        let res: Response = stream_changes(
+            Path(AppEnv::TEST_DB_NAME.to_owned()),
+            Query::try_from_uri(
+                &"/?site_id=&db_version=0&schema_version=1&tables=bar,secret"
+                    .parse()
+                    .unwrap(),
+            )
+            .unwrap(),
+            State(state.clone()),
+            DatabasePermissions::Full,
+            Encoding::Json,
+        )
+        .await
+        .expect("Failed to start stream")
+        .into_response();
+
+        let mut body = res.into_body();
+
+        let changeset = read_change_event(&mut body).await;
+        assert_eq!(changeset.table(), "bar");
+        assert_eq!(changeset.pk(), &Value::Text("'b'".to_owned()));
+
+        read_cursor_event(&mut body).await;
+        read_ready_event(&mut body).await;
+
+        state
+            .env()
+            .test_db()
+            .execute_batch(
+                "INSERT INTO foo (val) VALUES ('c'); INSERT INTO bar (val) VALUES ('d');",
+            )
+            .unwrap();
+
+        let changeset = read_change_event(&mut body).await;
+        assert_eq!(changeset.table(), "bar");
+        assert_eq!(changeset.pk(), &Value::Text("'d'".to_owned()));
+    }
+
+    #[tokio::test]
+    async fn stream_schema_only() {
+        let state = AppState::test_state();
+
+        setup_foo(state.env());
+
+        state
+            .env()
+            .test_db()
+            .apply_migration(
+                vec!["CREATE TABLE \"bar\" (val TEXT PRIMARY KEY)".to_string()],
+                Vec::new(),
+            )
+            .unwrap();
+
+        state
+            .env()
+            .test_db()
+            .execute("INSERT INTO foo (bar) VALUES ('foo')", [])
+            .unwrap();
+
+        let res: Response = stream_changes(
+            Path(AppEnv::TEST_DB_NAME.to_owned()),
+            Query::try_from_uri(
+                &"/?site_id=&db_version=0&schema_version=0&schema_only=true"
+                    .parse()
+                    .unwrap(),
+            )
+            .unwrap(),
+            State(state.clone()),
+            DatabasePermissions::Full,
+            Encoding::Json,
+        )
+        .await
+        .expect("Failed to start stream")
+        .into_response();
+
+        let mut body = res.into_body();
+
+        assert_eq!(read_migration_event(&mut body).await.version(), 1);
+        assert_eq!(read_migration_event(&mut body).await.version(), 2);
+        assert!(
+            body.data().await.is_none(),
+            "Stream ends without changes or ready event"
+        );
+        assert!(
+            state
+                .change_manager()
+                .metrics()
+                .await
+                .to_prometheus(false)
+                .contains("crr_database_handles 0"),
+            "No watcher task is started"
+        );
+    }
+
+    #[tokio::test]
+    async fn keep_idle_stream_open() {
+        let env = AppEnv::with_sse_keepalive(AppEnv::test_env(), Duration::from_millis(50));
+        let state = AppState::test_state().with_env(env);
+
+        setup_foo(state.env());
+
+        let res: Response = stream_changes(
+            Path(AppEnv::TEST_DB_NAME.to_owned()),
+            Query::try_from_uri(&"/?site_id=&db_version=0&schema_version=1".parse().unwrap())
+                .unwrap(),
+            State(state.clone()),
+            DatabasePermissions::Full,
+            Encoding::Json,
+        )
+        .await
+        .expect("Failed to start stream")
+        .into_response();
+
+        let mut body = res.into_body();
+
+        assert_eq!(read_ready_event(&mut body).await, Ready::new(0, 1));
+
+        let event_data = tokio::time::timeout(Duration::from_secs(5), body.data())
+            .await
+            .expect("No heartbeat on idle stream")
+            .expect("Stream is empty")
+            .expect("Received Error");
+        assert_eq!(&event_data[..], b":\n\n");
+
+        state
+            .env()
+            .test_db()
+            .execute("INSERT INTO foo (bar) VALUES ('foo')", [])
+            .unwrap();
+
+        // heartbeats may still come in before the change
+        let changeset = loop {
+            let event_data = body
+                .data()
+                .await
+                .expect("Stream is empty")
+                .expect("Received Error");
+
+            if &event_data[..] == b":\n\n" {
+                continue;
+            }
+
+            assert!(event_data.starts_with("event:change\ndata:".as_bytes()));
+            break serde_json::from_slice::<Changeset>(&event_data.slice(18..))
+                .expect("Failed to parse response data");
+        };
+        assert_eq!(changeset.val(), &Value::Text("'foo'".to_owned()));
+    }
+
+    #[tokio::test]
+    async fn serialize_migration_and_backfill() {
+        use futures::StreamExt;
+
+        use crate::database::changes::Message;
+
+        let state = AppState::test_state();
+        setup_foo(state.env());
+        state
+            .env()
+            .test_db()
+            .execute("INSERT INTO foo (bar) VALUES ('a')", [])
+            .unwrap();
+
+        let schema_lock = state
+            .change_manager()
+            .schema_lock(AppEnv::TEST_DB_NAME)
+            .await;
+
+        // a running backfill holds up migrations
+        let backfill_guard = Arc::clone(&schema_lock).read_owned().await;
+        let migrate = tokio::spawn(post_migrate(
+            Path(AppEnv::TEST_DB_NAME.to_owned()),
+            DatabasePermissions::Full,
+            State(state.clone()),
+            Json(MigratePostData {
+                queries: vec!["CREATE TABLE \"baz\" (id INTEGER PRIMARY KEY)".to_owned()],
+                down: Vec::new(),
+                dry_run: false,
+            }),
+        ));
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(!migrate.is_finished(), "Migration waits for the backfill");
+
+        // a running migration holds up backfills
+        drop(backfill_guard);
+        migrate.await.unwrap().unwrap();
+
+        let migration_guard = Arc::clone(&schema_lock).write_owned().await;
+
+        let messages = subscribe_changes(
+            &state,
+            AppEnv::TEST_DB_NAME.to_owned(),
+            Query::try_from_uri(&"/?site_id=&db_version=0&schema_version=1".parse().unwrap())
+                .unwrap()
+                .0,
+            DatabasePermissions::Full,
+        )
+        .await
+        .unwrap();
+        futures::pin_mut!(messages);
+
+        assert!(
+            tokio::time::timeout(Duration::from_millis(100), messages.next())
+                .await
+                .is_err(),
+            "Backfill waits for the migration"
+        );
+
+        state
+            .env()
+            .test_db()
+            .apply_migration(
+                vec!["CREATE TABLE \"qux\" (id INTEGER PRIMARY KEY)".to_owned()],
+                Vec::new(),
+            )
+            .unwrap();
+        drop(migration_guard);
+
+        let mut versions = Vec::new();
+        let mut changes = 0;
+        while let Some(message) = messages.next().await {
+            match message.unwrap() {
+                Message::Migration(migration) => versions.push(migration.version()),
+                Message::Change(changeset) => {
+                    assert_eq!(versions, vec![2, 3], "Migrations come first");
+                    assert_eq!(changeset.table(), "foo");
+                    changes += 1;
+                }
+                Message::Ready(_) => break,
+                _ => (),
+            }
+        }
+
+        assert_eq!(versions, vec![2, 3]);
+        assert!(changes > 0);
+    }
+
+    #[tokio::test]
+    async fn dont_block_migrations_on_stalled_backfill() {
+        use futures::StreamExt;
+
+        let state = AppState::test_state();
+        setup_foo(state.env());
+        for bar in ["a", "b", "c"] {
+            state
+                .env()
+                .test_db()
+                .execute("INSERT INTO foo (bar) VALUES (?)", [bar])
+                .unwrap();
+        }
+
+        let messages = subscribe_changes(
+            &state,
+            AppEnv::TEST_DB_NAME.to_owned(),
+            Query::try_from_uri(&"/?site_id=&db_version=0&schema_version=0".parse().unwrap())
+                .unwrap()
+                .0,
+            DatabasePermissions::Full,
+        )
+        .await
+        .unwrap();
+        futures::pin_mut!(messages);
+
+        // the client stops reading in the middle of the backfill
+        messages.next().await.unwrap().unwrap();
+
+        tokio::time::timeout(
+            Duration::from_secs(1),
+            post_migrate(
+                Path(AppEnv::TEST_DB_NAME.to_owned()),
+                DatabasePermissions::Full,
+                State(state.clone()),
+                Json(MigratePostData {
+                    queries: vec!["CREATE TABLE \"baz\" (id INTEGER PRIMARY KEY)".to_owned()],
+                    down: Vec::new(),
+                    dry_run: false,
+                }),
+            ),
+        )
+        .await
+        .expect("Migration waits for the stalled backfill")
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn send_error_event() {
+        let state = AppState::test_state();
+
+        setup_foo(state.env());
+
+        let res: Response = stream_changes(
+            Path(AppEnv::TEST_DB_NAME.to_owned()),
+            Query::try_from_uri(&"/?site_id=&db_version=0&schema_version=1".parse().unwrap())
+                .unwrap(),
+            State(state.clone()),
+            DatabasePermissions::Full,
+            Encoding::Json,
+        )
+        .await
+        .expect("Failed to start stream")
+        .into_response();
+
+        let mut body = res.into_body();
+
+        assert_eq!(read_ready_event(&mut body).await, Ready::new(0, 1));
+
+        // the resync after lagging behind can't reopen the database
+        std::fs::remove_file(Database::file_path(state.env(), AppEnv::TEST_DB_NAME)).unwrap();
+
+        for _ in 0..(state.env().change_channel_capacity() + 8) {
+            state
+                .change_manager()
+                .publish_migration(
+                    AppEnv::TEST_DB_NAME,
+                    Migration::new(1, "SELECT 1".to_owned()),
+                )
+                .await;
+        }
+
+        let event_data = body
+            .data()
+            .await
+            .expect("Stream is empty")
+            .expect("Received Error");
+        assert!(event_data.starts_with("event:resync\n".as_bytes()));
+
+        let event_data = body
+            .data()
+            .await
+            .expect("Stream is empty")
+            .expect("Received Error");
+        assert!(event_data.starts_with("event:error\ndata:".as_bytes()));
+
+        let error: serde_json::Value =
+            serde_json::from_slice(&event_data.slice(17..)).expect("Failed to parse error");
+        assert_eq!(error["code"], "database_not_found");
+
+        assert!(body.data().await.is_none(), "Stream ends after the error");
+    }
+
+    #[tokio::test]
+    async fn stream_missing_database() {
+        let state = AppState::test_state();
+
+        let read = DatabasePermissions::Partial {
+            database: PartialPermissions {
+                read: true,
+                insert: false,
+                update: false,
+                delete: false,
+            },
+            tables: HashMap::new(),
+        };
+
+        let result = stream_changes(
+            Path("missing".to_owned()),
+            Query::try_from_uri(&"/?site_id=&db_version=0&schema_version=0".parse().unwrap())
+                .unwrap(),
+            State(state.clone()),
+            read,
+            Encoding::Json,
+        )
+        .await;
+
+        match result {
+            Err(error) => assert_eq!(error.into_response().status(), StatusCode::NOT_FOUND),
+            Ok(_) => panic!("Streamed a missing database"),
+        }
+
+        stream_changes(
+            Path("created".to_owned()),
+            Query::try_from_uri(&"/?site_id=&db_version=0&schema_version=0".parse().unwrap())
+                .unwrap(),
+            State(state),
+            DatabasePermissions::Create,
+            Encoding::Json,
+        )
+        .await
+        .expect("Creating a database still works");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn create_concurrently() {
+        let state = AppState::test_state();
+
+        let subscribe = || {
+            let state = state.clone();
+
+            tokio::spawn(async move {
+                subscribe_changes(
+                    &state,
+                    "created".to_owned(),
+                    Query::try_from_uri(
+                        &"/?site_id=&db_version=0&schema_version=0".parse().unwrap(),
+                    )
+                    .unwrap()
+                    .0,
+                    DatabasePermissions::Create,
+                )
+                .await
+                .map(|_| ())
+            })
+        };
+
+        let (first, second) = tokio::join!(subscribe(), subscribe());
+
+        first.unwrap().expect("First subscription failed");
+        second.unwrap().expect("Second subscription failed");
+
+        let migrations =
+            Database::open(state.env(), "created".to_owned(), DatabasePermissions::Full)
+                .unwrap()
+                .migrations(0)
+                .count();
+
+        assert_eq!(migrations, 0);
+    }
+
+    #[tokio::test]
+    async fn stream_many_migrations() {
+        let state = AppState::test_state();
+
+        for i in 1..=50 {
+            state
+                .env()
+                .test_db()
+                .apply_migration(
+                    vec![format!("CREATE TABLE \"t{}\" (id INTEGER PRIMARY KEY)", i)],
+                    Vec::new(),
+                )
+                .unwrap();
+        }
+
+        let res: Response = stream_changes(
+            Path(AppEnv::TEST_DB_NAME.to_owned()),
+            Query::try_from_uri(&"/?site_id=&db_version=0&schema_version=0".parse().unwrap())
+                .unwrap(),
+            State(state.clone()),
+            DatabasePermissions::Full,
+            Encoding::Json,
+        )
+        .await
+        .expect("Failed to start stream")
+        .into_response();
+
+        let mut body = res.into_body();
+
+        assert_eq!(read_migration_event(&mut body).await.version(), 1);
+
+        // the rest are still read lazily, which doesn't hold up new migrations
+        tokio::time::timeout(
+            Duration::from_secs(1),
+            post_migrate(
+                Path(AppEnv::TEST_DB_NAME.to_owned()),
+                DatabasePermissions::Full,
+                State(state.clone()),
+                Json(MigratePostData {
+                    queries: vec!["CREATE TABLE \"t51\" (id INTEGER PRIMARY KEY)".to_owned()],
+                    down: Vec::new(),
+                    dry_run: false,
+                }),
+            ),
+        )
+        .await
+        .expect("Migration waits for the stream")
+        .unwrap();
+
+        for version in 2..=50 {
+            assert_eq!(read_migration_event(&mut body).await.version(), version);
+        }
+
+        // applied after the snapshot was taken, so it arrives as a live migration
+        assert_eq!(read_ready_event(&mut body).await, Ready::new(0, 50));
+        assert_eq!(read_migration_event(&mut body).await.version(), 51);
+    }
+
+    #[test]
+    fn reject_repeated_site_ids() {
+        let query: Result<Query<super::StreamChangesQuery>, _> = Query::try_from_uri(
+            &"/?site_id=AAAA&site_id=AAAB&db_version=0&schema_version=0"
+                .parse()
+                .unwrap(),
+        );
+
+        assert!(query
+            .err()
+            .expect("Repeated site_id is rejected")
+            .body_text()
+            .contains("duplicate field `site_id`"));
+    }
+
+    #[test]
+    fn exclude_site_ids() {
+        use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+
+        let migration = vec!["CREATE TABLE \"foo\" (val TEXT PRIMARY KEY)".to_string()];
+
+        let env = AppEnv::test_env();
+        env.test_db()
+            .apply_migration(migration.clone(), Vec::new())
+            .unwrap();
+
+        let mut site_ids = Vec::new();
+
+        for val in ["a", "b", "c"] {
+            let replica = AppEnv::test_env();
+            let mut db = replica.test_db();
+
+            db.apply_migration(migration.clone(), Vec::new()).unwrap();
+            db.execute("INSERT INTO foo (val) VALUES (?)", [val])
+                .unwrap();
+
+            let site_id: Vec<u8> = db
+                .query_row("SELECT crsql_siteid()", [], |row| row.get(0))
+                .unwrap();
+            site_ids.push(site_id);
+
+            let changes = db
+                .all_changes()
+                .collect::<Result<Vec<Changeset>, CRRError>>()
+                .unwrap();
+            env.test_db()
+                .apply_changes(changes, ApplyMode::Strict)
+                .unwrap();
+        }
+
+        let Query(query): Query<super::StreamChangesQuery> = Query::try_from_uri(
+            &format!(
+                "/?site_id={},{}&db_version=0&schema_version=0",
+                URL_SAFE_NO_PAD.encode(&site_ids[0]),
+                URL_SAFE_NO_PAD.encode(&site_ids[1])
+            )
+            .parse()
+            .unwrap(),
+        )
+        .unwrap();
+
+        assert_eq!(query.site_ids, site_ids[..2]);
+
+        let changes = env
+            .test_db()
+            .changes(&query.site_ids, None, env.change_buffer_bytes())
+            .unwrap()
+            .collect::<Result<Vec<Changeset>, CRRError>>()
+            .unwrap();
+
+        assert!(!changes.is_empty());
+        assert!(changes
+            .iter()
+            .all(|changeset| changeset.site_id() == &site_ids[2]
+                && changeset.pk() == &Value::text("c")));
+    }
 }
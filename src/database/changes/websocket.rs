@@ -0,0 +1,189 @@
+use axum::{
+    extract::{
+        ws::{Message as WsMessage, WebSocket, WebSocketUpgrade},
+        Path, Query, State,
+    },
+    response::Response,
+};
+use futures::StreamExt;
+use serde::Serialize;
+
+use crate::{auth::DatabasePermissions, error::CRRError, AppState};
+
+use super::{stream::subscribe_changes, Message, StreamChangesQuery};
+
+pub(crate) async fn stream_changes_ws(
+    Path(db_name): Path<String>,
+    query: Option<Query<StreamChangesQuery>>,
+    State(state): State<AppState>,
+    permissions: DatabasePermissions,
+    ws: WebSocketUpgrade,
+) -> Response {
+    ws.on_upgrade(move |socket| {
+        handle_socket(
+            socket,
+            state,
+            db_name,
+            query.map(|Query(query)| query),
+            permissions,
+        )
+    })
+}
+
+async fn handle_socket(
+    mut socket: WebSocket,
+    state: AppState,
+    db_name: String,
+    query: Option<StreamChangesQuery>,
+    permissions: DatabasePermissions,
+) {
+    // clients that didn't pass the query params send them as their first message
+    let query = match query {
+        Some(query) => query,
+        None => match socket.recv().await {
+            Some(Ok(WsMessage::Text(text))) => match serde_json::from_str(&text) {
+                Ok(query) => query,
+                Err(error) => {
+                    send_error(&mut socket, CRRError::from(error)).await;
+                    return;
+                }
+            },
+            _ => return,
+        },
+    };
+
+    let messages = match subscribe_changes(&state, db_name, query, permissions).await {
+        Ok(messages) => messages,
+        Err(error) => {
+            send_error(&mut socket, error).await;
+            return;
+        }
+    };
+
+    futures::pin_mut!(messages);
+
+    loop {
+        tokio::select! {
+            incoming = socket.recv() => match incoming {
+                Some(Ok(WsMessage::Close(_))) | Some(Err(_)) | None => return,
+                // further client messages are currently ignored
+                Some(Ok(_)) => (),
+            },
+            message = messages.next() => {
+                let message = match message {
+                    Some(Ok(message)) => message,
+                    Some(Err(error)) => Message::Error(error),
+                    None => return,
+                };
+
+                let is_last = matches!(message, Message::Error(_) | Message::Closing);
+
+                let frame = match WsMessage::try_from(message) {
+                    Ok(frame) => frame,
+                    Err(error) => {
+                        send_error(&mut socket, error).await;
+                        return;
+                    }
+                };
+
+                if socket.send(frame).await.is_err() || is_last {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+async fn send_error(socket: &mut WebSocket, error: CRRError) {
+    if let Ok(frame) = WsMessage::try_from(Message::Error(error.into())) {
+        let _ = socket.send(frame).await;
+    }
+    let _ = socket.close().await;
+}
+
+#[derive(Serialize)]
+struct Frame<'m, T: Serialize> {
+    event: &'m str,
+    data: T,
+}
+
+#[derive(Serialize)]
+struct ErrorData<'m> {
+    code: &'m str,
+    message: &'m str,
+}
+
+impl TryFrom<Message> for WsMessage {
+    type Error = CRRError;
+
+    fn try_from(value: Message) -> Result<Self, Self::Error> {
+        let text = match &value {
+            Message::Change(changeset) => serde_json::to_string(&Frame {
+                event: "change",
+                data: changeset,
+            })?,
+            Message::Changes(changes) => serde_json::to_string(&Frame {
+                event: "changes",
+                data: changes,
+            })?,
+            Message::Migration(migration) => serde_json::to_string(&Frame {
+                event: "migration",
+                data: migration,
+            })?,
+            Message::Rollback(migration) => serde_json::to_string(&Frame {
+                event: "rollback",
+                data: migration,
+            })?,
+            Message::Cursor(cursor) => serde_json::to_string(&Frame {
+                event: "cursor",
+                data: cursor,
+            })?,
+            Message::Error(error) => serde_json::to_string(&Frame {
+                event: "error",
+                data: ErrorData {
+                    code: error.code(),
+                    message: error.message(),
+                },
+            })?,
+            Message::Ready(ready) => serde_json::to_string(&Frame {
+                event: "ready",
+                data: ready,
+            })?,
+            Message::Resync => serde_json::to_string(&Frame {
+                event: "resync",
+                data: (),
+            })?,
+            Message::Closing => serde_json::to_string(&Frame {
+                event: "closing",
+                data: (),
+            })?,
+        };
+
+        Ok(WsMessage::Text(text))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::extract::ws::Message as WsMessage;
+
+    use crate::database::changes::{Message, Migration};
+
+    #[test]
+    fn serialize_migration_frame() {
+        let frame = WsMessage::try_from(Message::Migration(Migration::new(
+            1,
+            "CREATE TABLE foo (bar TEXT)".to_owned(),
+        )))
+        .unwrap();
+
+        match frame {
+            WsMessage::Text(text) => {
+                let value: serde_json::Value = serde_json::from_str(&text).unwrap();
+                assert_eq!(value["event"], "migration");
+                assert_eq!(value["data"]["version"], 1);
+            }
+            _ => panic!("Expected a text frame"),
+        }
+    }
+}
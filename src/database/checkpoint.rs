@@ -0,0 +1,116 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use rusqlite::named_params;
+
+use crate::error::CRRError;
+
+use super::Database;
+
+/// checkpoints kept per database, a week's worth at one checkpoint per minute
+const MAX_CHECKPOINTS: i64 = 7 * 24 * 60;
+
+impl Database {
+    /// current time in milliseconds since the unix epoch, the unit of checkpoint wallclocks
+    pub(crate) fn wallclock_now() -> i64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_millis() as i64)
+            .unwrap_or_default()
+    }
+
+    /// Records that all changes up to `db_version` existed at `wallclock`
+    pub(crate) fn write_checkpoint(&self, wallclock: i64, db_version: i64) -> Result<(), CRRError> {
+        self.execute(
+            "INSERT OR REPLACE INTO crr_server_checkpoints (wallclock, db_version) VALUES (:wallclock, :db_version)",
+            named_params! { ":wallclock": wallclock, ":db_version": db_version },
+        )?;
+
+        Ok(())
+    }
+
+    /// Deletes all but the latest [`MAX_CHECKPOINTS`] checkpoints, streams resuming
+    /// from before the oldest one start at the beginning
+    pub(crate) fn prune_checkpoints(&self) -> Result<(), CRRError> {
+        self.execute(
+            "
+                DELETE FROM crr_server_checkpoints
+                WHERE wallclock < (
+                    SELECT wallclock FROM crr_server_checkpoints
+                    ORDER BY wallclock DESC
+                    LIMIT 1 OFFSET :offset
+                )
+            ",
+            named_params! { ":offset": MAX_CHECKPOINTS - 1 },
+        )?;
+
+        Ok(())
+    }
+
+    /// The `db_version` of the latest checkpoint at or before `wallclock`,
+    /// `0` if there is none. Resuming from it never skips a change made after `wallclock`.
+    pub(crate) fn db_version_at(&mut self, wallclock: i64) -> Result<i64, CRRError> {
+        let authorized = self.disable_authorization();
+
+        // read-only connections to databases that were never opened with full access lack the table
+        let has_checkpoints = authorized
+            .prepare("SELECT name FROM sqlite_master WHERE type = 'table' AND name = 'crr_server_checkpoints'")?
+            .exists([])?;
+
+        if !has_checkpoints {
+            return Ok(0);
+        }
+
+        let db_version = authorized
+            .prepare(
+                "
+                SELECT db_version FROM crr_server_checkpoints
+                WHERE wallclock <= :wallclock
+                ORDER BY wallclock DESC
+                LIMIT 1
+            ",
+            )?
+            .query_row(named_params! { ":wallclock": wallclock }, |row| row.get(0))
+            .or_else(|error| match error {
+                rusqlite::Error::QueryReturnedNoRows => Ok(0),
+                error => Err(error),
+            })?;
+
+        Ok(db_version)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{app_state::AppEnv, database::migrate::tests::setup_foo};
+
+    use super::MAX_CHECKPOINTS;
+
+    #[test]
+    fn prune_old_checkpoints() {
+        let env = AppEnv::test_env();
+        setup_foo(&env);
+
+        let mut db = env.test_db();
+
+        db.execute_batch("BEGIN").unwrap();
+        for wallclock in 1..=MAX_CHECKPOINTS + 2 {
+            db.write_checkpoint(wallclock, wallclock).unwrap();
+        }
+        db.execute_batch("COMMIT").unwrap();
+        db.prune_checkpoints().unwrap();
+
+        let count: i64 = db
+            .query_row("SELECT COUNT(*) FROM crr_server_checkpoints", [], |row| {
+                row.get(0)
+            })
+            .unwrap();
+
+        assert_eq!(count, MAX_CHECKPOINTS);
+        assert_eq!(
+            db.db_version_at(2).unwrap(),
+            0,
+            "Oldest checkpoints are gone"
+        );
+        assert_eq!(db.db_version_at(3).unwrap(), 3);
+    }
+}
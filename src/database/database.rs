@@ -1,5 +1,11 @@
-use std::path::PathBuf;
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
 
+use lazy_static::lazy_static;
 use rusqlite::{
     hooks::{AuthAction, AuthContext, Authorization},
     LoadExtensionGuard,
@@ -7,11 +13,38 @@ use rusqlite::{
 
 use crate::{app_state::AppEnv, auth::DatabasePermissions, error::CRRError};
 
+use super::ConnectionPool;
+
+/// how long a new database may take to show up in the replica directory
+const REPLICA_WAIT: Duration = Duration::from_secs(5);
+
+/// how often the replica directory is checked for a new database
+const REPLICA_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Database names become file names in the data directory,
+/// so only `[A-Za-z0-9_-]` is allowed to keep them from escaping it
+pub(crate) fn validate_db_name(name: &str) -> Result<(), CRRError> {
+    if name.is_empty()
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+    {
+        return Err(CRRError::BadRequest(format!(
+            "Invalid database name \"{}\", only letters, digits, _ and - are allowed",
+            name
+        )));
+    }
+
+    Ok(())
+}
+
 pub struct Database {
-    conn: rusqlite::Connection,
+    /// only `None` while the connection is released on drop
+    conn: Option<rusqlite::Connection>,
     name: String,
     db_version: i64,
     permissions: DatabasePermissions,
+    pool: Option<ConnectionPool>,
 }
 
 impl Database {
@@ -19,17 +52,26 @@ impl Database {
         &self.name
     }
 
+    /// the primary copy of a database, all writes go here
     pub(crate) fn file_path(env: &AppEnv, name: &str) -> PathBuf {
         let mut path = PathBuf::from(env.data_dir());
         path.push(format!("{}.sqlite3", name));
         path
     }
 
+    /// the replica if `CRR_REPLICA_DATA_DIR` is set, the primary otherwise
+    pub(crate) fn readonly_file_path(env: &AppEnv, name: &str) -> PathBuf {
+        let mut path = PathBuf::from(env.replica_data_dir().unwrap_or(env.data_dir()));
+        path.push(format!("{}.sqlite3", name));
+        path
+    }
+
     pub(crate) fn permissions(&self) -> &DatabasePermissions {
         return &self.permissions;
     }
 
-    fn load_crsqlite(conn: &rusqlite::Connection) -> Result<(), CRRError> {
+    /// `./extensions/crsqlite-{os}-{arch}.{ext}` for the current platform
+    pub(crate) fn default_crsqlite_path() -> Result<PathBuf, CRRError> {
         let os = match std::env::consts::OS {
             "macos" => "darwin",
             "windows" => "windows",
@@ -39,14 +81,24 @@ impl Database {
 
         let arch = std::env::consts::ARCH;
         let ext = std::env::consts::DLL_EXTENSION;
-        let extension_name = format!(
+
+        Ok(PathBuf::from(format!(
             "./extensions/crsqlite-{os}-{arch}.{ext}",
             os = os,
             arch = arch,
             ext = ext
-        );
+        )))
+    }
 
-        tracing::info!("load extension {}", extension_name);
+    fn load_crsqlite(conn: &rusqlite::Connection, env: &AppEnv) -> Result<(), CRRError> {
+        let extension_name = env.crsqlite_path();
+
+        // sqlite's own error for a missing library is platform specific and hard to read
+        if !extension_name.is_file() {
+            return Err(CRRError::MissingExtension(extension_name.to_owned()));
+        }
+
+        tracing::info!("load extension {}", extension_name.display());
 
         unsafe {
             let _guard = LoadExtensionGuard::new(conn)?;
@@ -56,11 +108,100 @@ impl Database {
         Ok(())
     }
 
+    /// Sets busy timeout and extra pragmas on every connection and switches
+    /// the file to WAL mode once, the journal mode is persisted in the file.
+    fn configure(conn: &rusqlite::Connection, env: &AppEnv, path: &Path) -> Result<(), CRRError> {
+        lazy_static! {
+            static ref WAL_FILES: Mutex<HashSet<PathBuf>> = Mutex::new(HashSet::new());
+        }
+
+        conn.busy_timeout(env.sqlite_busy_timeout())?;
+
+        {
+            let mut wal_files = WAL_FILES
+                .lock()
+                .map_err(|_| CRRError::PoisonedLockError("WAL_FILES"))?;
+
+            if !wal_files.contains(path) {
+                let journal_mode: String =
+                    conn.pragma_update_and_check(None, "journal_mode", "WAL", |row| row.get(0))?;
+
+                // read-only connections can't switch the journal mode, try again on the next open
+                if journal_mode.eq_ignore_ascii_case("wal") {
+                    wal_files.insert(path.to_owned());
+                }
+            }
+        }
+
+        for pragma in env.sqlite_pragmas() {
+            conn.execute_batch(&format!("PRAGMA {};", pragma))?;
+        }
+
+        Ok(())
+    }
+
     fn init_migrations(conn: &rusqlite::Connection) -> Result<(), CRRError> {
         conn.execute(
-            "CREATE TABLE IF NOT EXISTS crr_server_migrations (version INTEGER PRIMARY KEY, sql TEXT NOT NULL)",
+            "CREATE TABLE IF NOT EXISTS crr_server_migrations (version INTEGER PRIMARY KEY AUTOINCREMENT, sql TEXT NOT NULL, down_sql TEXT)",
+            [],
+        )?;
+
+        // databases created before rollback support lack the down_sql column
+        let has_down_sql = conn
+            .prepare("SELECT name FROM pragma_table_info('crr_server_migrations') WHERE name = 'down_sql'")?
+            .exists([])?;
+
+        if !has_down_sql {
+            conn.execute(
+                "ALTER TABLE crr_server_migrations ADD COLUMN down_sql TEXT",
+                [],
+            )?;
+        }
+
+        // without AUTOINCREMENT a rolled back version would be handed out again
+        // and clients that already saw it would skip the next migration
+        let has_autoincrement = conn
+            .prepare("SELECT name FROM sqlite_master WHERE type = 'table' AND name = 'crr_server_migrations' AND sql LIKE '%AUTOINCREMENT%'")?
+            .exists([])?;
+
+        if !has_autoincrement {
+            conn.execute_batch(
+                "
+                SAVEPOINT crr_server_migrations_autoincrement;
+                ALTER TABLE crr_server_migrations RENAME TO crr_server_migrations_old;
+                CREATE TABLE crr_server_migrations (version INTEGER PRIMARY KEY AUTOINCREMENT, sql TEXT NOT NULL, down_sql TEXT);
+                INSERT INTO crr_server_migrations (version, sql, down_sql)
+                    SELECT version, sql, down_sql FROM crr_server_migrations_old;
+                DROP TABLE crr_server_migrations_old;
+                RELEASE crr_server_migrations_autoincrement;
+                ",
+            )?;
+        }
+
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS crr_server_checkpoints (wallclock INTEGER PRIMARY KEY, db_version INTEGER NOT NULL)",
             [],
         )?;
+
+        Ok(())
+    }
+
+    /// Owners bring the bookkeeping tables of databases created by older versions
+    /// up to date, which only has to happen on the first open of each file.
+    fn upgrade_bookkeeping(conn: &rusqlite::Connection, path: &Path) -> Result<(), CRRError> {
+        lazy_static! {
+            static ref UPGRADED_FILES: Mutex<HashSet<PathBuf>> = Mutex::new(HashSet::new());
+        }
+
+        let mut upgraded_files = UPGRADED_FILES
+            .lock()
+            .map_err(|_| CRRError::PoisonedLockError("UPGRADED_FILES"))?;
+
+        if !upgraded_files.contains(path) {
+            Self::init_migrations(conn)?;
+            upgraded_files.insert(path.to_owned());
+        }
+
         Ok(())
     }
 
@@ -78,11 +219,27 @@ impl Database {
         } else {
             Some(move |context: AuthContext| match context.action {
                 AuthAction::Select => Authorization::Allow,
-                AuthAction::Read { table_name, .. } => auth(permissions.read_table(table_name)),
+                AuthAction::Read {
+                    table_name,
+                    column_name,
+                } => {
+                    if !permissions.read_table(table_name) {
+                        Authorization::Deny
+                    } else if !permissions.read_column(table_name, column_name) {
+                        // denied columns read as NULL, in results as well as in conditions
+                        Authorization::Ignore
+                    } else {
+                        Authorization::Allow
+                    }
+                }
                 AuthAction::Update { table_name, .. } => auth(permissions.update_table(table_name)),
                 AuthAction::Insert { table_name } => auth(permissions.insert_table(table_name)),
                 AuthAction::Delete { table_name } => auth(permissions.delete_table(table_name)),
                 AuthAction::Transaction { operation: _ } => Authorization::Allow,
+                // savepoints only serve to roll back writes
+                AuthAction::Savepoint { .. } => auth(permissions.write()),
+                // everything else, including schema changes which go through
+                // migrations and require full access
                 _ => Authorization::Deny,
             })
         });
@@ -93,53 +250,157 @@ impl Database {
         name: String,
         permissions: DatabasePermissions,
     ) -> Result<Self, CRRError> {
-        let conn = rusqlite::Connection::open(Self::file_path(env, &name))?;
+        let path = Self::file_path(env, &name);
+        let conn = rusqlite::Connection::open(&path)?;
+
+        Self::configure(&conn, env, &path)?;
+        Self::load_crsqlite(&conn, env)?;
+
+        if permissions.full() {
+            Self::upgrade_bookkeeping(&conn, &path)?;
+        }
+
+        Self::set_authorizer(&conn, permissions.clone());
+
+        Ok(Self {
+            conn: Some(conn),
+            name,
+            db_version: 0,
+            permissions,
+            pool: None,
+        })
+    }
 
-        Self::load_crsqlite(&conn)?;
+    /// Like [`Database::open`] but reuses an idle connection of `pool` if there is one
+    /// and hands the connection back to the pool when dropped.
+    pub(crate) fn open_pooled(
+        env: &AppEnv,
+        pool: &ConnectionPool,
+        name: String,
+        permissions: DatabasePermissions,
+    ) -> Result<Self, CRRError> {
+        let path = Self::file_path(env, &name);
+        let conn = match pool.take(&name)? {
+            Some(conn) => {
+                // the pool only resets pragmas to sqlite's defaults
+                Self::configure(&conn, env, &path)?;
+                conn
+            }
+            None => {
+                let conn = rusqlite::Connection::open(&path)?;
+                Self::configure(&conn, env, &path)?;
+                Self::load_crsqlite(&conn, env)?;
+                conn
+            }
+        };
 
-        if permissions.create() {
-            Self::init_migrations(&conn)?;
+        if permissions.full() {
+            Self::upgrade_bookkeeping(&conn, &path)?;
         }
 
         Self::set_authorizer(&conn, permissions.clone());
 
         Ok(Self {
-            conn,
+            conn: Some(conn),
             name,
             db_version: 0,
             permissions,
+            pool: Some(pool.clone()),
         })
     }
 
+    pub(crate) fn open_in_memory(
+        env: &AppEnv,
+        permissions: DatabasePermissions,
+    ) -> Result<Self, CRRError> {
+        let conn = rusqlite::Connection::open_in_memory()?;
+
+        Self::load_crsqlite(&conn, env)?;
+        Self::init_migrations(&conn)?;
+        Self::set_authorizer(&conn, permissions.clone());
+
+        Ok(Self {
+            conn: Some(conn),
+            name: ":memory:".to_owned(),
+            db_version: 0,
+            permissions,
+            pool: None,
+        })
+    }
+
+    /// Creates the database file with the server's bookkeeping tables,
+    /// does nothing if it already exists
     pub(crate) fn create(env: &AppEnv, name: &str) -> Result<(), CRRError> {
-        let conn = rusqlite::Connection::open(Self::file_path(env, name))?;
+        lazy_static! {
+            static ref CREATE_LOCK: Mutex<()> = Mutex::new(());
+        }
+
+        // concurrent subscriptions to a new database would otherwise initialize it twice
+        let _guard = CREATE_LOCK
+            .lock()
+            .map_err(|_| CRRError::PoisonedLockError("CREATE_LOCK"))?;
+
+        let path = Self::file_path(env, name);
+        if path.exists() {
+            return Ok(());
+        }
+
+        let conn = rusqlite::Connection::open(&path)?;
 
-        Self::load_crsqlite(&conn)?;
+        Self::configure(&conn, env, &path)?;
+        Self::load_crsqlite(&conn, env)?;
         Self::init_migrations(&conn)?;
         conn.execute_batch("SELECT crsql_finalize()")?;
 
         Ok(())
     }
 
+    /// Waits for the replication tool to copy a newly created database
+    /// to the replica directory, if there is one
+    pub(crate) async fn wait_for_replica(env: &AppEnv, name: &str) -> Result<(), CRRError> {
+        if env.replica_data_dir().is_none() {
+            return Ok(());
+        }
+
+        let path = Self::readonly_file_path(env, name);
+        let started_at = Instant::now();
+
+        while !path.exists() {
+            if started_at.elapsed() >= REPLICA_WAIT {
+                return Err(CRRError::ReplicaUnavailable(name.to_owned()));
+            }
+
+            tokio::time::sleep(REPLICA_POLL_INTERVAL).await;
+        }
+
+        Ok(())
+    }
+
     pub(crate) fn open_readonly(
         env: &AppEnv,
         name: String,
         db_version: i64,
         permissions: DatabasePermissions,
     ) -> Result<Self, CRRError> {
+        let path = Self::readonly_file_path(env, &name);
+        if !path.exists() {
+            return Err(CRRError::DatabaseNotFound(name));
+        }
         let conn = rusqlite::Connection::open_with_flags(
-            Self::file_path(env, &name),
+            &path,
             rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY | rusqlite::OpenFlags::SQLITE_OPEN_NO_MUTEX,
         )?;
 
-        Self::load_crsqlite(&conn)?;
+        Self::configure(&conn, env, &path)?;
+        Self::load_crsqlite(&conn, env)?;
         Self::set_authorizer(&conn, permissions.clone());
 
         Ok(Self {
-            conn,
+            conn: Some(conn),
             name,
             db_version,
             permissions,
+            pool: None,
         })
     }
 
@@ -148,24 +409,41 @@ impl Database {
         name: String,
         permissions: DatabasePermissions,
     ) -> Result<Self, CRRError> {
+        let path = Self::readonly_file_path(env, &name);
+        if !path.exists() {
+            return Err(CRRError::DatabaseNotFound(name));
+        }
         let conn = rusqlite::Connection::open_with_flags(
-            Self::file_path(env, &name),
+            &path,
             rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY | rusqlite::OpenFlags::SQLITE_OPEN_NO_MUTEX,
         )?;
 
-        Self::load_crsqlite(&conn)?;
+        Self::configure(&conn, env, &path)?;
+        Self::load_crsqlite(&conn, env)?;
         Self::set_authorizer(&conn, permissions.clone());
 
         let db_version: i64 = conn.query_row("SELECT crsql_dbversion()", [], |row| row.get(0))?;
 
         Ok(Self {
-            conn,
+            conn: Some(conn),
             name,
             permissions,
             db_version,
+            pool: None,
         })
     }
 
+    pub(crate) fn set_query_timeout(&self, timeout: Duration) {
+        if timeout.is_zero() {
+            self.progress_handler(0, None::<fn() -> bool>);
+            return;
+        }
+
+        let deadline = Instant::now() + timeout;
+
+        self.progress_handler(1000, Some(move || Instant::now() > deadline));
+    }
+
     pub(crate) fn db_version(&self) -> i64 {
         self.db_version
     }
@@ -174,6 +452,18 @@ impl Database {
         self.db_version = db_version;
     }
 
+    /// names of all tables, regardless of permissions
+    pub(crate) fn table_names(&mut self) -> Result<Vec<String>, CRRError> {
+        let authorized = self.disable_authorization();
+        let mut stmt = authorized.prepare("SELECT name FROM sqlite_master WHERE type = 'table'")?;
+
+        let table_names = stmt
+            .query_map([], |row| row.get(0))?
+            .collect::<Result<Vec<String>, _>>()?;
+
+        Ok(table_names)
+    }
+
     pub(crate) fn disable_authorization<'d>(&'d mut self) -> AuthorizedDatabaseHandle<'d> {
         AuthorizedDatabaseHandle::new(self)
     }
@@ -183,20 +473,36 @@ impl std::ops::Deref for Database {
     type Target = rusqlite::Connection;
 
     fn deref(&self) -> &Self::Target {
-        &self.conn
+        self.conn.as_ref().expect("Connection was already released")
     }
 }
 
 impl std::ops::DerefMut for Database {
     fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.conn
+        self.conn.as_mut().expect("Connection was already released")
     }
 }
 
 impl std::ops::Drop for Database {
     fn drop(&mut self) {
-        self.authorizer(None::<for<'r> fn(AuthContext<'r>) -> _>);
-        let _err = self.execute_batch("SELECT crsql_finalize()");
+        let conn = match self.conn.take() {
+            Some(conn) => conn,
+            None => return,
+        };
+
+        conn.authorizer(None::<for<'r> fn(AuthContext<'r>) -> _>);
+        conn.progress_handler(0, None::<fn() -> bool>);
+
+        // connections left inside a transaction are not reused
+        let conn = match self.pool.as_ref() {
+            Some(pool) if conn.is_autocommit() => match pool.put(&self.name, conn) {
+                Ok(()) => return,
+                Err(conn) => conn,
+            },
+            _ => conn,
+        };
+
+        let _err = conn.execute_batch("SELECT crsql_finalize()");
     }
 }
 
@@ -225,6 +531,144 @@ impl<'d> std::ops::DerefMut for AuthorizedDatabaseHandle<'d> {
 
 impl<'d> std::ops::Drop for AuthorizedDatabaseHandle<'d> {
     fn drop(&mut self) {
-        Database::set_authorizer(&self.0.conn, self.0.permissions.clone())
+        Database::set_authorizer(&*self.0, self.0.permissions.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::{app_state::AppEnv, auth::DatabasePermissions, error::CRRError};
+
+    use super::{validate_db_name, Database};
+
+    #[test]
+    fn reject_invalid_names() {
+        for name in ["data", "my-app_2"] {
+            assert!(validate_db_name(name).is_ok(), "{} is valid", name);
+        }
+
+        for name in [
+            "", "../auth", "..", "foo/bar", "foo\\bar", "foo.bar", "föö", "a b",
+        ] {
+            assert!(
+                matches!(validate_db_name(name), Err(CRRError::BadRequest(_))),
+                "{} is rejected",
+                name
+            );
+        }
+    }
+
+    fn table_exists(db: &rusqlite::Connection, table_name: &str) -> bool {
+        db.prepare("SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = ?")
+            .unwrap()
+            .exists([table_name])
+            .unwrap()
+    }
+
+    #[test]
+    fn open_in_wal_mode() {
+        let env = AppEnv::test_env();
+
+        let journal_mode: String = env
+            .test_db()
+            .query_row("PRAGMA journal_mode", [], |row| row.get(0))
+            .unwrap();
+
+        assert_eq!(journal_mode, "wal");
+    }
+
+    #[test]
+    fn reject_missing_extension() {
+        let env = AppEnv::with_crsqlite_path(AppEnv::test_env(), "./extensions/does-not-exist.so");
+
+        let result = Database::open(
+            &env,
+            AppEnv::TEST_DB_NAME.to_owned(),
+            DatabasePermissions::Full,
+        );
+
+        assert!(matches!(result, Err(CRRError::MissingExtension(_))));
+    }
+
+    #[tokio::test]
+    async fn wait_for_new_replica() {
+        let env = AppEnv::test_env();
+        let replica_dir = env.data_dir().join("replica");
+        std::fs::create_dir_all(&replica_dir).unwrap();
+        let env = AppEnv::with_replica_data_dir(env, replica_dir.to_str().unwrap());
+
+        Database::create(&env, AppEnv::TEST_DB_NAME).unwrap();
+
+        let replicate = {
+            let primary = Database::file_path(&env, AppEnv::TEST_DB_NAME);
+            let replica = Database::readonly_file_path(&env, AppEnv::TEST_DB_NAME);
+
+            tokio::spawn(async move {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                std::fs::copy(primary, replica).unwrap();
+            })
+        };
+
+        Database::wait_for_replica(&env, AppEnv::TEST_DB_NAME)
+            .await
+            .expect("Replica shows up");
+        replicate.await.unwrap();
+    }
+
+    #[test]
+    fn read_from_replica() {
+        let env = AppEnv::test_env();
+        let replica_dir = env.data_dir().join("replica");
+        std::fs::create_dir_all(&replica_dir).unwrap();
+        let env = AppEnv::with_replica_data_dir(env, replica_dir.to_str().unwrap());
+
+        Database::open(
+            &env,
+            AppEnv::TEST_DB_NAME.to_owned(),
+            DatabasePermissions::Full,
+        )
+        .unwrap()
+        .execute("CREATE TABLE primary_only (id INTEGER PRIMARY KEY)", [])
+        .unwrap();
+
+        rusqlite::Connection::open(replica_dir.join(format!("{}.sqlite3", AppEnv::TEST_DB_NAME)))
+            .unwrap()
+            .execute("CREATE TABLE replica_only (id INTEGER PRIMARY KEY)", [])
+            .unwrap();
+
+        assert_eq!(
+            Database::file_path(&env, AppEnv::TEST_DB_NAME),
+            env.data_dir()
+                .join(format!("{}.sqlite3", AppEnv::TEST_DB_NAME))
+        );
+
+        let readonly = Database::open_readonly(
+            &env,
+            AppEnv::TEST_DB_NAME.to_owned(),
+            0,
+            DatabasePermissions::Full,
+        )
+        .unwrap();
+
+        assert!(
+            table_exists(&readonly, "replica_only"),
+            "Reads use the replica"
+        );
+        assert!(!table_exists(&readonly, "primary_only"));
+
+        let primary = Database::open(
+            &env,
+            AppEnv::TEST_DB_NAME.to_owned(),
+            DatabasePermissions::Full,
+        )
+        .unwrap();
+
+        assert!(
+            table_exists(&primary, "primary_only"),
+            "Writes use the primary"
+        );
+        assert!(!table_exists(&primary, "replica_only"));
     }
 }
@@ -0,0 +1,76 @@
+use axum::extract::{Json, State};
+
+use crate::{
+    app_state::AppState,
+    auth::{AuthDatabase, DatabaseAccess, Token},
+    error::CRRError,
+};
+
+pub(crate) async fn get_databases(
+    Token(token): Token,
+    State(state): State<AppState>,
+) -> Result<Json<Vec<DatabaseAccess>>, CRRError> {
+    let auth = AuthDatabase::open(state.env().clone())?;
+
+    Ok(Json(auth.list_databases_for_token(&token)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::extract::{Json, State};
+
+    use super::get_databases;
+    use crate::{
+        app_state::AppState,
+        auth::{AuthDatabase, ObjectPermissions, PartialPermissions, Token},
+    };
+
+    #[tokio::test]
+    async fn list_granted_databases() {
+        let state = AppState::test_state();
+        let auth = AuthDatabase::open(state.env().clone()).unwrap();
+
+        let owner_token = AuthDatabase::create_test_token(state.env());
+        auth.get_permissions(&owner_token, "first").unwrap();
+        auth.get_permissions(&owner_token, "second").unwrap();
+
+        let token = AuthDatabase::create_test_token(state.env());
+        let user_id = auth.authenticate_user(&token).unwrap();
+
+        let owner_id = auth.authenticate_user(&owner_token).unwrap();
+        let role_id = auth.create_role("readers", owner_id).unwrap();
+        auth.update_permissions(
+            role_id,
+            "first",
+            None,
+            &ObjectPermissions::partial(PartialPermissions {
+                read: true,
+                ..Default::default()
+            }),
+        )
+        .unwrap();
+        auth.add_user_to_role(role_id, user_id).unwrap();
+
+        let Json(databases) = get_databases(Token(token), State(state.clone()))
+            .await
+            .unwrap();
+
+        assert_eq!(
+            serde_json::to_value(databases).unwrap(),
+            serde_json::json!([{
+                "name": "first",
+                "read": true,
+                "insert": false,
+                "update": false,
+                "delete": false,
+                "full": false,
+            }])
+        );
+
+        let Json(databases) = get_databases(Token(owner_token), State(state))
+            .await
+            .unwrap();
+
+        assert_eq!(databases.len(), 2);
+    }
+}
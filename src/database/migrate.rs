@@ -1,14 +1,35 @@
-use crate::{auth::DatabasePermissions, error::CRRError, AppState};
-use axum::extract::{Json, Path, State};
+use crate::{
+    auth::{is_server_table, DatabasePermissions},
+    error::CRRError,
+    AppState,
+};
+use axum::{
+    extract::{Json, Path, Query, State},
+    response::{IntoResponse, Response},
+};
 use lazy_static::lazy_static;
 use regex::Regex;
-use serde::Deserialize;
+use rusqlite::OptionalExtension;
+use serde::{Deserialize, Serialize};
+use std::ffi::CString;
 
 use super::{changes::Migration, Database};
 
 #[derive(Deserialize)]
 pub(crate) struct MigratePostData {
     pub(crate) queries: Vec<String>,
+    #[serde(default)]
+    pub(crate) down: Vec<String>,
+    /// runs the migration and rolls it back instead of committing it
+    #[serde(default)]
+    pub(crate) dry_run: bool,
+}
+
+#[derive(Serialize, Debug)]
+pub(crate) struct DryRunResponse {
+    /// the migration as it would be applied, including the crsqlite calls
+    sql: String,
+    error: Option<String>,
 }
 
 pub(crate) async fn post_migrate(
@@ -16,23 +37,83 @@ pub(crate) async fn post_migrate(
     permissions: DatabasePermissions,
     State(state): State<AppState>,
     Json(data): Json<MigratePostData>,
-) -> Result<(), CRRError> {
-    let mut db = Database::open(&state.env(), db_name.clone(), permissions)?;
+) -> Result<Response, CRRError> {
+    let mut db = Database::open_pooled(
+        state.env(),
+        state.database_pool(),
+        db_name.clone(),
+        permissions,
+    )?;
+
+    if data.dry_run {
+        return Ok(Json(db.dry_run_migration(data.queries)?).into_response());
+    }
 
-    let migration = db.apply_migration(data.queries)?;
+    let schema_lock = state.change_manager().schema_lock(&db_name).await;
+    let migration = {
+        // waits for running backfills to finish
+        let _guard = schema_lock.write().await;
+        db.apply_migration(data.queries, data.down)?
+    };
 
     state
         .change_manager()
         .publish_migration(&db_name, migration)
         .await;
 
+    Ok(().into_response())
+}
+
+pub(crate) async fn post_rollback(
+    Path(db_name): Path<String>,
+    permissions: DatabasePermissions,
+    State(state): State<AppState>,
+) -> Result<(), CRRError> {
+    let schema_lock = state.change_manager().schema_lock(&db_name).await;
+    let mut db = Database::open_pooled(state.env(), state.database_pool(), db_name, permissions)?;
+
+    let rollback = {
+        let _guard = schema_lock.write().await;
+        db.rollback_migration()?
+    };
+
+    state
+        .change_manager()
+        .publish_rollback(db.name(), rollback)
+        .await;
+
     Ok(())
 }
 
+#[derive(Deserialize)]
+pub(crate) struct MigrationsQuery {
+    #[serde(default)]
+    schema_version: i64,
+}
+
+pub(crate) async fn get_migrations(
+    Path(db_name): Path<String>,
+    Query(query): Query<MigrationsQuery>,
+    State(state): State<AppState>,
+    permissions: DatabasePermissions,
+) -> Result<Json<Vec<Migration>>, CRRError> {
+    permissions.require_read()?;
+
+    let mut db = Database::open_pooled(state.env(), state.database_pool(), db_name, permissions)?;
+
+    let migrations = db
+        .disable_authorization()
+        .migrations(query.schema_version)
+        .collect::<Result<Vec<Migration>, CRRError>>()?;
+
+    Ok(Json(migrations))
+}
+
 impl Database {
     pub(crate) fn apply_migration(
         &mut self,
         migrations: Vec<String>,
+        down: Vec<String>,
     ) -> Result<Migration, CRRError> {
         if !self.permissions().full() {
             return Err(CRRError::Unauthorized(
@@ -41,13 +122,19 @@ impl Database {
             ));
         }
 
-        let mut crr_migrations: Vec<String> = Vec::with_capacity(migrations.len() * 3 + 2);
+        let joined_migrations = Self::crr_migration_sql(migrations)?;
 
-        for migration in migrations.into_iter() {
-            Self::enable_migration_crr(&mut crr_migrations, migration);
-        }
+        let joined_down = if down.is_empty() {
+            None
+        } else {
+            let mut crr_down: Vec<String> = Vec::with_capacity(down.len() * 3);
+
+            for migration in down.into_iter() {
+                Self::enable_migration_crr(&mut crr_down, migration)?;
+            }
 
-        let joined_migrations: String = crr_migrations.join(";\n");
+            Some(crr_down.join(";\n"))
+        };
 
         tracing::debug!("Run Migration\n{}", joined_migrations);
 
@@ -56,113 +143,504 @@ impl Database {
         savepoint.execute_batch(&joined_migrations)?;
 
         savepoint
-            .prepare("INSERT INTO crr_server_migrations (sql) VALUES (?)")?
-            .insert([&joined_migrations])?;
+            .prepare("INSERT INTO crr_server_migrations (sql, down_sql) VALUES (?, ?)")?
+            .insert(rusqlite::params![&joined_migrations, &joined_down])?;
 
         savepoint.commit()?;
 
         Ok(Migration::new(self.last_insert_rowid(), joined_migrations))
     }
 
-    fn enable_migration_crr(crr_migrations: &mut Vec<String>, sql: String) {
-        match MigrationType::detect(&sql) {
+    /// Runs the migration in a savepoint that is always rolled back,
+    /// SQL errors are reported instead of returned.
+    pub(crate) fn dry_run_migration(
+        &mut self,
+        migrations: Vec<String>,
+    ) -> Result<DryRunResponse, CRRError> {
+        if !self.permissions().full() {
+            return Err(CRRError::Unauthorized(
+                "User must be authorized with full access to the database to apply migrations"
+                    .to_owned(),
+            ));
+        }
+
+        let sql = Self::crr_migration_sql(migrations)?;
+
+        tracing::debug!("Dry run Migration\n{}", sql);
+
+        let savepoint = self.savepoint()?;
+        let error = savepoint
+            .execute_batch(&sql)
+            .err()
+            .map(|error| error.to_string());
+
+        // savepoints roll back unless they are committed
+        savepoint.finish()?;
+
+        Ok(DryRunResponse { sql, error })
+    }
+
+    /// Runs the down migration of the latest migration and deletes it,
+    /// returns the version that was rolled back with the down migration's SQL
+    pub(crate) fn rollback_migration(&mut self) -> Result<Migration, CRRError> {
+        if !self.permissions().full() {
+            return Err(CRRError::Unauthorized(
+                "User must be authorized with full access to the database to roll back migrations"
+                    .to_owned(),
+            ));
+        }
+
+        let savepoint = self.savepoint()?;
+
+        let (version, down_sql): (i64, Option<String>) = savepoint
+            .query_row(
+                "SELECT version, down_sql FROM crr_server_migrations ORDER BY version DESC LIMIT 1",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .map_err(|error| match error {
+                rusqlite::Error::QueryReturnedNoRows => {
+                    CRRError::BadRequest("There are no migrations to roll back".to_owned())
+                }
+                error => error.into(),
+            })?;
+
+        let down_sql = down_sql.ok_or_else(|| {
+            CRRError::BadRequest(format!("Migration {} has no down migration", version))
+        })?;
+
+        tracing::debug!("Roll back Migration {}\n{}", version, down_sql);
+
+        savepoint.execute_batch(&down_sql)?;
+        savepoint.execute(
+            "DELETE FROM crr_server_migrations WHERE version = ?",
+            [version],
+        )?;
+
+        savepoint.commit()?;
+
+        Ok(Migration::new(version, down_sql))
+    }
+
+    fn crr_migration_sql(migrations: Vec<String>) -> Result<String, CRRError> {
+        let mut crr_migrations: Vec<String> = Vec::with_capacity(migrations.len() * 3 + 2);
+
+        for migration in migrations.into_iter() {
+            Self::enable_migration_crr(&mut crr_migrations, migration)?;
+        }
+
+        Ok(crr_migrations.join(";\n"))
+    }
+
+    fn enable_migration_crr(crr_migrations: &mut Vec<String>, sql: String) -> Result<(), CRRError> {
+        match MigrationType::detect(&sql)? {
+            // crr-server's own tables like `crr_server_merge_policies` aren't synced
+            MigrationType::Alter(table_name)
+            | MigrationType::Create(table_name)
+            | MigrationType::CreateIfNotExists(table_name)
+                if is_server_table(&table_name) =>
+            {
+                crr_migrations.push(sql);
+            }
             MigrationType::Alter(table_name) => {
+                let table_name = table_name.replace('\'', "''");
                 crr_migrations.push(format!("SELECT crsql_begin_alter('{}')", &table_name));
                 crr_migrations.push(sql);
                 crr_migrations.push(format!("SELECT crsql_commit_alter('{}')", table_name));
             }
             MigrationType::Create(table_name) => {
                 crr_migrations.push(sql);
-                crr_migrations.push(format!("SELECT crsql_as_crr('{}')", table_name));
+                crr_migrations.push(format!(
+                    "SELECT crsql_as_crr('{}')",
+                    table_name.replace('\'', "''")
+                ));
+            }
+            MigrationType::CreateIfNotExists(table_name) => {
+                let table_name = table_name.replace('\'', "''");
+                crr_migrations.push(sql);
+                crr_migrations.push(format!(
+                    "SELECT crsql_as_crr('{0}') WHERE NOT EXISTS (SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = '{0}__crsql_clock')",
+                    table_name
+                ));
             }
             MigrationType::Other => {
                 crr_migrations.push(sql);
             }
         }
-    }
 
-    pub(crate) fn migrations(&self, schema_version: i64) -> Result<Vec<Migration>, CRRError> {
-        let mut stmt =
-            self.prepare("SELECT version, \"sql\" FROM crr_server_migrations WHERE version > ?")?;
+        Ok(())
+    }
 
-        let mut rows = stmt.query([schema_version])?;
+    /// Migrations after `schema_version` in version order, loaded one at a time
+    pub(crate) fn migrations(&mut self, schema_version: i64) -> MigrationsIter<'_> {
+        MigrationsIter {
+            db: self,
+            schema_version,
+        }
+    }
+}
 
-        let mut migrations = Vec::new();
+/// Holds `&mut Database` rather than `&Database` so it can be kept across
+/// `.await` points, the connection isn't `Sync`.
+pub(crate) struct MigrationsIter<'d> {
+    db: &'d mut Database,
+    schema_version: i64,
+}
 
-        while let Some(row) = rows.next()? {
-            migrations.push(Migration::new(row.get(0)?, row.get(1)?));
+impl<'d> Iterator for MigrationsIter<'d> {
+    type Item = Result<Migration, CRRError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let migration = self
+            .db
+            .prepare_cached(
+                "
+                    SELECT version, \"sql\"
+                    FROM crr_server_migrations
+                    WHERE version > ?
+                    ORDER BY version
+                    LIMIT 1
+                ",
+            )
+            .and_then(|mut stmt| {
+                stmt.query_row([self.schema_version], |row| {
+                    Ok(Migration::new(row.get(0)?, row.get(1)?))
+                })
+                .optional()
+            });
+
+        match migration {
+            Ok(Some(migration)) => {
+                self.schema_version = migration.version();
+                Some(Ok(migration))
+            }
+            Ok(None) => None,
+            Err(error) => Some(Err(error.into())),
         }
-
-        Ok(migrations)
     }
 }
 
 #[derive(PartialEq, Debug)]
 enum MigrationType {
     Create(String),
+    /// might already exist as a crr
+    CreateIfNotExists(String),
     Alter(String),
     Other,
 }
 
 impl MigrationType {
-    fn detect(sql: &str) -> Self {
+    /// Table names may be unquoted or quoted with `"`, `[]` or backticks.
+    /// Queries creating or altering more than one table are rejected,
+    /// only the first one would be turned into a crr.
+    fn detect(sql: &str) -> Result<Self, CRRError> {
         lazy_static! {
-            static ref RE_CREATE: Regex =
-                Regex::new("CREATE TABLE \"(.+)\"").expect("Failed to compile create table regex");
-            static ref RE_ALTER: Regex =
-                Regex::new("ALTER TABLE \"(.+)\"").expect("Failed to compile create table regex");
+            static ref RE_CREATE: Regex = Regex::new(
+                r#"(?i)^CREATE\s+(?P<temporary>TEMP\s+|TEMPORARY\s+)?TABLE\s+(?P<if_not_exists>IF\s+NOT\s+EXISTS\s+)?(?:"(?P<quoted>(?:[^"]|"")+)"|\[(?P<bracketed>[^\]]+)\]|`(?P<backticked>(?:[^`]|``)+)`|(?P<plain>[A-Za-z_][A-Za-z0-9_$]*))"#
+            )
+            .expect("Failed to compile create table regex");
+            static ref RE_ALTER: Regex = Regex::new(
+                r#"(?i)^ALTER\s+TABLE\s+(?:"(?P<quoted>(?:[^"]|"")+)"|\[(?P<bracketed>[^\]]+)\]|`(?P<backticked>(?:[^`]|``)+)`|(?P<plain>[A-Za-z_][A-Za-z0-9_$]*))"#
+            )
+            .expect("Failed to compile alter table regex");
         }
 
-        if let Some(altered) = RE_ALTER.captures(sql) {
-            Self::Alter(altered[1].to_owned())
-        } else if let Some(created) = RE_CREATE.captures(sql) {
-            Self::Create(created[1].to_owned())
+        // keywords in comments and string literals don't start a statement
+        let mut table_statements = Self::statements(sql)
+            .into_iter()
+            .map(Self::skip_comments)
+            .filter(|statement| RE_ALTER.is_match(statement) || RE_CREATE.is_match(statement));
+
+        let statement = match (table_statements.next(), table_statements.next()) {
+            (Some(statement), None) => statement,
+            (None, _) => return Ok(Self::Other),
+            (Some(_), Some(_)) => {
+                return Err(CRRError::BadRequest(
+                    "Each migration query may create or alter at most one table".to_owned(),
+                ))
+            }
+        };
+
+        if let Some(altered) = RE_ALTER.captures(statement) {
+            Ok(Self::Alter(Self::identifier(&altered)))
+        } else if let Some(created) = RE_CREATE.captures(statement) {
+            if created.name("temporary").is_some() {
+                // temp tables only live as long as the connection, there's nothing to sync
+                Ok(Self::Other)
+            } else if created.name("if_not_exists").is_some() {
+                Ok(Self::CreateIfNotExists(Self::identifier(&created)))
+            } else {
+                Ok(Self::Create(Self::identifier(&created)))
+            }
         } else {
-            Self::Other
+            Ok(Self::Other)
+        }
+    }
+
+    /// Splits `sql` at the semicolons that end a statement according to sqlite's
+    /// own tokenizer, so semicolons in literals, comments and triggers are skipped
+    fn statements(sql: &str) -> Vec<&str> {
+        let mut statements = Vec::new();
+        let mut start = 0;
+
+        for (index, _semicolon) in sql.match_indices(';') {
+            let statement = &sql[start..=index];
+
+            if Self::is_complete(statement) {
+                statements.push(statement);
+                start = index + 1;
+            }
+        }
+
+        if !sql[start..].trim().is_empty() {
+            statements.push(&sql[start..]);
+        }
+
+        statements
+    }
+
+    fn is_complete(statement: &str) -> bool {
+        match CString::new(statement) {
+            // SAFETY: sqlite3_complete only reads the nul-terminated string
+            Ok(statement) => unsafe { rusqlite::ffi::sqlite3_complete(statement.as_ptr()) != 0 },
+            Err(_) => false,
+        }
+    }
+
+    /// leading whitespace, `-- line` and `/* block */` comments
+    fn skip_comments(mut sql: &str) -> &str {
+        loop {
+            sql = sql.trim_start();
+
+            if let Some(rest) = sql.strip_prefix("--") {
+                sql = rest.split_once('\n').map(|(_, rest)| rest).unwrap_or("");
+            } else if let Some(rest) = sql.strip_prefix("/*") {
+                sql = rest.split_once("*/").map(|(_, rest)| rest).unwrap_or("");
+            } else {
+                return sql;
+            }
+        }
+    }
+
+    fn identifier(captures: &regex::Captures) -> String {
+        if let Some(quoted) = captures.name("quoted") {
+            quoted.as_str().replace("\"\"", "\"")
+        } else if let Some(bracketed) = captures.name("bracketed") {
+            bracketed.as_str().to_owned()
+        } else if let Some(backticked) = captures.name("backticked") {
+            backticked.as_str().replace("``", "`")
+        } else {
+            captures["plain"].to_owned()
         }
     }
 }
 
 #[cfg(test)]
 pub(crate) mod tests {
+    use std::collections::HashMap;
+
     use axum::{
-        extract::{Path, State},
+        extract::{Path, Query, State},
         Json,
     };
     use tracing_test::traced_test;
 
-    use super::{post_migrate, MigratePostData};
+    use super::{get_migrations, post_migrate, post_rollback, MigratePostData, MigrationsQuery};
     use crate::{
         app_state::{AppEnv, AppState},
-        auth::DatabasePermissions,
-        database::migrate::MigrationType,
+        auth::{DatabasePermissions, PartialPermissions},
+        database::{changes::Message, migrate::MigrationType, Database},
+        error::CRRError,
     };
 
     #[test]
     fn detect_migration_mode() {
         assert_eq!(
-            MigrationType::detect("CREATE TABLE \"foo\" (value TEXT)"),
+            MigrationType::detect("CREATE TABLE \"foo\" (value TEXT)").unwrap(),
             MigrationType::Create("foo".to_owned())
         );
         assert_eq!(
-            MigrationType::detect("ALTER TABLE \"foo\" ADD COLUMN value TEXT"),
+            MigrationType::detect("ALTER TABLE \"foo\" ADD COLUMN value TEXT").unwrap(),
             MigrationType::Alter("foo".to_owned())
         );
         assert_eq!(
-            MigrationType::detect("INSERT INTO \"foo\" (value) VALUES ('test')"),
+            MigrationType::detect("INSERT INTO \"foo\" (value) VALUES ('test')").unwrap(),
             MigrationType::Other
         );
     }
 
+    #[test]
+    fn detect_table_names() {
+        let detect = |sql: &str| MigrationType::detect(sql).unwrap();
+        let create = |name: &str| MigrationType::Create(name.to_owned());
+
+        assert_eq!(detect("CREATE TABLE foo (value TEXT)"), create("foo"));
+        assert_eq!(
+            detect("create table [foo bar] (value TEXT)"),
+            create("foo bar")
+        );
+        assert_eq!(detect("CREATE TABLE `foo` (value TEXT)"), create("foo"));
+        assert_eq!(
+            detect("CREATE TABLE \"say \"\"hi\"\"\" (value TEXT)"),
+            create("say \"hi\"")
+        );
+        assert_eq!(
+            detect("CREATE TABLE IF NOT EXISTS foo (value TEXT)"),
+            MigrationType::CreateIfNotExists("foo".to_owned())
+        );
+        assert_eq!(
+            detect("CREATE TEMP TABLE foo (value TEXT)"),
+            MigrationType::Other
+        );
+        assert_eq!(
+            detect(
+                "
+                -- the foo table
+                /* holds values */
+                CREATE TABLE foo (value TEXT)
+            "
+            ),
+            create("foo")
+        );
+        assert_eq!(
+            detect("ALTER TABLE foo ADD COLUMN bar TEXT"),
+            MigrationType::Alter("foo".to_owned())
+        );
+    }
+
+    #[test]
+    fn skip_temp_tables() {
+        let env = AppEnv::test_env();
+        let mut db = env.test_db();
+
+        db.apply_migration(
+            vec!["CREATE TEMP TABLE scratch (id INTEGER PRIMARY KEY, val TEXT)".to_string()],
+            Vec::new(),
+        )
+        .unwrap();
+
+        let crr = db
+            .prepare("SELECT name FROM sqlite_master WHERE name = 'scratch__crsql_clock'")
+            .unwrap()
+            .exists([])
+            .unwrap();
+
+        assert!(!crr, "Temp tables aren't turned into crrs");
+    }
+
+    #[test]
+    fn skip_server_tables() {
+        let env = AppEnv::test_env();
+        let mut db = env.test_db();
+
+        db.apply_migration(
+            vec!["CREATE TABLE crr_server_merge_policies (table_name TEXT NOT NULL, column_name TEXT NOT NULL, policy TEXT NOT NULL, PRIMARY KEY (table_name, column_name))".to_string()],
+            Vec::new(),
+        )
+        .unwrap();
+
+        let crr = db
+            .prepare("SELECT name FROM sqlite_master WHERE name = 'crr_server_merge_policies__crsql_clock'")
+            .unwrap()
+            .exists([])
+            .unwrap();
+
+        assert!(!crr, "crr-server's own tables aren't turned into crrs");
+    }
+
+    #[test]
+    fn rerun_create_if_not_exists() {
+        let env = AppEnv::test_env();
+        let migration =
+            vec!["CREATE TABLE IF NOT EXISTS foo (id INTEGER PRIMARY KEY, bar TEXT)".to_string()];
+
+        env.test_db()
+            .apply_migration(migration.clone(), Vec::new())
+            .unwrap();
+        env.test_db()
+            .apply_migration(migration, Vec::new())
+            .expect("Failed to re-run migration");
+
+        let mut db = env.test_db();
+        db.execute("INSERT INTO foo (bar) VALUES ('a')", [])
+            .unwrap();
+
+        let changes: i64 = db
+            .query_row(
+                "SELECT COUNT(*) FROM crsql_changes WHERE \"table\" = 'foo'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+
+        assert_eq!(changes, 1, "Table is still a crr");
+        assert_eq!(db.migrations(0).count(), 2);
+    }
+
+    #[test]
+    fn reject_multiple_tables() {
+        assert!(matches!(
+            MigrationType::detect("CREATE TABLE foo (value TEXT); CREATE TABLE bar (value TEXT)"),
+            Err(CRRError::BadRequest(_))
+        ));
+        assert!(matches!(
+            MigrationType::detect("CREATE TABLE foo (value TEXT); ALTER TABLE bar ADD x TEXT"),
+            Err(CRRError::BadRequest(_))
+        ));
+    }
+
+    #[test]
+    fn ignore_tables_in_comments_and_literals() {
+        assert_eq!(
+            MigrationType::detect(
+                "
+                    -- CREATE TABLE old (value TEXT);
+                    CREATE TABLE foo (value TEXT DEFAULT 'create table bar; alter table baz');
+                    /* ALTER TABLE foo ADD x TEXT; */
+                    INSERT INTO foo (value) VALUES ('CREATE TABLE qux (value TEXT)')
+                "
+            )
+            .unwrap(),
+            MigrationType::Create("foo".to_owned())
+        );
+        assert_eq!(
+            MigrationType::detect(
+                "INSERT INTO foo (value) VALUES ('x'); ALTER TABLE foo ADD y TEXT"
+            )
+            .unwrap(),
+            MigrationType::Alter("foo".to_owned())
+        );
+    }
+
     pub(crate) fn setup_foo(env: &AppEnv) {
+        setup_foo_in(&mut env.test_db());
+    }
+
+    /// like [`setup_foo`] for a database that isn't the env's test database
+    pub(crate) fn setup_foo_in(db: &mut Database) {
         let migrations =
             vec!["CREATE TABLE \"foo\" (id INTEGER PRIMARY KEY, bar TEXT)".to_string()];
 
-        env.test_db()
-            .apply_migration(migrations)
+        db.apply_migration(migrations, Vec::new())
             .expect("Failed to apply migrations");
     }
 
+    #[test]
+    fn migrate_in_memory() {
+        let mut db = AppEnv::test_env().test_db_in_memory();
+        setup_foo_in(&mut db);
+
+        db.execute("INSERT INTO foo (bar) VALUES ('a')", [])
+            .unwrap();
+
+        let changes: i64 = db
+            .query_row("SELECT COUNT(*) FROM crsql_changes", [], |row| row.get(0))
+            .unwrap();
+
+        assert!(changes > 0, "foo is a crr");
+        assert_eq!(db.migrations(0).count(), 1);
+    }
+
     #[test]
     fn create_simple_table() {
         let env = AppEnv::test_env();
@@ -193,9 +671,146 @@ pub(crate) mod tests {
                 queries: vec![
                     "CREATE TABLE \"test\" (id INTEGER PRIMARY KEY, val TEXT)".to_string()
                 ],
+                down: Vec::new(),
+                dry_run: false,
             }),
         )
         .await
         .unwrap();
     }
+
+    #[tokio::test]
+    async fn dry_run_migration() {
+        let env = AppEnv::test_env();
+        setup_foo(&env);
+
+        let mut db = env.test_db();
+
+        let result = db
+            .dry_run_migration(vec![
+                "CREATE TABLE \"bar\" (id INTEGER PRIMARY KEY, val TEXT)".to_string(),
+            ])
+            .unwrap();
+
+        assert!(result.error.is_none());
+        assert!(result.sql.contains("SELECT crsql_as_crr('bar')"));
+
+        let exists = db
+            .prepare("SELECT name FROM sqlite_master WHERE type = 'table' AND name = 'bar'")
+            .unwrap()
+            .exists([])
+            .unwrap();
+
+        assert!(!exists, "Dry run doesn't change the schema");
+        assert_eq!(db.migrations(0).count(), 1);
+
+        let result = db
+            .dry_run_migration(vec!["CREATE TABLE \"bar\" (".to_string()])
+            .unwrap();
+
+        assert!(result.error.is_some());
+        assert_eq!(db.migrations(0).count(), 1);
+    }
+
+    #[tokio::test]
+    async fn list_migrations() {
+        let state = AppState::test_state();
+        setup_foo(state.env());
+
+        let Json(migrations) = get_migrations(
+            Path(AppEnv::TEST_DB_NAME.to_owned()),
+            Query(MigrationsQuery { schema_version: 0 }),
+            State(state.clone()),
+            DatabasePermissions::Partial {
+                database: PartialPermissions {
+                    read: true,
+                    insert: false,
+                    update: false,
+                    delete: false,
+                },
+                tables: HashMap::new(),
+            },
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(migrations.len(), 1);
+        assert_eq!(migrations[0].version(), 1);
+
+        assert!(get_migrations(
+            Path(AppEnv::TEST_DB_NAME.to_owned()),
+            Query(MigrationsQuery { schema_version: 0 }),
+            State(state.clone()),
+            DatabasePermissions::default(),
+        )
+        .await
+        .is_err());
+    }
+
+    #[test]
+    fn rollback_create_table() {
+        let env = AppEnv::test_env();
+        let mut db = env.test_db();
+
+        db.apply_migration(
+            vec!["CREATE TABLE \"foo\" (id INTEGER PRIMARY KEY, bar TEXT)".to_string()],
+            vec!["DROP TABLE \"foo\"".to_string()],
+        )
+        .unwrap();
+
+        assert_eq!(db.rollback_migration().unwrap().version(), 1);
+
+        let exists = db
+            .prepare("SELECT name FROM sqlite_master WHERE type = 'table' AND name = 'foo'")
+            .unwrap()
+            .exists([])
+            .unwrap();
+
+        assert!(!exists, "Table has been dropped");
+        assert!(db.migrations(0).next().is_none());
+        assert!(db.rollback_migration().is_err());
+    }
+
+    #[tokio::test]
+    async fn never_reuse_rolled_back_versions() {
+        let state = AppState::test_state();
+        let mut db = state.env().test_db();
+
+        let create = |table_name: &str| {
+            (
+                vec![format!(
+                    "CREATE TABLE \"{}\" (id INTEGER PRIMARY KEY)",
+                    table_name
+                )],
+                vec![format!("DROP TABLE \"{}\"", table_name)],
+            )
+        };
+
+        let (up, down) = create("foo");
+        db.apply_migration(up, down).unwrap();
+
+        let mut sub = state
+            .change_manager()
+            .subscribe(state.env(), AppEnv::TEST_DB_NAME)
+            .await
+            .unwrap();
+
+        post_rollback(
+            Path(AppEnv::TEST_DB_NAME.to_owned()),
+            DatabasePermissions::Full,
+            State(state.clone()),
+        )
+        .await
+        .unwrap();
+
+        match sub.recv().await.expect("Failed to receive message") {
+            Message::Rollback(rollback) => assert_eq!(rollback.version(), 1),
+            message => panic!("Unexpected message {:?}", message),
+        }
+
+        let (up, down) = create("bar");
+        let migration = db.apply_migration(up, down).unwrap();
+
+        assert_eq!(migration.version(), 2);
+    }
 }
@@ -1,27 +1,52 @@
 pub(crate) mod changes;
+mod checkpoint;
 mod database;
+mod list;
 mod migrate;
+mod pool;
 mod run;
+mod site_id;
+mod status;
+mod tables;
+mod vacuum;
 mod value;
 
 use axum::{
     routing::{get, post},
     Router,
 };
-pub(crate) use database::Database;
+pub(crate) use database::{validate_db_name, Database};
+pub(crate) use pool::ConnectionPool;
 pub(crate) use value::Value;
 
 use crate::AppState;
 
 use self::{
-    changes::{post_changes, stream_changes},
-    migrate::post_migrate,
-    run::post_run,
+    changes::{count_changes, get_changes_range, post_changes, stream_changes, stream_changes_ws},
+    list::get_databases,
+    migrate::{get_migrations, post_migrate, post_rollback},
+    run::{post_run, post_run_batch},
+    site_id::get_site_id,
+    status::get_status,
+    tables::{get_row, get_tables},
+    vacuum::post_vacuum,
 };
 
 pub(crate) fn router() -> Router<AppState> {
     Router::new()
+        .route("/", get(get_databases))
         .route("/:db_name/migrate", post(post_migrate))
+        .route("/:db_name/migrate/rollback", post(post_rollback))
+        .route("/:db_name/migrations", get(get_migrations))
         .route("/:db_name/run", post(post_run))
+        .route("/:db_name/run/batch", post(post_run_batch))
+        .route("/:db_name/site-id", get(get_site_id))
+        .route("/:db_name/status", get(get_status))
+        .route("/:db_name/tables", get(get_tables))
+        .route("/:db_name/tables/:table/rows/:pk", get(get_row))
+        .route("/:db_name/vacuum", post(post_vacuum))
         .route("/:db_name/changes", get(stream_changes).post(post_changes))
+        .route("/:db_name/changes/count", get(count_changes))
+        .route("/:db_name/changes/range", get(get_changes_range))
+        .route("/:db_name/changes/ws", get(stream_changes_ws))
 }
@@ -0,0 +1,459 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use lazy_static::lazy_static;
+
+use crate::error::CRRError;
+
+/// per-connection settings that callers with full access can change through `/run`
+const SESSION_PRAGMAS: [&str; 12] = [
+    "query_only",
+    "foreign_keys",
+    "defer_foreign_keys",
+    "recursive_triggers",
+    "writable_schema",
+    "ignore_check_constraints",
+    "reverse_unordered_selects",
+    "legacy_alter_table",
+    "automatic_index",
+    "cache_size",
+    "temp_store",
+    "synchronous",
+];
+
+/// Idle connections per database with crsqlite already loaded.
+/// Connections are only finalized once they're retired from the pool.
+#[derive(Clone)]
+pub(crate) struct ConnectionPool {
+    idle: Arc<Mutex<IdleConnections>>,
+    size: usize,
+    max_idle: usize,
+}
+
+/// connections with the time they were handed back
+struct IdleConnections(HashMap<String, Vec<(Instant, rusqlite::Connection)>>);
+
+impl IdleConnections {
+    fn count(&self) -> usize {
+        self.0.values().map(Vec::len).sum()
+    }
+
+    /// finalizes the connection that has been idle the longest
+    fn evict_oldest(&mut self) {
+        let oldest = self
+            .0
+            .iter()
+            .filter_map(|(db_name, connections)| {
+                connections
+                    .first()
+                    .map(|(idle_since, _conn)| (*idle_since, db_name.to_owned()))
+            })
+            .min();
+
+        if let Some((_idle_since, db_name)) = oldest {
+            if let Some(connections) = self.0.get_mut(&db_name) {
+                let (_idle_since, conn) = connections.remove(0);
+                let _err = conn.execute_batch("SELECT crsql_finalize()");
+
+                if connections.is_empty() {
+                    self.0.remove(&db_name);
+                }
+            }
+        }
+    }
+}
+
+impl ConnectionPool {
+    /// keeps up to `size` idle connections per database and `max_idle` overall
+    pub(crate) fn new(size: usize, max_idle: usize) -> Self {
+        Self {
+            idle: Arc::new(Mutex::new(IdleConnections(HashMap::new()))),
+            size,
+            max_idle,
+        }
+    }
+
+    /// Pragmas the last request changed are reset to sqlite's defaults, settings from
+    /// the environment have to be applied again by the caller.
+    pub(crate) fn take(&self, db_name: &str) -> Result<Option<rusqlite::Connection>, CRRError> {
+        let mut idle = self
+            .idle
+            .lock()
+            .map_err(|_| CRRError::PoisonedLockError("ConnectionPool"))?;
+
+        let conn = match idle.0.get_mut(db_name) {
+            Some(connections) => connections.pop().map(|(_idle_since, conn)| conn),
+            None => return Ok(None),
+        };
+
+        // databases without idle connections don't keep an entry
+        if idle.0.get(db_name).map(Vec::is_empty).unwrap_or_default() {
+            idle.0.remove(db_name);
+        }
+
+        drop(idle);
+
+        match conn {
+            Some(conn) => {
+                reset_session_pragmas(&conn)?;
+                Ok(Some(conn))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Hands the connection back if the pool for this database is full or it
+    /// carries state of the last request, the longest idle connection of any
+    /// database makes room if the whole pool is full.
+    pub(crate) fn put(
+        &self,
+        db_name: &str,
+        conn: rusqlite::Connection,
+    ) -> Result<(), rusqlite::Connection> {
+        if self.size == 0 || self.max_idle == 0 || has_session_state(&conn) {
+            return Err(conn);
+        }
+
+        let mut idle = match self.idle.lock() {
+            Ok(idle) => idle,
+            Err(_) => return Err(conn),
+        };
+
+        if idle.0.get(db_name).map(Vec::len).unwrap_or_default() >= self.size {
+            return Err(conn);
+        }
+
+        while idle.count() >= self.max_idle {
+            idle.evict_oldest();
+        }
+
+        idle.0
+            .entry(db_name.to_owned())
+            .or_default()
+            .push((Instant::now(), conn));
+
+        Ok(())
+    }
+
+    /// finalizes and drops all idle connections to `db_name`
+    pub(crate) fn retire(&self, db_name: &str) -> Result<(), CRRError> {
+        let mut idle = self
+            .idle
+            .lock()
+            .map_err(|_| CRRError::PoisonedLockError("ConnectionPool"))?;
+
+        for (_idle_since, conn) in idle.0.remove(db_name).into_iter().flatten() {
+            let _err = conn.execute_batch("SELECT crsql_finalize()");
+        }
+
+        Ok(())
+    }
+
+    #[cfg(test)]
+    pub(crate) fn idle_count(&self, db_name: &str) -> usize {
+        self.idle
+            .lock()
+            .unwrap()
+            .0
+            .get(db_name)
+            .map(Vec::len)
+            .unwrap_or_default()
+    }
+}
+
+/// TEMP objects and attached databases would leak into the next request
+fn has_session_state(conn: &rusqlite::Connection) -> bool {
+    conn.query_row(
+        "
+            SELECT
+                (SELECT COUNT(*) FROM pragma_database_list WHERE name NOT IN ('main', 'temp'))
+                + (SELECT COUNT(*) FROM temp.sqlite_master)
+        ",
+        [],
+        |row| row.get::<usize, i64>(0),
+    )
+    .map(|count| count > 0)
+    .unwrap_or(true)
+}
+
+fn reset_session_pragmas(conn: &rusqlite::Connection) -> Result<(), CRRError> {
+    lazy_static! {
+        static ref DEFAULTS: Mutex<Option<Vec<(&'static str, i64)>>> = Mutex::new(None);
+    }
+
+    let mut defaults = DEFAULTS
+        .lock()
+        .map_err(|_| CRRError::PoisonedLockError("SESSION_PRAGMA_DEFAULTS"))?;
+
+    // read once from a fresh connection, so they match however sqlite was compiled
+    if defaults.is_none() {
+        let fresh = rusqlite::Connection::open_in_memory()?;
+
+        *defaults = Some(
+            SESSION_PRAGMAS
+                .into_iter()
+                .map(|pragma| {
+                    let value = fresh.pragma_query_value(None, pragma, |row| row.get(0))?;
+                    Ok((pragma, value))
+                })
+                .collect::<Result<_, CRRError>>()?,
+        );
+    }
+
+    for (pragma, value) in defaults.iter().flatten() {
+        conn.pragma_update(None, pragma, value)?;
+    }
+
+    Ok(())
+}
+
+impl Drop for IdleConnections {
+    fn drop(&mut self) {
+        for (_idle_since, conn) in self.0.values().flatten() {
+            let _err = conn.execute_batch("SELECT crsql_finalize()");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use super::ConnectionPool;
+    use crate::{
+        app_state::AppEnv,
+        auth::DatabasePermissions,
+        database::{migrate::tests::setup_foo, Database},
+    };
+
+    #[test]
+    fn reuse_connections() {
+        let env = AppEnv::test_env();
+        setup_foo(&env);
+
+        let pool = ConnectionPool::new(1, 8);
+
+        let db = Database::open_pooled(
+            &env,
+            &pool,
+            AppEnv::TEST_DB_NAME.to_owned(),
+            DatabasePermissions::Full,
+        )
+        .unwrap();
+        let other = Database::open_pooled(
+            &env,
+            &pool,
+            AppEnv::TEST_DB_NAME.to_owned(),
+            DatabasePermissions::Full,
+        )
+        .unwrap();
+
+        drop(db);
+        drop(other);
+
+        // the second connection is retired because the pool is full
+        assert_eq!(pool.idle_count(AppEnv::TEST_DB_NAME), 1);
+
+        let db = Database::open_pooled(
+            &env,
+            &pool,
+            AppEnv::TEST_DB_NAME.to_owned(),
+            DatabasePermissions::Full,
+        )
+        .unwrap();
+
+        assert_eq!(pool.idle_count(AppEnv::TEST_DB_NAME), 0);
+
+        drop(db);
+    }
+
+    #[test]
+    fn reset_authorizer() {
+        let env = AppEnv::test_env();
+        setup_foo(&env);
+
+        let pool = ConnectionPool::new(1, 8);
+
+        let db = Database::open_pooled(
+            &env,
+            &pool,
+            AppEnv::TEST_DB_NAME.to_owned(),
+            DatabasePermissions::Full,
+        )
+        .unwrap();
+        db.execute("INSERT INTO foo (bar) VALUES ('baz')", [])
+            .unwrap();
+        drop(db);
+
+        let db = Database::open_pooled(
+            &env,
+            &pool,
+            AppEnv::TEST_DB_NAME.to_owned(),
+            DatabasePermissions::default(),
+        )
+        .unwrap();
+
+        assert!(db
+            .execute("INSERT INTO foo (bar) VALUES ('baz')", [])
+            .is_err());
+        drop(db);
+
+        let db = Database::open_pooled(
+            &env,
+            &pool,
+            AppEnv::TEST_DB_NAME.to_owned(),
+            DatabasePermissions::Full,
+        )
+        .unwrap();
+
+        assert!(db
+            .execute("INSERT INTO foo (bar) VALUES ('baz')", [])
+            .is_ok());
+    }
+
+    #[test]
+    fn retire_connection_in_transaction() {
+        let env = AppEnv::test_env();
+        setup_foo(&env);
+
+        let pool = ConnectionPool::new(1, 8);
+
+        let db = Database::open_pooled(
+            &env,
+            &pool,
+            AppEnv::TEST_DB_NAME.to_owned(),
+            DatabasePermissions::Full,
+        )
+        .unwrap();
+        db.execute_batch("BEGIN").unwrap();
+        drop(db);
+
+        assert_eq!(pool.idle_count(AppEnv::TEST_DB_NAME), 0);
+    }
+
+    #[test]
+    fn retire_connection_with_session_state() {
+        let env = AppEnv::test_env();
+        setup_foo(&env);
+
+        let pool = ConnectionPool::new(1, 8);
+
+        for sql in [
+            "CREATE TEMP TABLE scratch (val TEXT)",
+            "ATTACH ':memory:' AS other",
+        ] {
+            let db = Database::open_pooled(
+                &env,
+                &pool,
+                AppEnv::TEST_DB_NAME.to_owned(),
+                DatabasePermissions::Full,
+            )
+            .unwrap();
+            db.execute_batch(sql).unwrap();
+            drop(db);
+
+            assert_eq!(pool.idle_count(AppEnv::TEST_DB_NAME), 0, "{}", sql);
+        }
+    }
+
+    #[test]
+    fn reset_session_pragmas() {
+        let env = AppEnv::test_env();
+        setup_foo(&env);
+
+        let pool = ConnectionPool::new(1, 8);
+
+        let db = Database::open_pooled(
+            &env,
+            &pool,
+            AppEnv::TEST_DB_NAME.to_owned(),
+            DatabasePermissions::Full,
+        )
+        .unwrap();
+        db.execute_batch("PRAGMA query_only=1").unwrap();
+        drop(db);
+
+        assert_eq!(pool.idle_count(AppEnv::TEST_DB_NAME), 1);
+
+        let db = Database::open_pooled(
+            &env,
+            &pool,
+            AppEnv::TEST_DB_NAME.to_owned(),
+            DatabasePermissions::Full,
+        )
+        .unwrap();
+
+        assert!(db
+            .execute("INSERT INTO foo (bar) VALUES ('baz')", [])
+            .is_ok());
+    }
+
+    #[test]
+    fn limit_idle_connections_overall() {
+        let env = AppEnv::test_env();
+        setup_foo(&env);
+
+        let pool = ConnectionPool::new(1, 1);
+
+        let db = Database::open_pooled(
+            &env,
+            &pool,
+            AppEnv::TEST_DB_NAME.to_owned(),
+            DatabasePermissions::Full,
+        )
+        .unwrap();
+        let other =
+            Database::open_pooled(&env, &pool, "other".to_owned(), DatabasePermissions::Full)
+                .unwrap();
+
+        drop(db);
+        drop(other);
+
+        assert_eq!(
+            pool.idle_count(AppEnv::TEST_DB_NAME),
+            0,
+            "The longest idle connection makes room"
+        );
+        assert_eq!(pool.idle_count("other"), 1);
+    }
+
+    /// run with `cargo test compare_open_latency -- --ignored --nocapture` to compare
+    /// opening a fresh connection with checking one out of the pool
+    #[test]
+    #[ignore]
+    fn compare_open_latency() {
+        const ROUNDS: u32 = 200;
+
+        let env = AppEnv::test_env();
+        setup_foo(&env);
+
+        let start = Instant::now();
+        for _ in 0..ROUNDS {
+            Database::open(
+                &env,
+                AppEnv::TEST_DB_NAME.to_owned(),
+                DatabasePermissions::Full,
+            )
+            .unwrap();
+        }
+        let unpooled = start.elapsed() / ROUNDS;
+
+        let pool = ConnectionPool::new(1, 8);
+        let start = Instant::now();
+        for _ in 0..ROUNDS {
+            Database::open_pooled(
+                &env,
+                &pool,
+                AppEnv::TEST_DB_NAME.to_owned(),
+                DatabasePermissions::Full,
+            )
+            .unwrap();
+        }
+        let pooled = start.elapsed() / ROUNDS;
+
+        println!("open: {:?}, pooled: {:?}", unpooled, pooled);
+    }
+}
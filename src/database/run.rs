@@ -1,3 +1,5 @@
+use std::time::Instant;
+
 use axum::{
     extract::{Path, State},
     Json,
@@ -5,7 +7,11 @@ use axum::{
 use rusqlite::params_from_iter;
 use serde::{Deserialize, Serialize};
 
-use crate::{app_state::AppState, auth::DatabasePermissions, error::CRRError};
+use crate::{
+    app_state::{AppEnv, AppState},
+    auth::DatabasePermissions,
+    error::CRRError,
+};
 
 use super::{Database, Value};
 
@@ -14,88 +20,247 @@ pub(crate) struct RunPostData {
     sql: String,
     params: Vec<Value>,
     method: String,
+    /// names of columns whose Text values are returned as parsed JSON
+    #[serde(default)]
+    parse_json_columns: Vec<String>,
+    /// collect the rows of a `RETURNING` clause for the `run` method
+    #[serde(default)]
+    returning: bool,
+    /// also return the names of the result columns
+    #[serde(default)]
+    with_columns: bool,
+}
+
+#[cfg(test)]
+impl RunPostData {
+    pub(crate) fn new(sql: &str, method: &str) -> Self {
+        Self {
+            sql: sql.to_owned(),
+            params: Vec::new(),
+            method: method.to_owned(),
+            parse_json_columns: Vec::new(),
+            returning: false,
+            with_columns: false,
+        }
+    }
 }
 
 #[derive(Serialize)]
 pub(crate) struct RunPostResponse {
     rows: Vec<Vec<Value>>,
     changes: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    columns: Option<Vec<String>>,
 }
 
+#[derive(Deserialize)]
+pub(crate) struct RunBatchPostData(Vec<RunPostData>);
+
 pub(crate) async fn post_run(
     Path(db_name): Path<String>,
     State(state): State<AppState>,
     permissions: DatabasePermissions,
     Json(data): Json<RunPostData>,
 ) -> Result<axum::Json<RunPostResponse>, CRRError> {
-    let db = Database::open(&state.env(), db_name.clone(), permissions)?;
+    let db = Database::open_pooled(state.env(), state.database_pool(), db_name, permissions)?;
+    db.set_query_timeout(state.env().query_timeout());
 
-    let mut stmt = db.prepare(&data.sql)?;
-    let column_count = stmt.column_count();
+    Ok(axum::Json(run_timed_statement(&db, state.env(), data)?))
+}
+
+pub(crate) async fn post_run_batch(
+    Path(db_name): Path<String>,
+    State(state): State<AppState>,
+    permissions: DatabasePermissions,
+    Json(RunBatchPostData(statements)): Json<RunBatchPostData>,
+) -> Result<axum::Json<Vec<RunPostResponse>>, CRRError> {
+    let mut db = Database::open_pooled(state.env(), state.database_pool(), db_name, permissions)?;
+    db.set_query_timeout(state.env().query_timeout());
+
+    let run_all = |conn: &rusqlite::Connection| {
+        statements
+            .into_iter()
+            .map(|data| run_timed_statement(conn, state.env(), data))
+            .collect::<Result<Vec<RunPostResponse>, CRRError>>()
+    };
+
+    // savepoints need write access, read-only batches have nothing to roll back
+    // and still see a single snapshot in a plain transaction
+    let responses = if db.permissions().write() {
+        let savepoint = db.savepoint()?;
+        let responses = run_all(&savepoint)?;
+        savepoint.commit()?;
+        responses
+    } else {
+        let transaction = db.transaction()?;
+        let responses = run_all(&transaction)?;
+        transaction.commit()?;
+        responses
+    };
+
+    Ok(axum::Json(responses))
+}
+
+/// Logs statements running longer than `CRR_SLOW_QUERY_MS`,
+/// bound params are redacted unless `CRR_SLOW_QUERY_LOG_PARAMS` is set
+fn run_timed_statement(
+    conn: &rusqlite::Connection,
+    env: &AppEnv,
+    data: RunPostData,
+) -> Result<RunPostResponse, CRRError> {
+    let threshold = match env.slow_query_threshold() {
+        Some(threshold) => threshold,
+        None => return run_statement(conn, data),
+    };
+
+    let sql = data.sql.clone();
+    let params = env.log_query_params().then(|| data.params.clone());
+
+    let start = Instant::now();
+    let result = run_statement(conn, data);
+    let elapsed = start.elapsed();
+
+    if elapsed >= threshold {
+        match params {
+            Some(params) => tracing::warn!(
+                "Slow query took {}ms: {} with params {:?}",
+                elapsed.as_millis(),
+                sql,
+                params
+            ),
+            None => tracing::warn!(
+                "Slow query took {}ms: {} with params <redacted>",
+                elapsed.as_millis(),
+                sql
+            ),
+        }
+    }
+
+    result
+}
+
+fn run_statement(
+    conn: &rusqlite::Connection,
+    data: RunPostData,
+) -> Result<RunPostResponse, CRRError> {
+    execute_statement(conn, data).map_err(|error| match error {
+        CRRError::DatabaseError(rusqlite::Error::SqliteFailure(failure, _), _)
+            if failure.code == rusqlite::ErrorCode::OperationInterrupted =>
+        {
+            CRRError::QueryTimeout
+        }
+        CRRError::DatabaseError(rusqlite::Error::SqliteFailure(failure, _), _)
+            if failure.code == rusqlite::ErrorCode::AuthorizationForStatementDenied =>
+        {
+            CRRError::Unauthorized("User is not authorized to run this statement".to_owned())
+        }
+        error => error,
+    })
+}
+
+fn execute_statement(
+    conn: &rusqlite::Connection,
+    data: RunPostData,
+) -> Result<RunPostResponse, CRRError> {
+    let mut stmt = conn.prepare(&data.sql)?;
+    let json_columns: Vec<bool> = stmt
+        .column_names()
+        .into_iter()
+        .map(|name| data.parse_json_columns.iter().any(|column| column == name))
+        .collect();
+    let columns = data.with_columns.then(|| {
+        stmt.column_names()
+            .into_iter()
+            .map(str::to_owned)
+            .collect::<Vec<String>>()
+    });
 
     tracing::debug!("{} {}", &data.method, &data.sql);
 
     match &data.method[..] {
+        "run" if data.returning => {
+            let mut raw_rows = stmt.query(params_from_iter(data.params.into_iter()))?;
+            let mut rows = Vec::new();
+
+            while let Some(raw_row) = raw_rows.next()? {
+                rows.push(read_row(raw_row, &json_columns)?);
+            }
+            drop(raw_rows);
+
+            Ok(RunPostResponse {
+                rows,
+                changes: Some(conn.changes() as usize),
+                columns,
+            })
+        }
         "run" => {
             let affected_rows = stmt.execute(params_from_iter(data.params.into_iter()))?;
 
-            Ok(axum::Json(RunPostResponse {
+            Ok(RunPostResponse {
                 rows: Vec::new(),
                 changes: Some(affected_rows),
-            }))
+                columns,
+            })
         }
         "get" => {
-            let row: Vec<Value> =
-                stmt.query_row(params_from_iter(data.params.into_iter()), |raw_row| {
-                    let mut row = Vec::new();
-
-                    for i in 0..column_count {
-                        row.push(raw_row.get(i)?);
-                    }
-
-                    Ok(row)
+            let row: Vec<Value> = stmt
+                .query_row(params_from_iter(data.params.into_iter()), |raw_row| {
+                    read_row(raw_row, &json_columns)
                 })?;
 
-            Ok(axum::Json(RunPostResponse {
+            Ok(RunPostResponse {
                 rows: vec![row],
                 changes: None,
-            }))
+                columns,
+            })
         }
         _ => {
             let mut raw_rows = stmt.query(params_from_iter(data.params.into_iter()))?;
             let mut rows = Vec::new();
 
             while let Some(raw_row) = raw_rows.next()? {
-                let mut row = Vec::with_capacity(column_count);
-
-                for i in 0..column_count {
-                    row.push(raw_row.get(i)?);
-                }
-
-                rows.push(row);
+                rows.push(read_row(raw_row, &json_columns)?);
             }
 
-            Ok(axum::Json(RunPostResponse {
+            Ok(RunPostResponse {
                 rows,
                 changes: None,
-            }))
+                columns,
+            })
         }
     }
 }
 
+fn read_row(raw_row: &rusqlite::Row, json_columns: &[bool]) -> rusqlite::Result<Vec<Value>> {
+    let mut row = Vec::with_capacity(json_columns.len());
+
+    for (i, is_json) in json_columns.iter().enumerate() {
+        let value: Value = raw_row.get(i)?;
+
+        row.push(if *is_json { value.into_json() } else { value });
+    }
+
+    Ok(row)
+}
+
 #[cfg(test)]
 mod tests {
+    use std::{collections::HashMap, time::Duration};
+
     use axum::{
         extract::{Path, State},
         Json,
     };
+    use tracing_test::traced_test;
 
     use crate::{
         app_state::{AppEnv, AppState},
-        auth::DatabasePermissions,
+        auth::{DatabasePermissions, PartialPermissions},
+        database::{migrate::tests::setup_foo, Value},
+        error::CRRError,
     };
 
-    use super::{post_run, RunPostData};
+    use super::{post_run, post_run_batch, run_statement, RunBatchPostData, RunPostData};
 
     #[tokio::test]
     async fn post_create_table() {
@@ -105,11 +270,10 @@ mod tests {
             Path(AppEnv::TEST_DB_NAME.to_string()),
             State(state.clone()),
             DatabasePermissions::Full,
-            Json(RunPostData {
-                sql: "CREATE TABLE test (val TEXT PRIMARY KEY)".to_owned(),
-                params: Vec::new(),
-                method: "run".to_owned(),
-            }),
+            Json(RunPostData::new(
+                "CREATE TABLE test (val TEXT PRIMARY KEY)",
+                "run",
+            )),
         )
         .await
         .unwrap();
@@ -117,4 +281,339 @@ mod tests {
         assert!(res.rows.is_empty());
         assert_eq!(res.changes, Some(1));
     }
+
+    #[tokio::test]
+    async fn post_batch() {
+        let state = AppState::test_state();
+        setup_foo(state.env());
+
+        let Json(res) = post_run_batch(
+            Path(AppEnv::TEST_DB_NAME.to_string()),
+            State(state.clone()),
+            DatabasePermissions::Full,
+            Json(RunBatchPostData(vec![
+                RunPostData::new("INSERT INTO foo (bar) VALUES ('a')", "run"),
+                RunPostData::new("SELECT bar FROM foo", "all"),
+            ])),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(res.len(), 2);
+        assert_eq!(res[0].changes, Some(1));
+        assert_eq!(res[1].rows, vec![vec![Value::Text("a".to_owned())]]);
+
+        assert!(post_run_batch(
+            Path(AppEnv::TEST_DB_NAME.to_string()),
+            State(state.clone()),
+            DatabasePermissions::Full,
+            Json(RunBatchPostData(vec![
+                RunPostData::new("INSERT INTO foo (bar) VALUES ('b')", "run"),
+                RunPostData::new("INSERT INTO does_not_exist (bar) VALUES ('c')", "run"),
+            ])),
+        )
+        .await
+        .is_err());
+
+        let count: i64 = state
+            .env()
+            .test_db()
+            .query_row("SELECT COUNT(*) FROM foo", [], |row| row.get(0))
+            .unwrap();
+
+        assert_eq!(count, 1, "Failed batch has been rolled back");
+    }
+
+    #[tokio::test]
+    async fn deny_savepoints_without_write_access() {
+        let state = AppState::test_state();
+        setup_foo(state.env());
+
+        let read_only = || DatabasePermissions::Partial {
+            database: PartialPermissions {
+                read: true,
+                insert: false,
+                update: false,
+                delete: false,
+            },
+            tables: HashMap::new(),
+        };
+
+        assert!(matches!(
+            post_run(
+                Path(AppEnv::TEST_DB_NAME.to_string()),
+                State(state.clone()),
+                read_only(),
+                Json(RunPostData::new("SAVEPOINT dangling", "run")),
+            )
+            .await,
+            Err(CRRError::Unauthorized(_))
+        ));
+
+        let Json(res) = post_run_batch(
+            Path(AppEnv::TEST_DB_NAME.to_string()),
+            State(state),
+            read_only(),
+            Json(RunBatchPostData(vec![RunPostData::new(
+                "SELECT bar FROM foo",
+                "all",
+            )])),
+        )
+        .await
+        .expect("Read-only batches don't need a savepoint");
+
+        assert_eq!(res.len(), 1);
+    }
+
+    #[test]
+    fn abort_runaway_query() {
+        let env = AppEnv::test_env();
+        let db = env.test_db();
+
+        db.set_query_timeout(Duration::from_millis(100));
+
+        let result = run_statement(
+            &db,
+            RunPostData::new(
+                "
+                    WITH RECURSIVE counter(x) AS (SELECT 1 UNION ALL SELECT x + 1 FROM counter)
+                    SELECT COUNT(*) FROM counter
+                ",
+                "get",
+            ),
+        );
+
+        assert!(matches!(result, Err(CRRError::QueryTimeout)));
+    }
+
+    #[tokio::test]
+    async fn parse_json_columns() {
+        let state = AppState::test_state();
+
+        let Json(res) = post_run(
+            Path(AppEnv::TEST_DB_NAME.to_string()),
+            State(state),
+            DatabasePermissions::Full,
+            Json(RunPostData {
+                parse_json_columns: vec!["obj".to_owned()],
+                ..RunPostData::new(
+                    "
+                    SELECT json_object('a', 1, 'b', json_array(2, 'c')) AS obj,
+                        json_object('d', 3) AS raw
+                ",
+                    "all",
+                )
+            }),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(
+            serde_json::to_value(&res.rows).unwrap(),
+            serde_json::json!([[{ "a": 1, "b": [2, "c"] }, "{\"d\":3}"]])
+        );
+    }
+
+    #[tokio::test]
+    async fn return_inserted_ids() {
+        let state = AppState::test_state();
+
+        // crr tables' triggers need more than insert permissions
+        state
+            .env()
+            .test_db()
+            .execute("CREATE TABLE plain (id INTEGER PRIMARY KEY, bar TEXT)", [])
+            .unwrap();
+
+        let permissions = DatabasePermissions::Partial {
+            database: PartialPermissions {
+                read: true,
+                insert: true,
+                update: false,
+                delete: false,
+            },
+            tables: HashMap::new(),
+        };
+
+        let Json(res) = post_run(
+            Path(AppEnv::TEST_DB_NAME.to_string()),
+            State(state.clone()),
+            permissions,
+            Json(RunPostData {
+                returning: true,
+                ..RunPostData::new(
+                    "INSERT INTO plain (bar) VALUES ('a'), ('b') RETURNING id",
+                    "run",
+                )
+            }),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(res.changes, Some(2));
+        assert_eq!(
+            res.rows,
+            vec![vec![Value::Integer(1)], vec![Value::Integer(2)]]
+        );
+
+        assert!(
+            post_run(
+                Path(AppEnv::TEST_DB_NAME.to_string()),
+                State(state),
+                DatabasePermissions::Partial {
+                    database: PartialPermissions {
+                        read: true,
+                        insert: false,
+                        update: false,
+                        delete: false,
+                    },
+                    tables: HashMap::new(),
+                },
+                Json(RunPostData {
+                    returning: true,
+                    ..RunPostData::new("INSERT INTO plain (bar) VALUES ('c') RETURNING id", "run")
+                }),
+            )
+            .await
+            .is_err(),
+            "Authorizer still denies the insert"
+        );
+    }
+
+    #[tokio::test]
+    async fn deny_schema_changes_without_full_access() {
+        let state = AppState::test_state();
+        setup_foo(state.env());
+
+        let create_table = |permissions: DatabasePermissions| {
+            post_run(
+                Path(AppEnv::TEST_DB_NAME.to_string()),
+                State(state.clone()),
+                permissions,
+                Json(RunPostData::new(
+                    "CREATE TABLE other (val TEXT PRIMARY KEY)",
+                    "run",
+                )),
+            )
+        };
+
+        let result = create_table(DatabasePermissions::Partial {
+            database: PartialPermissions {
+                read: true,
+                insert: true,
+                update: true,
+                delete: true,
+            },
+            tables: HashMap::new(),
+        })
+        .await;
+
+        assert!(matches!(result, Err(CRRError::Unauthorized(_))));
+
+        create_table(DatabasePermissions::Full)
+            .await
+            .expect("Owners can change the schema");
+    }
+
+    #[tokio::test]
+    async fn hide_denied_columns() {
+        let state = AppState::test_state();
+
+        let mut db = state.env().test_db();
+        db.apply_migration(
+            vec![
+                "CREATE TABLE \"people\" (id INTEGER PRIMARY KEY, name TEXT, secret TEXT)"
+                    .to_string(),
+            ],
+            Vec::new(),
+        )
+        .unwrap();
+        db.execute(
+            "INSERT INTO people (id, name, secret) VALUES (1, 'a', 's')",
+            [],
+        )
+        .unwrap();
+
+        let mut permissions = DatabasePermissions::default();
+        permissions.set(PartialPermissions {
+            read: true,
+            ..Default::default()
+        });
+        permissions.deny_column("people".to_owned(), "secret".to_owned());
+
+        let select = |sql: &str| {
+            post_run(
+                Path(AppEnv::TEST_DB_NAME.to_string()),
+                State(state.clone()),
+                permissions.clone(),
+                Json(RunPostData::new(sql, "all")),
+            )
+        };
+
+        let Json(res) = select("SELECT name, secret FROM people").await.unwrap();
+        assert_eq!(
+            res.rows,
+            vec![vec![Value::Text("a".to_owned()), Value::Null]]
+        );
+
+        let Json(res) = select("SELECT name FROM people WHERE secret = 's'")
+            .await
+            .unwrap();
+        assert!(res.rows.is_empty(), "Denied columns can't be probed");
+    }
+
+    #[tokio::test]
+    async fn return_column_names() {
+        let state = AppState::test_state();
+
+        let run = |with_columns: bool| {
+            post_run(
+                Path(AppEnv::TEST_DB_NAME.to_string()),
+                State(state.clone()),
+                DatabasePermissions::Full,
+                Json(RunPostData {
+                    with_columns,
+                    ..RunPostData::new("SELECT 1 AS a, 2 AS b", "all")
+                }),
+            )
+        };
+
+        let Json(res) = run(true).await.unwrap();
+
+        assert_eq!(res.columns, Some(vec!["a".to_owned(), "b".to_owned()]));
+        assert_eq!(res.rows, vec![vec![Value::Integer(1), Value::Integer(2)]]);
+
+        let Json(res) = run(false).await.unwrap();
+
+        assert_eq!(res.columns, None, "Rows stay positional by default");
+    }
+
+    #[traced_test]
+    #[tokio::test]
+    async fn log_slow_query() {
+        let env = AppEnv::with_slow_query_threshold(AppEnv::test_env(), Duration::from_millis(1));
+        let state = AppState::test_state().with_env(env);
+
+        post_run(
+            Path(AppEnv::TEST_DB_NAME.to_string()),
+            State(state),
+            DatabasePermissions::Full,
+            Json(RunPostData {
+                params: vec![Value::Integer(1_000_000)],
+                ..RunPostData::new(
+                    "
+                    WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < ?)
+                    SELECT COUNT(*) FROM n
+                ",
+                    "get",
+                )
+            }),
+        )
+        .await
+        .unwrap();
+
+        assert!(logs_contain("Slow query took"));
+        assert!(logs_contain("with params <redacted>"));
+        assert!(!logs_contain("1000000"));
+    }
 }
@@ -0,0 +1,86 @@
+use axum::extract::{Json, Path, State};
+use serde::Serialize;
+
+use crate::{app_state::AppState, auth::DatabasePermissions, error::CRRError};
+
+use super::Database;
+
+#[derive(Serialize)]
+pub(crate) struct SiteIdResponse {
+    #[serde(with = "crate::serde_base64")]
+    site_id: Vec<u8>,
+}
+
+/// The site id of the server's copy of a database, clients use it to
+/// recognize changes that originated on the server.
+pub(crate) async fn get_site_id(
+    Path(db_name): Path<String>,
+    State(state): State<AppState>,
+    permissions: DatabasePermissions,
+) -> Result<Json<SiteIdResponse>, CRRError> {
+    permissions.require_read()?;
+
+    let mut db = Database::open_pooled(state.env(), state.database_pool(), db_name, permissions)?;
+
+    let site_id = db.disable_authorization().site_id()?;
+
+    Ok(Json(SiteIdResponse { site_id }))
+}
+
+impl Database {
+    pub(crate) fn site_id(&self) -> Result<Vec<u8>, CRRError> {
+        Ok(self.query_row("SELECT crsql_siteid()", [], |row| row.get(0))?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use axum::extract::{Json, Path, State};
+
+    use super::get_site_id;
+    use crate::{
+        app_state::{AppEnv, AppState},
+        auth::{DatabasePermissions, PartialPermissions},
+        database::migrate::tests::setup_foo,
+    };
+
+    #[tokio::test]
+    async fn return_stable_site_id() {
+        let state = AppState::test_state();
+        setup_foo(state.env());
+
+        let permissions = DatabasePermissions::Partial {
+            database: PartialPermissions {
+                read: true,
+                insert: false,
+                update: false,
+                delete: false,
+            },
+            tables: HashMap::new(),
+        };
+
+        let get = || {
+            get_site_id(
+                Path(AppEnv::TEST_DB_NAME.to_owned()),
+                State(state.clone()),
+                permissions.clone(),
+            )
+        };
+
+        let Json(first) = get().await.unwrap();
+        let Json(second) = get().await.unwrap();
+
+        assert_eq!(first.site_id.len(), 16);
+        assert_eq!(first.site_id, second.site_id);
+
+        assert!(get_site_id(
+            Path(AppEnv::TEST_DB_NAME.to_owned()),
+            State(state),
+            DatabasePermissions::default(),
+        )
+        .await
+        .is_err());
+    }
+}
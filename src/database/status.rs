@@ -0,0 +1,108 @@
+use axum::extract::{Json, Path, State};
+use serde::Serialize;
+
+use crate::{app_state::AppState, auth::DatabasePermissions, error::CRRError};
+
+use super::Database;
+
+#[derive(Serialize)]
+pub(crate) struct StatusResponse {
+    db_version: i64,
+    schema_version: i64,
+    #[serde(with = "crate::serde_base64")]
+    site_id: Vec<u8>,
+    table_count: i64,
+}
+
+/// Everything a client needs to assess its sync state before opening a change stream
+pub(crate) async fn get_status(
+    Path(db_name): Path<String>,
+    State(state): State<AppState>,
+    permissions: DatabasePermissions,
+) -> Result<Json<StatusResponse>, CRRError> {
+    permissions.require_read()?;
+
+    let mut db = Database::open_pooled(state.env(), state.database_pool(), db_name, permissions)?;
+
+    Ok(Json(db.status()?))
+}
+
+impl Database {
+    pub(crate) fn status(&mut self) -> Result<StatusResponse, CRRError> {
+        let authorized = self.disable_authorization();
+
+        // crr tables are the ones crsqlite keeps a clock table for
+        Ok(authorized.query_row(
+            "
+            SELECT
+                crsql_dbversion(),
+                (SELECT COALESCE(MAX(version), 0) FROM crr_server_migrations),
+                crsql_siteid(),
+                (
+                    SELECT COUNT(*) FROM sqlite_master
+                    WHERE type = 'table' AND name LIKE '%\\_\\_crsql\\_clock' ESCAPE '\\'
+                )
+        ",
+            [],
+            |row| {
+                Ok(StatusResponse {
+                    db_version: row.get(0)?,
+                    schema_version: row.get(1)?,
+                    site_id: row.get(2)?,
+                    table_count: row.get(3)?,
+                })
+            },
+        )?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::extract::{Json, Path, State};
+
+    use super::get_status;
+    use crate::{
+        app_state::{AppEnv, AppState},
+        auth::DatabasePermissions,
+        database::migrate::tests::setup_foo,
+    };
+
+    #[tokio::test]
+    async fn report_db_version() {
+        let state = AppState::test_state();
+        setup_foo(state.env());
+
+        let get = || {
+            get_status(
+                Path(AppEnv::TEST_DB_NAME.to_owned()),
+                State(state.clone()),
+                DatabasePermissions::Full,
+            )
+        };
+
+        let Json(before) = get().await.unwrap();
+
+        for bar in ["a", "b"] {
+            state
+                .env()
+                .test_db()
+                .execute("INSERT INTO foo (bar) VALUES (?)", [bar])
+                .unwrap();
+        }
+
+        let Json(after) = get().await.unwrap();
+
+        assert_eq!(after.db_version, before.db_version + 2);
+        assert_eq!(after.schema_version, 1);
+        assert_eq!(after.table_count, 1);
+        assert_eq!(after.site_id.len(), 16);
+
+        assert!(get_status(
+            Path(AppEnv::TEST_DB_NAME.to_owned()),
+            State(state),
+            DatabasePermissions::default(),
+        )
+        .await
+        .is_err());
+    }
+}
@@ -0,0 +1,378 @@
+use std::collections::HashMap;
+
+use axum::extract::{Json, Path, State};
+use serde::Serialize;
+
+use crate::{app_state::AppState, auth::DatabasePermissions, error::CRRError};
+
+use super::{Database, Value};
+
+#[derive(Serialize, Debug, PartialEq)]
+pub(crate) struct TableVersion {
+    name: String,
+    db_version: i64,
+}
+
+/// The latest `db_version` of every readable table with changes,
+/// so clients can tell which tables changed without scanning the changes.
+pub(crate) async fn get_tables(
+    Path(db_name): Path<String>,
+    State(state): State<AppState>,
+    permissions: DatabasePermissions,
+) -> Result<Json<Vec<TableVersion>>, CRRError> {
+    permissions.require_read()?;
+
+    let mut db = Database::open_pooled(state.env(), state.database_pool(), db_name, permissions)?;
+
+    Ok(Json(db.table_versions()?))
+}
+
+/// The current row with primary key `pk` as an object of column names and values,
+/// compound primary keys are passed as quoted values joined by `|`, e.g. `1|'a'`
+pub(crate) async fn get_row(
+    Path((db_name, table, pk)): Path<(String, String, String)>,
+    State(state): State<AppState>,
+    permissions: DatabasePermissions,
+) -> Result<Json<HashMap<String, Value>>, CRRError> {
+    let mut db = Database::open_pooled(state.env(), state.database_pool(), db_name, permissions)?;
+
+    Ok(Json(db.row(&table, &pk)?))
+}
+
+impl Database {
+    pub(crate) fn row(
+        &mut self,
+        table: &str,
+        pk: &str,
+    ) -> Result<HashMap<String, Value>, CRRError> {
+        // before looking at the schema, which would tell apart tables that don't exist
+        if !self.permissions().read_table(table) {
+            return Err(CRRError::Unauthorized(format!(
+                "User is not authorized to read table \"{}\"",
+                table
+            )));
+        }
+
+        let pk_columns = {
+            let authorized = self.disable_authorization();
+            let mut stmt = authorized
+                .prepare("SELECT name FROM pragma_table_info(?) WHERE pk > 0 ORDER BY pk")?;
+            let pk_columns = stmt
+                .query_map([table], |row| row.get::<_, String>(0))?
+                .collect::<Result<Vec<String>, rusqlite::Error>>()?;
+
+            pk_columns
+        };
+
+        if pk_columns.is_empty() {
+            return Err(CRRError::BadRequest(format!(
+                "Table \"{}\" does not exist",
+                table
+            )));
+        }
+
+        // a single column pk is bound as is and left to column affinity,
+        // compound pks are quoted literals joined by `|` like in crsql_changes
+        let pk_values = match &pk_columns[..] {
+            [_] => vec![rusqlite::types::Value::Text(pk.to_owned())],
+            _ => Value::Text(pk.to_owned()).pk_values()?,
+        };
+
+        if pk_values.len() != pk_columns.len() {
+            return Err(CRRError::BadRequest(format!(
+                "Primary key of \"{}\" has {} columns, got {} values",
+                table,
+                pk_columns.len(),
+                pk_values.len()
+            )));
+        }
+
+        let condition = pk_columns
+            .iter()
+            .map(|column| format!("\"{}\" = ?", column.replace('"', "\"\"")))
+            .collect::<Vec<String>>()
+            .join(" AND ");
+
+        // runs with the authorizer so only readable tables and columns can be selected
+        let mut stmt = self
+            .prepare(&format!(
+                "SELECT * FROM \"{}\" WHERE {}",
+                table.replace('"', "\"\""),
+                condition
+            ))
+            .map_err(|error| match error {
+                rusqlite::Error::SqliteFailure(failure, _)
+                    if failure.code == rusqlite::ErrorCode::AuthorizationForStatementDenied =>
+                {
+                    CRRError::Unauthorized(format!(
+                        "User is not authorized to read table \"{}\"",
+                        table
+                    ))
+                }
+                error => error.into(),
+            })?;
+
+        let columns: Vec<String> = stmt.column_names().into_iter().map(str::to_owned).collect();
+        let mut rows = stmt.query(rusqlite::params_from_iter(pk_values))?;
+
+        let row = rows.next()?.ok_or_else(|| {
+            CRRError::NotFound(format!("No row with primary key {} in \"{}\"", pk, table))
+        })?;
+
+        columns
+            .iter()
+            .enumerate()
+            .map(|(index, column)| Ok::<_, CRRError>((column.clone(), row.get(index)?)))
+            .collect()
+    }
+
+    pub(crate) fn table_versions(&mut self) -> Result<Vec<TableVersion>, CRRError> {
+        let permissions = self.permissions().clone();
+        let authorized = self.disable_authorization();
+
+        let mut stmt = authorized.prepare(
+            "
+            SELECT \"table\", MAX(db_version)
+            FROM crsql_changes
+            GROUP BY \"table\"
+            ORDER BY \"table\"
+        ",
+        )?;
+
+        let tables = stmt
+            .query_map([], |row| {
+                Ok(TableVersion {
+                    name: row.get(0)?,
+                    db_version: row.get(1)?,
+                })
+            })?
+            .filter(|table| match table {
+                Ok(table) => permissions.read_table(&table.name),
+                Err(_) => true,
+            })
+            .collect::<Result<Vec<TableVersion>, rusqlite::Error>>()?;
+
+        Ok(tables)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use axum::extract::{Json, Path, State};
+
+    use super::{get_row, get_tables, TableVersion};
+    use crate::{
+        app_state::{AppEnv, AppState},
+        auth::{DatabasePermissions, ObjectPermissions, PartialPermissions},
+        database::{migrate::tests::setup_foo, Value},
+        error::CRRError,
+    };
+
+    #[tokio::test]
+    async fn fetch_row_by_pk() {
+        let state = AppState::test_state();
+        setup_foo(state.env());
+
+        state
+            .env()
+            .test_db()
+            .execute("INSERT INTO foo (id, bar) VALUES (7, 'baz')", [])
+            .unwrap();
+
+        let Json(row) = get_row(
+            Path((
+                AppEnv::TEST_DB_NAME.to_owned(),
+                "foo".to_owned(),
+                "7".to_owned(),
+            )),
+            State(state.clone()),
+            DatabasePermissions::Full,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(row["id"], Value::Integer(7));
+        assert_eq!(row["bar"], Value::Text("baz".to_owned()));
+
+        assert!(matches!(
+            get_row(
+                Path((
+                    AppEnv::TEST_DB_NAME.to_owned(),
+                    "foo".to_owned(),
+                    "8".to_owned(),
+                )),
+                State(state.clone()),
+                DatabasePermissions::Full,
+            )
+            .await,
+            Err(CRRError::NotFound(_))
+        ));
+
+        assert!(matches!(
+            get_row(
+                Path((
+                    AppEnv::TEST_DB_NAME.to_owned(),
+                    "foo".to_owned(),
+                    "7".to_owned(),
+                )),
+                State(state),
+                DatabasePermissions::default(),
+            )
+            .await,
+            Err(CRRError::Unauthorized(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn fetch_row_by_compound_pk() {
+        let state = AppState::test_state();
+
+        state
+            .env()
+            .test_db()
+            .apply_migration(
+                vec![
+                    "CREATE TABLE \"pairs\" (a INTEGER NOT NULL, b TEXT NOT NULL, val TEXT, PRIMARY KEY (a, b))".to_string(),
+                ],
+                Vec::new(),
+            )
+            .unwrap();
+
+        state
+            .env()
+            .test_db()
+            .execute(
+                "INSERT INTO pairs (a, b, val) VALUES (1, 'x|''y', 'first'), (1, 'z', 'second')",
+                [],
+            )
+            .unwrap();
+
+        let fetch = |pk: &str| {
+            get_row(
+                Path((
+                    AppEnv::TEST_DB_NAME.to_owned(),
+                    "pairs".to_owned(),
+                    pk.to_owned(),
+                )),
+                State(state.clone()),
+                DatabasePermissions::Full,
+            )
+        };
+
+        let Json(row) = fetch("1|'x|''y'").await.unwrap();
+        assert_eq!(row["a"], Value::Integer(1));
+        assert_eq!(row["b"], Value::Text("x|'y".to_owned()));
+        assert_eq!(row["val"], Value::Text("first".to_owned()));
+
+        let Json(row) = fetch("1|'z'").await.unwrap();
+        assert_eq!(row["val"], Value::Text("second".to_owned()));
+
+        assert!(matches!(fetch("2|'z'").await, Err(CRRError::NotFound(_))));
+        assert!(matches!(fetch("1").await, Err(CRRError::BadRequest(_))));
+        assert!(matches!(
+            fetch("1|'z'|3").await,
+            Err(CRRError::BadRequest(_))
+        ));
+        assert!(matches!(fetch("1|'z").await, Err(CRRError::BadRequest(_))));
+    }
+
+    #[tokio::test]
+    async fn hide_schema_of_unreadable_tables() {
+        let state = AppState::test_state();
+        setup_foo(state.env());
+
+        let result = get_row(
+            Path((
+                AppEnv::TEST_DB_NAME.to_owned(),
+                "missing".to_owned(),
+                "1".to_owned(),
+            )),
+            State(state),
+            DatabasePermissions::default(),
+        )
+        .await;
+
+        assert!(
+            matches!(result, Err(CRRError::Unauthorized(_))),
+            "Missing tables can't be told apart from unreadable ones"
+        );
+    }
+
+    #[tokio::test]
+    async fn report_latest_version_per_table() {
+        let state = AppState::test_state();
+
+        state
+            .env()
+            .test_db()
+            .apply_migration(
+                vec![
+                    "CREATE TABLE \"foo\" (id INTEGER PRIMARY KEY, val TEXT)".to_string(),
+                    "CREATE TABLE \"bar\" (id INTEGER PRIMARY KEY, val TEXT)".to_string(),
+                ],
+                Vec::new(),
+            )
+            .unwrap();
+
+        let insert = |table: &str| {
+            let db = state.env().test_db();
+            db.execute(&format!("INSERT INTO {} (val) VALUES ('a')", table), [])
+                .unwrap();
+            db.query_row("SELECT crsql_dbversion()", [], |row| row.get::<_, i64>(0))
+                .unwrap()
+        };
+
+        insert("foo");
+        let bar_version = insert("bar");
+        let foo_version = insert("foo");
+
+        let Json(tables) = get_tables(
+            Path(AppEnv::TEST_DB_NAME.to_owned()),
+            State(state.clone()),
+            DatabasePermissions::Full,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(
+            tables,
+            vec![
+                TableVersion {
+                    name: "bar".to_owned(),
+                    db_version: bar_version,
+                },
+                TableVersion {
+                    name: "foo".to_owned(),
+                    db_version: foo_version,
+                },
+            ]
+        );
+
+        let Json(tables) = get_tables(
+            Path(AppEnv::TEST_DB_NAME.to_owned()),
+            State(state),
+            DatabasePermissions::Partial {
+                database: PartialPermissions::default(),
+                tables: HashMap::from([(
+                    "bar".to_owned(),
+                    ObjectPermissions::partial(PartialPermissions {
+                        read: true,
+                        ..PartialPermissions::default()
+                    }),
+                )]),
+            },
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(
+            tables,
+            vec![TableVersion {
+                name: "bar".to_owned(),
+                db_version: bar_version,
+            }]
+        );
+    }
+}
@@ -0,0 +1,113 @@
+use axum::extract::{Json, Path, State};
+use serde::Serialize;
+
+use crate::{app_state::AppState, auth::DatabasePermissions, error::CRRError};
+
+use super::Database;
+
+#[derive(Serialize)]
+pub(crate) struct VacuumResponse {
+    size_before: u64,
+    size_after: u64,
+}
+
+/// Rebuilds the database file to reclaim the space of deleted rows.
+/// Change streams are closed first so nothing holds the database open, clients reconnect afterwards.
+pub(crate) async fn post_vacuum(
+    Path(db_name): Path<String>,
+    State(state): State<AppState>,
+    permissions: DatabasePermissions,
+) -> Result<Json<VacuumResponse>, CRRError> {
+    if !permissions.full() {
+        return Err(CRRError::Unauthorized(
+            "Full access is required to vacuum a database".to_owned(),
+        ));
+    }
+
+    state.change_manager().close(&db_name).await;
+    state.database_pool().retire(&db_name)?;
+
+    let mut db = Database::open(state.env(), db_name, permissions)?;
+
+    let size_before = db.size()?;
+    db.disable_authorization().execute_batch("VACUUM")?;
+    let size_after = db.size()?;
+
+    Ok(Json(VacuumResponse {
+        size_before,
+        size_after,
+    }))
+}
+
+impl Database {
+    /// size of the database in bytes, independent of what's still in the WAL
+    pub(crate) fn size(&self) -> Result<u64, CRRError> {
+        Ok(self.query_row(
+            "SELECT page_count * page_size FROM pragma_page_count(), pragma_page_size()",
+            [],
+            |row| row.get(0),
+        )?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::extract::{Json, Path, State};
+
+    use super::post_vacuum;
+    use crate::{
+        app_state::{AppEnv, AppState},
+        auth::DatabasePermissions,
+        database::{changes::Message, migrate::tests::setup_foo},
+    };
+
+    #[tokio::test]
+    async fn shrink_after_delete() {
+        let state = AppState::test_state();
+        setup_foo(state.env());
+
+        {
+            let db = state.env().test_db();
+            for _ in 0..200 {
+                db.execute("INSERT INTO foo (bar) VALUES (?)", ["x".repeat(4096)])
+                    .unwrap();
+            }
+            db.execute("DELETE FROM foo", []).unwrap();
+        }
+
+        let mut subscription = state
+            .change_manager()
+            .subscribe(state.env(), AppEnv::TEST_DB_NAME)
+            .await
+            .unwrap();
+
+        let Json(response) = post_vacuum(
+            Path(AppEnv::TEST_DB_NAME.to_owned()),
+            State(state.clone()),
+            DatabasePermissions::Full,
+        )
+        .await
+        .unwrap();
+
+        assert!(response.size_after < response.size_before);
+
+        loop {
+            match subscription
+                .recv()
+                .await
+                .expect("Failed to receive message")
+            {
+                Message::Closing => break,
+                _ => continue,
+            }
+        }
+
+        assert!(post_vacuum(
+            Path(AppEnv::TEST_DB_NAME.to_owned()),
+            State(state),
+            DatabasePermissions::default(),
+        )
+        .await
+        .is_err());
+    }
+}
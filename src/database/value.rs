@@ -4,8 +4,11 @@ use rusqlite::{
 };
 use serde::{Deserialize, Serialize};
 
-use crate::serde_base64;
+use crate::{error::CRRError, serde_base64};
 
+/// `pk` and `val` of `crsql_changes` are SQL literals as produced by `quote()`,
+/// e.g. the text `b` is `Text("'b'")` and a blob is `Text("X'01'")`.
+/// They're passed back to crsqlite verbatim, `ToSql` never adds quotes.
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 #[serde(untagged)]
 pub(crate) enum Value {
@@ -15,6 +18,9 @@ pub(crate) enum Value {
     Text(String),
     #[serde(with = "serde_base64")]
     Blob(Vec<u8>),
+    /// a Text value emitted as raw JSON, only produced for responses
+    #[serde(skip_deserializing)]
+    Json(serde_json::Value),
 }
 
 impl Value {
@@ -25,12 +31,103 @@ impl Value {
             Self::Real(_) => 8,
             Self::Text(value) => value.len(),
             Self::Blob(value) => value.len(),
+            Self::Json(value) => value.to_string().len(),
         }
     }
 
+    /// parses Text values as JSON, anything else (including invalid JSON) is kept as is
+    pub(crate) fn into_json(self) -> Self {
+        match self {
+            Self::Text(text) => match serde_json::from_str(&text) {
+                Ok(value) => Self::Json(value),
+                Err(_) => Self::Text(text),
+            },
+            value => value,
+        }
+    }
+
+    /// integers as well as integer literals of `crsql_changes`
+    pub(crate) fn as_integer(&self) -> Option<i64> {
+        match self {
+            Self::Integer(value) => Some(*value),
+            Self::Text(value) => value.parse().ok(),
+            _ => None,
+        }
+    }
+
+    /// The primary key values of a `pk` of `crsql_changes`,
+    /// compound keys are their quoted values joined by `|`, e.g. `1|'x'`
+    pub(crate) fn pk_values(&self) -> Result<Vec<rusqlite::types::Value>, CRRError> {
+        use rusqlite::types::Value as RusqliteValue;
+
+        let invalid = || CRRError::BadRequest(format!("Invalid primary key {:?}", self));
+
+        let mut literals = match self {
+            Self::Integer(value) => return Ok(vec![RusqliteValue::Integer(*value)]),
+            Self::Real(value) => return Ok(vec![RusqliteValue::Real(*value)]),
+            Self::Text(literals) => literals.as_str(),
+            _ => return Err(invalid()),
+        };
+        let mut values = Vec::new();
+
+        loop {
+            let (value, rest) = Self::unquote(literals).ok_or_else(invalid)?;
+            values.push(value);
+
+            match rest.strip_prefix('|') {
+                Some(rest) => literals = rest,
+                None if rest.is_empty() => return Ok(values),
+                None => return Err(invalid()),
+            }
+        }
+    }
+
+    /// the first literal of `literals` and whatever follows it
+    fn unquote(literals: &str) -> Option<(rusqlite::types::Value, &str)> {
+        use rusqlite::types::Value as RusqliteValue;
+
+        if let Some(quoted) = literals.strip_prefix('\'') {
+            let mut text = String::new();
+            let mut chars = quoted.char_indices();
+
+            while let Some((index, c)) = chars.next() {
+                match c {
+                    '\'' if quoted[index + 1..].starts_with('\'') => {
+                        text.push('\'');
+                        chars.next();
+                    }
+                    '\'' => return Some((RusqliteValue::Text(text), &quoted[index + 1..])),
+                    c => text.push(c),
+                }
+            }
+
+            None
+        } else if let Some(hex) = literals.strip_prefix("X'") {
+            let end = hex.find('\'')?;
+            let blob = (0..end)
+                .step_by(2)
+                .map(|index| u8::from_str_radix(hex.get(index..index + 2)?, 16).ok())
+                .collect::<Option<Vec<u8>>>()?;
+
+            Some((RusqliteValue::Blob(blob), &hex[end + 1..]))
+        } else {
+            let (literal, rest) = literals.split_at(literals.find('|').unwrap_or(literals.len()));
+            let value = match literal {
+                "NULL" => RusqliteValue::Null,
+                literal => match literal.parse() {
+                    Ok(value) => RusqliteValue::Integer(value),
+                    Err(_) => RusqliteValue::Real(literal.parse().ok()?),
+                },
+            };
+
+            Some((value, rest))
+        }
+    }
+
+    /// quotes `value` like SQLite's `quote()` does for change values
     #[cfg(test)]
     pub(crate) fn text(value: &str) -> Self {
-        Self::Text(format!("'{}'", value))
+        Self::Text(format!("'{}'", value.replace('\'', "''")))
     }
 }
 
@@ -57,6 +154,79 @@ impl ToSql for Value {
             Self::Real(value) => Ok(ToSqlOutput::Borrowed(ValueRef::Real(value.clone()))),
             Self::Null => Ok(ToSqlOutput::Borrowed(ValueRef::Null)),
             Self::Text(value) => Ok(ToSqlOutput::Borrowed(ValueRef::Text(value.as_bytes()))),
+            Self::Json(value) => Ok(ToSqlOutput::from(value.to_string())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rusqlite::Connection;
+
+    use super::Value;
+
+    #[test]
+    fn pass_text_verbatim() {
+        let conn = Connection::open_in_memory().unwrap();
+
+        for text in ["b", "'b'", "it's", "X'01'"] {
+            let value: Value = conn
+                .query_row("SELECT ?", [&Value::Text(text.to_owned())], |row| {
+                    row.get(0)
+                })
+                .unwrap();
+
+            assert_eq!(value, Value::Text(text.to_owned()), "Text is not quoted");
+        }
+    }
+
+    #[test]
+    fn quote_like_sqlite() {
+        let conn = Connection::open_in_memory().unwrap();
+
+        for text in ["b", "it's", ""] {
+            let quoted: Value = conn
+                .query_row("SELECT quote(?)", [text], |row| row.get(0))
+                .unwrap();
+
+            assert_eq!(quoted, Value::text(text));
+        }
+    }
+
+    #[test]
+    fn split_compound_pk() {
+        use rusqlite::types::Value as RusqliteValue;
+
+        let conn = Connection::open_in_memory().unwrap();
+
+        let pk: Value = conn
+            .query_row(
+                "SELECT quote(1) || '|' || quote('a|''b') || '|' || quote(X'01ff') || '|' || quote(1.5)",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+
+        assert_eq!(
+            pk.pk_values().unwrap(),
+            vec![
+                RusqliteValue::Integer(1),
+                RusqliteValue::Text("a|'b".to_owned()),
+                RusqliteValue::Blob(vec![0x01, 0xff]),
+                RusqliteValue::Real(1.5),
+            ]
+        );
+        assert_eq!(
+            Value::Integer(2).pk_values().unwrap(),
+            vec![RusqliteValue::Integer(2)]
+        );
+
+        for invalid in ["'a", "1|", "X'0'", "1 OR 1"] {
+            assert!(
+                Value::Text(invalid.to_owned()).pk_values().is_err(),
+                "{} is rejected",
+                invalid
+            );
         }
     }
 }
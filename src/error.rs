@@ -1,8 +1,8 @@
-use std::{backtrace::Backtrace, convert::Infallible};
+use std::{backtrace::Backtrace, convert::Infallible, time::Duration};
 
 use axum::{
     extract::rejection::PathRejection,
-    http::StatusCode,
+    http::{header::RETRY_AFTER, HeaderValue, StatusCode},
     response::{IntoResponse, Response},
     Json,
 };
@@ -22,24 +22,58 @@ pub enum CRRError {
     SmtpError(#[from] lettre::transport::smtp::Error),
     #[error("Mailing Error: {0}")]
     MailingError(#[from] lettre::error::Error),
+    #[error("Webhook Error: {0}")]
+    WebhookError(String),
     #[error("Invalid email-Address: {0}")]
     InvalidAddress(#[from] AddressError),
     #[error("Environment Error: {0}")]
     EnvVarError(#[from] std::env::VarError),
+    #[error("Invalid Bind Address \"{0}\": {1}")]
+    InvalidBindAddress(String, std::net::AddrParseError),
+    #[error("Invalid value \"{1}\" for Environment Variable {0}")]
+    InvalidEnvVar(&'static str, String),
     #[error("Message Passing Error: {0}")]
     BroadcastRecvError(#[from] tokio::sync::broadcast::error::RecvError),
     #[error("IO Error: {0}")]
     IOError(#[from] std::io::Error),
     #[error("Unauthorized: {0}")]
     Unauthorized(String),
+    #[error("Bad Request: {0}")]
+    BadRequest(String),
+    #[error("Query Timeout")]
+    QueryTimeout,
+    #[error("Too Many Requests: {0}")]
+    TooManyRequests(String, Duration),
+    #[error("Too Many Connections: at most {0} subscribers per database")]
+    TooManyConnections(usize),
+    #[error("Payload Too Large: {0}")]
+    PayloadTooLarge(String),
     #[error("Unsupported OS: {0}")]
     UnsupportedOS(String),
+    #[error("Data directory {0} is not writable: {1}")]
+    InvalidDataDir(std::path::PathBuf, std::io::Error),
+    #[error("crsqlite extension not found at {0}")]
+    MissingExtension(std::path::PathBuf),
     #[error("Poisoned Lock Error in {0}")]
     PoisonedLockError(&'static str),
+    #[error("Audit Log Error: {0}")]
+    AuditLogError(&'static str),
+    #[error("Background Task Error: {0}")]
+    TaskError(#[from] tokio::task::JoinError),
     #[error("Message Passing Error: {0}")]
     SignalSendError(#[from] tokio::sync::mpsc::error::SendError<()>),
     #[error("JSON Error: {0}")]
     JsonError(#[from] serde_json::Error),
+    #[error("MessagePack Encoding Error: {0}")]
+    MsgPackEncodeError(#[from] rmp_serde::encode::Error),
+    #[error("Invalid MessagePack: {0}")]
+    MsgPackDecodeError(#[from] rmp_serde::decode::Error),
+    #[error("Database {0} not found")]
+    DatabaseNotFound(String),
+    #[error("Replica of database {0} is not available yet")]
+    ReplicaUnavailable(String),
+    #[error("Not Found: {0}")]
+    NotFound(String),
     #[error("Database {0} is reserved for Internal Purposes")]
     ReservedName(String),
     #[error("Invalid Path Parameter: {0}")]
@@ -58,22 +92,51 @@ impl From<Infallible> for CRRError {
 #[error("{status_code}: {message}")]
 pub(crate) struct HttpError {
     status_code: StatusCode,
+    code: &'static str,
     message: String,
+    /// sent as `Retry-After` header
+    retry_after: Option<Duration>,
 }
 
 impl From<CRRError> for HttpError {
     fn from(value: CRRError) -> Self {
         tracing::error!("{}", value);
 
-        match value {
-            CRRError::Unauthorized(message) => Self {
-                status_code: StatusCode::UNAUTHORIZED,
-                message,
-            },
-            _ => Self {
-                status_code: StatusCode::INTERNAL_SERVER_ERROR,
-                message: "Internal Server Error".to_owned(),
-            },
+        let code = value.code();
+        let retry_after = match &value {
+            CRRError::TooManyRequests(_, retry_after) => Some(*retry_after),
+            _ => None,
+        };
+
+        let (status_code, message) = match value {
+            CRRError::Unauthorized(message) => (StatusCode::UNAUTHORIZED, message),
+            CRRError::BadRequest(message) => (StatusCode::BAD_REQUEST, message),
+            CRRError::QueryTimeout => (
+                StatusCode::REQUEST_TIMEOUT,
+                "Query exceeded the configured timeout".to_owned(),
+            ),
+            CRRError::TooManyRequests(message, _) => (StatusCode::TOO_MANY_REQUESTS, message),
+            CRRError::TooManyConnections(_) => (StatusCode::TOO_MANY_REQUESTS, value.to_string()),
+            CRRError::PayloadTooLarge(message) => (StatusCode::PAYLOAD_TOO_LARGE, message),
+            CRRError::DatabaseNotFound(_) => (StatusCode::NOT_FOUND, value.to_string()),
+            CRRError::ReplicaUnavailable(_) => (StatusCode::SERVICE_UNAVAILABLE, value.to_string()),
+            CRRError::NotFound(message) => (StatusCode::NOT_FOUND, message),
+            CRRError::ReservedName(_)
+            | CRRError::Base64DecodeError(_)
+            | CRRError::MsgPackDecodeError(_)
+            | CRRError::PathRejection(_)
+            | CRRError::InvalidURLError(_) => (StatusCode::BAD_REQUEST, value.to_string()),
+            _ => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Internal Server Error".to_owned(),
+            ),
+        };
+
+        Self {
+            status_code,
+            code,
+            message,
+            retry_after,
         }
     }
 }
@@ -83,21 +146,40 @@ impl HttpError {
         self.status_code
     }
 
-    fn message(&self) -> &str {
+    pub(crate) fn code(&self) -> &'static str {
+        self.code
+    }
+
+    pub(crate) fn message(&self) -> &str {
         &self.message
     }
 }
 
 impl IntoResponse for HttpError {
     fn into_response(self) -> Response {
-        (
+        let mut response = (
             self.status_code(),
-            Json(json!({ "message": self.message() })),
+            Json(json!({ "code": self.code(), "message": self.message() })),
         )
-            .into_response()
+            .into_response();
+
+        if let Some(retry_after) = self.retry_after {
+            response
+                .headers_mut()
+                .insert(RETRY_AFTER, retry_after_header(retry_after));
+        }
+
+        response
     }
 }
 
+/// whole seconds, rounded up so clients don't retry too early
+pub(crate) fn retry_after_header(retry_after: Duration) -> HeaderValue {
+    let seconds = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+
+    HeaderValue::from(seconds)
+}
+
 impl IntoResponse for CRRError {
     fn into_response(self) -> Response {
         let http_error: HttpError = self.into();
@@ -110,4 +192,72 @@ impl CRRError {
     pub(crate) fn unauthorized(msg: String) -> Self {
         Self::Unauthorized(msg)
     }
+
+    /// stable, machine-readable identifier for clients to branch on
+    pub(crate) fn code(&self) -> &'static str {
+        match self {
+            Self::DatabaseError(..) => "database",
+            Self::ParserError(_) => "parser",
+            Self::InvalidURLError(_) => "invalid_url",
+            Self::SmtpError(_) => "smtp",
+            Self::MailingError(_) => "mailing",
+            Self::WebhookError(_) => "webhook",
+            Self::InvalidAddress(_) => "invalid_address",
+            Self::EnvVarError(_) => "env_var",
+            Self::InvalidBindAddress(..) => "invalid_bind_address",
+            Self::InvalidEnvVar(..) => "invalid_env_var",
+            Self::BroadcastRecvError(_) => "broadcast_recv",
+            Self::IOError(_) => "io",
+            Self::Unauthorized(_) => "unauthorized",
+            Self::BadRequest(_) => "bad_request",
+            Self::QueryTimeout => "query_timeout",
+            Self::TooManyRequests(..) => "too_many_requests",
+            Self::TooManyConnections(_) => "too_many_connections",
+            Self::ReplicaUnavailable(_) => "replica_unavailable",
+            Self::PayloadTooLarge(_) => "payload_too_large",
+            Self::UnsupportedOS(_) => "unsupported_os",
+            Self::InvalidDataDir(..) => "invalid_data_dir",
+            Self::MissingExtension(_) => "missing_extension",
+            Self::PoisonedLockError(_) => "poisoned_lock",
+            Self::AuditLogError(_) => "audit_log",
+            Self::TaskError(_) => "task",
+            Self::SignalSendError(_) => "signal_send",
+            Self::JsonError(_) => "json",
+            Self::MsgPackEncodeError(_) => "msgpack_encode",
+            Self::MsgPackDecodeError(_) => "msgpack_decode",
+            Self::DatabaseNotFound(_) => "database_not_found",
+            Self::NotFound(_) => "not_found",
+            Self::ReservedName(_) => "reserved_name",
+            Self::PathRejection(_) => "path_rejection",
+            Self::Base64DecodeError(_) => "base64_decode",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{body::HttpBody, http::StatusCode, response::IntoResponse};
+
+    use super::CRRError;
+
+    #[tokio::test]
+    async fn structured_error_body() {
+        let response = CRRError::ReservedName("auth".to_owned()).into_response();
+
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        let body = response
+            .into_body()
+            .data()
+            .await
+            .expect("Body is empty")
+            .expect("Failed to read body");
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+
+        assert_eq!(body["code"], "reserved_name");
+        assert_eq!(
+            body["message"],
+            "Database auth is reserved for Internal Purposes"
+        );
+    }
 }
@@ -0,0 +1,83 @@
+use std::time::Duration;
+
+use axum::{
+    extract::State,
+    http::{header::RETRY_AFTER, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde_json::json;
+
+use crate::{
+    app_state::AppState,
+    auth::{AuthDatabase, DatabasePermissions},
+    database::Database,
+    error::retry_after_header,
+};
+
+/// suggested delay before probing readiness again
+const NOT_READY_RETRY_AFTER: Duration = Duration::from_secs(5);
+
+pub(crate) async fn get_health() -> StatusCode {
+    StatusCode::OK
+}
+
+pub(crate) async fn get_ready(State(state): State<AppState>) -> Response {
+    let auth_check = AuthDatabase::open(state.env().clone()).and_then(|auth| {
+        auth.query_row("SELECT 1", [], |row| row.get::<usize, i64>(0))?;
+        Ok(())
+    });
+
+    if let Err(error) = auth_check {
+        return not_ready(format!("Auth database unavailable: {}", error));
+    }
+
+    if let Err(error) = Database::open_in_memory(state.env(), DatabasePermissions::Full) {
+        return not_ready(format!("Failed to load crsqlite: {}", error));
+    }
+
+    (StatusCode::OK, Json(json!({ "ready": true }))).into_response()
+}
+
+fn not_ready(reason: String) -> Response {
+    tracing::error!("Not ready: {}", reason);
+
+    (
+        StatusCode::SERVICE_UNAVAILABLE,
+        [(RETRY_AFTER, retry_after_header(NOT_READY_RETRY_AFTER))],
+        Json(json!({ "ready": false, "reason": reason })),
+    )
+        .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        extract::State,
+        http::{header::RETRY_AFTER, StatusCode},
+    };
+
+    use super::{get_health, get_ready};
+    use crate::app_state::{AppEnv, AppState};
+
+    #[tokio::test]
+    async fn health_and_readiness() {
+        assert_eq!(get_health().await, StatusCode::OK);
+
+        let state = AppState::test_state();
+        assert_eq!(get_ready(State(state)).await.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn retry_when_not_ready() {
+        let state = AppState::test_state().with_env(AppEnv::with_crsqlite_path(
+            AppEnv::test_env(),
+            "./extensions/crsqlite-missing.so",
+        ));
+
+        let response = get_ready(State(state)).await;
+
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers()[RETRY_AFTER], "5");
+    }
+}
@@ -2,17 +2,236 @@
 #![feature(provide_any)]
 
 pub mod app_state;
+mod audit;
 pub mod auth;
+mod compression;
 mod database;
 pub(crate) mod error;
+mod health;
 pub(crate) mod mail;
+mod metrics;
 mod serde_base64;
+mod serde_base64_urlsafe;
+pub mod tls;
 
-use app_state::AppState;
-use axum::Router;
+use app_state::{AppEnv, AppState};
+use axum::{
+    extract::DefaultBodyLimit,
+    http::{
+        header::{AUTHORIZATION, CONTENT_TYPE},
+        HeaderName, HeaderValue, Method, Request,
+    },
+    middleware,
+    routing::get,
+    Router,
+};
+use tower_http::{
+    compression::CompressionLayer,
+    cors::{AllowOrigin, CorsLayer},
+    request_id::{MakeRequestId, PropagateRequestIdLayer, RequestId, SetRequestIdLayer},
+    trace::TraceLayer,
+};
+use tracing::Span;
 
-pub fn router() -> Router<AppState> {
+const X_REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+
+pub fn router(env: &AppEnv) -> Router<AppState> {
     Router::<AppState>::new()
+        .route("/health", get(health::get_health))
+        .route("/ready", get(health::get_ready))
+        .route("/metrics", get(metrics::get_metrics))
         .nest("/auth", auth::router())
         .nest("/db", database::router())
+        // enforced by the body extractors, which answer with 413 once the limit is exceeded
+        .layer(DefaultBodyLimit::max(env.max_body_bytes()))
+        // the default predicate skips text/event-stream, which is gzipped per event instead
+        .layer(CompressionLayer::new())
+        .layer(middleware::from_fn(compression::compress_event_stream))
+        .layer(cors(env))
+        .layer(PropagateRequestIdLayer::new(X_REQUEST_ID))
+        .layer(TraceLayer::new_for_http().make_span_with(request_span))
+        // outermost, so the id is set before the span is created
+        .layer(SetRequestIdLayer::new(X_REQUEST_ID, MakeRequestNanoid))
+}
+
+/// keeps the `X-Request-Id` sent by the client, generates one otherwise
+#[derive(Clone, Copy)]
+struct MakeRequestNanoid;
+
+impl MakeRequestId for MakeRequestNanoid {
+    fn make_request_id<B>(&mut self, _request: &Request<B>) -> Option<RequestId> {
+        HeaderValue::from_str(&nanoid::nanoid!())
+            .ok()
+            .map(RequestId::new)
+    }
+}
+
+/// every log line of a request carries its id
+fn request_span<B>(request: &Request<B>) -> Span {
+    let request_id = request
+        .headers()
+        .get(X_REQUEST_ID)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default();
+
+    tracing::info_span!(
+        "request",
+        method = %request.method(),
+        uri = %request.uri(),
+        request_id
+    )
+}
+
+fn cors(env: &AppEnv) -> CorsLayer {
+    let allowed_origins = env.allowed_origins();
+
+    // any website could send credentialed requests with the refresh token cookie otherwise,
+    // so credentials are only allowed for an explicit list of origins
+    let any_origin = allowed_origins.iter().any(|origin| origin == "*");
+    let allow_origin = if any_origin {
+        AllowOrigin::any()
+    } else {
+        AllowOrigin::list(allowed_origins.iter().cloned())
+    };
+
+    CorsLayer::new()
+        .allow_origin(allow_origin)
+        .allow_methods([Method::GET, Method::POST, Method::DELETE])
+        .allow_headers([AUTHORIZATION, CONTENT_TYPE, X_REQUEST_ID])
+        .expose_headers([X_REQUEST_ID])
+        .allow_credentials(!any_origin)
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::Body,
+        http::{
+            header::{
+                ACCESS_CONTROL_ALLOW_CREDENTIALS, ACCESS_CONTROL_ALLOW_ORIGIN,
+                ACCESS_CONTROL_REQUEST_METHOD, AUTHORIZATION, CONTENT_TYPE, ORIGIN,
+            },
+            Method, Request, StatusCode,
+        },
+    };
+    use tower::ServiceExt;
+
+    use crate::{
+        app_state::{AppEnv, AppState},
+        auth::AuthDatabase,
+        X_REQUEST_ID,
+    };
+
+    fn preflight(origin: &str) -> Request<Body> {
+        Request::builder()
+            .method(Method::OPTIONS)
+            .uri(format!("/db/{}/changes", AppEnv::TEST_DB_NAME))
+            .header(ORIGIN, origin)
+            .header(ACCESS_CONTROL_REQUEST_METHOD, "GET")
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn echo_allowed_origin() {
+        let env = AppEnv::with_allowed_origins(
+            AppEnv::test_env(),
+            "https://example.com, https://example.org",
+        );
+        let app = crate::router(&env).with_state(AppState::test_state());
+
+        let res = app
+            .clone()
+            .oneshot(preflight("https://example.org"))
+            .await
+            .unwrap();
+
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(
+            res.headers()[ACCESS_CONTROL_ALLOW_ORIGIN],
+            "https://example.org"
+        );
+        assert_eq!(res.headers()[ACCESS_CONTROL_ALLOW_CREDENTIALS], "true");
+
+        let res = app.oneshot(preflight("https://evil.com")).await.unwrap();
+
+        assert!(res.headers().get(ACCESS_CONTROL_ALLOW_ORIGIN).is_none());
+    }
+
+    #[tokio::test]
+    async fn allow_any_origin_without_credentials() {
+        let env = AppEnv::with_allowed_origins(AppEnv::test_env(), "*");
+        let app = crate::router(&env).with_state(AppState::test_state());
+
+        let res = app.oneshot(preflight("https://example.com")).await.unwrap();
+
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()[ACCESS_CONTROL_ALLOW_ORIGIN], "*");
+        assert!(res
+            .headers()
+            .get(ACCESS_CONTROL_ALLOW_CREDENTIALS)
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn echo_request_id() {
+        let state = AppState::test_state();
+        let app = crate::router(state.env()).with_state(state.clone());
+
+        let res = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri("/health")
+                    .header(X_REQUEST_ID, "client-id")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(res.headers()[X_REQUEST_ID], "client-id");
+
+        let res = app
+            .oneshot(
+                Request::builder()
+                    .uri("/health")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert!(
+            !res.headers()[X_REQUEST_ID].is_empty(),
+            "Generates a request id"
+        );
+    }
+
+    #[tokio::test]
+    async fn reject_oversized_body() {
+        let state = AppState::test_state();
+        let token = AuthDatabase::create_test_token(state.env());
+        let app = crate::router(state.env()).with_state(state.clone());
+
+        for path in ["run", "changes"] {
+            let body = vec![b' '; state.env().max_body_bytes() + 1];
+
+            let res = app
+                .clone()
+                .oneshot(
+                    Request::builder()
+                        .method(Method::POST)
+                        .uri(format!("/db/oversized/{}", path))
+                        .header(AUTHORIZATION, format!("Bearer {}", token))
+                        .header(CONTENT_TYPE, "application/json")
+                        .body(Body::from(body))
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+
+            assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        }
+    }
 }
@@ -1,24 +1,236 @@
+use std::{str::FromStr, time::Duration};
+
 use crate::error::CRRError;
-use lettre::Transport;
-
-pub(crate) fn send_email(receiver: &str, subject: String, message: String) -> Result<(), CRRError> {
-    let credentials = lettre::transport::smtp::authentication::Credentials::new(
-        std::env::var("SMTP_USERNAME")?,
-        std::env::var("SMTP_PASSWORD")?,
-    );
-    let mailer = lettre::SmtpTransport::relay(&std::env::var("SMTP_SERVER")?)?
-        .credentials(credentials)
-        .port(465)
-        .build();
-
-    let sender = std::env::var("SMTP_SENDER")?;
-    let email = lettre::Message::builder()
-        .from(sender.parse()?)
+use lettre::{
+    message::{Mailbox, MultiPart},
+    SmtpTransport, Transport,
+};
+use serde_json::json;
+
+/// delivers one-time passwords to users
+pub(crate) trait Notifier: Send + Sync {
+    fn send_otp(&self, email: &str, otp: &str) -> Result<(), CRRError>;
+}
+
+pub(crate) trait Mailer: Send + Sync {
+    /// sends a multipart email if `html` is set, plain text otherwise
+    fn send(
+        &self,
+        receiver: &str,
+        subject: String,
+        text: String,
+        html: Option<String>,
+    ) -> Result<(), CRRError>;
+}
+
+/// Trims and lowercases an email address so different spellings map to one user,
+/// malformed addresses are rejected
+pub(crate) fn normalize_email(email: &str) -> Result<String, CRRError> {
+    let email = email.trim().to_lowercase();
+
+    email
+        .parse::<lettre::Address>()
+        .map_err(|error| CRRError::BadRequest(format!("Invalid email address: {}", error)))?;
+
+    Ok(email)
+}
+
+/// substitutes `{{otp}}` in an email template
+pub(crate) fn render_otp_template(template: &str, otp: &str) -> String {
+    template.replace("{{otp}}", otp)
+}
+
+fn build_message(
+    sender: Mailbox,
+    receiver: &str,
+    subject: String,
+    text: String,
+    html: Option<String>,
+) -> Result<lettre::Message, CRRError> {
+    let builder = lettre::Message::builder()
+        .from(sender)
         .to(receiver.parse()?)
-        .subject(subject)
-        .body(message)?;
+        .subject(subject);
+
+    let email = match html {
+        Some(html) => builder.multipart(MultiPart::alternative_plain_html(text, html))?,
+        None => builder.body(text)?,
+    };
+
+    Ok(email)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum OtpChannel {
+    Email,
+    Webhook,
+}
+
+impl FromStr for OtpChannel {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "email" => Ok(Self::Email),
+            "webhook" => Ok(Self::Webhook),
+            _ => Err(value.to_owned()),
+        }
+    }
+}
+
+/// sends OTPs by email, as HTML if a template is configured
+pub(crate) struct EmailNotifier {
+    mailer: Box<dyn Mailer>,
+    template: Option<String>,
+}
+
+impl EmailNotifier {
+    pub(crate) fn new(mailer: Box<dyn Mailer>, template: Option<String>) -> Self {
+        Self { mailer, template }
+    }
+}
+
+impl Notifier for EmailNotifier {
+    fn send_otp(&self, email: &str, otp: &str) -> Result<(), CRRError> {
+        let html = self
+            .template
+            .as_deref()
+            .map(|template| render_otp_template(template, otp));
+
+        self.mailer
+            .send(email, "Your OTP".to_owned(), otp.to_owned(), html)
+    }
+}
+
+/// a webhook that doesn't answer within this time fails the OTP request
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// POSTs `{"email": ..., "otp": ...}` to a webhook, e.g. an SMS gateway
+pub(crate) struct WebhookNotifier {
+    url: String,
+    agent: ureq::Agent,
+}
+
+impl WebhookNotifier {
+    pub(crate) fn new(url: String) -> Self {
+        Self::with_timeout(url, WEBHOOK_TIMEOUT)
+    }
+
+    pub(crate) fn with_timeout(url: String, timeout: Duration) -> Self {
+        Self {
+            url,
+            agent: ureq::AgentBuilder::new().timeout(timeout).build(),
+        }
+    }
+}
+
+impl Notifier for WebhookNotifier {
+    fn send_otp(&self, email: &str, otp: &str) -> Result<(), CRRError> {
+        self.agent
+            .post(&self.url)
+            .send_json(json!({ "email": email, "otp": otp }))
+            .map_err(|error| CRRError::WebhookError(error.to_string()))?;
+
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum MailBackend {
+    Smtp,
+    Stdout,
+}
+
+impl FromStr for MailBackend {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "smtp" => Ok(Self::Smtp),
+            "stdout" => Ok(Self::Stdout),
+            _ => Err(value.to_owned()),
+        }
+    }
+}
+
+pub(crate) struct SmtpMailer {
+    transport: SmtpTransport,
+    sender: Mailbox,
+}
+
+impl SmtpMailer {
+    pub(crate) fn from_env(port: u16) -> Result<Self, CRRError> {
+        let credentials = lettre::transport::smtp::authentication::Credentials::new(
+            std::env::var("SMTP_USERNAME")?,
+            std::env::var("SMTP_PASSWORD")?,
+        );
+        let transport = SmtpTransport::relay(&std::env::var("SMTP_SERVER")?)?
+            .credentials(credentials)
+            .port(port)
+            .build();
+
+        Ok(Self {
+            transport,
+            sender: std::env::var("SMTP_SENDER")?.parse()?,
+        })
+    }
+}
+
+impl Mailer for SmtpMailer {
+    fn send(
+        &self,
+        receiver: &str,
+        subject: String,
+        text: String,
+        html: Option<String>,
+    ) -> Result<(), CRRError> {
+        let email = build_message(self.sender.clone(), receiver, subject, text, html)?;
+
+        self.transport.send(&email)?;
+
+        Ok(())
+    }
+}
+
+/// prints emails instead of sending them, for local development
+pub(crate) struct StdoutMailer;
+
+impl Mailer for StdoutMailer {
+    fn send(
+        &self,
+        receiver: &str,
+        subject: String,
+        text: String,
+        _html: Option<String>,
+    ) -> Result<(), CRRError> {
+        println!("To: {}\nSubject: {}\n\n{}\n", receiver, subject, text);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{build_message, render_otp_template};
+
+    #[test]
+    fn render_html_template() {
+        let html = render_otp_template("<p>Your code is <b>{{otp}}</b></p>", "abc123");
+
+        let email = build_message(
+            "OTP Service <otp@example.com>".parse().unwrap(),
+            "test@example.com",
+            "Your OTP".to_owned(),
+            "abc123".to_owned(),
+            Some(html),
+        )
+        .expect("Failed to build message");
 
-    mailer.send(&email)?;
+        let formatted = String::from_utf8(email.formatted()).unwrap();
 
-    Ok(())
+        assert!(formatted.contains("multipart/alternative"));
+        assert!(formatted.contains("text/html"));
+        assert!(formatted.contains("<p>Your code is <b>abc123</b></p>"));
+        assert!(!formatted.contains("{{otp}}"));
+    }
 }
@@ -1,5 +1,6 @@
 use axum::Server;
-pub(crate) use crr_server::{app_state::AppState, auth::AuthDatabase, router};
+use axum_server::{AddrIncomingConfig, Handle, HttpConfig};
+pub(crate) use crr_server::{app_state::AppState, auth::AuthDatabase, router, tls::rustls_config};
 
 #[tokio::main]
 async fn main() {
@@ -7,22 +8,105 @@ async fn main() {
 
     dotenv::dotenv().expect("Failed to read environment");
 
-    let state = AppState::init();
+    let state = AppState::init().expect("Failed to load configuration");
+
+    if let Err(error) = state.env().validate() {
+        tracing::error!("Invalid configuration: {}", error);
+        std::process::exit(1);
+    }
 
     let auth = AuthDatabase::open(state.env().clone()).expect("Failed to open Auth Database");
 
     auth.apply_migrations()
         .expect("Failed to apply Auth Migrations");
 
-    let app = router().with_state(state);
-
-    tracing::info!("Starting server...");
-    Server::bind(
-        &"0.0.0.0:6839"
-            .parse()
-            .expect("Failed to parse bind address"),
-    )
-    .serve(app.into_make_service())
-    .await
-    .expect("Failed to start server");
+    let bind_addr = state.env().bind_addr();
+    let enable_http2 = state.env().enable_http2();
+    let tcp_keepalive = state.env().tcp_keepalive();
+    let grace_period = state.env().shutdown_grace_period();
+    let app = router(state.env()).with_state(state.clone());
+
+    if let Some((cert, key)) = state.env().tls() {
+        let config = rustls_config(cert, key, enable_http2)
+            .await
+            .expect("Failed to load TLS certificate");
+        let handle = Handle::new();
+
+        tracing::info!("Starting server with TLS on {}...", bind_addr);
+        let server = axum_server::bind_rustls(bind_addr, config)
+            .addr_incoming_config(
+                AddrIncomingConfig::new()
+                    .tcp_keepalive(tcp_keepalive)
+                    .build(),
+            )
+            .http_config(HttpConfig::new().http1_only(!enable_http2).build())
+            .handle(handle.clone())
+            .serve(app.into_make_service());
+
+        tokio::pin!(server);
+
+        tokio::select! {
+            result = &mut server => result.expect("Failed to start server"),
+            _ = shutdown_signal() => {
+                tracing::info!("Shutting down...");
+                state.shutdown().await;
+                // connections still open after the grace period are dropped
+                handle.graceful_shutdown(Some(grace_period));
+                server.await.expect("Failed to shut down server");
+            }
+        }
+
+        return;
+    }
+
+    let (stop_sender, stop_receiver) = tokio::sync::oneshot::channel::<()>();
+
+    tracing::info!("Starting server on {}...", bind_addr);
+    let server = Server::bind(&bind_addr)
+        .tcp_keepalive(tcp_keepalive)
+        .http1_only(!enable_http2)
+        .serve(app.into_make_service())
+        .with_graceful_shutdown(async {
+            let _ = stop_receiver.await;
+        });
+
+    tokio::pin!(server);
+
+    tokio::select! {
+        result = &mut server => result.expect("Failed to start server"),
+        _ = shutdown_signal() => {
+            tracing::info!("Shutting down...");
+            state.shutdown().await;
+            let _ = stop_sender.send(());
+
+            match tokio::time::timeout(grace_period, server).await {
+                Ok(result) => result.expect("Failed to shut down server"),
+                Err(_) => tracing::warn!("Grace period elapsed, dropping remaining connections"),
+            }
+        }
+    }
+}
+
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("Failed to listen for SIGINT");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("Failed to listen for SIGTERM")
+            .recv()
+            .await;
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => (),
+        _ = terminate => (),
+    }
 }
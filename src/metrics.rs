@@ -0,0 +1,69 @@
+use axum::{
+    extract::{State, TypedHeader},
+    headers::{authorization::Bearer, Authorization},
+    http::header,
+    response::IntoResponse,
+};
+
+use crate::{app_state::AppState, auth::authenticate_admin, error::CRRError};
+
+/// authenticated with `CRR_ADMIN_SECRET` like the service token endpoint
+pub(crate) async fn get_metrics(
+    admin_secret: Option<TypedHeader<Authorization<Bearer>>>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, CRRError> {
+    authenticate_admin(&state, admin_secret)?;
+
+    let metrics = state.change_manager().metrics().await;
+
+    Ok((
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        metrics.to_prometheus(state.env().metrics_per_database()),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::{Body, HttpBody},
+        http::{header::AUTHORIZATION, Request, StatusCode},
+    };
+    use tower::ServiceExt;
+
+    use crate::app_state::{AppEnv, AppState};
+
+    #[tokio::test]
+    async fn require_admin_secret() {
+        let state = AppState::test_state()
+            .with_env(AppEnv::with_admin_secret(AppEnv::test_env(), "secret"));
+        let app = crate::router(state.env()).with_state(state.clone());
+
+        let request = |secret: Option<&str>| {
+            let mut req = Request::builder().uri("/metrics");
+
+            if let Some(secret) = secret {
+                req = req.header(AUTHORIZATION, format!("Bearer {}", secret));
+            }
+
+            req.body(Body::empty()).unwrap()
+        };
+
+        let res = app.clone().oneshot(request(None)).await.unwrap();
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+        let res = app.clone().oneshot(request(Some("wrong"))).await.unwrap();
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+        let mut res = app.oneshot(request(Some("secret"))).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let body = res.body_mut().data().await.unwrap().unwrap();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+
+        assert!(body.contains("crr_database_connections 0"));
+        assert!(
+            !body.contains("database=\""),
+            "Database names are only exported with CRR_METRICS_PER_DATABASE"
+        );
+    }
+}
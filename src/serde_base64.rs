@@ -1,9 +1,8 @@
 use base64::{engine::general_purpose::STANDARD as base64, Engine};
-use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde::{Deserialize, Deserializer, Serializer};
 
 pub fn serialize<S: Serializer>(v: &Vec<u8>, s: S) -> Result<S::Ok, S::Error> {
-    let encoded = base64.encode(v);
-    String::serialize(&encoded, s)
+    s.serialize_str(&base64.encode(v))
 }
 
 pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Vec<u8>, D::Error> {
@@ -0,0 +1,93 @@
+use base64::{
+    alphabet::URL_SAFE,
+    engine::{
+        general_purpose::{GeneralPurpose, GeneralPurposeConfig, STANDARD},
+        DecodePaddingMode,
+    },
+    Engine,
+};
+#[cfg(test)]
+use serde::{Deserialize, Deserializer, Serializer};
+
+/// padding is optional because `=` needs to be percent-encoded in urls as well
+const URL_SAFE_INDIFFERENT: GeneralPurpose = GeneralPurpose::new(
+    &URL_SAFE,
+    GeneralPurposeConfig::new()
+        .with_encode_padding(false)
+        .with_decode_padding_mode(DecodePaddingMode::Indifferent),
+);
+
+/// site ids are only read from query strings, serializing is for round trip tests
+#[cfg(test)]
+pub fn serialize<S: Serializer>(v: &Vec<u8>, s: S) -> Result<S::Ok, S::Error> {
+    s.serialize_str(&URL_SAFE_INDIFFERENT.encode(v))
+}
+
+/// also accepts the standard alphabet for clients that don't use the url-safe one yet
+pub fn decode(encoded: &str) -> Result<Vec<u8>, base64::DecodeError> {
+    URL_SAFE_INDIFFERENT
+        .decode(encoded.as_bytes())
+        .or_else(|_| STANDARD.decode(encoded.as_bytes()))
+}
+
+#[cfg(test)]
+pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Vec<u8>, D::Error> {
+    let encoded = String::deserialize(d)?;
+
+    decode(&encoded).map_err(|e| serde::de::Error::custom(e))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Serialize, Deserialize, PartialEq, Debug)]
+    struct Standard {
+        #[serde(with = "crate::serde_base64")]
+        site_id: Vec<u8>,
+    }
+
+    #[derive(Serialize, Deserialize, PartialEq, Debug)]
+    struct UrlSafe {
+        #[serde(with = "crate::serde_base64_urlsafe")]
+        site_id: Vec<u8>,
+    }
+
+    const SITE_ID: [u8; 16] = [
+        251, 255, 3, 166, 76, 47, 79, 47, 178, 78, 194, 120, 89, 221, 198, 254,
+    ];
+
+    #[test]
+    fn round_trip_site_id() {
+        let standard = Standard {
+            site_id: SITE_ID.to_vec(),
+        };
+        let url_safe = UrlSafe {
+            site_id: SITE_ID.to_vec(),
+        };
+
+        let standard_json = serde_json::to_string(&standard).unwrap();
+        let url_safe_json = serde_json::to_string(&url_safe).unwrap();
+
+        assert!(standard_json.contains('+') || standard_json.contains('/'));
+        assert!(!url_safe_json.contains('+') && !url_safe_json.contains('/'));
+        assert!(!url_safe_json.contains('='));
+
+        assert_eq!(
+            serde_json::from_str::<Standard>(&standard_json).unwrap(),
+            standard
+        );
+        assert_eq!(
+            serde_json::from_str::<UrlSafe>(&url_safe_json).unwrap(),
+            url_safe
+        );
+
+        // url-safe fields still accept the standard alphabet
+        assert_eq!(
+            serde_json::from_str::<UrlSafe>(&standard_json)
+                .unwrap()
+                .site_id,
+            SITE_ID.to_vec()
+        );
+    }
+}
@@ -0,0 +1,114 @@
+use std::{
+    io::{Error, ErrorKind},
+    path::Path,
+    sync::Arc,
+};
+
+use axum_server::tls_rustls::RustlsConfig;
+use rustls::{Certificate, PrivateKey, ServerConfig};
+use rustls_pemfile::Item;
+
+/// Loads the PEM encoded certificate chain and private key.
+/// ALPN only offers `h2` if HTTP/2 is enabled, clients that negotiate it
+/// with an HTTP/1.1-only server fail on their first request otherwise.
+pub async fn rustls_config(
+    cert: &Path,
+    key: &Path,
+    enable_http2: bool,
+) -> std::io::Result<RustlsConfig> {
+    let cert = tokio::fs::read(cert).await?;
+    let key = tokio::fs::read(key).await?;
+
+    let cert = rustls_pemfile::certs(&mut cert.as_ref())?
+        .into_iter()
+        .map(Certificate)
+        .collect();
+
+    let key = match rustls_pemfile::read_one(&mut key.as_ref())? {
+        Some(Item::RSAKey(key)) | Some(Item::PKCS8Key(key)) | Some(Item::ECKey(key)) => {
+            PrivateKey(key)
+        }
+        _ => return Err(Error::new(ErrorKind::InvalidData, "No private key found")),
+    };
+
+    let mut config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(cert, key)
+        .map_err(|error| Error::new(ErrorKind::InvalidData, error))?;
+
+    config.alpn_protocols = if enable_http2 {
+        vec![b"h2".to_vec(), b"http/1.1".to_vec()]
+    } else {
+        vec![b"http/1.1".to_vec()]
+    };
+
+    Ok(RustlsConfig::from_config(Arc::new(config)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use rustls::{Certificate, ClientConfig, RootCertStore, ServerName};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+    use super::rustls_config;
+
+    /// the ALPN protocol a client offering both HTTP versions ends up with
+    async fn negotiated_protocol(enable_http2: bool) -> Option<Vec<u8>> {
+        let generated = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+
+        let dir = std::env::temp_dir().join(format!("crr-tls-{}", nanoid::nanoid!()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let cert = dir.join("cert.pem");
+        let key = dir.join("key.pem");
+        std::fs::write(&cert, generated.serialize_pem().unwrap()).unwrap();
+        std::fs::write(&key, generated.serialize_private_key_pem()).unwrap();
+
+        let config = rustls_config(&cert, &key, enable_http2).await.unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let acceptor = TlsAcceptor::from(config.get_inner());
+
+        let server = tokio::spawn(async move {
+            let (stream, _addr) = listener.accept().await.unwrap();
+            acceptor.accept(stream).await.unwrap();
+        });
+
+        let mut roots = RootCertStore::empty();
+        roots
+            .add(&Certificate(generated.serialize_der().unwrap()))
+            .unwrap();
+        let mut client_config = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        client_config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+
+        let stream = TlsConnector::from(Arc::new(client_config))
+            .connect(
+                ServerName::try_from("localhost").unwrap(),
+                TcpStream::connect(addr).await.unwrap(),
+            )
+            .await
+            .unwrap();
+
+        server.await.unwrap();
+
+        stream.get_ref().1.alpn_protocol().map(<[u8]>::to_vec)
+    }
+
+    #[tokio::test]
+    async fn negotiate_http1_without_http2() {
+        assert_eq!(negotiated_protocol(false).await, Some(b"http/1.1".to_vec()));
+    }
+
+    #[tokio::test]
+    async fn negotiate_http2_if_enabled() {
+        assert_eq!(negotiated_protocol(true).await, Some(b"h2".to_vec()));
+    }
+}
@@ -30,13 +30,13 @@ fn prepare_app(token: &str) -> Router<()> {
     let user_id = auth.last_insert_rowid();
 
     auth.prepare(
-        "INSERT INTO tokens (user_id, token, expires) VALUES (?, ?, DATE('now', '+1 day'))",
+        "INSERT INTO access_tokens (user_id, token, expires) VALUES (?, ?, JULIANDAY('now') + 1)",
     )
     .unwrap()
     .insert(params![user_id, token])
     .unwrap();
 
-    router().with_state(state)
+    router(state.env()).with_state(state)
 }
 
 async fn run_tests(path: &Path, url: &str, token: &str) {